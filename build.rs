@@ -0,0 +1,136 @@
+// Builds the userlib example programs for the kernel target and packs them, together
+// with everything under initrd/, into $OUT_DIR/initrd.tar for `fs::init` to unpack.
+// Files go in with their SHA-256 and, where it pays, LZ4-compressed (see fs::initrd).
+extern crate alloc;
+
+// the kernel's own encoder and hash, so both sides agree
+#[allow(dead_code)]
+#[path = "src/compress.rs"]
+mod compress;
+#[allow(dead_code)]
+#[path = "src/sha256.rs"]
+mod sha256;
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::{env, fs};
+
+// (example name, path inside the initrd)
+const USER_BINS: &[(&str, &str)] = &[("hello", "bin/hello-rs"), ("cat", "bin/cat"), ("upper", "bin/upper")];
+
+// Files whose mode can't come from the checkout (see `executable`)
+const MODES: &[(&str, u32)] = &[("etc/shadow", 0o600)];
+
+fn main() {
+    let out = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let target_dir = out.join("userlib-target");
+    let cargo = env::var("CARGO").unwrap_or_else(|_| "cargo".into());
+
+    // Static, non-PIE executables at 4 MiB so the ELF loader can map p_vaddr as-is.
+    let status = Command::new(cargo)
+        .current_dir("userlib")
+        .args(["build", "--release", "--examples", "--target", "x86_64-unknown-none", "--target-dir"])
+        .arg(&target_dir)
+        .env_remove("RUSTFLAGS")
+        .env("CARGO_ENCODED_RUSTFLAGS", "-Crelocation-model=static\x1f-Clink-arg=--image-base=0x400000")
+        .status();
+    let built = matches!(status, Ok(s) if s.success());
+    if !built { println!("cargo:warning=userlib examples failed to build; /bin will not contain them"); }
+
+    let mut tar = Vec::new();
+    add_tree(&mut tar, Path::new("initrd"), "");
+    let examples = target_dir.join("x86_64-unknown-none/release/examples");
+    for (name, path) in USER_BINS.iter().filter(|_| built) {
+        if let Ok(data) = fs::read(examples.join(name)) { add_file(&mut tar, path, 0o755, &data); }
+    }
+    tar.extend_from_slice(&[0; 1024]); // end-of-archive marker
+    fs::write(out.join("initrd.tar"), tar).unwrap();
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/compress.rs");
+    println!("cargo:rerun-if-changed=src/sha256.rs");
+    println!("cargo:rerun-if-changed=initrd");
+    println!("cargo:rerun-if-changed=userlib/src");
+    println!("cargo:rerun-if-changed=userlib/examples");
+}
+
+// Archive `dir` recursively in name order so the output is reproducible.
+fn add_tree(tar: &mut Vec<u8>, dir: &Path, prefix: &str) {
+    let mut entries: Vec<_> = fs::read_dir(dir).unwrap().map(|e| e.unwrap()).collect();
+    entries.sort_by_key(|e| e.file_name());
+    for e in entries {
+        let path = format!("{}{}", prefix, e.file_name().to_string_lossy());
+        let meta = fs::symlink_metadata(e.path()).unwrap();
+        if meta.file_type().is_symlink() {
+            let target = fs::read_link(e.path()).unwrap();
+            tar_entry(tar, &path, b'2', 0o777, &[], &target.to_string_lossy());
+        } else if meta.is_dir() {
+            tar_entry(tar, &format!("{}/", path), b'5', 0o755, &[], "");
+            add_tree(tar, &e.path(), &format!("{}/", path));
+        } else {
+            let mode = MODES.iter().find(|m| m.0 == path).map(|m| m.1).unwrap_or(if executable(&meta) { 0o755 } else { 0o644 });
+            add_file(tar, &path, mode, &fs::read(e.path()).unwrap());
+        }
+    }
+}
+
+// Git only records the executable bit, so that is all we take from the checkout.
+#[cfg(unix)]
+fn executable(meta: &fs::Metadata) -> bool { use std::os::unix::fs::PermissionsExt; meta.permissions().mode() & 0o111 != 0 }
+
+#[cfg(not(unix))]
+fn executable(_meta: &fs::Metadata) -> bool { false }
+
+// "<len> key=value\n", where len counts the whole record including its own digits.
+fn pax_record(key: &str, value: &str) -> String {
+    let rest = format!(" {}={}\n", key, value);
+    let mut len = rest.len() + 1;
+    while len.to_string().len() + rest.len() != len { len = len.to_string().len() + rest.len(); }
+    format!("{}{}", len, rest)
+}
+
+// A regular file behind a pax header carrying its hash. Files LZ4 shrinks by at least an
+// eighth go in compressed, with the codec and unpacked size recorded too.
+fn add_file(tar: &mut Vec<u8>, path: &str, mode: u32, data: &[u8]) {
+    let mut pax = pax_record("WAEMOM.sha256", &sha256::hex(&sha256::sha256(data)));
+    let packed = compress::lz4_compress(data);
+    let body = if packed.len() < data.len() - data.len() / 8 {
+        assert!(compress::lz4_decompress(&packed, data.len()).as_deref() == Some(data), "LZ4 round trip failed for {}", path);
+        pax += &pax_record("WAEMOM.codec", compress::Codec::Lz4.name());
+        pax += &pax_record("WAEMOM.size", &data.len().to_string());
+        &packed[..]
+    } else {
+        data
+    };
+    tar_entry(tar, "PaxHeader", b'x', 0o644, pax.as_bytes(), "");
+    tar_entry(tar, path, b'0', mode, body, "");
+}
+
+// One ustar header plus its data padded to 512 bytes.
+fn tar_entry(tar: &mut Vec<u8>, path: &str, kind: u8, mode: u32, data: &[u8], link: &str) {
+    assert!(path.len() <= 100 && link.len() <= 100, "initrd path too long for ustar: {}", path);
+    let octal = |f: &mut [u8], v: u64| {
+        let s = format!("{:0w$o}", v, w = f.len() - 1);
+        f[..s.len()].copy_from_slice(s.as_bytes());
+    };
+    let mut h = [0u8; 512];
+    h[..path.len()].copy_from_slice(path.as_bytes());
+    octal(&mut h[100..108], mode as u64);
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    octal(&mut h[124..136], data.len() as u64);
+    octal(&mut h[136..148], 0);
+    h[156] = kind;
+    h[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    h[265..269].copy_from_slice(b"root");
+    h[297..301].copy_from_slice(b"root");
+    h[148..156].fill(b' ');
+    let sum: u32 = h.iter().map(|&b| b as u32).sum();
+    octal(&mut h[148..155], sum as u64);
+    h[154] = 0;
+    tar.extend_from_slice(&h);
+    tar.extend_from_slice(data);
+    tar.resize(tar.len().next_multiple_of(512), 0);
+}
@@ -0,0 +1,75 @@
+// Stand-ins for the kernel modules the shared files refer to but mkimage never exercises.
+#![allow(dead_code)]
+
+pub mod ata {
+    use crate::block::BlockError;
+
+    pub struct Drive { pub sectors: u64 }
+    pub struct AtaError;
+
+    impl From<AtaError> for BlockError {
+        fn from(_: AtaError) -> Self { BlockError::NoDevice }
+    }
+
+    pub fn read(_d: &Drive, _lba: u64, _buf: &mut [u8]) -> Result<(), AtaError> { Err(AtaError) }
+    pub fn write(_d: &Drive, _lba: u64, _buf: &[u8]) -> Result<(), AtaError> { Err(AtaError) }
+    pub fn flush(_d: &Drive) -> Result<(), AtaError> { Err(AtaError) }
+}
+
+// No cache: every access goes straight to the device.
+pub mod bcache {
+    use crate::block::{BlockError, Shared};
+
+    pub fn attach(_id: usize, _dev: Shared) {}
+    pub fn detach(_id: usize) {}
+    pub fn read(_id: usize, shared: &Shared, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> { shared.lock().read_blocks(lba, buf) }
+    pub fn write(_id: usize, shared: &Shared, lba: u64, buf: &[u8]) -> Result<(), BlockError> { shared.lock().write_blocks(lba, buf) }
+    pub fn sync_dev(_id: usize, shared: &Shared) -> Result<(), BlockError> { shared.lock().flush() }
+}
+
+pub mod pit {
+    pub fn ticks() -> u64 { 0 }
+    pub fn hz() -> u64 { 1000 }
+}
+
+pub mod task {
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct Creds { pub uid: u32, pub gid: u32 }
+
+    impl Creds {
+        pub const ROOT: Creds = Creds { uid: 0, gid: 0 };
+    }
+
+    // Just the pid and handle table, for the IPC tests.
+    #[cfg(test)]
+    pub struct Task { pub pid: u64, pub caps: Vec<Option<crate::ipc::Cap>> }
+
+    #[cfg(test)]
+    impl Task {
+        pub fn install_cap(&mut self, c: crate::ipc::Cap) -> usize {
+            if let Some(i) = self.caps.iter().position(|s| s.is_none()) { self.caps[i] = Some(c); return i; }
+            self.caps.push(Some(c));
+            self.caps.len() - 1
+        }
+    }
+}
+
+// Test threads stand in for tasks: each has its own current task, pid 0 until `set_pid`.
+pub mod scheduler {
+    use super::task::Creds;
+
+    pub fn current_creds() -> Creds { Creds::ROOT }
+    pub fn yield_now() { std::thread::yield_now() }
+
+    #[cfg(test)]
+    std::thread_local! {
+        static CURRENT: std::cell::RefCell<super::task::Task> = const { std::cell::RefCell::new(super::task::Task { pid: 0, caps: Vec::new() }) };
+    }
+
+    #[cfg(test)]
+    pub fn set_pid(pid: u64) { CURRENT.with(|t| t.borrow_mut().pid = pid) }
+    #[cfg(test)]
+    pub fn current_pid() -> Option<u64> { Some(CURRENT.with(|t| t.borrow().pid)) }
+    #[cfg(test)]
+    pub fn with_current<T>(f: impl FnOnce(&mut super::task::Task) -> T) -> Option<T> { Some(CURRENT.with(|t| f(&mut t.borrow_mut()))) }
+}
@@ -0,0 +1,300 @@
+// Laying out the image, filling it from the source tree and reading it back.
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use crate::block::{BlockDevice, BlockError};
+use crate::fs::fat::{self, FatFs, FatType};
+use crate::fs::vfs::{FileType, Filesystem, FsError, Ino};
+use crate::partition::{self, PartKind, Partition, PartitionDevice};
+
+const BLOCK: usize = 512;
+// 1 MiB in, where partitioning tools put the first partition
+const PART_START: u64 = 2048;
+// the backup GPT table and header at the end of the disk
+const GPT_TAIL: u64 = 33;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Table { Mbr, Gpt, None }
+
+pub struct Options {
+    pub fat_type: FatType,
+    pub table: Table,
+    pub size: Option<u64>, // in bytes; picked from the tree when not given
+    pub label: String,
+    pub src: PathBuf,
+    pub out: PathBuf,
+}
+
+#[derive(Default)]
+pub struct Summary {
+    pub bytes: u64,
+    pub files: usize,
+    pub dirs: usize,
+}
+
+/// An image file as a block device.
+struct FileDisk {
+    file: File,
+    blocks: u64,
+}
+
+impl FileDisk {
+    fn at(&mut self, lba: u64, len: usize) -> Result<(), BlockError> {
+        if !len.is_multiple_of(BLOCK) { return Err(BlockError::BadBuffer); }
+        if lba + (len / BLOCK) as u64 > self.blocks { return Err(BlockError::OutOfRange); }
+        self.file.seek(SeekFrom::Start(lba * BLOCK as u64)).map_err(|_| BlockError::Io)?;
+        Ok(())
+    }
+}
+
+impl BlockDevice for FileDisk {
+    fn block_size(&self) -> usize { BLOCK }
+    fn capacity(&self) -> u64 { self.blocks }
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.at(lba, buf.len())?;
+        self.file.read_exact(buf).map_err(|_| BlockError::Io)
+    }
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        self.at(lba, buf.len())?;
+        self.file.write_all(buf).map_err(|_| BlockError::Io)
+    }
+    fn flush(&mut self) -> Result<(), BlockError> { self.file.sync_data().map_err(|_| BlockError::Io) }
+}
+
+fn io_err(path: &Path, e: io::Error) -> String { format!("{}: {}", path.display(), e) }
+fn fs_err(path: &Path, e: FsError) -> String { format!("{}: {:?}", path.display(), e) }
+
+// Subdirectories and regular files of `dir` in name order as (name, path, is_dir). Anything
+// else has no FAT equivalent and is left out, with a warning if `warn` is set.
+fn entries(dir: &Path, warn: bool) -> Result<Vec<(String, PathBuf, bool)>, String> {
+    let mut out = Vec::new();
+    for e in fs::read_dir(dir).map_err(|e| io_err(dir, e))? {
+        let e = e.map_err(|e| io_err(dir, e))?;
+        let path = e.path();
+        let kind = e.file_type().map_err(|e| io_err(&path, e))?;
+        if !kind.is_dir() && !kind.is_file() {
+            if warn { eprintln!("mkimage: skipping {}: not a file or directory", path.display()); }
+            continue;
+        }
+        let name = e.file_name().into_string().map_err(|_| format!("{}: name is not UTF-8", path.display()))?;
+        out.push((name, path, kind.is_dir()));
+    }
+    out.sort();
+    Ok(out)
+}
+
+// Bytes the tree takes in 4 KiB clusters, counting each entry as one more cluster.
+fn tree_size(dir: &Path) -> Result<u64, String> {
+    let mut total = 4096;
+    for (_, path, is_dir) in entries(dir, false)? {
+        total += 4096 + if is_dir { tree_size(&path)? } else { fs::metadata(&path).map_err(|e| io_err(&path, e))?.len().next_multiple_of(4096) };
+    }
+    Ok(total)
+}
+
+// The tree with a quarter to spare plus room for the partition table, in whole MiB, and no
+// smaller than the FAT type needs to have enough clusters.
+fn default_size(src: &Path, fat_type: FatType) -> Result<u64, String> {
+    let min: u64 = if fat_type == FatType::Fat32 { 64 << 20 } else { 16 << 20 };
+    Ok((tree_size(src)? * 5 / 4 + (2 << 20)).max(min).next_multiple_of(1 << 20))
+}
+
+fn copy_tree(fs: &mut FatFs, dir: Ino, src: &Path, sum: &mut Summary) -> Result<(), String> {
+    for (name, path, is_dir) in entries(src, true)? {
+        if is_dir {
+            let ino = fs.mkdir(dir, &name).map_err(|e| fs_err(&path, e))?;
+            copy_tree(fs, ino, &path, sum)?;
+            sum.dirs += 1;
+        } else {
+            let data = fs::read(&path).map_err(|e| io_err(&path, e))?;
+            let ino = fs.create(dir, &name).map_err(|e| fs_err(&path, e))?;
+            if fs.write_at(ino, 0, &data).map_err(|e| fs_err(&path, e))? != data.len() { return Err(fs_err(&path, FsError::NoSpace)); }
+            sum.files += 1;
+        }
+    }
+    Ok(())
+}
+
+// Same names, kinds and contents as the source tree, nothing more.
+fn verify_tree(fs: &mut FatFs, dir: Ino, src: &Path) -> Result<(), String> {
+    let want = entries(src, false)?;
+    let mut have: Vec<String> = fs.readdir(dir).map_err(|e| fs_err(src, e))?.into_iter().map(|e| e.name).collect();
+    have.sort();
+    if have.iter().ne(want.iter().map(|w| &w.0)) {
+        return Err(format!("{}: directory reads back as {:?}", src.display(), have));
+    }
+    for (name, path, is_dir) in want {
+        let ino = fs.lookup(dir, &name).map_err(|e| fs_err(&path, e))?;
+        let st = fs.stat(ino).map_err(|e| fs_err(&path, e))?;
+        if (st.kind == FileType::Dir) != is_dir { return Err(format!("{}: reads back as {:?}", path.display(), st.kind)); }
+        if is_dir { verify_tree(fs, ino, &path)?; continue; }
+        let data = fs::read(&path).map_err(|e| io_err(&path, e))?;
+        let mut back = vec![0u8; st.size as usize];
+        let n = fs.read_at(ino, 0, &mut back).map_err(|e| fs_err(&path, e))?;
+        if back[..n] != data[..] { return Err(format!("{}: contents differ when read back", path.display())); }
+    }
+    Ok(())
+}
+
+// A random-enough GPT disk GUID, with the version 4 and variant bits set.
+fn disk_guid(out: &Path) -> [u8; 16] {
+    let seed = format!("{:?} {} {}", std::time::SystemTime::now(), std::process::id(), out.display());
+    let mut g = [0u8; 16];
+    g.copy_from_slice(&crate::sha256::sha256(seed.as_bytes())[..16]);
+    g[7] = g[7] & 0x0F | 0x40;
+    g[8] = g[8] & 0x3F | 0x80;
+    g
+}
+
+/// Write the image described by `opts`, then reopen it the way the kernel would and check it
+/// holds exactly the source tree.
+pub fn build(opts: &Options) -> Result<Summary, String> {
+    if !opts.src.is_dir() { return Err(format!("{}: not a directory", opts.src.display())); }
+    let size = match opts.size { Some(s) => s, None => default_size(&opts.src, opts.fat_type)? };
+    let blocks = size / BLOCK as u64;
+    let (start, len) = match opts.table {
+        Table::None => (0, blocks),
+        Table::Mbr => (PART_START, blocks.saturating_sub(PART_START)),
+        Table::Gpt => (PART_START, blocks.saturating_sub(PART_START + GPT_TAIL)),
+    };
+    if len == 0 { return Err(format!("{} bytes is too small for a partition", size)); }
+
+    let file = OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&opts.out).map_err(|e| io_err(&opts.out, e))?;
+    file.set_len(blocks * BLOCK as u64).map_err(|e| io_err(&opts.out, e))?;
+    let mut disk = FileDisk { file, blocks };
+    let table = match opts.table {
+        Table::None => Ok(()),
+        Table::Mbr => {
+            let t = if opts.fat_type == FatType::Fat32 { 0x0C } else { 0x0E }; // LBA-addressed FAT32 / FAT16
+            partition::write_mbr(&mut disk, &[Partition { index: 1, start, blocks: len, kind: PartKind::Mbr(t) }])
+        }
+        Table::Gpt => {
+            let kind = PartKind::Gpt { type_guid: partition::GPT_BASIC_DATA, name: opts.label.clone() };
+            partition::write_gpt(&mut disk, &[Partition { index: 1, start, blocks: len, kind }], disk_guid(&opts.out))
+        }
+    };
+    table.map_err(|e| format!("writing the partition table: {:?}", e))?;
+
+    let mut vol = PartitionDevice::new(Box::new(disk), start, len);
+    fat::format(&mut vol, opts.fat_type, &opts.label).map_err(|e| match e {
+        FsError::Invalid => format!("a {} MiB volume is out of range for {}", (len * BLOCK as u64) >> 20, if opts.fat_type == FatType::Fat32 { "FAT32" } else { "FAT16" }),
+        e => format!("formatting: {:?}", e),
+    })?;
+    let mut fs = FatFs::new(Box::new(vol)).ok_or("the new volume doesn't read back as FAT")?;
+    let mut sum = Summary { bytes: blocks * BLOCK as u64, ..Summary::default() };
+    let root = fs.root();
+    copy_tree(&mut fs, root, &opts.src, &mut sum)?;
+    fs.sync().map_err(|e| fs_err(&opts.out, e))?;
+    drop(fs);
+
+    let file = File::open(&opts.out).map_err(|e| io_err(&opts.out, e))?;
+    let mut disk = FileDisk { file, blocks };
+    if opts.table != Table::None {
+        let found = partition::scan(&mut disk);
+        if !matches!(found.as_slice(), [p] if p.start == start && p.blocks == len) {
+            return Err(format!("partition table reads back as {:?}", found));
+        }
+    }
+    let mut vol = PartitionDevice::new(Box::new(disk), start, len);
+    if fat::read_bpb(&mut vol).map(|b| b.fat_type) != Some(opts.fat_type) { return Err("the volume reads back as a different FAT type".into()); }
+    let mut fs = FatFs::new(Box::new(vol)).ok_or("the volume doesn't read back as FAT")?;
+    let report = fs.check(false).map_err(|e| fs_err(&opts.out, e))?;
+    if !report.clean() || report.free_count_wrong { return Err(format!("fsck found problems: {:?}", report.problems)); }
+    let root = fs.root();
+    verify_tree(&mut fs, root, &opts.src)?;
+    Ok(sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+
+    // A scratch directory under the system temp dir, removed again when dropped.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("mkimage-test-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Scratch(dir)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) { let _ = fs::remove_dir_all(&self.0); }
+    }
+
+    fn tree(root: &Path) -> Vec<(&'static str, Vec<u8>)> {
+        let files = vec![
+            ("hello.txt", b"hello from the image\n".to_vec()),
+            ("A long file name.txt", b"needs LFN entries".to_vec()),
+            ("sub/data.bin", (0..20_000u32).map(|i| (i * 7 % 251) as u8).collect()),
+            ("sub/deeper/empty", Vec::new()),
+        ];
+        for (path, data) in &files {
+            let p = root.join(path);
+            fs::create_dir_all(p.parent().unwrap()).unwrap();
+            fs::write(p, data).unwrap();
+        }
+        fs::create_dir_all(root.join("sub/hollow")).unwrap();
+        files
+    }
+
+    fn opts(dir: &Scratch, fat_type: FatType, table: Table) -> Options {
+        Options { fat_type, table, size: None, label: "TEST".into(), src: dir.0.join("src"), out: dir.0.join("disk.img") }
+    }
+
+    // Open a finished image from scratch, the way the kernel finds and mounts it.
+    fn mount(image: Vec<u8>, table: Table) -> FatFs {
+        let mut disk = RamDisk::from_vec(image, BLOCK);
+        let (start, len) = match table {
+            Table::None => (0, disk.capacity()),
+            _ => match partition::scan(&mut disk).as_slice() {
+                [p] => (p.start, p.blocks),
+                found => panic!("partitions: {:?}", found),
+            },
+        };
+        FatFs::new(Box::new(PartitionDevice::new(Box::new(disk), start, len))).unwrap()
+    }
+
+    fn walk(fs: &mut FatFs, path: &str) -> Ino {
+        path.split('/').fold(fs.root(), |dir, name| fs.lookup(dir, name).unwrap())
+    }
+
+    #[test]
+    fn round_trip() {
+        for (name, fat_type, table) in [("fat16-mbr", FatType::Fat16, Table::Mbr), ("fat32-gpt", FatType::Fat32, Table::Gpt), ("fat16-bare", FatType::Fat16, Table::None)] {
+            let dir = Scratch::new(name);
+            let files = tree(&dir.0.join("src"));
+            let o = opts(&dir, fat_type, table);
+            let sum = build(&o).unwrap();
+            assert_eq!((sum.files, sum.dirs), (4, 3), "{}", name);
+            let image = fs::read(&o.out).unwrap();
+            assert_eq!(sum.bytes, image.len() as u64);
+            let mut fs = mount(image, table);
+            for (path, data) in &files {
+                let ino = walk(&mut fs, path);
+                let mut back = vec![0u8; data.len() + 1];
+                assert_eq!(fs.read_at(ino, 0, &mut back).unwrap(), data.len(), "{} {}", name, path);
+                assert_eq!(&back[..data.len()], &data[..], "{} {}", name, path);
+            }
+            let hollow = walk(&mut fs, "sub/hollow");
+            assert!(fs.readdir(hollow).unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn refuses_bad_input() {
+        let dir = Scratch::new("bad");
+        let mut o = opts(&dir, FatType::Fat16, Table::Mbr);
+        assert!(build(&o).is_err()); // no source tree
+        tree(&o.src);
+        o.size = Some(512 << 10); // smaller than the partition offset
+        assert!(build(&o).is_err());
+        o.fat_type = FatType::Fat32;
+        o.size = Some(8 << 20); // too few clusters for FAT32
+        assert!(build(&o).is_err());
+    }
+}
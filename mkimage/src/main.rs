@@ -0,0 +1,115 @@
+// mkimage: build a partitioned disk image holding a FAT16 or FAT32 copy of a directory tree.
+//
+// The image is written by the kernel's own code: `partition::write_mbr`/`write_gpt`,
+// `fat::format` and `FatFs`, compiled for the host from ../src. The few kernel services those
+// files reach for (disk drivers, block cache, clock, scheduler) are stubbed in `host`. Once
+// written, the image is reopened through `partition::scan` and `FatFs`, checked with
+// `FatFs::check` and compared file by file against the source tree.
+//
+// Compiling those files for the host also makes this the place their `#[cfg(test)]` unit tests
+// run: `cargo test` here.
+extern crate alloc;
+
+#[macro_export]
+macro_rules! serial_println { ($($t:tt)*) => { eprintln!($($t)*) } }
+
+#[allow(dead_code)]
+#[path = "../../src/block.rs"]
+mod block;
+#[allow(dead_code)]
+#[path = "../../src/compress.rs"]
+mod compress;
+#[allow(dead_code)]
+#[path = "../../src/partition.rs"]
+mod partition;
+// not used by mkimage; compiled for their tests
+#[cfg(test)]
+#[allow(dead_code)]
+#[path = "../../src/ipc.rs"]
+mod ipc;
+#[cfg(test)]
+#[path = "../../src/pipe.rs"]
+mod pipe;
+#[allow(dead_code)]
+#[path = "../../src/sha256.rs"]
+mod sha256;
+#[allow(dead_code)]
+#[path = "../../src/fs"]
+mod fs {
+    pub mod fat;
+    pub mod vfs;
+    pub mod watch;
+    // only for their tests; memfs gives the initrd unpacker somewhere to unpack to
+    #[cfg(test)]
+    pub mod ext2;
+    #[cfg(test)]
+    pub mod initrd;
+    #[cfg(test)]
+    pub mod memfs;
+}
+mod host;
+mod image;
+mod rtc;
+
+use host::{ata, bcache, pit, scheduler, task};
+
+use std::path::PathBuf;
+use std::process::ExitCode;
+use fs::fat::FatType;
+use image::{Options, Table};
+
+const USAGE: &str = "usage: mkimage [--fat16|--fat32] [--mbr|--gpt|--no-table] [--size <n>[K|M|G]] [--label <name>] <dir> <image>";
+
+fn parse_size(s: &str) -> Option<u64> {
+    let (num, unit) = match s.char_indices().last()? {
+        (i, 'K' | 'k') => (&s[..i], 1 << 10),
+        (i, 'M' | 'm') => (&s[..i], 1 << 20),
+        (i, 'G' | 'g') => (&s[..i], 1 << 30),
+        _ => (s, 1),
+    };
+    num.parse::<u64>().ok()?.checked_mul(unit)
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut opts = Options { fat_type: FatType::Fat32, table: Table::Mbr, size: None, label: String::new(), src: PathBuf::new(), out: PathBuf::new() };
+    let mut paths = Vec::new();
+    let mut args = std::env::args().skip(1);
+    while let Some(a) = args.next() {
+        match a.as_str() {
+            "--fat16" => opts.fat_type = FatType::Fat16,
+            "--fat32" => opts.fat_type = FatType::Fat32,
+            "--mbr" => opts.table = Table::Mbr,
+            "--gpt" => opts.table = Table::Gpt,
+            "--no-table" => opts.table = Table::None,
+            "--size" => {
+                let v = args.next().ok_or("--size needs a value")?;
+                opts.size = Some(parse_size(&v).ok_or_else(|| format!("bad size '{}'", v))?);
+            }
+            "--label" => opts.label = args.next().ok_or("--label needs a value")?,
+            "-h" | "--help" => return Err(USAGE.into()),
+            _ if a.starts_with('-') => return Err(format!("unknown option '{}'\n{}", a, USAGE)),
+            _ => paths.push(PathBuf::from(a)),
+        }
+    }
+    let [src, out] = <[PathBuf; 2]>::try_from(paths).map_err(|_| USAGE.to_string())?;
+    (opts.src, opts.out) = (src, out);
+    Ok(opts)
+}
+
+fn main() -> ExitCode {
+    let opts = match parse_args() {
+        Ok(o) => o,
+        Err(e) => { eprintln!("{}", e); return ExitCode::from(2); }
+    };
+    match image::build(&opts) {
+        Ok(s) => {
+            println!("{}: {} MiB, {} files, {} directories, verified", opts.out.display(), s.bytes >> 20, s.files, s.dirs);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("mkimage: {}", e);
+            let _ = std::fs::remove_file(&opts.out); // don't leave a half-written image behind
+            ExitCode::FAILURE
+        }
+    }
+}
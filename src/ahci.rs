@@ -0,0 +1,264 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use crate::block::{BlockDevice, BlockError};
+use crate::pci::Bar;
+
+// HBA registers
+const CAP: usize = 0x00;
+const GHC: usize = 0x04;
+const PI: usize = 0x0C;
+
+const CAP_SNCQ: u32 = 1 << 30;
+const GHC_AE: u32 = 1 << 31;
+
+// Port registers, relative to 0x100 + port * 0x80
+const P_CLB: usize = 0x00;
+const P_FB: usize = 0x08;
+const P_IS: usize = 0x10;
+const P_CMD: usize = 0x18;
+const P_TFD: usize = 0x20;
+const P_SIG: usize = 0x24;
+const P_SSTS: usize = 0x28;
+const P_SERR: usize = 0x30;
+const P_SACT: usize = 0x34;
+const P_CI: usize = 0x38;
+
+const CMD_ST: u32 = 1 << 0;
+const CMD_FRE: u32 = 1 << 4;
+const CMD_FR: u32 = 1 << 14;
+const CMD_CR: u32 = 1 << 15;
+
+const IS_TFES: u32 = 1 << 30; // task file error
+const TFD_ERR: u32 = 0x01;
+const TFD_BUSY: u32 = 0x88; // BSY | DRQ
+
+const SIG_ATA: u32 = 0x0000_0101;
+
+const FIS_H2D: u8 = 0x27;
+
+const ATA_IDENTIFY: u8 = 0xEC;
+const ATA_READ_DMA_EXT: u8 = 0x25;
+const ATA_WRITE_DMA_EXT: u8 = 0x35;
+const ATA_FLUSH_EXT: u8 = 0xEA;
+const ATA_READ_FPDMA: u8 = 0x60;
+const ATA_WRITE_FPDMA: u8 = 0x61;
+
+// Command slots we use per port, each with one PRD and its own bounce buffer.
+const SLOTS: usize = 4;
+const SLOT_BYTES: usize = 32 * 1024;
+// Command table: 0x80 bytes of FIS/ATAPI area, then the PRDT
+const TABLE_BYTES: usize = 0x100;
+
+const TIMEOUT_POLLS: u32 = 50_000_000;
+
+/// One SATA disk behind an AHCI port.
+pub struct AhciDisk {
+    port: *mut u8,
+    cl: *mut u32,   // command list (32 headers)
+    tables: *mut u8,
+    tables_phys: u64,
+    bufs: Vec<(u64, *mut u8)>,
+    sectors: u64,
+    ncq: bool,
+}
+
+unsafe impl Send for AhciDisk {}
+
+unsafe fn rd(base: *mut u8, off: usize) -> u32 { read_volatile(base.add(off) as *const u32) }
+unsafe fn wr(base: *mut u8, off: usize, v: u32) { write_volatile(base.add(off) as *mut u32, v) }
+
+fn wait_clear(base: *mut u8, off: usize, mask: u32) -> bool {
+    (0..TIMEOUT_POLLS).any(|_| unsafe { rd(base, off) } & mask == 0)
+}
+
+impl AhciDisk {
+    fn stop(port: *mut u8) -> bool {
+        unsafe {
+            wr(port, P_CMD, rd(port, P_CMD) & !CMD_ST);
+            if !wait_clear(port, P_CMD, CMD_CR) { return false; }
+            wr(port, P_CMD, rd(port, P_CMD) & !CMD_FRE);
+            wait_clear(port, P_CMD, CMD_FR)
+        }
+    }
+
+    fn new(port: *mut u8, hba_ncq: bool) -> Option<Self> {
+        if !Self::stop(port) { return None; }
+        // command list (1 KiB) and received-FIS area (256 B) share a page
+        let (meta_phys, meta) = crate::mm::alloc_dma(1)?;
+        let (tables_phys, tables) = crate::mm::alloc_dma((SLOTS * TABLE_BYTES).div_ceil(4096))?;
+        let mut bufs = Vec::with_capacity(SLOTS);
+        for _ in 0..SLOTS { bufs.push(crate::mm::alloc_dma(SLOT_BYTES / 4096)?); }
+        unsafe {
+            wr(port, P_CLB, meta_phys as u32);
+            wr(port, P_CLB + 4, (meta_phys >> 32) as u32);
+            wr(port, P_FB, (meta_phys + 1024) as u32);
+            wr(port, P_FB + 4, ((meta_phys + 1024) >> 32) as u32);
+            wr(port, P_SERR, u32::MAX);
+            wr(port, P_IS, u32::MAX);
+            wr(port, P_CMD, rd(port, P_CMD) | CMD_FRE);
+            wr(port, P_CMD, rd(port, P_CMD) | CMD_ST);
+        }
+        let mut disk = Self { port, cl: meta as *mut u32, tables, tables_phys, bufs, sectors: 0, ncq: false };
+        disk.command(0, ATA_IDENTIFY, 0, 0, 512, false).ok()?;
+        let id = unsafe { core::slice::from_raw_parts(disk.bufs[0].1 as *const u16, 256) };
+        disk.sectors = if id[83] & (1 << 10) != 0 {
+            id[100] as u64 | (id[101] as u64) << 16 | (id[102] as u64) << 32 | (id[103] as u64) << 48
+        } else { id[60] as u64 | (id[61] as u64) << 16 };
+        // NCQ needs support on both sides and a queue at least as deep as our slot count
+        disk.ncq = hba_ncq && id[76] & (1 << 8) != 0 && (id[75] & 0x1F) as usize + 1 >= SLOTS;
+        Some(disk)
+    }
+
+    /// Fill in header, FIS and PRD for `slot`. For NCQ commands the count goes in FEATURES and the tag in COUNT.
+    fn prepare(&mut self, slot: usize, cmd: u8, lba: u64, count: u16, bytes: usize, write: bool) {
+        let queued = matches!(cmd, ATA_READ_FPDMA | ATA_WRITE_FPDMA);
+        let table = unsafe { self.tables.add(slot * TABLE_BYTES) };
+        let table_phys = self.tables_phys + (slot * TABLE_BYTES) as u64;
+        unsafe {
+            core::ptr::write_bytes(table, 0, TABLE_BYTES);
+            let fis = core::slice::from_raw_parts_mut(table, 20);
+            fis[0] = FIS_H2D;
+            fis[1] = 0x80; // this is a command, not a control update
+            fis[2] = cmd;
+            fis[4] = lba as u8;
+            fis[5] = (lba >> 8) as u8;
+            fis[6] = (lba >> 16) as u8;
+            fis[7] = 0x40; // LBA mode
+            fis[8] = (lba >> 24) as u8;
+            fis[9] = (lba >> 32) as u8;
+            fis[10] = (lba >> 40) as u8;
+            if queued {
+                fis[3] = count as u8;
+                fis[11] = (count >> 8) as u8;
+                fis[12] = (slot as u8) << 3;
+            } else {
+                fis[12] = count as u8;
+                fis[13] = (count >> 8) as u8;
+            }
+            let prds = if bytes > 0 { 1u32 } else { 0 };
+            if bytes > 0 {
+                let prd = table.add(0x80) as *mut u32;
+                let phys = self.bufs[slot].0;
+                write_volatile(prd, phys as u32);
+                write_volatile(prd.add(1), (phys >> 32) as u32);
+                write_volatile(prd.add(3), (bytes as u32 - 1) & 0x3F_FFFF);
+            }
+            // header: FIS length in dwords, write flag, PRD count, then the table address
+            let hdr = self.cl.add(slot * 8);
+            write_volatile(hdr, 5 | if write { 1 << 6 } else { 0 } | prds << 16);
+            write_volatile(hdr.add(1), 0);
+            write_volatile(hdr.add(2), table_phys as u32);
+            write_volatile(hdr.add(3), (table_phys >> 32) as u32);
+        }
+    }
+
+    /// Issue the prepared slots in `mask` and poll until the HBA clears them.
+    fn issue(&mut self, mask: u32, queued: bool) -> Result<(), BlockError> {
+        let port = self.port;
+        if !wait_clear(port, P_TFD, TFD_BUSY) { return Err(BlockError::Timeout); }
+        unsafe {
+            wr(port, P_IS, u32::MAX);
+            if queued { wr(port, P_SACT, mask); }
+            wr(port, P_CI, mask);
+        }
+        let done_reg = if queued { P_SACT } else { P_CI };
+        for _ in 0..TIMEOUT_POLLS {
+            let (busy, is) = unsafe { (rd(port, done_reg) | rd(port, P_CI), rd(port, P_IS)) };
+            if is & IS_TFES != 0 || unsafe { rd(port, P_TFD) } & TFD_ERR != 0 {
+                // recover: restarting the port clears CI/SACT and the error state
+                Self::stop(port);
+                unsafe {
+                    wr(port, P_SERR, u32::MAX);
+                    wr(port, P_IS, u32::MAX);
+                    wr(port, P_CMD, rd(port, P_CMD) | CMD_FRE);
+                    wr(port, P_CMD, rd(port, P_CMD) | CMD_ST);
+                }
+                return Err(BlockError::Io);
+            }
+            if busy & mask == 0 { return Ok(()); }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    fn command(&mut self, slot: usize, cmd: u8, lba: u64, count: u16, bytes: usize, write: bool) -> Result<(), BlockError> {
+        self.prepare(slot, cmd, lba, count, bytes, write);
+        self.issue(1 << slot, false)
+    }
+
+    /// Run a read or write of whole SLOT_BYTES chunks; with NCQ up to SLOTS of them are in flight together.
+    fn transfer(&mut self, lba: u64, len: usize, write: bool, mut copy: impl FnMut(usize, *mut u8, usize)) -> Result<(), BlockError> {
+        if len % 512 != 0 { return Err(BlockError::BadBuffer); }
+        if lba + (len / 512) as u64 > self.sectors { return Err(BlockError::OutOfRange); }
+        let per_batch = if self.ncq { SLOTS } else { 1 };
+        let mut off = 0;
+        while off < len {
+            let mut mask = 0u32;
+            let mut chunks = Vec::new();
+            for slot in 0..per_batch {
+                if off >= len { break; }
+                let n = (len - off).min(SLOT_BYTES);
+                if write { copy(off, self.bufs[slot].1, n); }
+                let sector = lba + (off / 512) as u64;
+                let cmd = match (self.ncq, write) {
+                    (true, true) => ATA_WRITE_FPDMA,
+                    (true, false) => ATA_READ_FPDMA,
+                    (false, true) => ATA_WRITE_DMA_EXT,
+                    (false, false) => ATA_READ_DMA_EXT,
+                };
+                self.prepare(slot, cmd, sector, (n / 512) as u16, n, write);
+                mask |= 1 << slot;
+                chunks.push((slot, off, n));
+                off += n;
+            }
+            self.issue(mask, self.ncq)?;
+            if !write { for (slot, at, n) in chunks { copy(at, self.bufs[slot].1, n); } }
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for AhciDisk {
+    fn block_size(&self) -> usize { 512 }
+    fn capacity(&self) -> u64 { self.sectors }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let dst = buf.as_mut_ptr();
+        self.transfer(lba, buf.len(), false, |at, bounce, n| unsafe { core::ptr::copy_nonoverlapping(bounce, dst.add(at), n) })
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let src = buf.as_ptr();
+        self.transfer(lba, buf.len(), true, |at, bounce, n| unsafe { core::ptr::copy_nonoverlapping(src.add(at), bounce, n) })
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> { self.command(0, ATA_FLUSH_EXT, 0, 0, 0, false) }
+}
+
+/// Find AHCI controllers (class 0x01, subclass 0x06) and register each SATA disk as `sda`, `sdb`, ...
+pub fn init() {
+    let mut hbas = Vec::new();
+    crate::pci::enumerate(|dev| { if dev.class == 0x01 && dev.subclass == 0x06 && dev.prog_if == 0x01 { hbas.push(dev); } });
+    let mut n = 0u8;
+    for dev in hbas {
+        dev.enable();
+        let Bar::Mem(abar) = dev.bar(5) else { continue };
+        let Some(hba) = crate::mm::map_mmio(abar, 0x1100) else { continue };
+        let (cap, pi) = unsafe {
+            wr(hba, GHC, rd(hba, GHC) | GHC_AE);
+            (rd(hba, CAP), rd(hba, PI))
+        };
+        for p in (0..32).filter(|p| pi & (1 << p) != 0) {
+            let port = unsafe { hba.add(0x100 + p * 0x80) };
+            let ssts = unsafe { rd(port, P_SSTS) };
+            // device present with PHY up, active power state, plain ATA signature
+            if ssts & 0xF != 3 || (ssts >> 8) & 0xF != 1 || unsafe { rd(port, P_SIG) } != SIG_ATA { continue; }
+            let Some(disk) = AhciDisk::new(port, cap & CAP_SNCQ != 0) else { continue };
+            let name = alloc::format!("sd{}", (b'a' + n) as char);
+            crate::serial_println!("ahci: {} port {} {} sectors{}", name, p, disk.sectors, if disk.ncq { ", ncq" } else { "" });
+            crate::block::register(&name, Box::new(disk));
+            n += 1;
+        }
+    }
+}
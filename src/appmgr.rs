@@ -36,4 +36,10 @@ pub fn set_state(id: u32, st: WindowState) {
     if let Some(a) = apps.iter_mut().find(|a| a.id == id) { a.state = st; }
 }
 
+/// Replace a window's contents; redraw with `window::refresh_desktop`.
+pub fn set_body(id: u32, body: String) {
+    let mut apps = APPS.lock();
+    if let Some(a) = apps.iter_mut().find(|a| a.id == id) { a.body = body; }
+}
+
 pub fn list() -> Vec<App> { APPS.lock().clone() }
@@ -15,6 +15,21 @@ pub fn files(list: &[alloc::string::String]) -> alloc::string::String {
     s
 }
 
+// appmgr id of the Files window and the directory it lists
+static FILES_WINDOW: core::sync::atomic::AtomicU32 = core::sync::atomic::AtomicU32::new(0);
+const FILES_DIR: &str = "/";
+
+/// Keep the Files window `id` in step with its directory.
+pub fn follow_files(id: u32) {
+    use crate::fs::watch;
+    FILES_WINDOW.store(id, core::sync::atomic::Ordering::Relaxed);
+    watch::subscribe(FILES_DIR, watch::ALL, |_| {
+        let list = crate::fs::list(FILES_DIR).unwrap_or_default();
+        crate::appmgr::set_body(FILES_WINDOW.load(core::sync::atomic::Ordering::Relaxed), files(&list));
+        crate::window::refresh_desktop();
+    });
+}
+
 pub fn about() -> &'static str {
     "About waemom\nA tiny hobby OS kernel demo.\n"
 }
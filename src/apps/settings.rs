@@ -1,5 +1,10 @@
 use alloc::string::String;
-use crate::settings::Settings;
+use core::sync::atomic::{AtomicU32, Ordering};
+use crate::fs::watch;
+use crate::settings::{self, Settings};
+
+// appmgr id of the settings window, 0 until `launch`
+static WINDOW: AtomicU32 = AtomicU32::new(0);
 
 pub fn view(settings: &Settings) -> String {
     let mut s = String::from("System Settings\n\n");
@@ -15,3 +20,18 @@ pub fn view(settings: &Settings) -> String {
     s.push_str(&format!("Loading animations: {}\n", if settings.loading_animations {"on"} else {"off"}));
     s
 }
+
+/// Open the settings window and hot-reload waemon.lock whenever it is written or replaced.
+pub fn launch(x: usize, y: usize, w: usize, h: usize) -> u32 {
+    let id = crate::appmgr::spawn("Settings", x, y, w, h, crate::ui::icons::icon_settings(), view(&settings::current()));
+    WINDOW.store(id, Ordering::Relaxed);
+    watch::subscribe(settings::LOCK_PATH, watch::CREATE | watch::MODIFY | watch::RENAME, on_change);
+    id
+}
+
+fn on_change(e: &watch::Event) {
+    // renamed away: nothing to read until a new one appears
+    if e.path != settings::LOCK_PATH || !settings::reload() { return; }
+    crate::appmgr::set_body(WINDOW.load(Ordering::Relaxed), view(&settings::current()));
+    crate::window::refresh_desktop();
+}
@@ -1,34 +1,255 @@
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Mutex;
 use x86_64::instructions::port::Port;
 
-const ATA_REG_DATA: u16 = 0x1F0;
-const ATA_REG_SECCOUNT0: u16 = 0x1F2;
-const ATA_REG_LBA0: u16 = 0x1F3;
-const ATA_REG_LBA1: u16 = 0x1F4;
-const ATA_REG_LBA2: u16 = 0x1F5;
-const ATA_REG_HDDEVSEL: u16 = 0x1F6;
-const ATA_REG_COMMAND: u16 = 0x1F7;
-const ATA_REG_STATUS: u16 = 0x1F7;
+// Register offsets from the channel's I/O base
+const REG_DATA: u16 = 0;
+const REG_ERROR: u16 = 1;
+const REG_SECCOUNT: u16 = 2;
+const REG_LBA0: u16 = 3;
+const REG_LBA1: u16 = 4;
+const REG_LBA2: u16 = 5;
+const REG_HDDEVSEL: u16 = 6;
+const REG_COMMAND: u16 = 7;
+const REG_STATUS: u16 = 7;
 
-pub fn read_sector_lba28(lba: u32, buf: &mut [u16;256]) -> bool {
+const SR_BSY: u8 = 0x80;
+const SR_DF: u8 = 0x20;
+const SR_DRQ: u8 = 0x08;
+const SR_ERR: u8 = 0x01;
+
+const CMD_READ_PIO: u8 = 0x20;
+const CMD_READ_PIO_EXT: u8 = 0x24;
+const CMD_WRITE_PIO: u8 = 0x30;
+const CMD_WRITE_PIO_EXT: u8 = 0x34;
+const CMD_CACHE_FLUSH: u8 = 0xE7;
+const CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+// (I/O base, control base) for the primary and secondary channels
+const CHANNELS: [(u16, u16); 2] = [(0x1F0, 0x3F6), (0x170, 0x376)];
+
+// Status polls before giving up; each poll is an I/O read (~1us on real hardware).
+const TIMEOUT_POLLS: u32 = 2_000_000;
+
+// One command at a time per channel; master and slave share the registers.
+static CHANNEL_LOCKS: [Mutex<()>; 2] = [Mutex::new(()), Mutex::new(())];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AtaError {
+    NoDevice,
+    Timeout,
+    DeviceFault,
+    Aborted,
+    IdNotFound,    // sector outside the drive
+    Uncorrectable, // media error
+    BadBlock,
+    Other(u8),
+}
+
+impl AtaError {
+    fn decode(err: u8) -> Self {
+        if err & 0x80 != 0 { AtaError::BadBlock }
+        else if err & 0x40 != 0 { AtaError::Uncorrectable }
+        else if err & 0x10 != 0 { AtaError::IdNotFound }
+        else if err & 0x04 != 0 { AtaError::Aborted }
+        else { AtaError::Other(err) }
+    }
+}
+
+impl From<AtaError> for crate::block::BlockError {
+    fn from(e: AtaError) -> Self {
+        use crate::block::BlockError;
+        match e {
+            AtaError::NoDevice => BlockError::NoDevice,
+            AtaError::Timeout => BlockError::Timeout,
+            AtaError::IdNotFound => BlockError::OutOfRange,
+            _ => BlockError::Io,
+        }
+    }
+}
+
+/// A drive found by IDENTIFY DEVICE.
+#[derive(Clone, Debug)]
+pub struct Drive {
+    pub channel: u8, // 0 = primary, 1 = secondary
+    pub slave: bool,
+    pub lba48: bool,
+    pub sectors: u64,
+    pub model: String,
+}
+
+fn port(channel: u8, reg: u16) -> Port<u8> { Port::new(CHANNELS[channel as usize].0 + reg) }
+
+fn status(channel: u8) -> u8 { unsafe { port(channel, REG_STATUS).read() } }
+
+// Reading the alternate status register four times gives the drive its 400ns to settle.
+fn delay_400ns(channel: u8) {
+    let mut alt: Port<u8> = Port::new(CHANNELS[channel as usize].1);
+    for _ in 0..4 { unsafe { alt.read(); } }
+}
+
+fn wait_not_busy(channel: u8) -> Result<u8, AtaError> {
+    for _ in 0..TIMEOUT_POLLS {
+        let s = status(channel);
+        if s == 0xFF { return Err(AtaError::NoDevice); } // floating bus
+        if s & SR_BSY == 0 { return Ok(s); }
+    }
+    Err(AtaError::Timeout)
+}
+
+/// Wait until the drive wants data (DRQ), decoding any error it reports instead.
+fn wait_drq(channel: u8) -> Result<(), AtaError> {
+    for _ in 0..TIMEOUT_POLLS {
+        let s = status(channel);
+        if s & SR_BSY != 0 { continue; }
+        if s & SR_ERR != 0 { return Err(AtaError::decode(unsafe { port(channel, REG_ERROR).read() })); }
+        if s & SR_DF != 0 { return Err(AtaError::DeviceFault); }
+        if s & SR_DRQ != 0 { return Ok(()); }
+    }
+    Err(AtaError::Timeout)
+}
+
+fn select(channel: u8, slave: bool, bits: u8) -> Result<(), AtaError> {
+    wait_not_busy(channel)?;
+    unsafe { port(channel, REG_HDDEVSEL).write(0xA0 | bits | if slave { 0x10 } else { 0 }); }
+    delay_400ns(channel);
+    wait_not_busy(channel).map(|_| ())
+}
+
+fn identify(channel: u8, slave: bool) -> Result<Drive, AtaError> {
+    if status(channel) == 0xFF { return Err(AtaError::NoDevice); }
+    select(channel, slave, 0)?;
     unsafe {
-        let mut status: u8;
-        // Select drive and LBA bits 24-27
-        let mut hd: Port<u8> = Port::new(ATA_REG_HDDEVSEL);
-        hd.write(0xE0 | (((lba >> 24) & 0x0F) as u8));
-        Port::<u8>::new(ATA_REG_SECCOUNT0).write(1);
-        Port::<u8>::new(ATA_REG_LBA0).write((lba & 0xFF) as u8);
-        Port::<u8>::new(ATA_REG_LBA1).write(((lba >> 8) & 0xFF) as u8);
-        Port::<u8>::new(ATA_REG_LBA2).write(((lba >> 16) & 0xFF) as u8);
-        Port::<u8>::new(ATA_REG_COMMAND).write(0x20); // READ SECTORS
-        // Wait for DRQ
-        loop {
-            status = Port::<u8>::new(ATA_REG_STATUS).read();
-            if status & 0x08 != 0 { break; }
-            if status & 0x01 != 0 { return false; }
+        for reg in [REG_SECCOUNT, REG_LBA0, REG_LBA1, REG_LBA2] { port(channel, reg).write(0); }
+        port(channel, REG_COMMAND).write(CMD_IDENTIFY);
+    }
+    delay_400ns(channel);
+    if status(channel) == 0 { return Err(AtaError::NoDevice); }
+    wait_not_busy(channel)?;
+    // ATAPI and SATA devices put a signature in LBA1/LBA2 and don't answer IDENTIFY DEVICE
+    if unsafe { port(channel, REG_LBA1).read() != 0 || port(channel, REG_LBA2).read() != 0 } { return Err(AtaError::NoDevice); }
+    wait_drq(channel)?;
+    let mut id = [0u16; 256];
+    let mut data: Port<u16> = Port::new(CHANNELS[channel as usize].0 + REG_DATA);
+    for w in id.iter_mut() { *w = unsafe { data.read() }; }
+
+    let lba48 = id[83] & (1 << 10) != 0;
+    let sectors = if lba48 {
+        id[100] as u64 | (id[101] as u64) << 16 | (id[102] as u64) << 32 | (id[103] as u64) << 48
+    } else { id[60] as u64 | (id[61] as u64) << 16 };
+    // model string is stored as big-endian byte pairs in words 27..47
+    let mut model = String::new();
+    for w in &id[27..47] { for b in w.to_be_bytes() { model.push(b as char); } }
+    let model = String::from(model.trim());
+    Ok(Drive { channel, slave, lba48, sectors, model })
+}
+
+/// Find every ATA drive on both channels.
+pub fn probe() -> Vec<Drive> {
+    let mut out = Vec::new();
+    for channel in 0..2u8 {
+        let _guard = CHANNEL_LOCKS[channel as usize].lock();
+        for slave in [false, true] {
+            if let Ok(d) = identify(channel, slave) { out.push(d); }
+        }
+    }
+    out
+}
+
+// Program the task file for `count` sectors at `lba` and issue `cmd`.
+fn setup(d: &Drive, lba: u64, count: u16, cmd28: u8, cmd48: u8) -> Result<(), AtaError> {
+    let ch = d.channel;
+    if d.lba48 {
+        select(ch, d.slave, 0x40)?;
+        unsafe {
+            // high-order bytes first, then low-order, through the same registers
+            port(ch, REG_SECCOUNT).write((count >> 8) as u8);
+            port(ch, REG_LBA0).write((lba >> 24) as u8);
+            port(ch, REG_LBA1).write((lba >> 32) as u8);
+            port(ch, REG_LBA2).write((lba >> 40) as u8);
+            port(ch, REG_SECCOUNT).write(count as u8);
+            port(ch, REG_LBA0).write(lba as u8);
+            port(ch, REG_LBA1).write((lba >> 8) as u8);
+            port(ch, REG_LBA2).write((lba >> 16) as u8);
+            port(ch, REG_COMMAND).write(cmd48);
+        }
+    } else {
+        select(ch, d.slave, 0x40 | ((lba >> 24) & 0x0F) as u8)?;
+        unsafe {
+            port(ch, REG_SECCOUNT).write(count as u8); // 0 means 256
+            port(ch, REG_LBA0).write(lba as u8);
+            port(ch, REG_LBA1).write((lba >> 8) as u8);
+            port(ch, REG_LBA2).write((lba >> 16) as u8);
+            port(ch, REG_COMMAND).write(cmd28);
+        }
+    }
+    Ok(())
+}
+
+fn check_range(d: &Drive, lba: u64, len: usize) -> Result<(), AtaError> {
+    if len % 512 != 0 || lba + (len / 512) as u64 > d.sectors { return Err(AtaError::IdNotFound); }
+    if !d.lba48 && lba + (len / 512) as u64 > 1 << 28 { return Err(AtaError::IdNotFound); }
+    Ok(())
+}
+
+/// PIO read of `buf.len() / 512` sectors starting at `lba`.
+pub fn read(d: &Drive, lba: u64, buf: &mut [u8]) -> Result<(), AtaError> {
+    check_range(d, lba, buf.len())?;
+    let _guard = CHANNEL_LOCKS[d.channel as usize].lock();
+    let mut data: Port<u16> = Port::new(CHANNELS[d.channel as usize].0 + REG_DATA);
+    for (i, chunk) in buf.chunks_mut(256 * 512).enumerate() {
+        let n = chunk.len() / 512;
+        // LBA28 encodes 256 sectors as 0; LBA48 takes the full 16-bit count
+        let count = if d.lba48 { n as u16 } else { n as u16 & 0xFF };
+        setup(d, lba + i as u64 * 256, count, CMD_READ_PIO, CMD_READ_PIO_EXT)?;
+        for sector in chunk.chunks_exact_mut(512) {
+            delay_400ns(d.channel);
+            wait_drq(d.channel)?;
+            for w in sector.chunks_exact_mut(2) { w.copy_from_slice(&unsafe { data.read() }.to_le_bytes()); }
         }
-        // Read 256 words
-        let mut data: Port<u16> = Port::new(ATA_REG_DATA);
-        for i in 0..256 { buf[i] = data.read(); }
-        true
+    }
+    Ok(())
+}
+
+/// PIO write of `buf.len() / 512` sectors starting at `lba`.
+pub fn write(d: &Drive, lba: u64, buf: &[u8]) -> Result<(), AtaError> {
+    check_range(d, lba, buf.len())?;
+    let _guard = CHANNEL_LOCKS[d.channel as usize].lock();
+    let mut data: Port<u16> = Port::new(CHANNELS[d.channel as usize].0 + REG_DATA);
+    for (i, chunk) in buf.chunks(256 * 512).enumerate() {
+        let n = chunk.len() / 512;
+        let count = if d.lba48 { n as u16 } else { n as u16 & 0xFF };
+        setup(d, lba + i as u64 * 256, count, CMD_WRITE_PIO, CMD_WRITE_PIO_EXT)?;
+        for sector in chunk.chunks_exact(512) {
+            delay_400ns(d.channel);
+            wait_drq(d.channel)?;
+            for w in sector.chunks_exact(2) { unsafe { data.write(u16::from_le_bytes([w[0], w[1]])); } }
+        }
+        let s = wait_not_busy(d.channel)?;
+        if s & SR_ERR != 0 { return Err(AtaError::decode(unsafe { port(d.channel, REG_ERROR).read() })); }
+        if s & SR_DF != 0 { return Err(AtaError::DeviceFault); }
+    }
+    Ok(())
+}
+
+/// Flush the drive's write cache.
+pub fn flush(d: &Drive) -> Result<(), AtaError> {
+    let _guard = CHANNEL_LOCKS[d.channel as usize].lock();
+    select(d.channel, d.slave, 0)?;
+    unsafe { port(d.channel, REG_COMMAND).write(if d.lba48 { CMD_CACHE_FLUSH_EXT } else { CMD_CACHE_FLUSH }); }
+    delay_400ns(d.channel);
+    let s = wait_not_busy(d.channel)?;
+    if s & SR_ERR != 0 { return Err(AtaError::decode(unsafe { port(d.channel, REG_ERROR).read() })); }
+    Ok(())
+}
+
+/// Probe both channels and register each drive as `hda`..`hdd`.
+pub fn init() {
+    for d in probe() {
+        let name = ["hda", "hdb", "hdc", "hdd"][d.channel as usize * 2 + d.slave as usize];
+        crate::serial_println!("ata: {} {} ({} sectors{})", name, d.model, d.sectors, if d.lba48 { ", lba48" } else { "" });
+        crate::block::register(name, alloc::boxed::Box::new(crate::block::AtaDevice::new(d)));
     }
 }
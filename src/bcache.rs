@@ -0,0 +1,242 @@
+use alloc::vec::Vec;
+use spin::Mutex;
+use crate::block::{BlockDevice, BlockError, Shared};
+
+// The cache works in 4 KiB pages of device bytes, whatever the device's block size.
+const PAGE: usize = 4096;
+const SLOTS: usize = 128;
+const READAHEAD_MAX: u64 = 16; // pages
+const FLUSH_SECS: u64 = 5;
+
+#[derive(Clone, Copy)]
+struct Entry { dev: usize, page: u64, valid: bool, dirty: bool, last: u64 }
+
+// Per-device read pattern: the page after the last read, the read-ahead window,
+// and the page up to which read-ahead has already been issued.
+#[derive(Clone, Copy)]
+struct Seq { id: usize, next: u64, window: u64, end: u64 }
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub readahead: u64,
+    pub writebacks: u64,
+    pub dirty: usize,
+    pub used: usize,
+}
+
+struct Cache {
+    entries: [Entry; SLOTS],
+    clock: u64,
+    devs: Vec<(usize, Shared)>,
+    seq: Vec<Seq>,
+    stats: Stats,
+}
+
+static CACHE: Mutex<Cache> = Mutex::new(Cache {
+    entries: [Entry { dev: 0, page: 0, valid: false, dirty: false, last: 0 }; SLOTS],
+    clock: 0,
+    devs: Vec::new(),
+    seq: Vec::new(),
+    stats: Stats { hits: 0, misses: 0, readahead: 0, writebacks: 0, dirty: 0, used: 0 },
+});
+
+// Page data, only touched while CACHE is held.
+static mut POOL: [[u8; PAGE]; SLOTS] = [[0; PAGE]; SLOTS];
+
+fn data(slot: usize) -> &'static mut [u8; PAGE] { unsafe { &mut *core::ptr::addr_of_mut!(POOL[slot]) } }
+
+fn cacheable(dev: &dyn BlockDevice) -> bool { let bs = dev.block_size(); bs <= PAGE && PAGE % bs == 0 }
+
+/// Blocks of `page` that exist on the device (the last page may be short).
+fn page_blocks(dev: &dyn BlockDevice, page: u64) -> usize {
+    let per = (PAGE / dev.block_size()) as u64;
+    dev.capacity().saturating_sub(page * per).min(per) as usize
+}
+
+impl Cache {
+    fn find(&mut self, dev: usize, page: u64) -> Option<usize> {
+        let i = self.entries.iter().position(|e| e.valid && e.dev == dev && e.page == page)?;
+        self.clock += 1;
+        self.entries[i].last = self.clock;
+        Some(i)
+    }
+
+    fn write_back(&mut self, slot: usize, dev: &mut dyn BlockDevice) -> Result<(), BlockError> {
+        let e = self.entries[slot];
+        let n = page_blocks(dev, e.page);
+        let lba = e.page * (PAGE / dev.block_size()) as u64;
+        dev.write_blocks(lba, &data(slot)[..n * dev.block_size()])?;
+        self.entries[slot].dirty = false;
+        self.stats.writebacks += 1;
+        Ok(())
+    }
+
+    /// A free slot, else the least recently used clean one, else the LRU dirty one after writing it back.
+    /// `cur` is the device the caller already holds locked.
+    fn victim(&mut self, cur: usize, cur_dev: &mut dyn BlockDevice) -> Result<usize, BlockError> {
+        if let Some(i) = self.entries.iter().position(|e| !e.valid) { return Ok(i); }
+        let lru = |dirty: bool| (0..SLOTS).filter(|&i| self.entries[i].dirty == dirty).min_by_key(|&i| self.entries[i].last);
+        if let Some(i) = lru(false) { return Ok(i); }
+        let i = lru(true).unwrap_or(0);
+        let owner = self.entries[i].dev;
+        if owner == cur {
+            self.write_back(i, cur_dev)?;
+        } else {
+            let other = self.devs.iter().find(|(id, _)| *id == owner).map(|(_, d)| d.clone());
+            // a device that has gone away just loses the page
+            if let Some(d) = other { self.write_back(i, d.lock().as_mut())?; }
+        }
+        Ok(i)
+    }
+
+    fn install(&mut self, slot: usize, dev: usize, page: u64, dirty: bool) {
+        self.clock += 1;
+        self.entries[slot] = Entry { dev, page, valid: true, dirty, last: self.clock };
+    }
+
+    fn load(&mut self, id: usize, dev: &mut dyn BlockDevice, page: u64) -> Result<usize, BlockError> {
+        let slot = self.victim(id, dev)?;
+        self.entries[slot].valid = false;
+        let n = page_blocks(dev, page) * dev.block_size();
+        let buf = data(slot);
+        dev.read_blocks(page * (PAGE / dev.block_size()) as u64, &mut buf[..n])?;
+        buf[n..].fill(0);
+        self.install(slot, id, page, false);
+        Ok(slot)
+    }
+
+    // Sequential readers get an exponentially growing window of pages fetched ahead of them;
+    // pages already fetched for the current run aren't requested again.
+    fn readahead(&mut self, id: usize, dev: &mut dyn BlockDevice, first: u64, last: u64) {
+        let i = match self.seq.iter().position(|s| s.id == id) {
+            Some(i) => i,
+            None => { self.seq.push(Seq { id, next: u64::MAX, window: 0, end: 0 }); self.seq.len() - 1 }
+        };
+        let s = self.seq[i];
+        if first + 1 == s.next && last + 1 == s.next { return; } // same page again
+        let window = if first == s.next || first + 1 == s.next { (s.window * 2).clamp(2, READAHEAD_MAX) } else { 0 };
+        let from = if window == 0 { last + 1 } else { s.end.max(last + 1) };
+        let pages = (dev.capacity() * dev.block_size() as u64).div_ceil(PAGE as u64);
+        let end = (last + 1 + window).min(pages).max(from);
+        self.seq[i] = Seq { id, next: last + 1, window, end };
+        for page in from..end {
+            if self.entries.iter().any(|e| e.valid && e.dev == id && e.page == page) { continue; }
+            if self.load(id, dev, page).is_err() { break; }
+            self.stats.readahead += 1;
+        }
+    }
+}
+
+fn check(dev: &dyn BlockDevice, lba: u64, len: usize) -> Result<(), BlockError> {
+    if len % dev.block_size() != 0 { return Err(BlockError::BadBuffer); }
+    if lba + (len / dev.block_size()) as u64 > dev.capacity() { return Err(BlockError::OutOfRange); }
+    Ok(())
+}
+
+/// Make device `id` known to the cache so its dirty pages can be written back from anywhere.
+pub fn attach(id: usize, dev: Shared) {
+    let mut c = CACHE.lock();
+    c.devs.retain(|(d, _)| *d != id);
+    c.devs.push((id, dev));
+}
+
+/// Forget device `id` and drop its pages without writing them.
+pub fn detach(id: usize) {
+    let mut c = CACHE.lock();
+    c.devs.retain(|(d, _)| *d != id);
+    c.seq.retain(|s| s.id != id);
+    for e in c.entries.iter_mut().filter(|e| e.dev == id) { e.valid = false; e.dirty = false; }
+}
+
+pub fn read(id: usize, shared: &Shared, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+    let mut c = CACHE.lock();
+    let mut guard = shared.lock();
+    let dev = guard.as_mut();
+    if !cacheable(dev) { return dev.read_blocks(lba, buf); }
+    check(dev, lba, buf.len())?;
+    if buf.is_empty() { return Ok(()); }
+    let start = lba * dev.block_size() as u64;
+    let (first, last) = (start / PAGE as u64, (start + buf.len() as u64 - 1) / PAGE as u64);
+    let mut done = 0;
+    for page in first..=last {
+        let slot = match c.find(id, page) {
+            Some(s) => { c.stats.hits += 1; s }
+            None => { c.stats.misses += 1; c.load(id, dev, page)? }
+        };
+        let off = ((start + done as u64) % PAGE as u64) as usize;
+        let n = (PAGE - off).min(buf.len() - done);
+        buf[done..done + n].copy_from_slice(&data(slot)[off..off + n]);
+        done += n;
+    }
+    c.readahead(id, dev, first, last);
+    Ok(())
+}
+
+pub fn write(id: usize, shared: &Shared, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+    let mut c = CACHE.lock();
+    let mut guard = shared.lock();
+    let dev = guard.as_mut();
+    if !cacheable(dev) { return dev.write_blocks(lba, buf); }
+    check(dev, lba, buf.len())?;
+    let start = lba * dev.block_size() as u64;
+    let mut done = 0;
+    while done < buf.len() {
+        let pos = start + done as u64;
+        let (page, off) = (pos / PAGE as u64, (pos % PAGE as u64) as usize);
+        let n = (PAGE - off).min(buf.len() - done);
+        let slot = match c.find(id, page) {
+            Some(s) => s,
+            // a write covering everything the page holds needn't read it first
+            None if off == 0 && n == page_blocks(dev, page) * dev.block_size() => {
+                let s = c.victim(id, dev)?;
+                data(s)[n..].fill(0);
+                s
+            }
+            None => c.load(id, dev, page)?,
+        };
+        data(slot)[off..off + n].copy_from_slice(&buf[done..done + n]);
+        c.install(slot, id, page, true);
+        done += n;
+    }
+    Ok(())
+}
+
+/// Write back device `id`'s dirty pages and flush the device.
+pub fn sync_dev(id: usize, shared: &Shared) -> Result<(), BlockError> {
+    let mut c = CACHE.lock();
+    let mut guard = shared.lock();
+    for slot in 0..SLOTS {
+        let e = c.entries[slot];
+        if e.valid && e.dirty && e.dev == id { c.write_back(slot, guard.as_mut())?; }
+    }
+    guard.flush()
+}
+
+/// Write back every dirty page on every device.
+pub fn sync_all() -> Result<(), BlockError> {
+    let devs = CACHE.lock().devs.clone();
+    let mut res = Ok(());
+    for (id, dev) in devs {
+        if let Err(e) = sync_dev(id, &dev) { res = Err(e); }
+    }
+    res
+}
+
+pub fn stats() -> Stats {
+    let c = CACHE.lock();
+    let mut s = c.stats;
+    s.used = c.entries.iter().filter(|e| e.valid).count();
+    s.dirty = c.entries.iter().filter(|e| e.valid && e.dirty).count();
+    s
+}
+
+/// Kernel task writing dirty pages back every few seconds.
+pub extern "C" fn flusher_task() -> ! {
+    loop {
+        crate::scheduler::sleep_current(crate::pit::hz() * FLUSH_SECS);
+        crate::scheduler::yield_now();
+        let _ = sync_all();
+    }
+}
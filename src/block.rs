@@ -1,17 +1,214 @@
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use alloc::vec;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockError {
+    OutOfRange,
+    BadBuffer, // length not a multiple of the block size
+    ReadOnly,
+    NoDevice,
+    Timeout,
+    Io,
+}
+
+/// A device addressed in fixed-size blocks. Buffers passed to `read_blocks`/`write_blocks`
+/// must be a whole number of blocks long.
 pub trait BlockDevice {
-    fn read_sector(&mut self, lba: u32, buf: &mut [u8;512]) -> bool;
+    fn block_size(&self) -> usize;
+    /// Capacity in blocks.
+    fn capacity(&self) -> u64;
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+    /// Devices are read-only unless they override this.
+    fn write_blocks(&mut self, _lba: u64, _buf: &[u8]) -> Result<(), BlockError> { Err(BlockError::ReadOnly) }
+    /// Push any write-back cache out to stable storage.
+    fn flush(&mut self) -> Result<(), BlockError> { Ok(()) }
+}
+
+fn check(dev: &dyn BlockDevice, lba: u64, len: usize) -> Result<(), BlockError> {
+    let bs = dev.block_size();
+    if !len.is_multiple_of(bs) { return Err(BlockError::BadBuffer); }
+    if lba.checked_add((len / bs) as u64).is_none_or(|end| end > dev.capacity()) { return Err(BlockError::OutOfRange); }
+    Ok(())
+}
+
+/// Read `buf.len()` bytes at byte offset `pos`; whole blocks go straight into `buf`.
+pub fn read_bytes(dev: &mut dyn BlockDevice, mut pos: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+    let bs = dev.block_size();
+    let mut tmp = vec![0u8; bs];
+    let mut done = 0;
+    while done < buf.len() {
+        let lba = pos / bs as u64;
+        let off = (pos % bs as u64) as usize;
+        let left = buf.len() - done;
+        if off == 0 && left >= bs {
+            let n = left / bs * bs;
+            dev.read_blocks(lba, &mut buf[done..done + n])?;
+            done += n;
+            pos += n as u64;
+            continue;
+        }
+        let n = (bs - off).min(left);
+        dev.read_blocks(lba, &mut tmp)?;
+        buf[done..done + n].copy_from_slice(&tmp[off..off + n]);
+        done += n;
+        pos += n as u64;
+    }
+    Ok(())
+}
+
+/// Write `data` at byte offset `pos`, merging partial blocks with what is on the device.
+pub fn write_bytes(dev: &mut dyn BlockDevice, mut pos: u64, data: &[u8]) -> Result<(), BlockError> {
+    let bs = dev.block_size();
+    let mut tmp = vec![0u8; bs];
+    let mut done = 0;
+    while done < data.len() {
+        let lba = pos / bs as u64;
+        let off = (pos % bs as u64) as usize;
+        let left = data.len() - done;
+        if off == 0 && left >= bs {
+            let n = left / bs * bs;
+            dev.write_blocks(lba, &data[done..done + n])?;
+            done += n;
+            pos += n as u64;
+            continue;
+        }
+        let n = (bs - off).min(left);
+        dev.read_blocks(lba, &mut tmp)?;
+        tmp[off..off + n].copy_from_slice(&data[done..done + n]);
+        dev.write_blocks(lba, &tmp)?;
+        done += n;
+        pos += n as u64;
+    }
+    Ok(())
 }
 
-pub struct AtaDevice;
+/// One ATA drive found by `ata::probe`.
+pub struct AtaDevice {
+    drive: crate::ata::Drive,
+}
 impl AtaDevice {
-    pub fn new() -> Self { Self }
+    pub fn new(drive: crate::ata::Drive) -> Self { Self { drive } }
 }
 impl BlockDevice for AtaDevice {
-    fn read_sector(&mut self, lba: u32, buf: &mut [u8;512]) -> bool {
-        let mut words = [0u16;256];
-        if crate::ata::read_sector_lba28(lba, &mut words) {
-            for i in 0..256 { let b = words[i].to_le_bytes(); buf[i*2]=b[0]; buf[i*2+1]=b[1]; }
-            true
-        } else { false }
+    fn block_size(&self) -> usize { 512 }
+    fn capacity(&self) -> u64 { self.drive.sectors }
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check(self, lba, buf.len())?;
+        Ok(crate::ata::read(&self.drive, lba, buf)?)
+    }
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check(self, lba, buf.len())?;
+        Ok(crate::ata::write(&self.drive, lba, buf)?)
+    }
+    fn flush(&mut self) -> Result<(), BlockError> { Ok(crate::ata::flush(&self.drive)?) }
+}
+
+/// A block device backed by memory, mostly for exercising filesystems.
+pub struct RamDisk {
+    data: Vec<u8>,
+    block_size: usize,
+}
+
+impl RamDisk {
+    pub fn new(blocks: u64, block_size: usize) -> Self {
+        Self { data: vec![0; blocks as usize * block_size], block_size }
+    }
+    /// Wrap an existing image; a partial trailing block is dropped.
+    pub fn from_vec(mut data: Vec<u8>, block_size: usize) -> Self {
+        data.truncate(data.len() / block_size * block_size);
+        Self { data, block_size }
+    }
+    pub fn as_slice(&self) -> &[u8] { &self.data }
+    pub fn into_inner(self) -> Vec<u8> { self.data }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize { self.block_size }
+    fn capacity(&self) -> u64 { (self.data.len() / self.block_size) as u64 }
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check(self, lba, buf.len())?;
+        let at = lba as usize * self.block_size;
+        buf.copy_from_slice(&self.data[at..at + buf.len()]);
+        Ok(())
+    }
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check(self, lba, buf.len())?;
+        let at = lba as usize * self.block_size;
+        self.data[at..at + buf.len()].copy_from_slice(buf);
+        Ok(())
     }
 }
+
+pub(crate) type Shared = Arc<Mutex<Box<dyn BlockDevice + Send>>>;
+
+lazy_static! {
+    static ref DEVICES: Mutex<Vec<(String, DeviceRef)>> = Mutex::new(Vec::new());
+}
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// A registered device; clones share the same underlying driver.
+/// Access to disks goes through `bcache`; views layered on a disk (partitions) bypass it.
+#[derive(Clone)]
+pub struct DeviceRef {
+    id: usize,
+    dev: Shared,
+    cached: bool,
+}
+
+impl BlockDevice for DeviceRef {
+    fn block_size(&self) -> usize { self.dev.lock().block_size() }
+    fn capacity(&self) -> u64 { self.dev.lock().capacity() }
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        if self.cached { crate::bcache::read(self.id, &self.dev, lba, buf) } else { self.dev.lock().read_blocks(lba, buf) }
+    }
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.cached { crate::bcache::write(self.id, &self.dev, lba, buf) } else { self.dev.lock().write_blocks(lba, buf) }
+    }
+    fn flush(&mut self) -> Result<(), BlockError> {
+        if self.cached { crate::bcache::sync_dev(self.id, &self.dev) } else { self.dev.lock().flush() }
+    }
+}
+
+impl DeviceRef {
+    /// Unique for the lifetime of the kernel; a re-registered name gets a new id.
+    pub fn id(&self) -> usize { self.id }
+}
+
+fn insert(name: &str, dev: Box<dyn BlockDevice + Send>, cached: bool) -> DeviceRef {
+    let r = DeviceRef { id: NEXT_ID.fetch_add(1, Ordering::Relaxed), dev: Arc::new(Mutex::new(dev)), cached };
+    if cached { crate::bcache::attach(r.id, r.dev.clone()); }
+    let old = {
+        let mut devs = DEVICES.lock();
+        let old = devs.iter().position(|(n, _)| n == name).map(|i| devs.remove(i).1);
+        devs.push((name.to_string(), r.clone()));
+        old
+    };
+    if let Some(old) = old { crate::bcache::detach(old.id); }
+    r
+}
+
+/// Make disk `dev` available under `name` (e.g. `hda`), behind the block cache.
+/// A device already using that name is replaced.
+pub fn register(name: &str, dev: Box<dyn BlockDevice + Send>) -> DeviceRef { insert(name, dev, true) }
+
+/// Register a device that forwards to an already registered (and so already cached) one.
+pub fn register_view(name: &str, dev: Box<dyn BlockDevice + Send>) -> DeviceRef { insert(name, dev, false) }
+
+pub fn get(name: &str) -> Option<DeviceRef> {
+    DEVICES.lock().iter().find(|(n, _)| n == name).map(|(_, d)| d.clone())
+}
+
+/// The registered device with `id`, and its name.
+pub fn by_id(id: usize) -> Option<(String, DeviceRef)> {
+    DEVICES.lock().iter().find(|(_, d)| d.id == id).cloned()
+}
+
+/// Registered device names in registration order.
+pub fn devices() -> Vec<String> { DEVICES.lock().iter().map(|(n, _)| n.clone()).collect() }
@@ -0,0 +1,392 @@
+use alloc::vec::Vec;
+
+// Decoders for compressed initrd files and archives: LZ4 blocks, which build.rs writes with
+// `lz4_compress` below, and DEFLATE (RFC 1951) streams, bare or gzip-wrapped (RFC 1952).
+// Everything works on whole buffers and returns None on data that doesn't decode.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec { Lz4, Deflate }
+
+impl Codec {
+    /// Name used in initrd headers.
+    pub fn name(self) -> &'static str {
+        match self { Codec::Lz4 => "lz4", Codec::Deflate => "deflate" }
+    }
+
+    pub fn from_name(name: &str) -> Option<Codec> {
+        match name { "lz4" => Some(Codec::Lz4), "deflate" => Some(Codec::Deflate), _ => None }
+    }
+}
+
+/// Unpack `data`, which must come out at exactly `size` bytes.
+pub fn decompress(codec: Codec, data: &[u8], size: usize) -> Option<Vec<u8>> {
+    let out = match codec {
+        Codec::Lz4 => lz4_decompress(data, size)?,
+        Codec::Deflate => inflate_limit(data, size)?.0,
+    };
+    (out.len() == size).then_some(out)
+}
+
+const LZ4_MIN_MATCH: usize = 4;
+const LZ4_LAST_LITERALS: usize = 5; // the block format ends in at least this many literals
+const LZ4_MF_LIMIT: usize = 12;     // and no match may start closer to the end than this
+
+fn lz4_len(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 255 { out.push(255); n -= 255; }
+    out.push(n as u8);
+}
+
+fn lz4_sequence(out: &mut Vec<u8>, literals: &[u8], m: Option<(usize, usize)>) {
+    let lit = literals.len();
+    let ml = m.map_or(0, |m| m.1 - LZ4_MIN_MATCH);
+    out.push((lit.min(15) as u8) << 4 | ml.min(15) as u8);
+    if lit >= 15 { lz4_len(out, lit - 15); }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = m {
+        out.extend_from_slice(&(offset as u16).to_le_bytes());
+        if ml >= 15 { lz4_len(out, ml - 15); }
+    }
+}
+
+/// LZ4 block (no frame) with a greedy single-probe match finder: quick and small rather than
+/// tight.
+pub fn lz4_compress(src: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(src.len() / 2 + 16);
+    let mut table = alloc::vec![0u32; 1 << 14]; // position + 1 of the last 4 bytes hashing here
+    let hash = |i: usize| (u32::from_le_bytes([src[i], src[i + 1], src[i + 2], src[i + 3]]).wrapping_mul(2_654_435_761) >> 18) as usize;
+    let (mut anchor, mut i) = (0, 0);
+    while src.len() > LZ4_MF_LIMIT && i < src.len() - LZ4_MF_LIMIT {
+        let cand = core::mem::replace(&mut table[hash(i)], i as u32 + 1) as usize;
+        if cand == 0 || i - (cand - 1) > 0xFFFF || src[cand - 1..cand + 3] != src[i..i + 4] { i += 1; continue; }
+        let m = cand - 1;
+        let max = src.len() - LZ4_LAST_LITERALS - i;
+        let mut len = LZ4_MIN_MATCH;
+        while len < max && src[m + len] == src[i + len] { len += 1; }
+        lz4_sequence(&mut out, &src[anchor..i], Some((i - m, len)));
+        // remember the positions the match covered too, so repeats of them are found
+        for k in i + 1..i + len { table[hash(k)] = k as u32 + 1; }
+        i += len;
+        anchor = i;
+    }
+    lz4_sequence(&mut out, &src[anchor..], None);
+    out
+}
+
+fn lz4_extra(src: &[u8], i: &mut usize, mut n: usize) -> Option<usize> {
+    if n != 15 { return Some(n); }
+    loop {
+        let b = *src.get(*i)?;
+        *i += 1;
+        n += b as usize;
+        if b != 255 { return Some(n); }
+    }
+}
+
+/// Decode an LZ4 block that unpacks to `size` bytes.
+pub fn lz4_decompress(src: &[u8], size: usize) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(size);
+    let mut i = 0;
+    loop {
+        let token = *src.get(i)?;
+        i += 1;
+        let lit = lz4_extra(src, &mut i, (token >> 4) as usize)?;
+        if out.len() + lit > size { return None; }
+        out.extend_from_slice(src.get(i..i + lit)?);
+        i += lit;
+        if i == src.len() { break; }
+        let offset = u16::from_le_bytes([*src.get(i)?, *src.get(i + 1)?]) as usize;
+        i += 2;
+        let len = lz4_extra(src, &mut i, (token & 15) as usize)? + LZ4_MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + len > size { return None; }
+        // byte by byte: the match may overlap what it is producing
+        let start = out.len() - offset;
+        for k in 0..len { out.push(out[start + k]); }
+    }
+    Some(out)
+}
+
+// LSB-first bit reader over a DEFLATE stream.
+struct Bits<'a> { data: &'a [u8], pos: usize, buf: u32, cnt: u32 }
+
+impl Bits<'_> {
+    fn get(&mut self, n: u32) -> Option<u32> {
+        while self.cnt < n {
+            self.buf |= (*self.data.get(self.pos)? as u32) << self.cnt;
+            self.pos += 1;
+            self.cnt += 8;
+        }
+        let v = self.buf & ((1u32 << n) - 1);
+        self.buf >>= n;
+        self.cnt -= n;
+        Some(v)
+    }
+}
+
+// Canonical Huffman code: how many codes of each length, and the symbols in code order.
+struct Huffman { count: [u16; 16], symbol: Vec<u16> }
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Option<Huffman> {
+        let mut count = [0u16; 16];
+        for &l in lengths { count[l as usize] += 1; }
+        count[0] = 0;
+        // over-subscribed sets can't be decoded; incomplete ones are allowed (a lone distance code)
+        let mut left = 1i32;
+        for &c in &count[1..] {
+            left = (left << 1) - c as i32;
+            if left < 0 { return None; }
+        }
+        let mut offs = [0u16; 16];
+        for l in 1..15 { offs[l + 1] = offs[l] + count[l]; }
+        let mut symbol = alloc::vec![0u16; lengths.len()];
+        for (s, &l) in lengths.iter().enumerate() {
+            if l != 0 { symbol[offs[l as usize] as usize] = s as u16; offs[l as usize] += 1; }
+        }
+        Some(Huffman { count, symbol })
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Option<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= bits.get(1)? as i32;
+            let count = self.count[len] as i32;
+            if code - first < count { return self.symbol.get((index + code - first) as usize).copied(); }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        None
+    }
+}
+
+const LEN_BASE: [u16; 29] = [3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258];
+const LEN_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+// order the code length code lengths are sent in
+const CLEN_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn inflate_codes(bits: &mut Bits, out: &mut Vec<u8>, max: usize, lit: &Huffman, dist: &Huffman) -> Option<()> {
+    loop {
+        let sym = lit.decode(bits)? as usize;
+        match sym {
+            0..=255 if out.len() < max => out.push(sym as u8),
+            0..=255 => return None,
+            256 => return Some(()),
+            _ => {
+                let i = sym - 257;
+                let len = *LEN_BASE.get(i)? as usize + bits.get(*LEN_EXTRA.get(i)? as u32)? as usize;
+                let d = dist.decode(bits)? as usize;
+                let back = *DIST_BASE.get(d)? as usize + bits.get(*DIST_EXTRA.get(d)? as u32)? as usize;
+                if back > out.len() || out.len() + len > max { return None; }
+                let start = out.len() - back;
+                for k in 0..len { out.push(out[start + k]); }
+            }
+        }
+    }
+}
+
+fn dynamic_tables(bits: &mut Bits) -> Option<(Huffman, Huffman)> {
+    let nlen = bits.get(5)? as usize + 257;
+    let ndist = bits.get(5)? as usize + 1;
+    let ncode = bits.get(4)? as usize + 4;
+    if nlen > 286 || ndist > 30 { return None; }
+    let mut clens = [0u8; 19];
+    for &i in &CLEN_ORDER[..ncode] { clens[i] = bits.get(3)? as u8; }
+    let clen = Huffman::new(&clens)?;
+    let mut lengths = alloc::vec![0u8; nlen + ndist];
+    let mut i = 0;
+    while i < nlen + ndist {
+        let (value, repeat) = match clen.decode(bits)? {
+            sym @ 0..=15 => (sym as u8, 1),
+            16 => (*lengths.get(i.checked_sub(1)?)?, 3 + bits.get(2)? as usize),
+            17 => (0, 3 + bits.get(3)? as usize),
+            _ => (0, 11 + bits.get(7)? as usize),
+        };
+        if i + repeat > lengths.len() { return None; }
+        lengths[i..i + repeat].fill(value);
+        i += repeat;
+    }
+    if lengths[256] == 0 { return None; } // no end-of-block code
+    Some((Huffman::new(&lengths[..nlen])?, Huffman::new(&lengths[nlen..])?))
+}
+
+/// Decode a raw DEFLATE stream; returns the data and how many input bytes it took.
+pub fn inflate(data: &[u8]) -> Option<(Vec<u8>, usize)> { inflate_limit(data, usize::MAX) }
+
+/// `inflate`, giving up as soon as the output would grow past `max` bytes.
+pub fn inflate_limit(data: &[u8], max: usize) -> Option<(Vec<u8>, usize)> {
+    let mut bits = Bits { data, pos: 0, buf: 0, cnt: 0 };
+    let mut out = Vec::with_capacity(data.len().saturating_mul(3).min(max));
+    loop {
+        let last = bits.get(1)? == 1;
+        match bits.get(2)? {
+            0 => {
+                // stored: byte aligned length, its complement, then the bytes
+                bits.buf = 0;
+                bits.cnt = 0;
+                let at = bits.pos;
+                let hdr = data.get(at..at + 4)?;
+                let len = u16::from_le_bytes([hdr[0], hdr[1]]);
+                if len != !u16::from_le_bytes([hdr[2], hdr[3]]) { return None; }
+                if out.len() + len as usize > max { return None; }
+                out.extend_from_slice(data.get(at + 4..at + 4 + len as usize)?);
+                bits.pos = at + 4 + len as usize;
+            }
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].fill(8);
+                lengths[144..256].fill(9);
+                lengths[256..280].fill(7);
+                lengths[280..].fill(8);
+                let lit = Huffman::new(&lengths)?;
+                let dist = Huffman::new(&[5u8; 30])?;
+                inflate_codes(&mut bits, &mut out, max, &lit, &dist)?;
+            }
+            2 => {
+                let (lit, dist) = dynamic_tables(&mut bits)?;
+                inflate_codes(&mut bits, &mut out, max, &lit, &dist)?;
+            }
+            _ => return None,
+        }
+        if last { return Some((out, bits.pos)); }
+    }
+}
+
+/// CRC-32 (IEEE), as in gzip trailers.
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 { crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg()); }
+    }
+    !crc
+}
+
+pub fn is_gzip(data: &[u8]) -> bool { data.starts_with(&[0x1F, 0x8B, 8]) }
+
+/// Unpack a gzip file (one member) of at most `max` bytes, checking its CRC and length.
+pub fn gunzip(data: &[u8], max: usize) -> Option<Vec<u8>> {
+    if !is_gzip(data) { return None; }
+    let flags = *data.get(3)?;
+    let mut at = 10;
+    if flags & 4 != 0 { at += 2 + u16::from_le_bytes([*data.get(at)?, *data.get(at + 1)?]) as usize; } // FEXTRA
+    for bit in [8, 16] { // FNAME, FCOMMENT: NUL-terminated
+        if flags & bit != 0 { at += data.get(at..)?.iter().position(|&b| b == 0)? + 1; }
+    }
+    if flags & 2 != 0 { at += 2; } // FHCRC
+    let (out, used) = inflate_limit(data.get(at..)?, max)?;
+    let trailer = data.get(at + used..at + used + 8)?;
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let len = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    (crc == crc32(&out) && len == out.len() as u32).then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::{format, vec};
+
+    const HELLO: &[u8] = b"hello, hello, hello world\n";
+    // HELLO as zlib writes it at level 0 (stored), level 9 (fixed codes) and as a gzip file
+    const STORED: [u8; 31] = [0x01, 0x1A, 0x00, 0xE5, 0xFF, 0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x2C, 0x20, 0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x2C, 0x20, 0x68, 0x65, 0x6C, 0x6C, 0x6F, 0x20, 0x77, 0x6F, 0x72, 0x6C, 0x64, 0x0A];
+    const FIXED: [u8; 18] = [0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0xD7, 0x51, 0xC8, 0x40, 0xA2, 0x14, 0xCA, 0xF3, 0x8B, 0x72, 0x52, 0xB8, 0x00];
+    const GZIP: [u8; 36] = [
+        0x1F, 0x8B, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xCB, 0x48, 0xCD, 0xC9, 0xC9, 0xD7, 0x51, 0xC8,
+        0x40, 0xA2, 0x14, 0xCA, 0xF3, 0x8B, 0x72, 0x52, 0xB8, 0x00, 0x87, 0x5D, 0x46, 0x2B, 0x1A, 0x00, 0x00, 0x00,
+    ];
+    // 20 a's, 10 b's, 5 c's and a d with Huffman codes only, which zlib sends as a dynamic block
+    const DYNAMIC: [u8; 23] = [0x05, 0xC1, 0x01, 0x01, 0x00, 0x00, 0x08, 0xC3, 0xA0, 0xAC, 0xEC, 0xF6, 0xCF, 0x20, 0x00, 0x00, 0x40, 0x55, 0x55, 0x6D, 0xDB, 0xEE, 0x01];
+
+    fn sample() -> Vec<u8> {
+        let mut v = Vec::new();
+        for i in 0..2000u32 { v.extend_from_slice(format!("line {} of {}\n", i, i % 7).as_bytes()); }
+        v.extend((0..5000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)); // incompressible tail
+        v
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn lz4_known_block() {
+        // "abc", then a 9-byte match 3 back that overlaps itself, then the 5 closing literals
+        let block = [0x35, b'a', b'b', b'c', 0x03, 0x00, 0x50, b'x', b'y', b'z', b'z', b'y'];
+        assert_eq!(lz4_decompress(&block, 17).unwrap(), b"abcabcabcabcxyzzy");
+        assert_eq!(lz4_decompress(&block, 16), None); // more than the stated size
+    }
+
+    #[test]
+    fn lz4_round_trip() {
+        for data in [Vec::new(), b"short".to_vec(), vec![7u8; 70_000], sample()] {
+            let packed = lz4_compress(&data);
+            assert_eq!(lz4_decompress(&packed, data.len()).unwrap(), data);
+            assert_eq!(decompress(Codec::Lz4, &packed, data.len()).unwrap(), data);
+        }
+        assert!(lz4_compress(&vec![7u8; 70_000]).len() < 1000);
+    }
+
+    #[test]
+    fn lz4_rejects_bad_blocks() {
+        assert_eq!(lz4_decompress(&[], 0), None);
+        assert_eq!(lz4_decompress(&[0x50, b'a', b'b'], 5), None); // literals run past the end
+        assert_eq!(lz4_decompress(&[0x10, b'a', 0x00, 0x00, 0x00], 10), None); // offset 0
+        assert_eq!(lz4_decompress(&[0x10, b'a', 0x02, 0x00, 0x00], 10), None); // offset before the start
+        let packed = lz4_compress(&sample());
+        for cut in [1, packed.len() / 2, packed.len() - 1] {
+            assert_ne!(lz4_decompress(&packed[..cut], sample().len()).as_deref(), Some(&sample()[..]));
+        }
+    }
+
+    #[test]
+    fn inflate_vectors() {
+        assert_eq!(inflate(&STORED).unwrap(), (HELLO.to_vec(), STORED.len()));
+        assert_eq!(inflate(&FIXED).unwrap(), (HELLO.to_vec(), FIXED.len()));
+        let want: Vec<u8> = [(b'a', 20), (b'b', 10), (b'c', 5), (b'd', 1)].iter().flat_map(|&(b, n)| vec![b; n]).collect();
+        assert_eq!(inflate(&DYNAMIC).unwrap().0, want);
+        assert_eq!(decompress(Codec::Deflate, &FIXED, HELLO.len()).unwrap(), HELLO);
+        assert_eq!(decompress(Codec::Deflate, &FIXED, HELLO.len() + 1), None);
+        assert_eq!(decompress(Codec::Deflate, &FIXED, HELLO.len() - 1), None);
+    }
+
+    // a stream that unpacks to more than the caller allows stops there instead of running on
+    #[test]
+    fn inflate_stops_at_the_limit() {
+        for stream in [&STORED[..], &FIXED[..]] {
+            assert_eq!(inflate_limit(stream, HELLO.len()).unwrap().0, HELLO);
+            assert_eq!(inflate_limit(stream, HELLO.len() - 1), None);
+            assert_eq!(inflate_limit(stream, 0), None);
+        }
+        assert_eq!(inflate_limit(&DYNAMIC, 35), None);
+        assert_eq!(gunzip(&GZIP, HELLO.len() - 1), None);
+    }
+
+    #[test]
+    fn inflate_rejects_bad_streams() {
+        assert_eq!(inflate(&[]), None);
+        assert_eq!(inflate(&[0x07]), None); // reserved block type
+        let mut bad = STORED;
+        bad[3] ^= 1; // length complement doesn't match
+        assert_eq!(inflate(&bad), None);
+        assert_eq!(inflate(&STORED[..20]), None);
+        assert_eq!(inflate(&FIXED[..10]), None);
+        assert_eq!(inflate(&DYNAMIC[..8]), None);
+    }
+
+    #[test]
+    fn gunzip_checks_trailer() {
+        assert!(is_gzip(&GZIP));
+        assert_eq!(gunzip(&GZIP, usize::MAX).unwrap(), HELLO);
+        let mut bad = GZIP;
+        bad[28] ^= 0xFF; // CRC
+        assert_eq!(gunzip(&bad, usize::MAX), None);
+        let mut bad = GZIP;
+        bad[32] += 1; // length
+        assert_eq!(gunzip(&bad, usize::MAX), None);
+        assert_eq!(gunzip(&GZIP[..GZIP.len() - 4], usize::MAX), None);
+        assert_eq!(gunzip(&FIXED, usize::MAX), None);
+    }
+}
@@ -67,5 +67,6 @@ pub fn draw() {
     graphics::draw_text(pad, y + bh - 24, line, Color::WHITE, None);
 }
 
+pub fn print(s: &str) { let _ = CONSOLE.lock().output.push_str(s); draw(); }
 pub fn println(s: &str) { let mut c = CONSOLE.lock(); let _ = c.output.push_str(s); let _ = c.output.push('\n'); drop(c); draw(); }
 pub fn clear() { CONSOLE.lock().output.clear(); draw(); }
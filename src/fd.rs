@@ -0,0 +1,80 @@
+use crate::pipe;
+use smoltcp::iface::SocketHandle;
+
+/// What a task's file descriptor refers to.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Fd {
+    Console,
+    PipeRead(usize),
+    PipeWrite(usize),
+    File(usize),
+    Udp(SocketHandle),
+    Watch(usize), // change events on a path, see fs::watch
+}
+
+pub fn read(fd: Fd, buf: &mut [u8]) -> isize {
+    match fd {
+        Fd::Console => read_console(buf),
+        Fd::PipeRead(id) => pipe::read(id, buf),
+        Fd::File(h) => crate::fs::read_handle(h, buf),
+        Fd::Udp(s) => crate::net::netstack::udp_recv(s, buf).map_or(0, |(n, _, _)| n as isize),
+        Fd::Watch(id) => crate::fs::watch::read(id, buf).map_or(-1, |n| n as isize),
+        Fd::PipeWrite(_) => -1,
+    }
+}
+
+pub fn write(fd: Fd, data: &[u8]) -> isize {
+    match fd {
+        Fd::Console => match core::str::from_utf8(data) {
+            Ok(text) => { crate::console::print(text); data.len() as isize }
+            Err(_) => -1,
+        },
+        Fd::PipeWrite(id) => pipe::write(id, data),
+        Fd::File(h) => crate::fs::write_handle(h, data),
+        // UDP needs a destination; use sendto
+        Fd::PipeRead(_) | Fd::Udp(_) | Fd::Watch(_) => -1,
+    }
+}
+
+/// Take another reference on the object behind `fd` (used by dup2 and fd inheritance).
+pub fn dup(fd: Fd) -> Fd {
+    match fd {
+        Fd::PipeRead(id) => pipe::add_reader(id),
+        Fd::PipeWrite(id) => pipe::add_writer(id),
+        Fd::File(h) => crate::fs::dup_handle(h),
+        Fd::Udp(s) => crate::net::netstack::udp_dup(s),
+        Fd::Watch(id) => crate::fs::watch::dup(id),
+        Fd::Console => {}
+    }
+    fd
+}
+
+pub fn close(fd: Fd) {
+    match fd {
+        Fd::PipeRead(id) => pipe::close_read(id),
+        Fd::PipeWrite(id) => pipe::close_write(id),
+        Fd::File(h) => crate::fs::close_handle(h),
+        Fd::Udp(s) => crate::net::netstack::udp_close(s),
+        Fd::Watch(id) => crate::fs::watch::close(id),
+        Fd::Console => {}
+    }
+}
+
+// Line-buffered read from the keyboard TTY; blocks until a newline or the buffer is full.
+fn read_console(buf: &mut [u8]) -> isize {
+    let mut n = 0;
+    while n < buf.len() {
+        match crate::tty::read_char() {
+            Some(c) => {
+                let mut tmp = [0u8; 4];
+                let enc = c.encode_utf8(&mut tmp).as_bytes();
+                if n + enc.len() > buf.len() { break; }
+                buf[n..n + enc.len()].copy_from_slice(enc);
+                n += enc.len();
+                if c == '\n' || c == '\r' { break; }
+            }
+            None => crate::scheduler::yield_now(),
+        }
+    }
+    n as isize
+}
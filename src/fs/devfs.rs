@@ -0,0 +1,196 @@
+use alloc::string::ToString;
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::vfs::{DirEntry, FileType, Filesystem, FsError, Ino, Stat};
+use crate::block::{self, BlockDevice};
+
+// /dev: device nodes backed by the drivers. Character devices ignore the file offset, except
+// fb0 which is addressed like a file; disks are read and written by byte offset through the
+// block cache. Reads from tty, serial and mouse wait for input (see `vfs::read`).
+
+const ROOT: Ino = 1;
+const NULL: Ino = 2;
+const ZERO: Ino = 3;
+const RANDOM: Ino = 4;
+const TTY0: Ino = 5;
+const TTYS0: Ino = 6;
+const FB0: Ino = 7;
+const INPUT: Ino = 8;
+const MOUSE: Ino = 9;
+const DISK_BASE: Ino = 0x1000; // + block::DeviceRef::id
+
+const TOP: &[(&str, Ino)] = &[("null", NULL), ("zero", ZERO), ("random", RANDOM), ("tty0", TTY0), ("ttyS0", TTYS0), ("fb0", FB0), ("input", INPUT)];
+
+fn disk(ino: Ino) -> Result<block::DeviceRef, FsError> {
+    ino.checked_sub(DISK_BASE).and_then(|id| block::by_id(id as usize)).map(|d| d.1).ok_or(FsError::NotFound)
+}
+
+fn kind(ino: Ino) -> Result<FileType, FsError> {
+    match ino {
+        ROOT | INPUT => Ok(FileType::Dir),
+        NULL..=MOUSE => Ok(FileType::CharDev),
+        _ => disk(ino).map(|_| FileType::BlockDev),
+    }
+}
+
+static SEED: Mutex<u64> = Mutex::new(0);
+
+// xorshift64* seeded from the TSC and the clock, with RDRAND mixed in when the CPU has it.
+// Good enough for salts and ids, not for keys.
+pub fn fill_random(buf: &mut [u8]) {
+    let rdrand = x86_64::instructions::random::RdRand::new();
+    let mut s = SEED.lock();
+    if *s == 0 { *s = (crate::strace::cycles() ^ crate::rtc::now().rotate_left(32)) | 1; }
+    for chunk in buf.chunks_mut(8) {
+        *s ^= *s << 13;
+        *s ^= *s >> 7;
+        *s ^= *s << 17;
+        let v = s.wrapping_mul(0x2545_F491_4F6C_DD1D) ^ rdrand.and_then(|r| r.get_u64()).unwrap_or(0);
+        chunk.copy_from_slice(&v.to_le_bytes()[..chunk.len()]);
+    }
+}
+
+// Writes to /dev/random stir the generator.
+fn stir(data: &[u8]) {
+    let mut s = SEED.lock();
+    for (i, &b) in data.iter().enumerate() { *s ^= (b as u64) << (i % 8 * 8); }
+    if *s == 0 { *s = 1; }
+}
+
+// Console input up to and including a newline; tty::read_char is fed by the keyboard IRQ.
+fn read_tty(buf: &mut [u8]) -> Result<usize, FsError> {
+    let mut n = 0;
+    while n < buf.len() {
+        let Some(c) = x86_64::instructions::interrupts::without_interrupts(crate::tty::read_char) else { break };
+        let mut tmp = [0u8; 4];
+        let enc = c.encode_utf8(&mut tmp).as_bytes();
+        if n + enc.len() > buf.len() { break; }
+        buf[n..n + enc.len()].copy_from_slice(enc);
+        n += enc.len();
+        if c == '\n' || c == '\r' { break; }
+    }
+    if n == 0 && !buf.is_empty() { Err(FsError::WouldBlock) } else { Ok(n) }
+}
+
+fn read_serial(buf: &mut [u8]) -> Result<usize, FsError> {
+    let mut n = 0;
+    while n < buf.len() {
+        let Some(b) = crate::serial::try_read_byte() else { break };
+        buf[n] = b;
+        n += 1;
+    }
+    if n == 0 && !buf.is_empty() { Err(FsError::WouldBlock) } else { Ok(n) }
+}
+
+// Whole 3-byte PS/2 packets: buttons, dx, dy.
+fn read_mouse(buf: &mut [u8]) -> Result<usize, FsError> {
+    if buf.len() < 3 { return Err(FsError::Invalid); }
+    let mut n = 0;
+    while n + 3 <= buf.len() {
+        let Some(p) = crate::mouse::read_packet() else { break };
+        buf[n..n + 3].copy_from_slice(&p);
+        n += 3;
+    }
+    if n == 0 { Err(FsError::WouldBlock) } else { Ok(n) }
+}
+
+fn disk_len(d: &block::DeviceRef) -> u64 { d.capacity() * d.block_size() as u64 }
+
+pub struct DevFs;
+
+impl Filesystem for DevFs {
+    fn name(&self) -> &'static str { "devfs" }
+
+    fn root(&self) -> Ino { ROOT }
+
+    fn lookup(&mut self, dir: Ino, name: &str) -> Result<Ino, FsError> {
+        match dir {
+            ROOT => match TOP.iter().find(|e| e.0 == name) {
+                Some(e) => Ok(e.1),
+                None => block::get(name).map(|d| DISK_BASE + d.id() as Ino).ok_or(FsError::NotFound),
+            },
+            INPUT if name == "mouse" => Ok(MOUSE),
+            INPUT => Err(FsError::NotFound),
+            _ => Err(FsError::NotDir),
+        }
+    }
+
+    fn stat(&mut self, ino: Ino) -> Result<Stat, FsError> {
+        let kind = kind(ino)?;
+        let (size, mode) = match kind {
+            FileType::Dir => (0, 0o755),
+            FileType::BlockDev => (disk_len(&disk(ino)?), 0o660),
+            _ if ino == FB0 => (crate::graphics::fb_len() as u64, 0o660),
+            _ => (0, 0o666),
+        };
+        Ok(Stat { ino, kind, size, mode, uid: 0, gid: 0, ctime: 0, mtime: 0 })
+    }
+
+    fn read_at(&mut self, ino: Ino, off: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        match ino {
+            NULL => Ok(0),
+            ZERO => { buf.fill(0); Ok(buf.len()) }
+            RANDOM => { fill_random(buf); Ok(buf.len()) }
+            TTY0 => read_tty(buf),
+            TTYS0 => read_serial(buf),
+            FB0 => Ok(crate::graphics::fb_read(off as usize, buf)),
+            MOUSE => read_mouse(buf),
+            ROOT | INPUT => Err(FsError::IsDir),
+            _ => {
+                let mut d = disk(ino)?;
+                let n = buf.len().min(disk_len(&d).saturating_sub(off) as usize);
+                block::read_bytes(&mut d, off, &mut buf[..n])?;
+                Ok(n)
+            }
+        }
+    }
+
+    fn readdir(&mut self, dir: Ino) -> Result<Vec<DirEntry>, FsError> {
+        let mut out = Vec::new();
+        match dir {
+            ROOT => {
+                for &(name, ino) in TOP { out.push(DirEntry { name: name.to_string(), ino, kind: kind(ino)? }); }
+                for name in block::devices() {
+                    let Some(d) = block::get(&name) else { continue };
+                    out.push(DirEntry { name, ino: DISK_BASE + d.id() as Ino, kind: FileType::BlockDev });
+                }
+            }
+            INPUT => out.push(DirEntry { name: "mouse".to_string(), ino: MOUSE, kind: FileType::CharDev }),
+            _ => { kind(dir)?; return Err(FsError::NotDir); }
+        }
+        Ok(out)
+    }
+
+    fn write_at(&mut self, ino: Ino, off: u64, data: &[u8]) -> Result<usize, FsError> {
+        match ino {
+            NULL | ZERO => Ok(data.len()),
+            RANDOM => { stir(data); Ok(data.len()) }
+            TTY0 => {
+                let text = core::str::from_utf8(data).map_err(|_| FsError::Invalid)?;
+                crate::console::println(text.trim_end_matches('\n'));
+                Ok(data.len())
+            }
+            TTYS0 => { crate::serial::write_bytes(data); Ok(data.len()) }
+            FB0 => match crate::graphics::fb_write(off as usize, data) {
+                0 if !data.is_empty() => Err(FsError::NoSpace),
+                n => Ok(n),
+            },
+            MOUSE => Err(FsError::Invalid),
+            ROOT | INPUT => Err(FsError::IsDir),
+            _ => {
+                let mut d = disk(ino)?;
+                let n = data.len().min(disk_len(&d).saturating_sub(off) as usize);
+                if n == 0 && !data.is_empty() { return Err(FsError::NoSpace); }
+                block::write_bytes(&mut d, off, &data[..n])?;
+                Ok(n)
+            }
+        }
+    }
+
+    // `>` redirection truncates first; devices have nothing to cut
+    fn truncate(&mut self, ino: Ino, _size: u64) -> Result<(), FsError> {
+        match kind(ino)? { FileType::Dir => Err(FsError::IsDir), _ => Ok(()) }
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> { Ok(()) }
+}
@@ -0,0 +1,884 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::block::{self, BlockDevice};
+use super::vfs::{DirEntry, FileType, Filesystem, FsError, Ino, Stat};
+
+const MAGIC: u16 = 0xEF53;
+const SB_OFFSET: u64 = 1024;
+const ROOT: Ino = 2;
+const NDIR: usize = 12; // direct block pointers in an inode
+const FAST_SYMLINK_MAX: usize = 60; // targets shorter than this live in i_block itself
+
+const S_IFMT: u16 = 0xF000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xA000;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const RO_COMPAT_SPARSE_SUPER: u32 = 0x0001;
+const RO_COMPAT_LARGE_FILE: u32 = 0x0002;
+const RO_COMPAT_BTREE_DIR: u32 = 0x0004;
+const INDEX_FL: u32 = 0x1000; // i_flags: directory has an htree index
+
+// directory entry file_type values
+const FT_REG: u8 = 1;
+const FT_DIR: u8 = 2;
+const FT_SYMLINK: u8 = 7;
+
+fn le16(b: &[u8], at: usize) -> u16 { u16::from_le_bytes([b[at], b[at + 1]]) }
+fn le32(b: &[u8], at: usize) -> u32 { u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]]) }
+fn put16(b: &mut [u8], at: usize, v: u16) { b[at..at + 2].copy_from_slice(&v.to_le_bytes()); }
+fn put32(b: &mut [u8], at: usize, v: u32) { b[at..at + 4].copy_from_slice(&v.to_le_bytes()); }
+
+#[derive(Clone)]
+pub struct Superblock {
+    pub inodes_count: u32,
+    pub blocks_count: u32,
+    pub free_blocks: u32,
+    pub free_inodes: u32,
+    pub first_data_block: u32,
+    pub block_size: usize,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub first_ino: u32,
+    pub inode_size: usize,
+    pub incompat: u32,
+    pub ro_compat: u32,
+    pub volume_name: String,
+}
+
+impl Superblock {
+    pub fn groups(&self) -> u32 { (self.blocks_count - self.first_data_block).div_ceil(self.blocks_per_group) }
+    /// Blocks belonging to group `g` (the last group may be short).
+    pub fn blocks_in_group(&self, g: u32) -> u32 {
+        (self.blocks_count - self.first_data_block - g * self.blocks_per_group).min(self.blocks_per_group)
+    }
+}
+
+pub fn read_superblock(dev: &mut dyn BlockDevice) -> Option<Superblock> {
+    let mut b = [0u8; 1024];
+    block::read_bytes(dev, SB_OFFSET, &mut b).ok()?;
+    if le16(&b, 56) != MAGIC { return None; }
+    let log = le32(&b, 24);
+    if log > 6 { return None; }
+    let block_size = 1024usize << log;
+    let (first_ino, inode_size) = if le32(&b, 76) == 0 { (11, 128) } else { (le32(&b, 84), le16(&b, 88) as usize) };
+    let sb = Superblock {
+        inodes_count: le32(&b, 0), blocks_count: le32(&b, 4), free_blocks: le32(&b, 12), free_inodes: le32(&b, 16),
+        first_data_block: le32(&b, 20), block_size, blocks_per_group: le32(&b, 32), inodes_per_group: le32(&b, 40),
+        first_ino, inode_size, incompat: le32(&b, 96), ro_compat: le32(&b, 100),
+        volume_name: String::from_utf8_lossy(&b[120..136]).trim_end_matches('\0').into(),
+    };
+    let sane = sb.blocks_per_group != 0 && sb.inodes_per_group != 0 && sb.blocks_count > sb.first_data_block
+        && inode_size >= 128 && inode_size.is_power_of_two() && inode_size <= block_size;
+    sane.then_some(sb)
+}
+
+#[derive(Clone, Copy)]
+struct Group { block_bitmap: u32, inode_bitmap: u32, inode_table: u32, free_blocks: u16, free_inodes: u16, used_dirs: u16 }
+
+/// The first 128 bytes of an on-disk inode; `raw` keeps the fields we don't interpret.
+#[derive(Clone)]
+struct Inode {
+    mode: u16,
+    size: u64,
+    ctime: u32,
+    mtime: u32,
+    links: u16,
+    sectors: u32, // 512-byte units, indirect blocks included
+    block: [u32; 15],
+    file_acl: u32,
+    raw: [u8; 128],
+}
+
+impl Inode {
+    fn parse(raw: [u8; 128], large_files: bool) -> Self {
+        let mode = le16(&raw, 0);
+        // for directories the high size word is i_dir_acl
+        let high = if large_files && mode & S_IFMT == S_IFREG { le32(&raw, 108) as u64 } else { 0 };
+        Self {
+            mode, size: le32(&raw, 4) as u64 | high << 32, ctime: le32(&raw, 12), mtime: le32(&raw, 16),
+            links: le16(&raw, 26), sectors: le32(&raw, 28), block: core::array::from_fn(|i| le32(&raw, 40 + i * 4)),
+            file_acl: le32(&raw, 104), raw,
+        }
+    }
+
+    fn new(mode: u16, links: u16) -> Self {
+        let mut i = Self::parse([0; 128], false);
+        i.mode = mode;
+        i.links = links;
+        i
+    }
+
+    fn encode(&self) -> [u8; 128] {
+        let mut raw = self.raw;
+        put16(&mut raw, 0, self.mode);
+        put32(&mut raw, 4, self.size as u32);
+        put32(&mut raw, 12, self.ctime);
+        put32(&mut raw, 16, self.mtime);
+        put16(&mut raw, 26, self.links);
+        put32(&mut raw, 28, self.sectors);
+        for (i, b) in self.block.iter().enumerate() { put32(&mut raw, 40 + i * 4, *b); }
+        if self.mode & S_IFMT == S_IFREG { put32(&mut raw, 108, (self.size >> 32) as u32); }
+        raw
+    }
+
+    // i_uid/i_gid hold the low 16 bits, the Linux osd2 fields the high ones
+    fn owner(&self) -> (u32, u32) {
+        (le16(&self.raw, 2) as u32 | (le16(&self.raw, 120) as u32) << 16, le16(&self.raw, 24) as u32 | (le16(&self.raw, 122) as u32) << 16)
+    }
+
+    fn set_owner(&mut self, uid: u32, gid: u32) {
+        put16(&mut self.raw, 2, uid as u16);
+        put16(&mut self.raw, 120, (uid >> 16) as u16);
+        put16(&mut self.raw, 24, gid as u16);
+        put16(&mut self.raw, 122, (gid >> 16) as u16);
+    }
+
+    fn kind(&self) -> FileType {
+        match self.mode & S_IFMT { S_IFDIR => FileType::Dir, S_IFLNK => FileType::Symlink, _ => FileType::File }
+    }
+}
+
+struct Dirent {
+    ino: u32,
+    name: String,
+    ftype: u8,
+    off: u64,     // within the directory's data
+    rec_len: usize,
+    prev: Option<(u64, usize)>, // previous entry in the same block: offset and rec_len
+}
+
+fn dirent_len(name_len: usize) -> usize { (8 + name_len).next_multiple_of(4) }
+
+/// Every record of a directory, including unused (inode 0) ones; a corrupt record ends its block.
+fn parse_dir(bytes: &[u8], bs: usize) -> Vec<Dirent> {
+    let mut out = Vec::new();
+    for start in (0..bytes.len()).step_by(bs) {
+        let (mut off, mut prev) = (0, None);
+        while off + 8 <= bs && start + off + 8 <= bytes.len() {
+            let at = start + off;
+            let (rec_len, name_len) = (le16(bytes, at + 4) as usize, bytes[at + 6] as usize);
+            if rec_len < 8 || rec_len % 4 != 0 || off + rec_len > bs || 8 + name_len > rec_len { break; }
+            let name = String::from_utf8_lossy(&bytes[at + 8..at + 8 + name_len]).into_owned();
+            out.push(Dirent { ino: le32(bytes, at), name, ftype: bytes[at + 7], off: at as u64, rec_len, prev });
+            prev = Some((at as u64, rec_len));
+            off += rec_len;
+        }
+    }
+    out
+}
+
+pub struct Ext2Fs {
+    dev: Box<dyn BlockDevice + Send>,
+    pub sb: Superblock,
+    groups: Vec<Group>,
+    gdt: Vec<u8>,  // raw descriptors, so fields we don't track survive a rewrite
+    writable: bool, // false when the volume uses features we can't keep consistent
+    dirty: bool,    // free counts changed since the last sync
+}
+
+impl Ext2Fs {
+    /// Open the ext2 volume on `dev`; `None` if there isn't one or it needs features we lack
+    /// (extents, 64-bit, journal recovery...). Unknown read-only-compatible features mount read-only.
+    pub fn new(mut dev: Box<dyn BlockDevice + Send>) -> Option<Self> {
+        let sb = read_superblock(dev.as_mut())?;
+        if sb.incompat & !(INCOMPAT_FILETYPE | INCOMPAT_FLEX_BG) != 0 { return None; }
+        let writable = sb.ro_compat & !(RO_COMPAT_SPARSE_SUPER | RO_COMPAT_LARGE_FILE | RO_COMPAT_BTREE_DIR) == 0;
+        let mut gdt = vec![0u8; sb.groups() as usize * 32];
+        let at = (sb.first_data_block as u64 + 1) * sb.block_size as u64;
+        block::read_bytes(dev.as_mut(), at, &mut gdt).ok()?;
+        let groups = gdt.as_chunks::<32>().0.iter().map(|d| Group {
+            block_bitmap: le32(d, 0), inode_bitmap: le32(d, 4), inode_table: le32(d, 8),
+            free_blocks: le16(d, 12), free_inodes: le16(d, 14), used_dirs: le16(d, 16),
+        }).collect();
+        Some(Self { dev, sb, groups, gdt, writable, dirty: false })
+    }
+
+    pub fn is_writable(&self) -> bool { self.writable }
+
+    fn rw(&self) -> Result<(), FsError> { if self.writable { Ok(()) } else { Err(FsError::ReadOnly) } }
+
+    fn read_bytes(&mut self, pos: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        Ok(block::read_bytes(self.dev.as_mut(), pos, buf)?)
+    }
+
+    fn write_bytes(&mut self, pos: u64, data: &[u8]) -> Result<(), FsError> {
+        Ok(block::write_bytes(self.dev.as_mut(), pos, data)?)
+    }
+
+    fn block_pos(&self, b: u32) -> u64 { b as u64 * self.sb.block_size as u64 }
+
+    fn read_block(&mut self, b: u32) -> Result<Vec<u8>, FsError> {
+        let mut buf = vec![0u8; self.sb.block_size];
+        self.read_bytes(self.block_pos(b), &mut buf)?;
+        Ok(buf)
+    }
+
+    fn sectors_per_block(&self) -> u32 { (self.sb.block_size / 512) as u32 }
+
+    fn ptrs_per_block(&self) -> u64 { (self.sb.block_size / 4) as u64 }
+
+    fn large_files(&self) -> bool { self.sb.ro_compat & RO_COMPAT_LARGE_FILE != 0 }
+
+    fn inode_pos(&self, ino: Ino) -> Result<u64, FsError> {
+        if ino == 0 || ino > self.sb.inodes_count as u64 { return Err(FsError::NotFound); }
+        let (g, i) = ((ino - 1) / self.sb.inodes_per_group as u64, (ino - 1) % self.sb.inodes_per_group as u64);
+        let table = self.groups.get(g as usize).ok_or(FsError::Io)?.inode_table;
+        Ok(self.block_pos(table) + i * self.sb.inode_size as u64)
+    }
+
+    fn read_inode(&mut self, ino: Ino) -> Result<Inode, FsError> {
+        let mut raw = [0u8; 128];
+        let at = self.inode_pos(ino)?;
+        self.read_bytes(at, &mut raw)?;
+        let inode = Inode::parse(raw, self.large_files());
+        if inode.links == 0 && inode.mode == 0 { return Err(FsError::NotFound); }
+        Ok(inode)
+    }
+
+    fn write_inode(&mut self, ino: Ino, inode: &Inode) -> Result<(), FsError> {
+        let at = self.inode_pos(ino)?;
+        self.write_bytes(at, &inode.encode())
+    }
+
+    fn is_fast_symlink(&self, i: &Inode) -> bool {
+        let acl = if i.file_acl != 0 { self.sectors_per_block() } else { 0 };
+        i.mode & S_IFMT == S_IFLNK && i.sectors == acl
+    }
+
+    // Find a clear bit in a group's bitmap, set it and return its index.
+    fn take_bit(&mut self, bitmap: u32, limit: u32) -> Result<Option<u32>, FsError> {
+        let map = self.read_block(bitmap)?;
+        let Some(bit) = (0..limit).find(|&i| map[i as usize / 8] & (1 << (i % 8)) == 0) else { return Ok(None) };
+        let at = self.block_pos(bitmap) + bit as u64 / 8;
+        self.write_bytes(at, &[map[bit as usize / 8] | 1 << (bit % 8)])?;
+        Ok(Some(bit))
+    }
+
+    fn clear_bit(&mut self, bitmap: u32, bit: u32) -> Result<(), FsError> {
+        let at = self.block_pos(bitmap) + bit as u64 / 8;
+        let mut b = [0u8];
+        self.read_bytes(at, &mut b)?;
+        self.write_bytes(at, &[b[0] & !(1 << (bit % 8))])
+    }
+
+    /// Allocate a zeroed block, preferring the group holding `goal`.
+    fn alloc_block(&mut self, goal: u32) -> Result<u32, FsError> {
+        let n = self.sb.groups();
+        let start = (goal.saturating_sub(self.sb.first_data_block) / self.sb.blocks_per_group).min(n - 1);
+        for g in (start..n).chain(0..start) {
+            if self.groups[g as usize].free_blocks == 0 { continue; }
+            let limit = self.sb.blocks_in_group(g);
+            let Some(bit) = self.take_bit(self.groups[g as usize].block_bitmap, limit)? else { continue };
+            self.groups[g as usize].free_blocks -= 1;
+            self.sb.free_blocks = self.sb.free_blocks.saturating_sub(1);
+            self.dirty = true;
+            let b = self.sb.first_data_block + g * self.sb.blocks_per_group + bit;
+            let zero = vec![0u8; self.sb.block_size];
+            self.write_bytes(self.block_pos(b), &zero)?;
+            return Ok(b);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_block(&mut self, b: u32) -> Result<(), FsError> {
+        if b < self.sb.first_data_block || b >= self.sb.blocks_count { return Err(FsError::Io); }
+        let rel = b - self.sb.first_data_block;
+        let g = (rel / self.sb.blocks_per_group) as usize;
+        self.clear_bit(self.groups[g].block_bitmap, rel % self.sb.blocks_per_group)?;
+        self.groups[g].free_blocks += 1;
+        self.sb.free_blocks += 1;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Allocate an inode, preferring the group of `near`; its on-disk slot is zeroed.
+    fn alloc_inode(&mut self, near: Ino, dir: bool) -> Result<Ino, FsError> {
+        let n = self.sb.groups();
+        let start = (((near.max(1) - 1) / self.sb.inodes_per_group as u64) as u32).min(n - 1);
+        for g in (start..n).chain(0..start) {
+            if self.groups[g as usize].free_inodes == 0 { continue; }
+            let Some(bit) = self.take_bit(self.groups[g as usize].inode_bitmap, self.sb.inodes_per_group)? else { continue };
+            let ino = (g * self.sb.inodes_per_group + bit + 1) as Ino;
+            if ino < self.sb.first_ino as Ino { continue; } // reserved inode with a clear bit; leave it marked
+            let grp = &mut self.groups[g as usize];
+            grp.free_inodes -= 1;
+            if dir { grp.used_dirs += 1; }
+            self.sb.free_inodes = self.sb.free_inodes.saturating_sub(1);
+            self.dirty = true;
+            let (at, zero) = (self.inode_pos(ino)?, vec![0u8; self.sb.inode_size]);
+            self.write_bytes(at, &zero)?;
+            return Ok(ino);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    fn free_inode(&mut self, ino: Ino, dir: bool) -> Result<(), FsError> {
+        let (g, bit) = (((ino - 1) / self.sb.inodes_per_group as u64) as usize, ((ino - 1) % self.sb.inodes_per_group as u64) as u32);
+        self.clear_bit(self.groups[g].inode_bitmap, bit)?;
+        let grp = &mut self.groups[g];
+        grp.free_inodes += 1;
+        if dir { grp.used_dirs = grp.used_dirs.saturating_sub(1); }
+        self.sb.free_inodes += 1;
+        self.dirty = true;
+        Ok(())
+    }
+
+    /// Disk block holding logical block `idx` of `inode`; 0 for a hole unless `alloc` fills it.
+    fn bmap(&mut self, inode: &mut Inode, idx: u64, alloc: bool) -> Result<u32, FsError> {
+        let ppb = self.ptrs_per_block();
+        let (slot, depth, mut rel) = if idx < NDIR as u64 { (idx as usize, 0, 0) }
+            else if idx - 12 < ppb { (12, 1, idx - 12) }
+            else if idx - 12 - ppb < ppb * ppb { (13, 2, idx - 12 - ppb) }
+            else if idx - 12 - ppb - ppb * ppb < ppb * ppb * ppb { (14, 3, idx - 12 - ppb - ppb * ppb) }
+            else { return Err(FsError::NoSpace) };
+        let spb = self.sectors_per_block();
+        let goal = inode.block[0];
+        let mut b = inode.block[slot];
+        if b == 0 {
+            if !alloc { return Ok(0); }
+            b = self.alloc_block(goal)?;
+            inode.block[slot] = b;
+            inode.sectors += spb;
+        }
+        for level in (0..depth).rev() {
+            if b >= self.sb.blocks_count { return Err(FsError::Io); }
+            let span = ppb.pow(level);
+            let at = self.block_pos(b) + (rel / span) * 4;
+            rel %= span;
+            let mut p = [0u8; 4];
+            self.read_bytes(at, &mut p)?;
+            let mut next = u32::from_le_bytes(p);
+            if next == 0 {
+                if !alloc { return Ok(0); }
+                next = self.alloc_block(b)?;
+                inode.sectors += spb;
+                self.write_bytes(at, &next.to_le_bytes())?;
+            }
+            b = next;
+        }
+        if b >= self.sb.blocks_count { return Err(FsError::Io); }
+        Ok(b)
+    }
+
+    // Free the part of the tree rooted at `b` (depth 0 is a data block) past its first `keep`
+    // data blocks; returns whether `b` itself was freed.
+    fn trim(&mut self, inode: &mut Inode, b: u32, depth: u32, keep: u64) -> Result<bool, FsError> {
+        if b >= self.sb.blocks_count { return Ok(true); } // corrupt pointer: just drop it
+        if depth > 0 {
+            let span = self.ptrs_per_block().pow(depth - 1);
+            let mut ptrs = self.read_block(b)?;
+            let mut changed = false;
+            for i in 0..self.ptrs_per_block() as usize {
+                let p = le32(&ptrs, i * 4);
+                let child_keep = keep.saturating_sub(i as u64 * span);
+                if p == 0 || child_keep >= span { continue; }
+                if self.trim(inode, p, depth - 1, child_keep)? { put32(&mut ptrs, i * 4, 0); changed = true; }
+            }
+            if keep > 0 {
+                if changed { self.write_bytes(self.block_pos(b), &ptrs)?; }
+                return Ok(false);
+            }
+        }
+        self.free_block(b)?;
+        inode.sectors = inode.sectors.saturating_sub(self.sectors_per_block());
+        Ok(true)
+    }
+
+    /// Release every block of `inode` beyond its first `keep`.
+    fn trim_blocks(&mut self, inode: &mut Inode, keep: u64) -> Result<(), FsError> {
+        let ppb = self.ptrs_per_block();
+        let mut base = 0;
+        for slot in 0..15usize {
+            let depth = slot.saturating_sub(NDIR - 1) as u32;
+            let span = ppb.pow(depth);
+            let b = inode.block[slot];
+            if b != 0 && keep < base + span && self.trim(inode, b, depth, keep.saturating_sub(base))? { inode.block[slot] = 0; }
+            base += span;
+        }
+        Ok(())
+    }
+
+    fn read_data(&mut self, inode: &mut Inode, off: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if off >= inode.size { return Ok(0); }
+        let want = buf.len().min((inode.size - off) as usize);
+        let bs = self.sb.block_size as u64;
+        let mut done = 0;
+        while done < want {
+            let pos = off + done as u64;
+            let n = (want - done).min((bs - pos % bs) as usize);
+            match self.bmap(inode, pos / bs, false)? {
+                0 => buf[done..done + n].fill(0),
+                b => self.read_bytes(self.block_pos(b) + pos % bs, &mut buf[done..done + n])?,
+            }
+            done += n;
+        }
+        Ok(done)
+    }
+
+    // Write into `ino`'s data, allocating blocks as needed; gaps past EOF stay holes.
+    fn write_data(&mut self, ino: Ino, off: u64, data: &[u8]) -> Result<(), FsError> {
+        let mut inode = self.read_inode(ino)?;
+        let end = off + data.len() as u64;
+        if end > u32::MAX as u64 && !self.large_files() { return Err(FsError::NoSpace); }
+        let bs = self.sb.block_size as u64;
+        let mut done = 0;
+        let res = loop {
+            if done == data.len() { break Ok(()); }
+            let pos = off + done as u64;
+            let n = (data.len() - done).min((bs - pos % bs) as usize);
+            let b = match self.bmap(&mut inode, pos / bs, true) { Ok(b) => b, Err(e) => break Err(e) };
+            if let Err(e) = self.write_bytes(self.block_pos(b) + pos % bs, &data[done..done + n]) { break Err(e); }
+            done += n;
+        };
+        // blocks allocated before a failure still belong to the inode
+        inode.size = inode.size.max(off + done as u64);
+        inode.mtime = crate::rtc::now() as u32;
+        inode.ctime = inode.mtime;
+        // we don't maintain htree indexes, so a directory we've touched must be scanned linearly
+        if inode.kind() == FileType::Dir {
+            let flags = le32(&inode.raw, 32) & !INDEX_FL;
+            put32(&mut inode.raw, 32, flags);
+        }
+        self.write_inode(ino, &inode)?;
+        res
+    }
+
+    fn dir_entries(&mut self, dir: Ino) -> Result<Vec<Dirent>, FsError> {
+        let mut inode = self.read_inode(dir)?;
+        if inode.kind() != FileType::Dir { return Err(FsError::NotDir); }
+        let mut bytes = vec![0u8; inode.size as usize];
+        self.read_data(&mut inode, 0, &mut bytes)?;
+        Ok(parse_dir(&bytes, self.sb.block_size))
+    }
+
+    fn find(&mut self, dir: Ino, name: &str) -> Result<Option<Dirent>, FsError> {
+        Ok(self.dir_entries(dir)?.into_iter().find(|e| e.ino != 0 && e.name == name))
+    }
+
+    fn encode_dirent(&self, buf: &mut [u8], ino: Ino, rec_len: usize, name: &str, ftype: u8) {
+        put32(buf, 0, ino as u32);
+        put16(buf, 4, rec_len as u16);
+        buf[6] = name.len() as u8;
+        buf[7] = if self.sb.incompat & INCOMPAT_FILETYPE != 0 { ftype } else { 0 };
+        buf[8..8 + name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    fn add_entry(&mut self, dir: Ino, name: &str, ino: Ino, ftype: u8) -> Result<(), FsError> {
+        if name.is_empty() || name.len() > 255 || name.contains('/') || name == "." || name == ".." { return Err(FsError::Invalid); }
+        let entries = self.dir_entries(dir)?;
+        if entries.iter().any(|e| e.ino != 0 && e.name == name) { return Err(FsError::Exists); }
+        let need = dirent_len(name.len());
+        // split the slack off the end of an existing record, or reuse an empty one
+        for e in &entries {
+            let used = if e.ino == 0 { 0 } else { dirent_len(e.name.len()) };
+            if e.rec_len < used + need { continue; }
+            let mut buf = vec![0u8; e.rec_len];
+            if used > 0 {
+                self.encode_dirent(&mut buf, e.ino as Ino, used, &e.name, e.ftype);
+                buf[7] = e.ftype;
+            }
+            self.encode_dirent(&mut buf[used..], ino, e.rec_len - used, name, ftype);
+            return self.write_data(dir, e.off, &buf);
+        }
+        let bs = self.sb.block_size;
+        let mut buf = vec![0u8; bs];
+        self.encode_dirent(&mut buf, ino, bs, name, ftype);
+        let size = self.read_inode(dir)?.size;
+        self.write_data(dir, size, &buf)
+    }
+
+    // Merge the record into its predecessor, or mark it unused if it starts a block.
+    fn remove_entry(&mut self, dir: Ino, e: &Dirent) -> Result<(), FsError> {
+        match e.prev {
+            Some((at, len)) => self.write_data(dir, at + 4, &((len + e.rec_len) as u16).to_le_bytes()),
+            None => self.write_data(dir, e.off, &[0; 4]),
+        }
+    }
+
+    fn parent_of(&mut self, dir: Ino) -> Result<Ino, FsError> {
+        Ok(self.find(dir, "..")?.ok_or(FsError::Io)?.ino as Ino)
+    }
+
+    fn adjust_links(&mut self, ino: Ino, delta: i32) -> Result<(), FsError> {
+        let mut i = self.read_inode(ino)?;
+        i.links = (i.links as i32 + delta).max(0) as u16;
+        self.write_inode(ino, &i)
+    }
+
+    // Drop one link to `ino`, freeing it with its blocks when none remain.
+    fn release(&mut self, ino: Ino) -> Result<(), FsError> {
+        let mut i = self.read_inode(ino)?;
+        let dir = i.kind() == FileType::Dir;
+        i.links = if dir { 0 } else { i.links.saturating_sub(1) };
+        if i.links == 0 {
+            if !self.is_fast_symlink(&i) { self.trim_blocks(&mut i, 0)?; }
+            i.size = 0;
+            // a zero mode marks the inode deleted even when the clock isn't set and dtime stays 0
+            i.mode = 0;
+            put32(&mut i.raw, 20, crate::rtc::now() as u32);
+            self.write_inode(ino, &i)?;
+            return self.free_inode(ino, dir);
+        }
+        self.write_inode(ino, &i)
+    }
+
+    fn new_inode(&mut self, dir: Ino, name: &str, mut inode: Inode, ftype: u8) -> Result<Ino, FsError> {
+        self.rw()?;
+        let now = crate::rtc::now() as u32;
+        (inode.ctime, inode.mtime) = (now, now);
+        put32(&mut inode.raw, 8, now); // atime
+        if self.find(dir, name)?.is_some() { return Err(FsError::Exists); }
+        let ino = self.alloc_inode(dir, ftype == FT_DIR)?;
+        self.write_inode(ino, &inode)?;
+        Ok(ino)
+    }
+
+    fn rollback(&mut self, ino: Ino, e: FsError) -> FsError {
+        let _ = self.release(ino);
+        e
+    }
+
+    /// Free blocks and inodes according to the superblock.
+    pub fn free_counts(&self) -> (u32, u32) { (self.sb.free_blocks, self.sb.free_inodes) }
+}
+
+impl Filesystem for Ext2Fs {
+    fn name(&self) -> &'static str { "ext2" }
+
+    fn root(&self) -> Ino { ROOT }
+
+    fn lookup(&mut self, dir: Ino, name: &str) -> Result<Ino, FsError> {
+        Ok(self.find(dir, name)?.ok_or(FsError::NotFound)?.ino as Ino)
+    }
+
+    fn stat(&mut self, ino: Ino) -> Result<Stat, FsError> {
+        let i = self.read_inode(ino)?;
+        let (uid, gid) = i.owner();
+        Ok(Stat { ino, kind: i.kind(), size: i.size, mode: i.mode & 0o7777, uid, gid, ctime: i.ctime as u64, mtime: i.mtime as u64 })
+    }
+
+    fn read_at(&mut self, ino: Ino, off: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut i = self.read_inode(ino)?;
+        if i.kind() == FileType::Dir { return Err(FsError::IsDir); }
+        self.read_data(&mut i, off, buf)
+    }
+
+    fn readdir(&mut self, dir: Ino) -> Result<Vec<DirEntry>, FsError> {
+        let filetype = self.sb.incompat & INCOMPAT_FILETYPE != 0;
+        let mut out = Vec::new();
+        for e in self.dir_entries(dir)? {
+            if e.ino == 0 || e.name == "." || e.name == ".." { continue; }
+            let kind = match e.ftype {
+                FT_DIR if filetype => FileType::Dir,
+                FT_SYMLINK if filetype => FileType::Symlink,
+                _ if filetype => FileType::File,
+                _ => self.read_inode(e.ino as Ino)?.kind(),
+            };
+            out.push(DirEntry { name: e.name, ino: e.ino as Ino, kind });
+        }
+        Ok(out)
+    }
+
+    fn write_at(&mut self, ino: Ino, off: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.rw()?;
+        match self.read_inode(ino)?.kind() {
+            FileType::File => {}
+            FileType::Dir => return Err(FsError::IsDir),
+            _ => return Err(FsError::Invalid),
+        }
+        self.write_data(ino, off, data)?;
+        Ok(data.len())
+    }
+
+    fn truncate(&mut self, ino: Ino, size: u64) -> Result<(), FsError> {
+        self.rw()?;
+        let mut i = self.read_inode(ino)?;
+        if i.kind() != FileType::File { return Err(FsError::IsDir); }
+        if size < i.size {
+            let bs = self.sb.block_size as u64;
+            self.trim_blocks(&mut i, size.div_ceil(bs))?;
+            // zero the tail of the last block so growing the file again can't expose old bytes
+            if !size.is_multiple_of(bs) {
+                let b = self.bmap(&mut i, size / bs, false)?;
+                if b != 0 { self.write_bytes(self.block_pos(b) + size % bs, &vec![0u8; (bs - size % bs) as usize])?; }
+            }
+        } else if size > u32::MAX as u64 && !self.large_files() {
+            return Err(FsError::NoSpace);
+        }
+        i.size = size;
+        i.mtime = crate::rtc::now() as u32;
+        i.ctime = i.mtime;
+        self.write_inode(ino, &i)
+    }
+
+    fn create(&mut self, dir: Ino, name: &str) -> Result<Ino, FsError> {
+        let ino = self.new_inode(dir, name, Inode::new(S_IFREG | 0o644, 1), FT_REG)?;
+        self.add_entry(dir, name, ino, FT_REG).map_err(|e| self.rollback(ino, e))?;
+        Ok(ino)
+    }
+
+    fn mkdir(&mut self, dir: Ino, name: &str) -> Result<Ino, FsError> {
+        let ino = self.new_inode(dir, name, Inode::new(S_IFDIR | 0o755, 2), FT_DIR)?;
+        let bs = self.sb.block_size;
+        let mut dots = vec![0u8; bs];
+        self.encode_dirent(&mut dots, ino, 12, ".", FT_DIR);
+        self.encode_dirent(&mut dots[12..], dir, bs - 12, "..", FT_DIR);
+        self.write_data(ino, 0, &dots).and_then(|_| self.add_entry(dir, name, ino, FT_DIR)).map_err(|e| self.rollback(ino, e))?;
+        self.adjust_links(dir, 1)?;
+        Ok(ino)
+    }
+
+    fn symlink(&mut self, dir: Ino, name: &str, target: &str) -> Result<Ino, FsError> {
+        if target.len() >= self.sb.block_size { return Err(FsError::Invalid); }
+        let mut inode = Inode::new(S_IFLNK | 0o777, 1);
+        if target.len() < FAST_SYMLINK_MAX {
+            let mut raw = [0u8; FAST_SYMLINK_MAX];
+            raw[..target.len()].copy_from_slice(target.as_bytes());
+            inode.block = core::array::from_fn(|i| le32(&raw, i * 4));
+            inode.size = target.len() as u64;
+        }
+        let ino = self.new_inode(dir, name, inode, FT_SYMLINK)?;
+        let res = if target.len() < FAST_SYMLINK_MAX { Ok(()) } else { self.write_data(ino, 0, target.as_bytes()) };
+        res.and_then(|_| self.add_entry(dir, name, ino, FT_SYMLINK)).map_err(|e| self.rollback(ino, e))?;
+        Ok(ino)
+    }
+
+    fn readlink(&mut self, ino: Ino) -> Result<String, FsError> {
+        let mut i = self.read_inode(ino)?;
+        if i.kind() != FileType::Symlink { return Err(FsError::Invalid); }
+        let bytes = if self.is_fast_symlink(&i) {
+            let raw: Vec<u8> = i.block.iter().flat_map(|b| b.to_le_bytes()).collect();
+            raw[..(i.size as usize).min(FAST_SYMLINK_MAX)].to_vec()
+        } else {
+            let mut buf = vec![0u8; i.size as usize];
+            let n = self.read_data(&mut i, 0, &mut buf)?;
+            buf.truncate(n);
+            buf
+        };
+        String::from_utf8(bytes).map_err(|_| FsError::Invalid)
+    }
+
+    fn unlink(&mut self, dir: Ino, name: &str) -> Result<(), FsError> {
+        self.rw()?;
+        let e = self.find(dir, name)?.ok_or(FsError::NotFound)?;
+        if self.read_inode(e.ino as Ino)?.kind() == FileType::Dir { return Err(FsError::IsDir); }
+        self.remove_entry(dir, &e)?;
+        self.release(e.ino as Ino)
+    }
+
+    fn rmdir(&mut self, dir: Ino, name: &str) -> Result<(), FsError> {
+        self.rw()?;
+        if name == "." || name == ".." { return Err(FsError::Invalid); }
+        let e = self.find(dir, name)?.ok_or(FsError::NotFound)?;
+        let ino = e.ino as Ino;
+        if self.read_inode(ino)?.kind() != FileType::Dir { return Err(FsError::NotDir); }
+        if self.dir_entries(ino)?.iter().any(|c| c.ino != 0 && c.name != "." && c.name != "..") { return Err(FsError::NotEmpty); }
+        self.remove_entry(dir, &e)?;
+        self.release(ino)?;
+        self.adjust_links(dir, -1)
+    }
+
+    fn rename(&mut self, odir: Ino, oname: &str, ndir: Ino, nname: &str) -> Result<(), FsError> {
+        self.rw()?;
+        let e = self.find(odir, oname)?.ok_or(FsError::NotFound)?;
+        let ino = e.ino as Ino;
+        let is_dir = self.read_inode(ino)?.kind() == FileType::Dir;
+        // refuse to move a directory underneath itself
+        let (mut p, mut hops) = (ndir, 0);
+        while p != ROOT {
+            if p == ino { return Err(FsError::Invalid); }
+            p = self.parent_of(p)?;
+            hops += 1;
+            if hops > 4096 { return Err(FsError::Io); }
+        }
+        if let Some(t) = self.find(ndir, nname)? {
+            if t.ino as Ino == ino { return Ok(()); }
+            if is_dir || self.read_inode(t.ino as Ino)?.kind() == FileType::Dir { return Err(FsError::Exists); }
+            self.unlink(ndir, nname)?;
+        }
+        self.add_entry(ndir, nname, ino, e.ftype)?;
+        // adding may have split the record before ours, so look it up again
+        let e = self.find(odir, oname)?.filter(|e| e.ino as Ino == ino).ok_or(FsError::Io)?;
+        self.remove_entry(odir, &e)?;
+        if is_dir && odir != ndir {
+            let dd = self.find(ino, "..")?.ok_or(FsError::Io)?;
+            self.write_data(ino, dd.off, &(ndir as u32).to_le_bytes())?;
+            self.adjust_links(odir, -1)?;
+            self.adjust_links(ndir, 1)?;
+        }
+        Ok(())
+    }
+
+    fn chmod(&mut self, ino: Ino, mode: u16) -> Result<(), FsError> {
+        self.rw()?;
+        let mut i = self.read_inode(ino)?;
+        i.mode = i.mode & S_IFMT | mode & 0o7777;
+        self.write_inode(ino, &i)
+    }
+
+    fn chown(&mut self, ino: Ino, uid: u32, gid: u32) -> Result<(), FsError> {
+        self.rw()?;
+        let mut i = self.read_inode(ino)?;
+        i.set_owner(uid, gid);
+        self.write_inode(ino, &i)
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        if self.dirty {
+            let mut sb = [0u8; 1024];
+            self.read_bytes(SB_OFFSET, &mut sb)?;
+            put32(&mut sb, 12, self.sb.free_blocks);
+            put32(&mut sb, 16, self.sb.free_inodes);
+            self.write_bytes(SB_OFFSET, &sb)?;
+            for (g, d) in self.groups.iter().zip(self.gdt.as_chunks_mut::<32>().0) {
+                put16(d, 12, g.free_blocks);
+                put16(d, 14, g.free_inodes);
+                put16(d, 16, g.used_dirs);
+            }
+            let at = (self.sb.first_data_block as u64 + 1) * self.sb.block_size as u64;
+            let gdt = core::mem::take(&mut self.gdt);
+            let res = self.write_bytes(at, &gdt);
+            self.gdt = gdt;
+            res?;
+            self.dirty = false;
+        }
+        Ok(self.dev.flush()?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+
+    const BS: usize = 1024;
+    const BIG_SIZE: usize = 14 * BS + 100;
+
+    // Logical block `k` of "big": its index plus one in every byte, except for a hole at 13.
+    fn big_block(k: usize) -> u8 { if k == 13 { 0 } else { k as u8 + 1 } }
+
+    fn inode(img: &mut [u8], ino: usize, mode: u16, size: u32, links: u16, blocks: &[u32]) {
+        let at = 5 * BS + (ino - 1) * 128;
+        put16(img, at, mode);
+        put32(img, at + 4, size);
+        put16(img, at + 26, links);
+        for (i, b) in blocks.iter().enumerate() { put32(img, at + 40 + i * 4, *b); }
+    }
+
+    fn dirent(img: &mut [u8], at: usize, ino: u32, rec_len: u16, name: &str, ftype: u8) {
+        put32(img, at, ino);
+        put16(img, at + 4, rec_len);
+        img[at + 6] = name.len() as u8;
+        img[at + 7] = ftype;
+        img[at + 8..at + 8 + name.len()].copy_from_slice(name.as_bytes());
+    }
+
+    // A one-group volume with 1 KiB blocks, laid out by hand: superblock in block 1, group
+    // descriptors in 2, bitmaps in 3 and 4, inode table in 5-6, the root directory in 7,
+    // "small" in 8, and "big" in 10-23 with 9 as its indirect block.
+    fn image() -> Vec<u8> {
+        let mut img = vec![0u8; 64 * BS];
+        let sb = BS;
+        for (at, v) in [(0, 16), (4, 64), (12, 40), (16, 3), (20, 1), (24, 0), (32, 8192), (40, 16), (76, 1), (84, 11), (96, INCOMPAT_FILETYPE)] {
+            put32(&mut img, sb + at, v);
+        }
+        put16(&mut img, sb + 56, MAGIC);
+        put16(&mut img, sb + 88, 128);
+        img[sb + 120..sb + 124].copy_from_slice(b"test");
+        let gd = 2 * BS;
+        for (at, v) in [(0, 3), (4, 4), (8, 5)] { put32(&mut img, gd + at, v); }
+        for (at, v) in [(12, 40), (14, 3), (16, 1)] { put16(&mut img, gd + at, v); }
+        img[3 * BS..3 * BS + 3].fill(0xFF); // blocks 1-23 in use
+        img[4 * BS] = 0xFF; // inodes 1-8
+        img[4 * BS + 1] = 0x1F; // 9-13
+
+        let mut big = [0u32; 13];
+        for (k, b) in big.iter_mut().take(12).enumerate() { *b = 10 + k as u32; }
+        big[12] = 9;
+        inode(&mut img, 2, S_IFDIR | 0o755, BS as u32, 2, &[7]);
+        inode(&mut img, 12, S_IFREG | 0o644, 11, 1, &[8]);
+        inode(&mut img, 13, S_IFREG | 0o600, BIG_SIZE as u32, 1, &big);
+
+        let root = 7 * BS;
+        dirent(&mut img, root, 2, 12, ".", FT_DIR);
+        dirent(&mut img, root + 12, 2, 12, "..", FT_DIR);
+        dirent(&mut img, root + 24, 12, 16, "small", FT_REG);
+        dirent(&mut img, root + 40, 13, (BS - 40) as u16, "big", FT_REG);
+        img[8 * BS..8 * BS + 11].copy_from_slice(b"hello ext2\n");
+        // logical blocks 12 and 14 through the indirect block; 13 is a hole
+        put32(&mut img, 9 * BS, 22);
+        put32(&mut img, 9 * BS + 8, 23);
+        for k in (0..15).filter(|&k| k != 13) {
+            let b = if k < 12 { 10 + k } else if k == 12 { 22 } else { 23 };
+            img[b * BS..(b + 1) * BS].fill(big_block(k));
+        }
+        img
+    }
+
+    fn mount(img: Vec<u8>) -> Option<Ext2Fs> { Ext2Fs::new(Box::new(RamDisk::from_vec(img, 512))) }
+
+    #[test]
+    fn superblock_and_group_descriptors() {
+        let sb = read_superblock(&mut RamDisk::from_vec(image(), 512)).unwrap();
+        assert_eq!((sb.block_size, sb.inodes_count, sb.blocks_count, sb.first_data_block), (1024, 16, 64, 1));
+        assert_eq!((sb.first_ino, sb.inode_size, sb.volume_name.as_str()), (11, 128, "test"));
+        assert_eq!((sb.groups(), sb.blocks_in_group(0)), (1, 63));
+        let fs = mount(image()).unwrap();
+        let g = fs.groups[0];
+        assert_eq!((g.block_bitmap, g.inode_bitmap, g.inode_table), (3, 4, 5));
+        assert_eq!((g.free_blocks, g.free_inodes, g.used_dirs), (40, 3, 1));
+        assert_eq!(fs.free_counts(), (40, 3));
+        assert!(fs.is_writable());
+    }
+
+    #[test]
+    fn reads_direct_and_indirect_blocks() {
+        let mut fs = mount(image()).unwrap();
+        let big = fs.lookup(ROOT, "big").unwrap();
+        assert_eq!(fs.stat(big).unwrap().size, BIG_SIZE as u64);
+        let mut buf = vec![0xEEu8; BIG_SIZE + 50];
+        assert_eq!(fs.read_at(big, 0, &mut buf).unwrap(), BIG_SIZE);
+        for (i, &b) in buf[..BIG_SIZE].iter().enumerate() { assert_eq!(b, big_block(i / BS), "byte {}", i); }
+        // a read straddling the last direct block and the first indirect one
+        let mut buf = [0u8; 8];
+        assert_eq!(fs.read_at(big, 12 * BS as u64 - 4, &mut buf).unwrap(), 8);
+        assert_eq!(buf, [12, 12, 12, 12, 13, 13, 13, 13]);
+        assert_eq!(fs.read_at(big, BIG_SIZE as u64, &mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn directory_lookup() {
+        let mut fs = mount(image()).unwrap();
+        let small = fs.lookup(ROOT, "small").unwrap();
+        assert_eq!(small, 12);
+        let mut buf = [0u8; 32];
+        assert_eq!(fs.read_at(small, 0, &mut buf).unwrap(), 11);
+        assert_eq!(&buf[..11], b"hello ext2\n");
+        assert_eq!(fs.lookup(ROOT, "..").unwrap(), ROOT);
+        assert_eq!(fs.lookup(ROOT, "nope"), Err(FsError::NotFound));
+        assert_eq!(fs.lookup(small, "x"), Err(FsError::NotDir));
+        let mut names: Vec<(String, FileType)> = fs.readdir(ROOT).unwrap().into_iter().map(|e| (e.name, e.kind)).collect();
+        names.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(names, [(String::from("big"), FileType::File), (String::from("small"), FileType::File)]);
+        assert_eq!(fs.read_at(ROOT, 0, &mut buf), Err(FsError::IsDir));
+    }
+
+    #[test]
+    fn rejects_bad_superblocks() {
+        let mut img = image();
+        put16(&mut img, BS + 56, 0xEF52);
+        assert!(read_superblock(&mut RamDisk::from_vec(img.clone(), 512)).is_none());
+        assert!(mount(img).is_none());
+        let mut img = image();
+        put32(&mut img, BS + 24, 7); // 128 KiB blocks
+        assert!(mount(img).is_none());
+        let mut img = image();
+        put32(&mut img, BS + 96, INCOMPAT_FILETYPE | 0x0040); // extents
+        assert!(mount(img).is_none());
+    }
+}
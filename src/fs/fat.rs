@@ -1,3 +1,21 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::block::{self, BlockDevice};
+use super::vfs::{DirEntry, FileType, Filesystem, FsError, Ino, Stat};
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_HIDDEN: u8 = 0x02;
+pub const ATTR_SYSTEM: u8 = 0x04;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+pub const ATTR_LFN: u8 = 0x0F;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FatType { Fat12, Fat16, Fat32 }
+
+#[derive(Clone)]
 pub struct Bpb {
     pub bps: u16,
     pub spc: u8,
@@ -10,109 +28,1428 @@ pub struct Bpb {
     pub root_dir_sectors: u32,
     pub first_data: u32,
     pub fat_bits: u8,
+    pub root_cluster: u32, // FAT32 only; 0 means the fixed FAT12/16 root region
+    pub fsinfo: u32,       // FAT32 FSInfo sector
+    pub cluster_count: u32,
+    pub fat_type: FatType,
 }
 
-pub fn read_bpb(dev: &mut dyn crate::block::BlockDevice) -> Option<Bpb> {
+impl Bpb {
+    pub fn cluster_bytes(&self) -> usize { self.bps as usize * self.spc as usize }
+    pub fn cluster_offset(&self, cluster: u32) -> u64 {
+        (self.first_data as u64 + (cluster as u64 - 2) * self.spc as u64) * self.bps as u64
+    }
+    pub fn root_dir_offset(&self) -> u64 { (self.rsv + self.nfats as u32 * self.fatsz) as u64 * self.bps as u64 }
+    /// Smallest FAT value that marks the end of a chain.
+    pub fn eoc(&self) -> u32 {
+        match self.fat_type { FatType::Fat12 => 0xFF8, FatType::Fat16 => 0xFFF8, FatType::Fat32 => 0x0FFF_FFF8 }
+    }
+    /// Value written to terminate a chain.
+    pub fn eoc_mark(&self) -> u32 {
+        match self.fat_type { FatType::Fat12 => 0xFFF, FatType::Fat16 => 0xFFFF, FatType::Fat32 => 0x0FFF_FFFF }
+    }
+}
+
+pub fn read_bpb(dev: &mut dyn BlockDevice) -> Option<Bpb> {
     let mut buf = [0u8;512];
-    if !dev.read_sector(0, &mut buf) { return None; }
-    if &buf[510..512] != [0x55,0xAA] { return None; }
+    block::read_bytes(dev, 0, &mut buf).ok()?;
+    if buf[510..512] != [0x55,0xAA] { return None; }
     let bps = u16::from_le_bytes([buf[11],buf[12]]);
     let spc = buf[13];
+    if !matches!(bps, 512 | 1024 | 2048 | 4096) || spc == 0 || !spc.is_power_of_two() { return None; }
     let rsv = u16::from_le_bytes([buf[14],buf[15]]) as u32;
     let nfats = buf[16];
+    if nfats == 0 || rsv == 0 { return None; }
     let root_entries = u16::from_le_bytes([buf[17],buf[18]]) as u32;
     let total16 = u16::from_le_bytes([buf[19],buf[20]]) as u32;
     let total = if total16!=0 { total16 } else { u32::from_le_bytes([buf[32],buf[33],buf[34],buf[35]]) };
     let fatsz16 = u16::from_le_bytes([buf[22],buf[23]]) as u32;
     let fatsz = if fatsz16 != 0 { fatsz16 } else { u32::from_le_bytes([buf[36],buf[37],buf[38],buf[39]]) };
     let fat_start = rsv;
-    let root_dir_sectors = ((root_entries * 32) + (bps as u32 -1)) / bps as u32;
+    let root_dir_sectors = (root_entries * 32).div_ceil(bps as u32);
     let first_data = rsv + (nfats as u32 * fatsz) + root_dir_sectors;
-    let fat_bits = if fatsz16 != 0 { 16 } else { 32 };
-    Some(Bpb{ bps, spc, rsv, nfats, fatsz, root_entries, total_sectors: total, fat_start, root_dir_sectors, first_data, fat_bits })
-}
-
-pub fn list_root(dev: &mut dyn crate::block::BlockDevice) -> heapless::Vec<heapless::String<32>, 128> {
-    let mut out = heapless::Vec::new();
-    if let Some(bpb) = read_bpb(dev) {
-        let bps = bpb.bps;
-        let root_dir_lba = bpb.rsv + (bpb.nfats as u32 * bpb.fatsz);
-        // assume FAT16 fixed root dir size from BPB
-        let mut buf = [0u8;512];
-        let mut lba = root_dir_lba;
-        for _ in 0..32 { // scan 32 sectors of root
-            if !dev.read_sector(lba, &mut buf) { break; }
-            for i in (0..512).step_by(32) {
-                let name = &buf[i..i+11];
-                let attr = buf[i+11];
-                if name[0] == 0x00 { break; }
-                if name[0] == 0xE5 || attr == 0x0F { continue; } // deleted or LFN
-                let mut s = heapless::String::<32>::new();
-                for &b in &name[0..8] { if b != b' ' { let _ = s.push(b as char); } }
-                if name[8] != b' ' { let _ = s.push('.'); for &b in &name[8..11] { if b != b' ' { let _ = s.push(b as char); } } }
-                let _ = out.push(s);
-            }
-            lba += 1;
+    if total <= first_data { return None; }
+    // The FAT type is decided by cluster count alone (Microsoft FAT spec, section 3.5).
+    let cluster_count = (total - first_data) / spc as u32;
+    let (fat_type, fat_bits) = if cluster_count < 4085 { (FatType::Fat12, 12) }
+        else if cluster_count < 65525 { (FatType::Fat16, 16) } else { (FatType::Fat32, 32) };
+    let (root_cluster, fsinfo) = if fat_type == FatType::Fat32 {
+        (u32::from_le_bytes([buf[44],buf[45],buf[46],buf[47]]), u16::from_le_bytes([buf[48],buf[49]]) as u32)
+    } else { (0, 0) };
+    Some(Bpb{ bps, spc, rsv, nfats, fatsz, root_entries, total_sectors: total, fat_start, root_dir_sectors, first_data, fat_bits,
+        root_cluster, fsinfo, cluster_count, fat_type })
+}
+
+/// Seconds since the Unix epoch for a FAT date/time pair.
+pub fn fat_time_to_unix(date: u16, time: u16) -> u64 {
+    if date == 0 { return 0; }
+    let y = 1980 + (date >> 9) as i64;
+    let m = ((date >> 5) & 0xF).clamp(1, 12) as i64;
+    let d = (date & 0x1F).max(1) as i64;
+    let days = crate::rtc::days_from_civil(y, m, d);
+    let secs = (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3F) as i64 * 60 + (time & 0x1F) as i64 * 2;
+    (days * 86400 + secs) as u64
+}
+
+/// FAT (date, time) for a Unix time; anything before 1980 (or an unset clock) becomes the FAT epoch.
+pub fn unix_to_fat_time(secs: u64) -> (u16, u16) {
+    let (y, m, d) = crate::rtc::civil_from_days((secs / 86400) as i64);
+    if !(1980..=2107).contains(&y) { return ((1 << 5) | 1, 0); }
+    let s = secs % 86400;
+    let date = ((y - 1980) as u16) << 9 | (m as u16) << 5 | d as u16;
+    let time = ((s / 3600) as u16) << 11 | ((s / 60 % 60) as u16) << 5 | (s % 60 / 2) as u16;
+    (date, time)
+}
+
+/// A directory entry as laid out on disk, with its long name already assembled.
+#[derive(Clone)]
+pub struct RawEntry {
+    pub name: String,
+    pub short: [u8; 11],
+    pub attr: u8,
+    pub cluster: u32,
+    pub size: u32,
+    pub ctime: u64,
+    pub mtime: u64,
+    pub offset: usize,    // byte offset of the 8.3 entry within the directory
+    pub lfn_start: usize, // offset of the first LFN slot belonging to it (== offset if none)
+}
+
+impl RawEntry {
+    pub fn is_dir(&self) -> bool { self.attr & ATTR_DIRECTORY != 0 }
+}
+
+/// Checksum of an 8.3 name, stored in each of its LFN slots.
+pub fn lfn_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, &b| ((sum & 1) << 7).wrapping_add(sum >> 1).wrapping_add(b))
+}
+
+fn short_name(raw: &[u8; 11], nt_flags: u8) -> String {
+    let mut s = String::new();
+    for (i, &b) in raw[0..8].iter().enumerate() {
+        if b == b' ' { continue; }
+        let b = if i == 0 && b == 0x05 { 0xE5 } else { b };
+        s.push(if nt_flags & 0x08 != 0 { (b as char).to_ascii_lowercase() } else { b as char });
+    }
+    if raw[8] != b' ' {
+        s.push('.');
+        for &b in raw[8..11].iter().filter(|&&b| b != b' ') {
+            s.push(if nt_flags & 0x10 != 0 { (b as char).to_ascii_lowercase() } else { b as char });
         }
     }
+    s
+}
+
+// UCS-2 character positions inside one 32-byte LFN slot
+const LFN_CHARS: [usize; 13] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+/// Parse the raw bytes of a directory. Deleted entries, volume labels and `.`/`..` are skipped.
+pub fn parse_dir(bytes: &[u8]) -> Vec<RawEntry> {
+    let mut out = Vec::new();
+    let mut lfn: Vec<u16> = Vec::new();
+    let mut lfn_sum = 0u8;
+    let mut lfn_start = None;
+    for (i, e) in bytes.as_chunks::<32>().0.iter().enumerate() {
+        let off = i * 32;
+        if e[0] == 0x00 { break; }
+        if e[0] == 0xE5 { lfn.clear(); lfn_start = None; continue; }
+        let attr = e[11];
+        if attr & 0x3F == ATTR_LFN {
+            let seq = e[0];
+            if seq & 0x40 != 0 {
+                // last slot comes first on disk; it tells us the total length
+                let n = (seq & 0x1F) as usize;
+                lfn = alloc::vec![0xFFFF; n * 13];
+                lfn_sum = e[13];
+                lfn_start = Some(off);
+            }
+            let idx = ((seq & 0x1F) as usize).wrapping_sub(1);
+            if lfn_start.is_none() || e[13] != lfn_sum || idx * 13 >= lfn.len() { lfn.clear(); lfn_start = None; continue; }
+            for (k, &p) in LFN_CHARS.iter().enumerate() { lfn[idx * 13 + k] = u16::from_le_bytes([e[p], e[p + 1]]); }
+            continue;
+        }
+        let mut short = [0u8; 11];
+        short.copy_from_slice(&e[0..11]);
+        if attr & ATTR_VOLUME_ID != 0 || short[0] == b'.' { lfn.clear(); lfn_start = None; continue; }
+        let name = match lfn_start {
+            Some(_) if lfn_checksum(&short) == lfn_sum => {
+                let units = lfn.iter().copied().take_while(|&c| c != 0 && c != 0xFFFF);
+                char::decode_utf16(units).map(|c| c.unwrap_or('?')).collect()
+            }
+            _ => short_name(&short, e[12]),
+        };
+        let hi = u16::from_le_bytes([e[20], e[21]]) as u32;
+        let lo = u16::from_le_bytes([e[26], e[27]]) as u32;
+        out.push(RawEntry {
+            name,
+            short,
+            attr,
+            cluster: (hi << 16) | lo,
+            size: u32::from_le_bytes([e[28], e[29], e[30], e[31]]),
+            ctime: fat_time_to_unix(u16::from_le_bytes([e[16], e[17]]), u16::from_le_bytes([e[14], e[15]])),
+            mtime: fat_time_to_unix(u16::from_le_bytes([e[24], e[25]]), u16::from_le_bytes([e[22], e[23]])),
+            offset: off,
+            lfn_start: lfn_start.unwrap_or(off),
+        });
+        lfn.clear();
+        lfn_start = None;
+    }
     out
 }
 
-pub fn read_file_root_8_3(dev: &mut dyn crate::block::BlockDevice, name83: &str) -> Option<alloc::vec::Vec<u8>> {
-    let bpb = read_bpb(dev)?;
-    let bps = bpb.bps;
-    let first_data = bpb.first_data;
-    let root_dir_lba = bpb.rsv + (bpb.nfats as u32 * bpb.fatsz);
-    let mut buf = [0u8;512];
-    let mut lba = root_dir_lba;
-    for _ in 0..32 {
-        if !dev.read_sector(lba, &mut buf) { break; }
-        for i in (0..512).step_by(32) {
-            let name = &buf[i..i+11];
-            let attr = buf[i+11];
-            if name[0] == 0x00 { break; }
-            if name[0] == 0xE5 || attr == 0x0F { continue; }
-            let mut s = heapless::String::<12>::new();
-            for &b in &name[0..8] { if b != b' ' { let _ = s.push(b as char); } }
-            if name[8] != b' ' { let _ = s.push('.'); for &b in &name[8..11] { if b != b' ' { let _ = s.push(b as char); } } }
-            if s.as_str().eq_ignore_ascii_case(name83) {
-                let lo = u16::from_le_bytes([buf[i+26],buf[i+27]]) as u32;
-                let hi = u16::from_le_bytes([buf[i+20],buf[i+21]]) as u32;
-                let first_cluster = (hi<<16)|lo;
-                let size = u32::from_le_bytes([buf[i+28],buf[i+29],buf[i+30],buf[i+31]]) as usize;
-                return read_chain_fat16(dev, bps, first_data, first_cluster, size);
-            }
-        }
-        lba += 1;
-    }
-    None
-}
-
-fn read_chain_fat16(dev: &mut dyn crate::block::BlockDevice, bps: u16, first_data: u32, mut cluster: u32, size: usize) -> Option<alloc::vec::Vec<u8>> {
-    // Minimal FAT16 cluster chain reader with FAT traversal
-    let spc = 1u32; // assumes 1 sector per cluster for small volumes; refine using BPB.spc as needed
-    let fat_start = read_bpb(dev)?.fat_start;
-    let mut out = alloc::vec::Vec::with_capacity(size);
-    let first_cluster = cluster;
-    let mut remaining = size;
-    let mut buf = [0u8;512];
-    while cluster >= 2 && cluster < 0xFFF8 {
-        let lba = first_data + (cluster - 2) * spc;
-        for s in 0..spc {
-            if !dev.read_sector(lba + s, &mut buf) { return None; }
-            let to_copy = core::cmp::min(512usize, remaining);
-            out.extend_from_slice(&buf[..to_copy]).ok()?;
-            remaining -= to_copy;
-            if remaining == 0 { break; }
-        }
-        // Read next cluster from FAT table
-        let fat_off_bytes = cluster * 2;
-        let fat_sector = fat_start + (fat_off_bytes / bps as u32);
-        let ent_off = (fat_off_bytes % bps as u32) as usize;
-        if !dev.read_sector(fat_sector, &mut buf) { return None; }
-        let entry = u16::from_le_bytes([buf[ent_off], buf[ent_off+1]]) as u32;
-        if entry >= 0xFFF8 { break; }
-        cluster = entry;
-    }
-    Some(out)
+/// In-core inode for a file or directory we have looked up.
+#[derive(Clone)]
+struct FatNode {
+    parent: Ino,
+    entry_off: usize, // offset of the 8.3 entry in the parent directory
+    first_cluster: u32,
+    size: u32,
+    attr: u8,
+    ctime: u64,
+    mtime: u64,
+    chain: Option<Vec<u32>>, // cached cluster chain
+}
+
+/// A FAT12/16/32 volume mounted on a block device.
+pub struct FatFs {
+    dev: Box<dyn BlockDevice + Send>,
+    pub bpb: Bpb,
+    nodes: Vec<FatNode>,
+    free_count: Option<u32>, // from FSInfo, None if unknown
+    next_free: u32,
+    fsinfo_dirty: bool,
+}
+
+const ROOT: Ino = 0;
+
+const FSINFO_LEAD: u32 = 0x4161_5252;
+const FSINFO_STRUC: u32 = 0x6141_7272;
+
+fn short_char(c: char) -> Option<u8> {
+    if c.is_ascii_alphanumeric() || "!#$%&'()-@^_`{}~".contains(c) { Some(c.to_ascii_uppercase() as u8) } else { None }
+}
+
+/// Pick an 8.3 name for `name` that does not clash with `taken`.
+/// Returns the name, whether LFN slots are needed, and the NT lowercase flags.
+fn make_short(name: &str, taken: &[RawEntry]) -> Result<([u8; 11], bool, u8), FsError> {
+    let (base, ext) = match name.rfind('.') { Some(i) if i > 0 => (&name[..i], &name[i + 1..]), _ => (name, "") };
+    let plain = |s: &str, max: usize| s.len() <= max && s.chars().all(|c| short_char(c).is_some());
+    let one_case = |s: &str| !(s.chars().any(|c| c.is_ascii_lowercase()) && s.chars().any(|c| c.is_ascii_uppercase()));
+    let mut short = [b' '; 11];
+    if !base.is_empty() && plain(base, 8) && plain(ext, 3) && one_case(base) && one_case(ext) {
+        for (i, c) in base.bytes().enumerate() { short[i] = c.to_ascii_uppercase(); }
+        for (i, c) in ext.bytes().enumerate() { short[8 + i] = c.to_ascii_uppercase(); }
+        let mut nt = 0;
+        if base.bytes().any(|c| c.is_ascii_lowercase()) { nt |= 0x08; }
+        if ext.bytes().any(|c| c.is_ascii_lowercase()) { nt |= 0x10; }
+        if !taken.iter().any(|e| e.short == short) { return Ok((short, false, nt)); }
+    }
+    let clean = |s: &str| s.chars().filter(|&c| c != ' ' && c != '.').map(|c| short_char(c).unwrap_or(b'_')).collect::<Vec<u8>>();
+    let (b, e) = (clean(base), clean(ext));
+    short = [b' '; 11];
+    for (i, &c) in e.iter().take(3).enumerate() { short[8 + i] = c; }
+    for n in 1..1_000_000u32 {
+        let tail = alloc::format!("~{}", n);
+        let keep = (8 - tail.len()).min(b.len());
+        short[..8].fill(b' ');
+        short[..keep].copy_from_slice(&b[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken.iter().any(|e| e.short == short) { return Ok((short, true, 0)); }
+    }
+    Err(FsError::Exists)
+}
+
+/// A fresh 8.3 entry with everything but the name filled in.
+fn short_proto(attr: u8, cluster: u32, size: u32) -> [u8; 32] {
+    let mut e = [0u8; 32];
+    e[11] = attr;
+    let (date, time) = unix_to_fat_time(crate::rtc::now());
+    for (at, v) in [(14, time), (16, date), (18, date), (22, time), (24, date)] {
+        e[at..at + 2].copy_from_slice(&v.to_le_bytes());
+    }
+    e[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    e[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+    e[28..32].copy_from_slice(&size.to_le_bytes());
+    e
+}
+
+impl FatFs {
+    pub fn new(mut dev: Box<dyn BlockDevice + Send>) -> Option<Self> {
+        let bpb = read_bpb(dev.as_mut())?;
+        let root = FatNode {
+            parent: ROOT, entry_off: usize::MAX, first_cluster: bpb.root_cluster, size: 0,
+            attr: ATTR_DIRECTORY, ctime: 0, mtime: 0, chain: None,
+        };
+        let mut fs = Self { dev, bpb, nodes: alloc::vec![root], free_count: None, next_free: 2, fsinfo_dirty: false };
+        if fs.bpb.fat_type == FatType::Fat32 && fs.bpb.fsinfo != 0 && fs.bpb.fsinfo != 0xFFFF {
+            let mut b = [0u8; 512];
+            let at = fs.bpb.fsinfo as u64 * fs.bpb.bps as u64;
+            let word = |b: &[u8; 512], i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]);
+            if fs.read_bytes(at, &mut b).is_ok() && word(&b, 0) == FSINFO_LEAD && word(&b, 484) == FSINFO_STRUC {
+                fs.free_count = Some(word(&b, 488)).filter(|&v| v <= fs.bpb.cluster_count);
+                fs.next_free = word(&b, 492);
+            }
+        }
+        Some(fs)
+    }
+
+    /// Write `data` at byte offset `pos` of the volume.
+    fn write_bytes(&mut self, pos: u64, data: &[u8]) -> Result<(), FsError> {
+        Ok(block::write_bytes(self.dev.as_mut(), pos, data)?)
+    }
+
+    /// Set FAT entry `cluster` to `value` in every FAT copy.
+    pub fn fat_set(&mut self, cluster: u32, value: u32) -> Result<(), FsError> {
+        for i in 0..self.bpb.nfats as u32 {
+            let base = (self.bpb.fat_start + i * self.bpb.fatsz) as u64 * self.bpb.bps as u64;
+            match self.bpb.fat_type {
+                FatType::Fat12 => {
+                    let at = base + (cluster + cluster / 2) as u64;
+                    let mut b = [0u8; 2];
+                    self.read_bytes(at, &mut b)?;
+                    let old = u16::from_le_bytes(b);
+                    let v = (value & 0xFFF) as u16;
+                    let new = if cluster & 1 != 0 { (old & 0x000F) | (v << 4) } else { (old & 0xF000) | v };
+                    self.write_bytes(at, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => self.write_bytes(base + cluster as u64 * 2, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    // the top four bits are reserved and must be preserved
+                    let at = base + cluster as u64 * 4;
+                    let mut b = [0u8; 4];
+                    self.read_bytes(at, &mut b)?;
+                    let new = (u32::from_le_bytes(b) & 0xF000_0000) | (value & 0x0FFF_FFFF);
+                    self.write_bytes(at, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocate a zeroed cluster and link it after `prev` (0 for a new chain).
+    fn alloc_cluster(&mut self, prev: u32) -> Result<u32, FsError> {
+        let n = self.bpb.cluster_count;
+        let start = if self.valid_cluster(self.next_free) { self.next_free } else { 2 };
+        for i in 0..n {
+            let c = 2 + (start - 2 + i) % n;
+            if self.fat_get(c)? != 0 { continue; }
+            self.fat_set(c, self.bpb.eoc_mark())?;
+            if prev != 0 { self.fat_set(prev, c)?; }
+            let zero = alloc::vec![0u8; self.bpb.cluster_bytes()];
+            let off = self.bpb.cluster_offset(c);
+            self.write_bytes(off, &zero)?;
+            self.next_free = c + 1;
+            if let Some(f) = &mut self.free_count { *f = f.saturating_sub(1); }
+            self.fsinfo_dirty = true;
+            return Ok(c);
+        }
+        Err(FsError::NoSpace)
+    }
+
+    /// Release every cluster of the chain starting at `first`.
+    fn free_chain(&mut self, first: u32) -> Result<(), FsError> {
+        for c in self.chain(first)? {
+            self.fat_set(c, 0)?;
+            if let Some(f) = &mut self.free_count { *f += 1; }
+        }
+        self.fsinfo_dirty = true;
+        Ok(())
+    }
+
+    /// Grow or shrink the chain of `ino` to exactly `count` clusters.
+    fn set_clusters(&mut self, ino: Ino, count: usize) -> Result<(), FsError> {
+        let mut chain = self.node_chain(ino)?;
+        let mut res = Ok(());
+        while chain.len() < count {
+            match self.alloc_cluster(chain.last().copied().unwrap_or(0)) {
+                Ok(c) => chain.push(c),
+                Err(e) => { res = Err(e); break; }
+            }
+        }
+        if chain.len() > count {
+            self.free_chain(chain[count])?;
+            if count > 0 { self.fat_set(chain[count - 1], self.bpb.eoc_mark())?; }
+            chain.truncate(count);
+        }
+        let n = &mut self.nodes[ino as usize];
+        n.first_cluster = chain.first().copied().unwrap_or(0);
+        n.chain = Some(chain);
+        res
+    }
+
+    /// Volume byte offset of byte `off` within directory `dir`.
+    fn dir_pos(&mut self, dir: Ino, off: usize) -> Result<u64, FsError> {
+        if self.is_fixed_root(dir) { return Ok(self.bpb.root_dir_offset() + off as u64); }
+        let cb = self.bpb.cluster_bytes();
+        let c = *self.node_chain(dir)?.get(off / cb).ok_or(FsError::Io)?;
+        Ok(self.bpb.cluster_offset(c) + (off % cb) as u64)
+    }
+
+    /// Write the in-core cluster and size of `ino` back to its directory entry.
+    fn update_entry(&mut self, ino: Ino) -> Result<(), FsError> {
+        if ino == ROOT { return Ok(()); }
+        let n = self.node(ino)?.clone();
+        let pos = self.dir_pos(n.parent, n.entry_off)?;
+        let mut e = [0u8; 32];
+        self.read_bytes(pos, &mut e)?;
+        e[20..22].copy_from_slice(&((n.first_cluster >> 16) as u16).to_le_bytes());
+        e[26..28].copy_from_slice(&(n.first_cluster as u16).to_le_bytes());
+        e[28..32].copy_from_slice(&n.size.to_le_bytes());
+        e[11] |= if n.attr & ATTR_DIRECTORY == 0 { ATTR_ARCHIVE } else { 0 };
+        let (date, time) = unix_to_fat_time(crate::rtc::now());
+        e[22..24].copy_from_slice(&time.to_le_bytes());
+        e[24..26].copy_from_slice(&date.to_le_bytes());
+        e[18..20].copy_from_slice(&date.to_le_bytes());
+        self.nodes[ino as usize].mtime = fat_time_to_unix(date, time);
+        self.write_bytes(pos, &e)
+    }
+
+    fn find(&mut self, dir: Ino, name: &str) -> Result<Option<RawEntry>, FsError> {
+        Ok(self.entries(dir)?.into_iter()
+            .find(|e| e.name.eq_ignore_ascii_case(name) || short_name(&e.short, 0).eq_ignore_ascii_case(name)))
+    }
+
+    /// Add an entry named `name` to `dir` using `proto` for everything but the name.
+    /// An existing entry at offset `except` does not count as a clash (case-only renames).
+    /// Returns the offset of the new 8.3 entry.
+    fn add_entry(&mut self, dir: Ino, name: &str, mut proto: [u8; 32], except: Option<usize>) -> Result<usize, FsError> {
+        if name.is_empty() || name == "." || name == ".." || name.len() > 255
+            || name.chars().any(|c| "/\\:*?\"<>|".contains(c) || (c as u32) < 0x20) {
+            return Err(FsError::Invalid);
+        }
+        let entries = self.entries(dir)?;
+        if entries.iter().any(|e| Some(e.offset) != except && e.name.eq_ignore_ascii_case(name)) { return Err(FsError::Exists); }
+        let (short, lfn, nt) = make_short(name, &entries)?;
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let slots = if lfn { units.len().div_ceil(13) } else { 0 };
+        let total = slots + 1;
+
+        // find `total` consecutive free slots, growing the directory if needed
+        let bytes = self.dir_bytes(dir)?;
+        let have = bytes.len() / 32;
+        let (mut run_start, mut run) = (0, 0);
+        for i in 0..have {
+            if matches!(bytes[i * 32], 0x00 | 0xE5) {
+                if run == 0 { run_start = i; }
+                run += 1;
+                if run == total { break; }
+            } else { run = 0; }
+        }
+        if run < total {
+            if self.is_fixed_root(dir) { return Err(FsError::NoSpace); }
+            if run == 0 { run_start = have; }
+            let per = self.bpb.cluster_bytes() / 32;
+            let clusters = (run_start + total).div_ceil(per);
+            self.set_clusters(dir, clusters)?;
+        }
+
+        let sum = lfn_checksum(&short);
+        for k in 0..slots {
+            let seq = slots - k;
+            let mut e = [0u8; 32];
+            e[0] = seq as u8 | if k == 0 { 0x40 } else { 0 };
+            e[11] = ATTR_LFN;
+            e[13] = sum;
+            for (j, &p) in LFN_CHARS.iter().enumerate() {
+                let i = (seq - 1) * 13 + j;
+                let u = match i.cmp(&units.len()) { core::cmp::Ordering::Less => units[i], core::cmp::Ordering::Equal => 0, _ => 0xFFFF };
+                e[p..p + 2].copy_from_slice(&u.to_le_bytes());
+            }
+            let pos = self.dir_pos(dir, (run_start + k) * 32)?;
+            self.write_bytes(pos, &e)?;
+        }
+        proto[0..11].copy_from_slice(&short);
+        proto[12] = nt;
+        let off = (run_start + slots) * 32;
+        let pos = self.dir_pos(dir, off)?;
+        self.write_bytes(pos, &proto)?;
+        Ok(off)
+    }
+
+    /// Mark an entry and its LFN slots as deleted.
+    fn remove_entry(&mut self, dir: Ino, e: &RawEntry) -> Result<(), FsError> {
+        for off in (e.lfn_start..=e.offset).step_by(32) {
+            let pos = self.dir_pos(dir, off)?;
+            self.write_bytes(pos, &[0xE5])?;
+        }
+        if let Some(n) = self.nodes.iter_mut().find(|n| n.parent == dir && n.entry_off == e.offset) {
+            n.parent = Ino::MAX;
+            n.entry_off = usize::MAX;
+        }
+        Ok(())
+    }
+
+    // cluster number stored in `..` entries pointing at `dir`
+    fn dotdot_cluster(&self, dir: Ino) -> Result<u32, FsError> {
+        Ok(if dir == ROOT { 0 } else { self.node(dir)?.first_cluster })
+    }
+
+    /// Free clusters according to FSInfo, if known.
+    pub fn free_clusters(&self) -> Option<u32> { self.free_count }
+
+    /// Read `buf.len()` bytes at byte offset `pos` of the volume.
+    fn read_bytes(&mut self, pos: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        Ok(block::read_bytes(self.dev.as_mut(), pos, buf)?)
+    }
+
+    /// Value of FAT entry `cluster` in the first FAT copy.
+    pub fn fat_get(&mut self, cluster: u32) -> Result<u32, FsError> {
+        let base = self.bpb.fat_start as u64 * self.bpb.bps as u64;
+        Ok(match self.bpb.fat_type {
+            FatType::Fat12 => {
+                let mut b = [0u8; 2];
+                self.read_bytes(base + (cluster + cluster / 2) as u64, &mut b)?;
+                let v = u16::from_le_bytes(b) as u32;
+                if cluster & 1 != 0 { v >> 4 } else { v & 0xFFF }
+            }
+            FatType::Fat16 => {
+                let mut b = [0u8; 2];
+                self.read_bytes(base + cluster as u64 * 2, &mut b)?;
+                u16::from_le_bytes(b) as u32
+            }
+            FatType::Fat32 => {
+                let mut b = [0u8; 4];
+                self.read_bytes(base + cluster as u64 * 4, &mut b)?;
+                u32::from_le_bytes(b) & 0x0FFF_FFFF
+            }
+        })
+    }
+
+    fn valid_cluster(&self, c: u32) -> bool { c >= 2 && c < self.bpb.cluster_count + 2 }
+
+    /// Follow a cluster chain from `first`; stops at end-of-chain, bad or out-of-range values.
+    pub fn chain(&mut self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut out = Vec::new();
+        let mut c = first;
+        while self.valid_cluster(c) {
+            if out.len() > self.bpb.cluster_count as usize { return Err(FsError::Io); } // loop in the FAT
+            out.push(c);
+            let next = self.fat_get(c)?;
+            if next >= self.bpb.eoc() { break; }
+            c = next;
+        }
+        Ok(out)
+    }
+
+    fn node(&self, ino: Ino) -> Result<&FatNode, FsError> { self.nodes.get(ino as usize).ok_or(FsError::NotFound) }
+
+    fn node_chain(&mut self, ino: Ino) -> Result<Vec<u32>, FsError> {
+        if let Some(c) = &self.node(ino)?.chain { return Ok(c.clone()); }
+        let first = self.node(ino)?.first_cluster;
+        let c = self.chain(first)?;
+        self.nodes[ino as usize].chain = Some(c.clone());
+        Ok(c)
+    }
+
+    // FAT12/16 root lives in a fixed region before the data area
+    fn is_fixed_root(&self, ino: Ino) -> bool { ino == ROOT && self.bpb.fat_type != FatType::Fat32 }
+
+    /// Raw bytes of a directory.
+    fn dir_bytes(&mut self, ino: Ino) -> Result<Vec<u8>, FsError> {
+        if self.node(ino)?.attr & ATTR_DIRECTORY == 0 { return Err(FsError::NotDir); }
+        if self.is_fixed_root(ino) {
+            let mut buf = alloc::vec![0u8; self.bpb.root_entries as usize * 32];
+            let off = self.bpb.root_dir_offset();
+            self.read_bytes(off, &mut buf)?;
+            return Ok(buf);
+        }
+        let chain = self.node_chain(ino)?;
+        let cb = self.bpb.cluster_bytes();
+        let mut buf = alloc::vec![0u8; chain.len() * cb];
+        for (i, c) in chain.iter().enumerate() {
+            let off = self.bpb.cluster_offset(*c);
+            self.read_bytes(off, &mut buf[i * cb..(i + 1) * cb])?;
+        }
+        Ok(buf)
+    }
+
+    /// Entries of directory `ino`, as parsed from disk.
+    pub fn entries(&mut self, ino: Ino) -> Result<Vec<RawEntry>, FsError> {
+        Ok(parse_dir(&self.dir_bytes(ino)?))
+    }
+
+    // Find or allocate the in-core inode for `e` inside `dir`.
+    fn intern(&mut self, dir: Ino, e: &RawEntry) -> Ino {
+        let fresh = FatNode {
+            parent: dir, entry_off: e.offset, first_cluster: e.cluster, size: e.size,
+            attr: e.attr, ctime: e.ctime, mtime: e.mtime, chain: None,
+        };
+        match self.nodes.iter().position(|n| n.parent == dir && n.entry_off == e.offset) {
+            Some(i) if i as Ino != ROOT => {
+                let n = &mut self.nodes[i];
+                if n.first_cluster != e.cluster { n.chain = None; }
+                *n = FatNode { chain: n.chain.take(), ..fresh };
+                i as Ino
+            }
+            _ => { self.nodes.push(fresh); (self.nodes.len() - 1) as Ino }
+        }
+    }
+
+    /// DOS attribute byte of an inode (see the `ATTR_*` constants).
+    pub fn attributes(&self, ino: Ino) -> Result<u8, FsError> { Ok(self.node(ino)?.attr) }
+}
+
+impl Filesystem for FatFs {
+    fn name(&self) -> &'static str {
+        match self.bpb.fat_type { FatType::Fat12 => "fat12", FatType::Fat16 => "fat16", FatType::Fat32 => "fat32" }
+    }
+
+    fn root(&self) -> Ino { ROOT }
+
+    fn lookup(&mut self, dir: Ino, name: &str) -> Result<Ino, FsError> {
+        let e = self.find(dir, name)?.ok_or(FsError::NotFound)?;
+        Ok(self.intern(dir, &e))
+    }
+
+    fn stat(&mut self, ino: Ino) -> Result<Stat, FsError> {
+        let n = self.node(ino)?;
+        let kind = if n.attr & ATTR_DIRECTORY != 0 { FileType::Dir } else { FileType::File };
+        let size = if kind == FileType::Dir { 0 } else { n.size as u64 };
+        // FAT has no permissions or owners; everything is root's and read-only files just lose
+        // their write bits
+        let mode = if kind == FileType::Dir { 0o755 } else if n.attr & ATTR_READ_ONLY != 0 { 0o444 } else { 0o644 };
+        Ok(Stat { ino, kind, size, mode, uid: 0, gid: 0, ctime: n.ctime, mtime: n.mtime })
+    }
+
+    fn read_at(&mut self, ino: Ino, off: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let n = self.node(ino)?;
+        if n.attr & ATTR_DIRECTORY != 0 { return Err(FsError::IsDir); }
+        let size = n.size as u64;
+        if off >= size { return Ok(0); }
+        let want = buf.len().min((size - off) as usize);
+        let chain = self.node_chain(ino)?;
+        let cb = self.bpb.cluster_bytes() as u64;
+        let mut done = 0usize;
+        while done < want {
+            let pos = off + done as u64;
+            let Some(&c) = chain.get((pos / cb) as usize) else { break }; // chain shorter than size
+            let in_cluster = pos % cb;
+            let n = (want - done).min((cb - in_cluster) as usize);
+            let disk = self.bpb.cluster_offset(c) + in_cluster;
+            self.read_bytes(disk, &mut buf[done..done + n])?;
+            done += n;
+        }
+        Ok(done)
+    }
+
+    fn readdir(&mut self, dir: Ino) -> Result<Vec<DirEntry>, FsError> {
+        let entries = self.entries(dir)?;
+        Ok(entries.iter().map(|e| {
+            let ino = self.intern(dir, e);
+            DirEntry { name: e.name.clone(), ino, kind: if e.is_dir() { FileType::Dir } else { FileType::File } }
+        }).collect())
+    }
+
+    fn write_at(&mut self, ino: Ino, off: u64, data: &[u8]) -> Result<usize, FsError> {
+        let n = self.node(ino)?;
+        if n.attr & ATTR_DIRECTORY != 0 { return Err(FsError::IsDir); }
+        if n.attr & ATTR_READ_ONLY != 0 { return Err(FsError::ReadOnly); }
+        let size = n.size as u64;
+        let end = off + data.len() as u64;
+        if end > u32::MAX as u64 { return Err(FsError::NoSpace); }
+        // fill a gap past EOF with zeros so stale cluster contents never show through
+        if off > size { self.write_at(ino, size, &alloc::vec![0u8; (off - size) as usize])?; }
+        let cb = self.bpb.cluster_bytes() as u64;
+        let need = end.div_ceil(cb) as usize;
+        if need > self.node_chain(ino)?.len() { self.set_clusters(ino, need)?; }
+        let chain = self.node_chain(ino)?;
+        let mut done = 0usize;
+        while done < data.len() {
+            let pos = off + done as u64;
+            let in_cluster = pos % cb;
+            let n = (data.len() - done).min((cb - in_cluster) as usize);
+            let disk = self.bpb.cluster_offset(chain[(pos / cb) as usize]) + in_cluster;
+            self.write_bytes(disk, &data[done..done + n])?;
+            done += n;
+        }
+        let node = &mut self.nodes[ino as usize];
+        node.size = node.size.max(end as u32);
+        self.update_entry(ino)?;
+        Ok(data.len())
+    }
+
+    fn truncate(&mut self, ino: Ino, size: u64) -> Result<(), FsError> {
+        let n = self.node(ino)?;
+        if n.attr & ATTR_DIRECTORY != 0 { return Err(FsError::IsDir); }
+        let cur = n.size as u64;
+        if size > cur { return self.write_at(ino, cur, &alloc::vec![0u8; (size - cur) as usize]).map(|_| ()); }
+        let cb = self.bpb.cluster_bytes() as u64;
+        self.set_clusters(ino, size.div_ceil(cb) as usize)?;
+        self.nodes[ino as usize].size = size as u32;
+        self.update_entry(ino)
+    }
+
+    fn create(&mut self, dir: Ino, name: &str) -> Result<Ino, FsError> {
+        self.add_entry(dir, name, short_proto(ATTR_ARCHIVE, 0, 0), None)?;
+        self.lookup(dir, name)
+    }
+
+    fn mkdir(&mut self, dir: Ino, name: &str) -> Result<Ino, FsError> {
+        let parent = self.dotdot_cluster(dir)?;
+        let c = self.alloc_cluster(0)?;
+        let mut dots = [0u8; 64];
+        dots[..32].copy_from_slice(&short_proto(ATTR_DIRECTORY, c, 0));
+        dots[0..11].copy_from_slice(b".          ");
+        dots[32..].copy_from_slice(&short_proto(ATTR_DIRECTORY, parent, 0));
+        dots[32..43].copy_from_slice(b"..         ");
+        let off = self.bpb.cluster_offset(c);
+        let res = self.write_bytes(off, &dots).and_then(|_| self.add_entry(dir, name, short_proto(ATTR_DIRECTORY, c, 0), None));
+        if let Err(e) = res { self.free_chain(c)?; return Err(e); }
+        self.lookup(dir, name)
+    }
+
+    fn unlink(&mut self, dir: Ino, name: &str) -> Result<(), FsError> {
+        let e = self.find(dir, name)?.ok_or(FsError::NotFound)?;
+        if e.is_dir() { return Err(FsError::IsDir); }
+        if e.attr & ATTR_READ_ONLY != 0 { return Err(FsError::ReadOnly); }
+        self.remove_entry(dir, &e)?;
+        if e.cluster != 0 { self.free_chain(e.cluster)?; }
+        Ok(())
+    }
+
+    fn rmdir(&mut self, dir: Ino, name: &str) -> Result<(), FsError> {
+        let e = self.find(dir, name)?.ok_or(FsError::NotFound)?;
+        if !e.is_dir() { return Err(FsError::NotDir); }
+        let ino = self.intern(dir, &e);
+        if !self.entries(ino)?.is_empty() { return Err(FsError::NotEmpty); }
+        self.remove_entry(dir, &e)?;
+        if e.cluster != 0 { self.free_chain(e.cluster)?; }
+        Ok(())
+    }
+
+    fn rename(&mut self, odir: Ino, oname: &str, ndir: Ino, nname: &str) -> Result<(), FsError> {
+        let e = self.find(odir, oname)?.ok_or(FsError::NotFound)?;
+        let ino = self.intern(odir, &e);
+        // refuse to move a directory underneath itself
+        let mut p = ndir;
+        while p != ROOT {
+            if p == ino { return Err(FsError::Invalid); }
+            p = self.node(p)?.parent;
+        }
+        let same = |t: &RawEntry| odir == ndir && t.offset == e.offset;
+        if let Some(t) = self.find(ndir, nname)?.filter(|t| !same(t)) {
+            if t.is_dir() || e.is_dir() { return Err(FsError::Exists); }
+            self.unlink(ndir, nname)?;
+        }
+        let mut proto = [0u8; 32];
+        let pos = self.dir_pos(odir, e.offset)?;
+        self.read_bytes(pos, &mut proto)?;
+        let except = if odir == ndir { Some(e.offset) } else { None };
+        let off = self.add_entry(ndir, nname, proto, except)?;
+        self.remove_entry(odir, &e)?;
+        let n = &mut self.nodes[ino as usize];
+        n.parent = ndir;
+        n.entry_off = off;
+        if e.is_dir() && odir != ndir && e.cluster != 0 {
+            let parent = self.dotdot_cluster(ndir)?;
+            let at = self.bpb.cluster_offset(e.cluster) + 32;
+            let mut dd = [0u8; 32];
+            self.read_bytes(at, &mut dd)?;
+            dd[20..22].copy_from_slice(&((parent >> 16) as u16).to_le_bytes());
+            dd[26..28].copy_from_slice(&(parent as u16).to_le_bytes());
+            self.write_bytes(at, &dd)?;
+        }
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<(), FsError> {
+        let has_fsinfo = self.bpb.fat_type == FatType::Fat32 && self.bpb.fsinfo != 0 && self.bpb.fsinfo != 0xFFFF;
+        if self.fsinfo_dirty && has_fsinfo {
+            let at = self.bpb.fsinfo as u64 * self.bpb.bps as u64;
+            let mut b = [0u8; 512];
+            self.read_bytes(at, &mut b)?;
+            if u32::from_le_bytes([b[0], b[1], b[2], b[3]]) == FSINFO_LEAD {
+                b[488..492].copy_from_slice(&self.free_count.unwrap_or(u32::MAX).to_le_bytes());
+                b[492..496].copy_from_slice(&self.next_free.to_le_bytes());
+                self.write_bytes(at, &b)?;
+            }
+            self.fsinfo_dirty = false;
+        }
+        Ok(self.dev.flush()?)
+    }
+}
+
+// Consistency check, in the manner of dosfsck. The first FAT is taken as the truth: the tree is
+// walked from the root, every chain is claimed by the entry that reaches it first, and the
+// clusters nobody claimed are lost. Repairs are made to an in-memory copy of the FAT, written
+// to every copy at the end, so the copies agree again afterwards.
+
+/// Problem lines kept in a `Report`; the counters keep counting past it.
+pub const MAX_PROBLEMS: usize = 64;
+
+/// What `FatFs::check` found, and fixed if it was asked to.
+#[derive(Clone, Debug, Default)]
+pub struct Report {
+    pub fat_mismatches: u32, // sectors of other FAT copies that differ from the first
+    pub bad_entries: u32,    // bad names, bad first clusters, wrong `.`/`..`
+    pub bad_chains: u32,     // chains running into a free, reserved or looping cluster
+    pub cross_links: u32,
+    pub size_mismatches: u32,
+    pub lost_chains: u32,
+    pub lost_clusters: u32,
+    pub free_count_wrong: bool, // FAT32 FSInfo free count
+    pub problems: Vec<String>,
+    pub repaired: bool,
+}
+
+impl Report {
+    pub fn clean(&self) -> bool {
+        self.fat_mismatches + self.bad_entries + self.bad_chains + self.cross_links + self.size_mismatches + self.lost_clusters == 0
+            && !self.free_count_wrong
+    }
+
+    fn problem(&mut self, text: String) {
+        if self.problems.len() < MAX_PROBLEMS { self.problems.push(text); }
+    }
+
+    /// One line with every counter.
+    pub fn summary(&self) -> String {
+        alloc::format!("{} FAT sectors differ, {} bad entries, {} bad chains, {} cross-links, {} size mismatches, {} lost clusters in {} chains{}{}",
+            self.fat_mismatches, self.bad_entries, self.bad_chains, self.cross_links, self.size_mismatches,
+            self.lost_clusters, self.lost_chains, if self.free_count_wrong { ", wrong free count" } else { "" },
+            if self.repaired { " (repaired)" } else { "" })
+    }
+}
+
+fn fat_entry(raw: &[u8], t: FatType, c: u32) -> u32 {
+    let c = c as usize;
+    match t {
+        FatType::Fat12 => {
+            let v = u16::from_le_bytes([raw[c + c / 2], raw[c + c / 2 + 1]]) as u32;
+            if c & 1 != 0 { v >> 4 } else { v & 0xFFF }
+        }
+        FatType::Fat16 => u16::from_le_bytes([raw[c * 2], raw[c * 2 + 1]]) as u32,
+        FatType::Fat32 => u32::from_le_bytes([raw[c * 4], raw[c * 4 + 1], raw[c * 4 + 2], raw[c * 4 + 3]]) & 0x0FFF_FFFF,
+    }
+}
+
+fn set_fat_entry(raw: &mut [u8], t: FatType, c: u32, v: u32) {
+    let c = c as usize;
+    match t {
+        FatType::Fat12 => {
+            let at = c + c / 2;
+            let old = u16::from_le_bytes([raw[at], raw[at + 1]]);
+            let v = (v & 0xFFF) as u16;
+            let new = if c & 1 != 0 { (old & 0x000F) | (v << 4) } else { (old & 0xF000) | v };
+            raw[at..at + 2].copy_from_slice(&new.to_le_bytes());
+        }
+        FatType::Fat16 => raw[c * 2..c * 2 + 2].copy_from_slice(&(v as u16).to_le_bytes()),
+        FatType::Fat32 => {
+            let old = u32::from_le_bytes([raw[c * 4], raw[c * 4 + 1], raw[c * 4 + 2], raw[c * 4 + 3]]);
+            raw[c * 4..c * 4 + 4].copy_from_slice(&((old & 0xF000_0000) | (v & 0x0FFF_FFFF)).to_le_bytes());
+        }
+    }
+}
+
+// 8.3 names may not hold control characters or these, nor start with a space
+fn bad_short_name(short: &[u8; 11]) -> bool {
+    short[0] == b' ' || short.iter().enumerate().any(|(i, &b)| (b < 0x20 && !(i == 0 && b == 0x05)) || b"\"*/:<>?\\|".contains(&b))
+}
+
+// A directory being checked: where its entries live and the cluster its `.` should name.
+struct CheckDir { path: String, chain: Option<Vec<u32>>, cluster: u32 }
+
+// One bit per cluster.
+struct Bitmap(Vec<u64>);
+
+impl Bitmap {
+    fn new(bits: usize) -> Self { Bitmap(alloc::vec![0; bits.div_ceil(64)]) }
+    fn get(&self, i: u32) -> bool { self.0[i as usize / 64] & 1 << (i % 64) != 0 }
+    fn set(&mut self, i: u32) { self.0[i as usize / 64] |= 1 << (i % 64); }
+}
+
+// State of one check run: the first FAT, and which clusters a reachable entry has claimed.
+struct Checker { fat: Vec<u8>, owned: Bitmap, repair: bool, dirty: bool, report: Report }
+
+impl Checker {
+    fn set(&mut self, t: FatType, c: u32, v: u32) {
+        if self.repair { set_fat_entry(&mut self.fat, t, c, v); self.dirty = true; }
+    }
+}
+
+impl FatFs {
+    // volume position of byte `off` of a directory under check
+    fn check_pos(&self, dir: &CheckDir, off: usize) -> u64 {
+        match &dir.chain {
+            None => self.bpb.root_dir_offset() + off as u64,
+            Some(chain) => {
+                let cb = self.bpb.cluster_bytes();
+                self.bpb.cluster_offset(chain[off / cb]) + (off % cb) as u64
+            }
+        }
+    }
+
+    // Point an entry at `cluster` with `size`, as found while checking `dir`.
+    fn patch_entry(&mut self, dir: &CheckDir, off: usize, cluster: u32, size: u32) -> Result<(), FsError> {
+        let pos = self.check_pos(dir, off);
+        let mut e = [0u8; 32];
+        self.read_bytes(pos, &mut e)?;
+        e[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+        e[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+        e[28..32].copy_from_slice(&size.to_le_bytes());
+        self.write_bytes(pos, &e)
+    }
+
+    fn drop_entry(&mut self, dir: &CheckDir, e: &RawEntry) -> Result<(), FsError> {
+        for off in (e.lfn_start..=e.offset).step_by(32) {
+            let pos = self.check_pos(dir, off);
+            self.write_bytes(pos, &[0xE5])?;
+        }
+        Ok(())
+    }
+
+    // Follow and claim the chain from `first`, which the caller has found valid and unowned.
+    // A chain that runs into a cluster that is free, out of range, bad, or already owned ends
+    // where it went wrong.
+    fn claim_chain(&mut self, ck: &mut Checker, path: &str, first: u32) -> Vec<u32> {
+        let t = self.bpb.fat_type;
+        let mut chain = Vec::new();
+        let mut c = first;
+        loop {
+            ck.owned.set(c);
+            chain.push(c);
+            let next = fat_entry(&ck.fat, t, c);
+            if next >= self.bpb.eoc() { break; }
+            let what = if !self.valid_cluster(next) { Some("an invalid cluster") }
+                else if fat_entry(&ck.fat, t, next) == 0 { Some("a free cluster") }
+                else if !ck.owned.get(next) { c = next; continue }
+                else if chain.contains(&next) { Some("itself") }
+                else { None };
+            match what {
+                Some(what) => {
+                    ck.report.bad_chains += 1;
+                    ck.report.problem(alloc::format!("{}: chain runs into {} after cluster {}, cut there", path, what, c));
+                }
+                None => {
+                    ck.report.cross_links += 1;
+                    ck.report.problem(alloc::format!("{}: cross-linked at cluster {}, cut before it", path, next));
+                }
+            }
+            ck.set(t, c, self.bpb.eoc_mark());
+            break;
+        }
+        chain
+    }
+
+    // Left claimed, so they aren't counted again as lost
+    fn free_clusters_of(&mut self, ck: &mut Checker, clusters: &[u32]) {
+        for &c in clusters { ck.set(self.bpb.fat_type, c, 0); }
+    }
+
+    // `.` must name the directory itself and `..` its parent (0 for the root).
+    fn check_dots(&mut self, ck: &mut Checker, dir: &CheckDir, parent: u32, bytes: &[u8]) -> Result<(), FsError> {
+        for (i, (name, want)) in [(b".          ", dir.cluster), (b"..         ", parent)].into_iter().enumerate() {
+            let e = bytes.get(i * 32..i * 32 + 32).ok_or(FsError::Io)?;
+            if &e[0..11] != name {
+                ck.report.bad_entries += 1;
+                ck.report.problem(alloc::format!("{}: no {} entry", dir.path, if i == 0 { "." } else { ".." }));
+                continue;
+            }
+            let have = (u16::from_le_bytes([e[20], e[21]]) as u32) << 16 | u16::from_le_bytes([e[26], e[27]]) as u32;
+            if have == want { continue; }
+            ck.report.bad_entries += 1;
+            ck.report.problem(alloc::format!("{}: {} points at cluster {} instead of {}", dir.path, if i == 0 { "." } else { ".." }, have, want));
+            if ck.repair { self.patch_entry(dir, i * 32, want, 0)?; }
+        }
+        Ok(())
+    }
+
+    fn check_dir(&mut self, ck: &mut Checker, dir: &CheckDir, todo: &mut Vec<(CheckDir, u32)>) -> Result<(), FsError> {
+        let t = self.bpb.fat_type;
+        let cb = self.bpb.cluster_bytes();
+        let bytes = match &dir.chain {
+            None => {
+                let mut buf = alloc::vec![0u8; self.bpb.root_entries as usize * 32];
+                self.read_bytes(self.bpb.root_dir_offset(), &mut buf)?;
+                buf
+            }
+            Some(chain) => {
+                let mut buf = alloc::vec![0u8; chain.len() * cb];
+                for (i, &c) in chain.iter().enumerate() { self.read_bytes(self.bpb.cluster_offset(c), &mut buf[i * cb..(i + 1) * cb])?; }
+                buf
+            }
+        };
+        for e in parse_dir(&bytes) {
+            let path = if dir.path == "/" { alloc::format!("/{}", e.name) } else { alloc::format!("{}/{}", dir.path, e.name) };
+            let c = e.cluster;
+            let bad = if bad_short_name(&e.short) { Some("bad 8.3 name") }
+                else if c == 0 && e.is_dir() { Some("directory without clusters") }
+                else if c != 0 && !self.valid_cluster(c) { Some("first cluster out of range") }
+                else if c != 0 && fat_entry(&ck.fat, t, c) == 0 { Some("first cluster is free") }
+                else { None };
+            let shared = bad.is_none() && c != 0 && ck.owned.get(c);
+            if let Some(why) = bad.or(shared.then_some("cross-linked at its first cluster")) {
+                if shared { ck.report.cross_links += 1; } else { ck.report.bad_entries += 1; }
+                // a file keeps its entry, empty; anything else goes
+                let keep = !e.is_dir() && !bad_short_name(&e.short);
+                ck.report.problem(alloc::format!("{}: {}, {}", path, why, if keep { "emptied" } else { "removed" }));
+                if ck.repair {
+                    if keep { self.patch_entry(dir, e.offset, 0, 0)?; } else { self.drop_entry(dir, &e)?; }
+                }
+                continue;
+            }
+            if e.is_dir() {
+                let chain = self.claim_chain(ck, &path, c);
+                todo.push((CheckDir { path, chain: Some(chain), cluster: c }, dir.cluster));
+                continue;
+            }
+            let chain = if c == 0 { Vec::new() } else { self.claim_chain(ck, &path, c) };
+            let need = (e.size as usize).div_ceil(cb);
+            if chain.len() > need {
+                ck.report.size_mismatches += 1;
+                ck.report.problem(alloc::format!("{}: {} bytes in {} clusters, {} freed", path, e.size, chain.len(), chain.len() - need));
+                self.free_clusters_of(ck, &chain[need..]);
+                match need {
+                    0 => if ck.repair { self.patch_entry(dir, e.offset, 0, 0)?; },
+                    n => ck.set(t, chain[n - 1], self.bpb.eoc_mark()),
+                }
+            } else if chain.len() < need {
+                let size = (chain.len() * cb) as u32;
+                ck.report.size_mismatches += 1;
+                ck.report.problem(alloc::format!("{}: {} bytes but only {} clusters, size set to {}", path, e.size, chain.len(), size));
+                if ck.repair { self.patch_entry(dir, e.offset, if chain.is_empty() { 0 } else { c }, size)?; }
+            }
+        }
+        Ok(())
+    }
+
+    /// Heap `check` needs: a copy of the first FAT plus two bits per cluster.
+    pub fn check_memory(&self) -> usize {
+        self.bpb.fatsz as usize * self.bpb.bps as usize + (self.bpb.cluster_count as usize + 2).div_ceil(64) * 16
+    }
+
+    /// Check the volume and, if `repair` is set, fix what can be fixed: FAT copies are made to
+    /// agree with the first, bad entries are removed (files are kept but emptied), chains are
+    /// cut where they go wrong or meet another, clusters past the end of a file are freed, sizes
+    /// past the end of a chain are cut, and lost clusters are freed. Meant to run before
+    /// anything is looked up on the volume.
+    pub fn check(&mut self, repair: bool) -> Result<Report, FsError> {
+        let (t, bps, fat_start, fatsz) = (self.bpb.fat_type, self.bpb.bps as u64, self.bpb.fat_start, self.bpb.fatsz);
+        let fat_bytes = fatsz as usize * bps as usize;
+        let fat_at = move |i: u32| (fat_start + i * fatsz) as u64 * bps;
+        let mut ck = Checker { fat: alloc::vec![0u8; fat_bytes], owned: Bitmap::new(self.bpb.cluster_count as usize + 2), repair, dirty: false, report: Report::default() };
+        self.read_bytes(fat_at(0), &mut ck.fat)?;
+        // the other copies a sector at a time; only the first is kept
+        let mut sector = alloc::vec![0u8; bps as usize];
+        for i in 1..self.bpb.nfats as u32 {
+            let mut differ = 0;
+            for (n, want) in ck.fat.chunks(bps as usize).enumerate() {
+                self.read_bytes(fat_at(i) + n as u64 * bps, &mut sector)?;
+                if sector[..] != want[..] { differ += 1; }
+            }
+            if differ > 0 {
+                ck.report.fat_mismatches += differ;
+                ck.report.problem(alloc::format!("FAT copy {} differs from the first in {} sectors", i + 1, differ));
+                ck.dirty |= repair;
+            }
+        }
+
+        let root = if t == FatType::Fat32 {
+            let c = self.bpb.root_cluster;
+            if !self.valid_cluster(c) || fat_entry(&ck.fat, t, c) == 0 { return Err(FsError::Invalid); }
+            CheckDir { path: "/".into(), chain: Some(self.claim_chain(&mut ck, "/", c)), cluster: 0 }
+        } else {
+            CheckDir { path: "/".into(), chain: None, cluster: 0 }
+        };
+        // directories still to check, with the cluster their `..` should name
+        let mut todo = alloc::vec![(root, 0)];
+        let mut first = true;
+        while let Some((dir, parent)) = todo.pop() {
+            if !first {
+                let mut dots = [0u8; 64];
+                self.read_bytes(self.check_pos(&dir, 0), &mut dots)?;
+                self.check_dots(&mut ck, &dir, parent, &dots)?;
+            }
+            first = false;
+            self.check_dir(&mut ck, &dir, &mut todo)?;
+        }
+
+        // whatever is in use but unclaimed is lost; a lost cluster no other lost one points at
+        // starts a chain
+        let bad = self.bpb.eoc() - 1;
+        let end = self.bpb.cluster_count + 2;
+        let is_lost = |ck: &Checker, c: u32| !ck.owned.get(c) && fat_entry(&ck.fat, t, c) != 0 && fat_entry(&ck.fat, t, c) != bad;
+        let mut pointed = Bitmap::new(end as usize);
+        let mut lost = 0;
+        for c in (2..end).filter(|&c| is_lost(&ck, c)) {
+            lost += 1;
+            let next = fat_entry(&ck.fat, t, c);
+            if next < end { pointed.set(next); }
+        }
+        if lost > 0 {
+            let heads = ((2..end).filter(|&c| is_lost(&ck, c) && !pointed.get(c)).count() as u32).max(1);
+            ck.report.lost_clusters = lost;
+            ck.report.lost_chains = heads;
+            ck.report.problem(alloc::format!("{} lost clusters in {} chains, freed", lost, heads));
+            for c in 2..end {
+                if is_lost(&ck, c) { ck.set(t, c, 0); }
+            }
+        }
+
+        let free = (2..self.bpb.cluster_count + 2).filter(|&c| fat_entry(&ck.fat, t, c) == 0).count() as u32;
+        if t == FatType::Fat32 && self.free_count.is_some_and(|f| f != free) {
+            ck.report.free_count_wrong = true;
+            ck.report.problem(alloc::format!("FSInfo free count {} should be {}", self.free_count.unwrap_or(0), free));
+        }
+
+        if repair {
+            if ck.dirty {
+                for i in 0..self.bpb.nfats as u32 { self.write_bytes(fat_at(i), &ck.fat)?; }
+            }
+            if self.free_count != Some(free) { self.free_count = Some(free); self.fsinfo_dirty = true; }
+            for n in &mut self.nodes { n.chain = None; }
+            self.sync()?;
+            ck.report.repaired = !ck.report.clean();
+        }
+        Ok(ck.report)
+    }
+}
+
+// Cluster size in bytes Microsoft's format uses for a FAT32 volume of `mib` MiB.
+fn fat32_cluster_bytes(mib: u64) -> u32 {
+    match mib { 0..=260 => 512, 261..=8192 => 4096, 8193..=16384 => 8192, 16385..=32768 => 16384, _ => 32768 }
+}
+
+// Zero `count` sectors from `first`, a chunk at a time.
+fn zero_sectors(dev: &mut dyn BlockDevice, first: u64, count: u64, bps: usize) -> Result<(), FsError> {
+    let chunk = alloc::vec![0u8; bps * 128];
+    let mut s = 0;
+    while s < count {
+        let n = (count - s).min(128);
+        block::write_bytes(dev, (first + s) * bps as u64, &chunk[..n as usize * bps])?;
+        s += n;
+    }
+    Ok(())
+}
+
+/// Lay down an empty FAT16 or FAT32 volume over all of `dev`: boot sector, two FATs and an
+/// empty root directory, plus FSInfo and a backup boot sector on FAT32. FAT16 gets the
+/// smallest clusters that keep it under its cluster limit, FAT32 Microsoft's default size.
+/// A device too small or too big for the type is `Invalid`; FAT12 is `Unsupported`.
+pub fn format(dev: &mut dyn BlockDevice, fat_type: FatType, label: &str) -> Result<Bpb, FsError> {
+    let bps = dev.block_size();
+    if !matches!(bps, 512 | 1024 | 2048 | 4096) { return Err(FsError::Unsupported); }
+    let total = u32::try_from(dev.capacity()).map_err(|_| FsError::Invalid)?;
+    let fat32 = match fat_type { FatType::Fat12 => return Err(FsError::Unsupported), t => t == FatType::Fat32 };
+    let (rsv, root_entries, entry_bytes) = if fat32 { (32u32, 0u32, 4u32) } else { (1, 512, 2) };
+    let root_secs = (root_entries * 32).div_ceil(bps as u32);
+    // the FAT has to cover the clusters left over after it; grow it until it does
+    let layout = |spc: u32| -> Option<(u32, u32)> {
+        let mut fatsz = 1u32;
+        loop {
+            let clusters = total.checked_sub(rsv + 2 * fatsz + root_secs)? / spc;
+            let need = ((clusters as u64 + 2) * entry_bytes as u64).div_ceil(bps as u64) as u32;
+            if need <= fatsz { return Some((fatsz, clusters)); }
+            fatsz = need;
+        }
+    };
+    let (spc, fatsz, clusters) = if fat32 {
+        let mut spc = (fat32_cluster_bytes((dev.capacity() * bps as u64) >> 20) / bps as u32).max(1);
+        let (mut fatsz, mut clusters) = layout(spc).ok_or(FsError::Invalid)?;
+        while clusters < 65525 && spc > 1 {
+            spc /= 2;
+            (fatsz, clusters) = layout(spc).ok_or(FsError::Invalid)?;
+        }
+        if clusters < 65525 { return Err(FsError::Invalid); }
+        (spc, fatsz, clusters)
+    } else {
+        let mut spc = 1;
+        loop {
+            let (fatsz, clusters) = layout(spc).ok_or(FsError::Invalid)?;
+            if clusters < 4085 { return Err(FsError::Invalid); }
+            if clusters < 65525 { break (spc, fatsz, clusters); }
+            if spc == 128 { return Err(FsError::Invalid); }
+            spc *= 2;
+        }
+    };
+    let first_data = rsv + 2 * fatsz + root_secs;
+    zero_sectors(dev, 0, first_data as u64 + if fat32 { spc as u64 } else { 0 }, bps)?;
+
+    let mut b = alloc::vec![0u8; bps];
+    b[0..3].copy_from_slice(if fat32 { &[0xEB, 0x58, 0x90] } else { &[0xEB, 0x3C, 0x90] });
+    b[3..11].copy_from_slice(b"WAEMOM  ");
+    b[11..13].copy_from_slice(&(bps as u16).to_le_bytes());
+    b[13] = spc as u8;
+    b[14..16].copy_from_slice(&(rsv as u16).to_le_bytes());
+    b[16] = 2;
+    b[17..19].copy_from_slice(&(root_entries as u16).to_le_bytes());
+    if !fat32 && total < 0x10000 { b[19..21].copy_from_slice(&(total as u16).to_le_bytes()); } else { b[32..36].copy_from_slice(&total.to_le_bytes()); }
+    b[21] = 0xF8; // fixed disk
+    if !fat32 { b[22..24].copy_from_slice(&(fatsz as u16).to_le_bytes()); }
+    b[24..26].copy_from_slice(&63u16.to_le_bytes());
+    b[26..28].copy_from_slice(&255u16.to_le_bytes());
+    if fat32 {
+        b[36..40].copy_from_slice(&fatsz.to_le_bytes());
+        b[44..48].copy_from_slice(&2u32.to_le_bytes()); // root directory cluster
+        b[48..50].copy_from_slice(&1u16.to_le_bytes()); // FSInfo sector
+        b[50..52].copy_from_slice(&6u16.to_le_bytes()); // backup boot sector
+    }
+    let ext = if fat32 { 64 } else { 36 };
+    b[ext] = 0x80;
+    b[ext + 2] = 0x29;
+    b[ext + 3..ext + 7].copy_from_slice(&(crate::rtc::now() as u32).to_le_bytes()); // volume serial
+    let mut name = [b' '; 11];
+    for (i, c) in label.chars().take(11).enumerate() { name[i] = if c == ' ' { b' ' } else { short_char(c).unwrap_or(b'_') }; }
+    b[ext + 7..ext + 18].copy_from_slice(if label.is_empty() { b"NO NAME    " } else { &name });
+    b[ext + 18..ext + 26].copy_from_slice(if fat32 { b"FAT32   " } else { b"FAT16   " });
+    b[510] = 0x55;
+    b[511] = 0xAA;
+    block::write_bytes(dev, 0, &b)?;
+
+    let root_at = if fat32 { first_data } else { rsv + 2 * fatsz } as u64 * bps as u64;
+    if fat32 {
+        block::write_bytes(dev, 6 * bps as u64, &b)?;
+        let mut fsi = alloc::vec![0u8; bps];
+        fsi[0..4].copy_from_slice(&FSINFO_LEAD.to_le_bytes());
+        fsi[484..488].copy_from_slice(&FSINFO_STRUC.to_le_bytes());
+        fsi[488..492].copy_from_slice(&(clusters - 1).to_le_bytes()); // all free but the root's
+        fsi[492..496].copy_from_slice(&3u32.to_le_bytes());
+        fsi[510] = 0x55;
+        fsi[511] = 0xAA;
+        block::write_bytes(dev, bps as u64, &fsi)?;
+        block::write_bytes(dev, 7 * bps as u64, &fsi)?;
+    }
+    // entries 0 and 1 are reserved (media byte, end of chain); on FAT32 the root takes 2
+    let head: Vec<u8> = if fat32 {
+        [0x0FFF_FFF8u32, 0x0FFF_FFFF, 0x0FFF_FFFF].iter().flat_map(|v| v.to_le_bytes()).collect()
+    } else {
+        [0xFFF8u16, 0xFFFF].iter().flat_map(|v| v.to_le_bytes()).collect()
+    };
+    for i in 0..2 { block::write_bytes(dev, (rsv + i * fatsz) as u64 * bps as u64, &head)?; }
+    if !label.is_empty() {
+        let mut e = short_proto(ATTR_VOLUME_ID, 0, 0);
+        e[0..11].copy_from_slice(&name);
+        block::write_bytes(dev, root_at, &e)?;
+    }
+    dev.flush()?;
+    read_bpb(dev).filter(|b| b.fat_type == fat_type).ok_or(FsError::Invalid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::sync::Arc;
+    use alloc::vec;
+    use crate::block::{BlockError, RamDisk};
+
+    // A RAM disk the test can still reach after handing it to a `FatFs`.
+    #[derive(Clone)]
+    struct Disk(Arc<spin::Mutex<RamDisk>>);
+
+    impl BlockDevice for Disk {
+        fn block_size(&self) -> usize { self.0.lock().block_size() }
+        fn capacity(&self) -> u64 { self.0.lock().capacity() }
+        fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> { self.0.lock().read_blocks(lba, buf) }
+        fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> { self.0.lock().write_blocks(lba, buf) }
+    }
+
+    impl Disk {
+        fn poke(&self, at: u64, bytes: &[u8]) {
+            let mut d = self.0.lock();
+            let mut block = vec![0u8; 512];
+            d.read_blocks(at / 512, &mut block).unwrap();
+            block[at as usize % 512..at as usize % 512 + bytes.len()].copy_from_slice(bytes);
+            d.write_blocks(at / 512, &block).unwrap();
+        }
+    }
+
+    // A freshly formatted volume of the smallest size the type allows.
+    fn volume(t: FatType) -> (Disk, FatFs) {
+        let disk = Disk(Arc::new(spin::Mutex::new(RamDisk::new(if t == FatType::Fat32 { 131_072 } else { 32_768 }, 512))));
+        format(&mut disk.clone(), t, "TEST").unwrap();
+        let fs = FatFs::new(Box::new(disk.clone())).unwrap();
+        (disk, fs)
+    }
+
+    fn reopen(disk: &Disk) -> FatFs { FatFs::new(Box::new(disk.clone())).unwrap() }
+
+    fn names(fs: &mut FatFs, dir: Ino) -> Vec<String> {
+        let mut v: Vec<String> = fs.readdir(dir).unwrap().into_iter().map(|e| e.name).collect();
+        v.sort();
+        v
+    }
+
+    fn read_all(fs: &mut FatFs, ino: Ino) -> Vec<u8> {
+        let mut buf = vec![0u8; fs.stat(ino).unwrap().size as usize];
+        let n = fs.read_at(ino, 0, &mut buf).unwrap();
+        buf.truncate(n);
+        buf
+    }
+
+    // byte offset on disk of the 8.3 entry `short` in the root directory
+    fn root_entry(fs: &mut FatFs, short: &[u8; 11]) -> u64 {
+        let e = fs.entries(ROOT).unwrap().into_iter().find(|e| &e.short == short).unwrap();
+        fs.dir_pos(ROOT, e.offset).unwrap()
+    }
+
+    #[test]
+    fn format_then_mount() {
+        for t in [FatType::Fat16, FatType::Fat32] {
+            let (disk, mut fs) = volume(t);
+            assert_eq!(fs.bpb.fat_type, t);
+            assert_eq!(read_bpb(&mut disk.clone()).map(|b| b.fat_type), Some(t));
+            assert!(fs.readdir(ROOT).unwrap().is_empty());
+            let report = fs.check(false).unwrap();
+            assert!(report.clean() && !report.free_count_wrong, "{:?}: {:?}", t, report.problems);
+            let data: Vec<u8> = (0..20_000u32).map(|i| (i % 251) as u8).collect();
+            let f = fs.create(ROOT, "DATA.BIN").unwrap();
+            assert_eq!(fs.write_at(f, 0, &data).unwrap(), data.len());
+            fs.sync().unwrap();
+            let mut fs = reopen(&disk);
+            let f = fs.lookup(ROOT, "DATA.BIN").unwrap();
+            assert_eq!(read_all(&mut fs, f), data);
+            assert!(fs.check(false).unwrap().clean());
+        }
+    }
+
+    #[test]
+    fn format_rejects_bad_sizes() {
+        assert_eq!(format(&mut RamDisk::new(4096, 512), FatType::Fat16, "").err(), Some(FsError::Invalid));
+        assert_eq!(format(&mut RamDisk::new(32_768, 512), FatType::Fat32, "").err(), Some(FsError::Invalid));
+        assert_eq!(format(&mut RamDisk::new(32_768, 512), FatType::Fat12, "").err(), Some(FsError::Unsupported));
+    }
+
+    #[test]
+    fn long_names() {
+        let (disk, mut fs) = volume(FatType::Fat16);
+        let a = fs.create(ROOT, "A rather long file name.txt").unwrap();
+        fs.write_at(a, 0, b"first").unwrap();
+        let b = fs.create(ROOT, "A rather long file name, again.txt").unwrap();
+        fs.write_at(b, 0, b"second").unwrap();
+        fs.create(ROOT, "readme.md").unwrap();
+        fs.create(ROOT, "Mixed.TXT").unwrap();
+        let shorts: Vec<[u8; 11]> = fs.entries(ROOT).unwrap().iter().map(|e| e.short).collect();
+        assert!(shorts.contains(b"ARATHE~1TXT") && shorts.contains(b"ARATHE~2TXT"), "{:?}", shorts);
+        assert!(shorts.contains(b"README  MD "));
+        fs.sync().unwrap();
+
+        let mut fs = reopen(&disk);
+        assert_eq!(names(&mut fs, ROOT), ["A rather long file name, again.txt", "A rather long file name.txt", "Mixed.TXT", "readme.md"]);
+        let b = fs.lookup(ROOT, "a RATHER long FILE name, AGAIN.TXT").unwrap();
+        assert_eq!(read_all(&mut fs, b), b"second");
+        assert_eq!(fs.lookup(ROOT, "ARATHE~1.TXT").unwrap(), fs.lookup(ROOT, "A rather long file name.txt").unwrap());
+        assert_eq!(fs.create(ROOT, "README.MD").err(), Some(FsError::Exists));
+    }
+
+    // slots whose checksum doesn't match the 8.3 entry after them are ignored
+    #[test]
+    fn lfn_checksum_mismatch() {
+        let (disk, mut fs) = volume(FatType::Fat16);
+        fs.create(ROOT, "A rather long file name.txt").unwrap();
+        fs.sync().unwrap();
+        let e = fs.entries(ROOT).unwrap().remove(0);
+        assert_eq!(e.lfn_start + 3 * 32, e.offset);
+        let at = fs.dir_pos(ROOT, e.lfn_start).unwrap();
+        disk.poke(at + 13, &[lfn_checksum(&e.short) ^ 1]);
+        let mut fs = reopen(&disk);
+        assert_eq!(names(&mut fs, ROOT), ["ARATHE~1.TXT"]);
+    }
+
+    #[test]
+    fn rename_files_and_directories() {
+        let (disk, mut fs) = volume(FatType::Fat32);
+        let docs = fs.mkdir(ROOT, "Documents").unwrap();
+        let sub = fs.mkdir(docs, "sub").unwrap();
+        let f = fs.create(sub, "notes.txt").unwrap();
+        fs.write_at(f, 0, b"notes").unwrap();
+        fs.create(ROOT, "old").unwrap();
+
+        // within a directory, changing only case, across directories, and over an existing file
+        fs.rename(sub, "notes.txt", sub, "Notes for the meeting.txt").unwrap();
+        fs.rename(sub, "Notes for the meeting.txt", sub, "NOTES FOR THE MEETING.TXT").unwrap();
+        fs.rename(sub, "NOTES FOR THE MEETING.TXT", ROOT, "old").unwrap();
+        assert!(fs.readdir(sub).unwrap().is_empty());
+        let f = fs.lookup(ROOT, "old").unwrap();
+        assert_eq!(read_all(&mut fs, f), b"notes");
+
+        // a directory moves with its `..`, but never under itself or over another directory
+        fs.rename(docs, "sub", ROOT, "moved").unwrap();
+        let moved = fs.lookup(ROOT, "moved").unwrap();
+        fs.rename(ROOT, "Documents", moved, "x").unwrap();
+        fs.rename(moved, "x", moved, "y").unwrap();
+        let y = fs.lookup(moved, "y").unwrap();
+        assert_eq!(fs.rename(ROOT, "moved", y, "loop").err(), Some(FsError::Invalid));
+        fs.mkdir(ROOT, "other").unwrap();
+        assert_eq!(fs.rename(ROOT, "other", ROOT, "moved").err(), Some(FsError::Exists));
+        fs.sync().unwrap();
+
+        let mut fs = reopen(&disk);
+        assert_eq!(names(&mut fs, ROOT), ["moved", "old", "other"]);
+        let moved = fs.lookup(ROOT, "moved").unwrap();
+        assert_eq!(names(&mut fs, moved), ["y"]);
+        let report = fs.check(false).unwrap();
+        assert!(report.clean(), "{:?}", report.problems);
+    }
+
+    // Damage a volume in every way the checker knows, check it reports each without writing,
+    // then repairs it to a clean state.
+    fn damage_and_repair(t: FatType) {
+        let (disk, mut fs) = volume(t);
+        let cb = fs.bpb.cluster_bytes();
+        let sub = fs.mkdir(ROOT, "sub").unwrap();
+        let a = fs.create(ROOT, "A.TXT").unwrap();
+        let data: Vec<u8> = (0..cb * 3).map(|i| (i % 253) as u8).collect();
+        fs.write_at(a, 0, &data).unwrap();
+        let b = fs.create(ROOT, "B.TXT").unwrap();
+        fs.write_at(b, 0, &vec![1u8; cb * 2]).unwrap();
+        let c = fs.create(sub, "C.TXT").unwrap();
+        fs.write_at(c, 0, b"short").unwrap();
+        fs.sync().unwrap();
+        assert!(fs.check(false).unwrap().clean());
+
+        let first = |fs: &mut FatFs, dir: Ino, name: &str| fs.entries(dir).unwrap().into_iter().find(|e| e.name == name).unwrap().cluster;
+        let a_chain = { let c = first(&mut fs, ROOT, "A.TXT"); fs.chain(c).unwrap() };
+        let b_first = first(&mut fs, ROOT, "B.TXT");
+        let c_first = first(&mut fs, sub, "C.TXT");
+        let eoc = fs.bpb.eoc_mark();
+        // C grows a cluster its size doesn't cover
+        let spare = (2..fs.bpb.cluster_count + 2).rev().find(|&x| fs.fat_get(x).unwrap() == 0).unwrap();
+        fs.fat_set(c_first, spare).unwrap();
+        fs.fat_set(spare, eoc).unwrap();
+        // a lost two-cluster chain
+        let lost: Vec<u32> = (2..spare).rev().filter(|&x| fs.fat_get(x).unwrap() == 0).take(2).collect();
+        fs.fat_set(lost[0], lost[1]).unwrap();
+        fs.fat_set(lost[1], eoc).unwrap();
+        fs.sync().unwrap();
+        // B cross-linked into the middle of A, leaving its own clusters lost; A claims five
+        // clusters; the second FAT disagrees with the first
+        let b_at = root_entry(&mut fs, b"B       TXT");
+        disk.poke(b_at + 20, &((a_chain[1] >> 16) as u16).to_le_bytes());
+        disk.poke(b_at + 26, &(a_chain[1] as u16).to_le_bytes());
+        let a_at = root_entry(&mut fs, b"A       TXT");
+        disk.poke(a_at + 28, &((cb * 5) as u32).to_le_bytes());
+        let fat2 = (fs.bpb.fat_start + fs.bpb.fatsz) as u64 * 512;
+        disk.poke(fat2 + 600, &[0xA5]);
+        drop(fs);
+
+        let before = disk.0.lock().as_slice().to_vec();
+        let mut fs = reopen(&disk);
+        let report = fs.check(false).unwrap();
+        assert_eq!(report.fat_mismatches, 1, "{:?}", report.problems);
+        assert_eq!(report.cross_links, 1);
+        assert_eq!(report.size_mismatches, 2);
+        assert_eq!((report.lost_clusters, report.lost_chains), (4, 2));
+        assert!(disk.0.lock().as_slice() == before, "check without repair wrote");
+
+        assert!(fs.check(true).unwrap().repaired);
+        let mut fs = reopen(&disk);
+        let again = fs.check(false).unwrap();
+        assert!(again.clean() && !again.free_count_wrong, "after repair: {:?}", again.problems);
+        // A keeps its data, B is emptied, C is back to one cluster
+        let a = fs.lookup(ROOT, "A.TXT").unwrap();
+        assert_eq!(read_all(&mut fs, a), data);
+        let b = fs.lookup(ROOT, "B.TXT").unwrap();
+        assert_eq!(fs.stat(b).unwrap().size, 0);
+        let sub = fs.lookup(ROOT, "sub").unwrap();
+        let c = fs.lookup(sub, "C.TXT").unwrap();
+        assert_eq!(read_all(&mut fs, c), b"short");
+        assert_eq!(fs.chain(c_first).unwrap().len(), 1);
+        assert_eq!(fs.fat_get(b_first).unwrap(), 0);
+    }
+
+    #[test]
+    fn check_fat16() { damage_and_repair(FatType::Fat16); }
+
+    #[test]
+    fn check_fat32() { damage_and_repair(FatType::Fat32); }
 }
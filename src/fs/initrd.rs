@@ -0,0 +1,429 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use super::vfs::{FileType, Filesystem, FsError, Ino};
+use crate::compress::{self, Codec};
+
+// Initial ramdisk unpacking: ustar/GNU/pax tar or "newc" cpio archives, as produced by
+// `tar --format=ustar`, `tar --format=gnu` and `cpio -H newc`, either of them possibly gzipped.
+//
+// build.rs compresses single files too, announcing each with pax records ahead of its header:
+// WAEMOM.codec (lz4 or deflate) and WAEMOM.size (unpacked length), plus WAEMOM.sha256 (hex
+// digest of the unpacked data) for every file. Filesystems that can keep files compressed get
+// them that way; see `Filesystem::write_packed`.
+
+/// Largest a gzipped archive may unpack to; anything bigger is refused as `Invalid`.
+const MAX_INFLATED: usize = 64 << 20;
+
+enum Kind<'a> {
+    File(&'a [u8]),
+    Dir,
+    Symlink(String),
+    Hardlink(String), // path of an earlier entry
+    Special,          // device node or fifo; nothing to create them as
+}
+
+struct Entry<'a> {
+    path: String,
+    mode: u16,
+    uid: u32,
+    gid: u32,
+    kind: Kind<'a>,
+    packed: Option<(Codec, u64)>, // how the File data is compressed, and its unpacked size
+    hash: Option<[u8; 32]>,
+}
+
+// WAEMOM.* pax records for the next entry
+#[derive(Default)]
+struct Pax { codec: Option<Codec>, size: Option<u64>, hash: Option<[u8; 32]> }
+
+fn digest(hex: &str) -> Option<[u8; 32]> {
+    if hex.len() != 64 { return None; }
+    let mut out = [0u8; 32];
+    for (i, b) in out.iter_mut().enumerate() { *b = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?; }
+    Some(out)
+}
+
+/// What `unpack` did.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Summary {
+    pub files: usize,
+    pub dirs: usize,
+    pub symlinks: usize,
+    pub skipped: usize, // device nodes, fifos and entries that couldn't be created
+}
+
+fn octal(field: &[u8]) -> Option<u64> {
+    let s = core::str::from_utf8(field).ok()?.trim_matches(|c| c == '\0' || c == ' ');
+    if s.is_empty() { return Some(0); }
+    u64::from_str_radix(s, 8).ok()
+}
+
+fn hex(field: &[u8]) -> Option<u32> { u32::from_str_radix(core::str::from_utf8(field).ok()?, 16).ok() }
+
+fn cstr(field: &[u8]) -> String {
+    let end = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+// Archive paths are relative to the root; drop "./", leading and trailing slashes.
+fn clean(path: &str) -> String {
+    path.split('/').filter(|p| !p.is_empty() && *p != ".").collect::<Vec<_>>().join("/")
+}
+
+fn tar_checksum_ok(h: &[u8]) -> bool {
+    let sum: u64 = h.iter().enumerate().map(|(i, &b)| if (148..156).contains(&i) { b' ' as u64 } else { b as u64 }).sum();
+    octal(&h[148..156]) == Some(sum)
+}
+
+fn parse_tar(data: &[u8]) -> Result<Vec<Entry<'_>>, FsError> {
+    let mut out = Vec::new();
+    let (mut long_name, mut long_link) = (None, None);
+    let mut pax = Pax::default();
+    let mut at = 0;
+    while at + 512 <= data.len() {
+        let h = &data[at..at + 512];
+        if h.iter().all(|&b| b == 0) { break; }
+        if !tar_checksum_ok(h) { return Err(FsError::Invalid); }
+        let size = octal(&h[124..136]).ok_or(FsError::Invalid)? as usize;
+        let body = data.get(at + 512..at + 512 + size).ok_or(FsError::Invalid)?;
+        at += 512 + size.next_multiple_of(512);
+        let mut name = cstr(&h[0..100]);
+        if &h[257..262] == b"ustar" && h[345] != 0 { name = alloc::format!("{}/{}", cstr(&h[345..500]), name); }
+        let name = long_name.take().unwrap_or(name);
+        let link = long_link.take().unwrap_or_else(|| cstr(&h[157..257]));
+        let mode = octal(&h[100..108]).unwrap_or(0o644) as u16 & 0o7777;
+        let (uid, gid) = (octal(&h[108..116]).unwrap_or(0) as u32, octal(&h[116..124]).unwrap_or(0) as u32);
+        let kind = match h[156] {
+            b'0' | 0 | b'7' => Kind::File(body),
+            b'5' => Kind::Dir,
+            b'2' => Kind::Symlink(link),
+            b'1' => Kind::Hardlink(clean(&link)),
+            // GNU long names apply to the next header
+            b'L' => { long_name = Some(cstr(body)); continue; }
+            b'K' => { long_link = Some(cstr(body)); continue; }
+            // pax records: "<len> key=value\n"
+            b'x' => {
+                for rec in body.split(|&b| b == b'\n') {
+                    let Some(kv) = core::str::from_utf8(rec).ok().and_then(|r| r.split_once(' ')).map(|r| r.1) else { continue };
+                    match kv.split_once('=') {
+                        Some(("path", v)) => long_name = Some(v.to_string()),
+                        Some(("linkpath", v)) => long_link = Some(v.to_string()),
+                        Some(("WAEMOM.codec", v)) => pax.codec = Some(Codec::from_name(v).ok_or(FsError::Invalid)?),
+                        Some(("WAEMOM.size", v)) => pax.size = Some(v.parse().map_err(|_| FsError::Invalid)?),
+                        Some(("WAEMOM.sha256", v)) => pax.hash = Some(digest(v).ok_or(FsError::Invalid)?),
+                        _ => {}
+                    }
+                }
+                continue;
+            }
+            b'g' => continue,
+            _ => Kind::Special,
+        };
+        let pax = core::mem::take(&mut pax);
+        let packed = match (pax.codec, pax.size) {
+            (Some(c), Some(n)) => Some((c, n)),
+            (None, _) => None,
+            (Some(_), None) => return Err(FsError::Invalid),
+        };
+        out.push(Entry { path: clean(&name), mode, uid, gid, kind, packed, hash: pax.hash });
+    }
+    Ok(out)
+}
+
+fn parse_cpio(data: &[u8]) -> Result<Vec<Entry<'_>>, FsError> {
+    const S_IFMT: u32 = 0o170000;
+    let mut out = Vec::new();
+    let mut at = 0;
+    while at + 110 <= data.len() {
+        let h = &data[at..at + 110];
+        if &h[0..6] != b"070701" && &h[0..6] != b"070702" { return Err(FsError::Invalid); }
+        let field = |i: usize| hex(&h[6 + i * 8..14 + i * 8]).ok_or(FsError::Invalid);
+        let (mode, size, namesize) = (field(1)?, field(6)? as usize, field(11)? as usize);
+        let name_end = at + 110 + namesize;
+        let name = cstr(data.get(at + 110..name_end).ok_or(FsError::Invalid)?);
+        let start = name_end.next_multiple_of(4);
+        let body = data.get(start..start + size).ok_or(FsError::Invalid)?;
+        at = (start + size).next_multiple_of(4);
+        if name == "TRAILER!!!" { break; }
+        let kind = match mode & S_IFMT {
+            0o100000 => Kind::File(body),
+            0o040000 => Kind::Dir,
+            0o120000 => Kind::Symlink(String::from_utf8_lossy(body).into_owned()),
+            _ => Kind::Special,
+        };
+        out.push(Entry { path: clean(&name), mode: (mode & 0o7777) as u16, uid: field(2)?, gid: field(3)?, kind, packed: None, hash: None });
+    }
+    Ok(out)
+}
+
+// Walk to the parent of `path`, creating directories on the way; returns it with the last component.
+fn parent<'p>(fs: &mut dyn Filesystem, path: &'p str) -> Result<(Ino, &'p str), FsError> {
+    let (dirs, name) = path.rsplit_once('/').unwrap_or(("", path));
+    let mut cur = fs.root();
+    for part in dirs.split('/').filter(|p| !p.is_empty()) {
+        cur = match fs.lookup(cur, part) {
+            Ok(i) if fs.stat(i)?.kind == FileType::Dir => i,
+            Ok(_) => return Err(FsError::NotDir),
+            Err(FsError::NotFound) => fs.mkdir(cur, part)?,
+            Err(e) => return Err(e),
+        };
+    }
+    Ok((cur, name))
+}
+
+fn write_file(fs: &mut dyn Filesystem, dir: Ino, name: &str, data: &[u8], packed: Option<(Codec, u64)>) -> Result<Ino, FsError> {
+    let ino = match fs.lookup(dir, name) {
+        Ok(i) => { fs.truncate(i, 0)?; i }
+        Err(FsError::NotFound) => fs.create(dir, name)?,
+        Err(e) => return Err(e),
+    };
+    match packed {
+        Some((codec, size)) => fs.write_packed(ino, codec, data, size)?,
+        None => { fs.write_at(ino, 0, data)?; }
+    }
+    Ok(ino)
+}
+
+fn read_file(fs: &mut dyn Filesystem, path: &str) -> Result<Vec<u8>, FsError> {
+    let (dir, name) = parent(fs, path)?;
+    let ino = fs.lookup(dir, name)?;
+    let mut buf = alloc::vec![0u8; fs.stat(ino)?.size as usize];
+    let n = fs.read_at(ino, 0, &mut buf)?;
+    buf.truncate(n);
+    Ok(buf)
+}
+
+fn apply(fs: &mut dyn Filesystem, e: &Entry) -> Result<(), FsError> {
+    let (dir, name) = parent(fs, &e.path)?;
+    let ino = match &e.kind {
+        Kind::File(data) => write_file(fs, dir, name, data, e.packed)?,
+        Kind::Hardlink(target) => {
+            // no hard links in the filesystem API; the copy gets the link's contents
+            let data = read_file(fs, target)?;
+            write_file(fs, dir, name, &data, None)?
+        }
+        Kind::Dir => match fs.lookup(dir, name) {
+            Ok(i) => i,
+            Err(FsError::NotFound) => fs.mkdir(dir, name)?,
+            Err(err) => return Err(err),
+        },
+        Kind::Symlink(target) => {
+            // a later archive may repoint a link
+            if fs.lookup(dir, name).is_ok() { fs.unlink(dir, name)?; }
+            fs.symlink(dir, name, target)?
+        }
+        Kind::Special => return Err(FsError::Unsupported),
+    };
+    for r in [fs.chmod(ino, e.mode), fs.chown(ino, e.uid, e.gid)] {
+        match r { Ok(()) | Err(FsError::Unsupported) => {}, Err(err) => return Err(err) }
+    }
+    if let (Kind::File(_), Some(h)) = (&e.kind, e.hash) { fs.set_content_hash(ino, h); }
+    Ok(())
+}
+
+/// Unpack a tar or newc cpio `archive`, optionally gzipped, into `fs`, creating missing parent
+/// directories and overwriting files that already exist. Entries that can't be created are
+/// counted and skipped; a malformed archive stops at the first bad header with `Invalid`.
+pub fn unpack(fs: &mut dyn Filesystem, archive: &[u8]) -> Result<Summary, FsError> {
+    let inflated;
+    let archive = if compress::is_gzip(archive) {
+        inflated = compress::gunzip(archive, MAX_INFLATED).ok_or(FsError::Invalid)?;
+        &inflated[..]
+    } else {
+        archive
+    };
+    let entries = if archive.starts_with(b"07070") { parse_cpio(archive)? } else { parse_tar(archive)? };
+    let mut sum = Summary::default();
+    for e in &entries {
+        if e.path.is_empty() { continue; } // the archive root itself
+        match (apply(fs, e), &e.kind) {
+            (Err(_), _) => sum.skipped += 1,
+            (Ok(()), Kind::Dir) => sum.dirs += 1,
+            (Ok(()), Kind::Symlink(_)) => sum.symlinks += 1,
+            (Ok(()), _) => sum.files += 1,
+        }
+    }
+    Ok(sum)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::format;
+    use crate::fs::memfs::MemFs;
+
+    // ustar header with a correct checksum
+    fn header(name: &str, mode: u32, size: usize, kind: u8, link: &str) -> [u8; 512] {
+        let mut h = [0u8; 512];
+        h[..name.len()].copy_from_slice(name.as_bytes());
+        h[100..107].copy_from_slice(format!("{:07o}", mode).as_bytes());
+        h[108..115].copy_from_slice(b"0001750");
+        h[116..123].copy_from_slice(b"0001750");
+        h[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        h[156] = kind;
+        h[157..157 + link.len()].copy_from_slice(link.as_bytes());
+        h[257..263].copy_from_slice(b"ustar\0");
+        h[263..265].copy_from_slice(b"00");
+        h[148..156].fill(b' ');
+        let sum: u32 = h.iter().map(|&b| b as u32).sum();
+        h[148..155].copy_from_slice(format!("{:06o}\0", sum).as_bytes());
+        h
+    }
+
+    fn tar_entry(out: &mut Vec<u8>, name: &str, mode: u32, kind: u8, link: &str, body: &[u8]) {
+        out.extend_from_slice(&header(name, mode, body.len(), kind, link));
+        out.extend_from_slice(body);
+        out.resize(out.len().next_multiple_of(512), 0);
+    }
+
+    // pax record: "<len> key=value\n", the length counting itself
+    fn pax(records: &[(&str, &str)]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for (k, v) in records {
+            let rest = format!(" {}={}\n", k, v);
+            let mut len = rest.len() + 1;
+            while format!("{}{}", len, rest).len() != len { len += 1; }
+            body.extend_from_slice(format!("{}{}", len, rest).as_bytes());
+        }
+        body
+    }
+
+    fn newc(out: &mut Vec<u8>, name: &str, mode: u32, body: &[u8]) {
+        out.extend_from_slice(format!("070701{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}{:08x}", 1, mode, 0, 0, 1, 0, body.len(), 0, 0, 0, 0, name.len() + 1, 0).as_bytes());
+        out.extend_from_slice(name.as_bytes());
+        out.push(0);
+        out.resize(out.len().next_multiple_of(4), 0);
+        out.extend_from_slice(body);
+        out.resize(out.len().next_multiple_of(4), 0);
+    }
+
+    fn ino(fs: &mut MemFs, path: &str) -> Ino {
+        let mut cur = fs.root();
+        for p in path.split('/') { cur = fs.lookup(cur, p).unwrap_or_else(|e| panic!("{}: {:?}", path, e)); }
+        cur
+    }
+
+    fn sample_tar() -> Vec<u8> {
+        let mut t = Vec::new();
+        tar_entry(&mut t, "./etc/", 0o755, b'5', "", b"");
+        tar_entry(&mut t, "./etc/motd", 0o644, b'0', "", b"hello\n");
+        tar_entry(&mut t, "./etc/shadow", 0o600, b'0', "", b"root:x\n");
+        tar_entry(&mut t, "./etc/motd.old", 0o644, b'1', "./etc/motd", b"");
+        tar_entry(&mut t, "./bin/sh", 0o755, b'2', "/bin/busybox", b"");
+        tar_entry(&mut t, "./dev/null", 0o666, b'3', "", b"");
+        let deep = format!("usr/{}/file", "long-directory-name/".repeat(8));
+        tar_entry(&mut t, "././@LongLink", 0, b'L', "", format!("{}\0", deep).as_bytes());
+        tar_entry(&mut t, "usr/trunc", 0o644, b'0', "", b"deep\n");
+        t.extend_from_slice(&[0u8; 1024]);
+        t
+    }
+
+    #[test]
+    fn ustar_and_gnu() {
+        let mut fs = MemFs::new_dir("/");
+        let sum = unpack(&mut fs, &sample_tar()).unwrap();
+        assert_eq!((sum.files, sum.dirs, sum.symlinks, sum.skipped), (4, 1, 1, 1));
+        assert_eq!(fs.read("/etc/motd").unwrap(), b"hello\n");
+        assert_eq!(fs.read("/etc/motd.old").unwrap(), b"hello\n");
+        assert_eq!(fs.read(&format!("/usr/{}/file", "long-directory-name/".repeat(8))).unwrap(), b"deep\n");
+        let shadow = ino(&mut fs, "etc/shadow");
+        let st = fs.stat(shadow).unwrap();
+        assert_eq!((st.mode, st.uid, st.gid), (0o600, 1000, 1000));
+        let sh = ino(&mut fs, "bin/sh");
+        assert_eq!(fs.readlink(sh).unwrap(), "/bin/busybox");
+        // unpacking again overwrites in place
+        let mut t = Vec::new();
+        tar_entry(&mut t, "etc/motd", 0o644, b'0', "", b"bye\n");
+        tar_entry(&mut t, "bin/sh", 0o777, b'2', "/bin/dash", b"");
+        unpack(&mut fs, &t).unwrap();
+        assert_eq!(fs.read("/etc/motd").unwrap(), b"bye\n");
+        let sh = ino(&mut fs, "bin/sh");
+        assert_eq!(fs.readlink(sh).unwrap(), "/bin/dash");
+    }
+
+    #[test]
+    fn pax_records() {
+        let data: Vec<u8> = b"compress me ".repeat(100);
+        let packed = compress::lz4_compress(&data);
+        let digest = crate::sha256::hex(&crate::sha256::sha256(&data));
+        let mut t = Vec::new();
+        let size = data.len().to_string();
+        tar_entry(&mut t, "PaxHeader", 0, b'x', "", &pax(&[("path", "a/pax name with spaces.txt"), ("WAEMOM.codec", "lz4"), ("WAEMOM.size", &size), ("WAEMOM.sha256", &digest)]));
+        tar_entry(&mut t, "a/short", 0o644, b'0', "", &packed);
+        let mut fs = MemFs::new_dir("/");
+        assert_eq!(unpack(&mut fs, &t).unwrap().files, 1);
+        assert_eq!(fs.read("/a/pax name with spaces.txt").unwrap(), data);
+        let f = ino(&mut fs, "a/pax name with spaces.txt");
+        assert_eq!(fs.content_hash(f), Some(crate::sha256::sha256(&data)));
+
+        // a codec without a size, or one nobody knows, is a broken archive
+        for records in [&[("WAEMOM.codec", "lz4")][..], &[("WAEMOM.codec", "zstd"), ("WAEMOM.size", "1")][..], &[("WAEMOM.sha256", "abc")][..]] {
+            let mut t = Vec::new();
+            tar_entry(&mut t, "PaxHeader", 0, b'x', "", &pax(records));
+            tar_entry(&mut t, "f", 0o644, b'0', "", b"x");
+            assert_eq!(unpack(&mut MemFs::new_dir("/"), &t).err(), Some(FsError::Invalid), "{:?}", records);
+        }
+    }
+
+    #[test]
+    fn newc_cpio() {
+        let mut c = Vec::new();
+        newc(&mut c, ".", 0o040755, b"");
+        newc(&mut c, "sbin", 0o040700, b"");
+        newc(&mut c, "sbin/init", 0o100755, b"#!init");
+        newc(&mut c, "init", 0o120777, b"sbin/init");
+        newc(&mut c, "dev/console", 0o020600, b"");
+        newc(&mut c, "TRAILER!!!", 0, b"");
+        let mut fs = MemFs::new_dir("/");
+        let sum = unpack(&mut fs, &c).unwrap();
+        assert_eq!((sum.files, sum.dirs, sum.symlinks, sum.skipped), (1, 1, 1, 1));
+        assert_eq!(fs.read("/sbin/init").unwrap(), b"#!init");
+        let sbin = ino(&mut fs, "sbin");
+        assert_eq!(fs.stat(sbin).unwrap().mode, 0o700);
+        let init = ino(&mut fs, "init");
+        assert_eq!(fs.readlink(init).unwrap(), "sbin/init");
+
+        let mut bad = c.clone();
+        bad[112 + 5] = b'9'; // second header's magic
+        assert_eq!(unpack(&mut MemFs::new_dir("/"), &bad).err(), Some(FsError::Invalid));
+        let body = c.windows(6).position(|w| w == b"#!init").unwrap();
+        assert_eq!(unpack(&mut MemFs::new_dir("/"), &c[..body + 3]).err(), Some(FsError::Invalid));
+    }
+
+    #[test]
+    fn gzipped_archive() {
+        // gzip around a single stored DEFLATE block
+        let tar = sample_tar();
+        let mut gz = alloc::vec![0x1F, 0x8B, 8, 0, 0, 0, 0, 0, 0, 3];
+        for (i, chunk) in tar.chunks(0xFFFF).enumerate() {
+            gz.push((i == tar.len().div_ceil(0xFFFF) - 1) as u8);
+            gz.extend_from_slice(&(chunk.len() as u16).to_le_bytes());
+            gz.extend_from_slice(&(!(chunk.len() as u16)).to_le_bytes());
+            gz.extend_from_slice(chunk);
+        }
+        gz.extend_from_slice(&compress::crc32(&tar).to_le_bytes());
+        gz.extend_from_slice(&(tar.len() as u32).to_le_bytes());
+        let mut fs = MemFs::new_dir("/");
+        assert_eq!(unpack(&mut fs, &gz).unwrap().files, 4);
+        assert_eq!(fs.read("/etc/motd").unwrap(), b"hello\n");
+        let n = gz.len();
+        gz[n - 8] ^= 1;
+        assert_eq!(unpack(&mut MemFs::new_dir("/"), &gz).err(), Some(FsError::Invalid));
+    }
+
+    #[test]
+    fn corrupt_tar() {
+        let t = sample_tar();
+        let mut bad = t.clone();
+        bad[512 + 10] ^= 1; // a name byte under the second header's checksum
+        assert_eq!(unpack(&mut MemFs::new_dir("/"), &bad).err(), Some(FsError::Invalid));
+        // a body running past the end
+        let mut short = Vec::new();
+        tar_entry(&mut short, "big", 0o644, b'0', "", &[1u8; 2000]);
+        short.truncate(1024);
+        assert_eq!(unpack(&mut MemFs::new_dir("/"), &short).err(), Some(FsError::Invalid));
+        assert_eq!(unpack(&mut MemFs::new_dir("/"), &[1u8; 1024]).err(), Some(FsError::Invalid));
+        // nothing but the end marker is an empty archive
+        assert_eq!(unpack(&mut MemFs::new_dir("/"), &[0u8; 1024]).unwrap().files, 0);
+    }
+}
@@ -1,136 +1,382 @@
 use alloc::vec::Vec;
-use alloc::string::String;
-use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use super::vfs::{DirEntry, FileType, Filesystem, FsError, Ino, Stat};
+use crate::compress::Codec;
 
 #[derive(Clone)]
 pub enum NodeKind {
-    File(Vec<u8>),
-    Dir(Vec<Box<Node>>),
+    File(Arc<Vec<u8>>), // shared with outstanding views, copied on write
+    Packed(Packed),     // unpacked on read, and for good by the first write
+    Dir(Vec<Ino>),
+    Symlink(String),
+}
+
+/// File data kept compressed, as it came out of the initrd.
+#[derive(Clone)]
+pub struct Packed {
+    pub codec: Codec,
+    pub data: Arc<Vec<u8>>,
+    pub size: usize, // unpacked
 }
 
 #[derive(Clone)]
 pub struct Node {
     pub name: String,
+    pub parent: Ino,
     pub kind: NodeKind,
+    pub mode: u16,
+    pub uid: u32,
+    pub gid: u32,
+    pub ctime: u64, // created, seconds since the Unix epoch
+    pub mtime: u64,
+    pub hash: Option<[u8; 32]>, // SHA-256 of the data: shipped with it or worked out on demand; dropped by writes
 }
 
+/// In-memory filesystem; inode numbers index straight into `nodes`, the root is 0.
+/// Removed nodes leave a `None` behind so a stale inode number never names a newer node.
 pub struct MemFs {
-    root: Node,
+    nodes: Vec<Option<Node>>,
+    unpacked: Option<(Ino, Arc<Vec<u8>>)>, // the last packed file read, so sequential reads unpack once
 }
 
+const ROOT: Ino = 0;
+
+/// Largest a file may grow to, by writing past its end or truncating it upwards.
+pub const MAX_FILE: u64 = 16 << 20;
+
 impl MemFs {
     pub fn new_dir(name: &str) -> Self {
-        Self { root: Node { name: name.to_string(), kind: NodeKind::Dir(Vec::new()) } }
-    }
-
-    pub fn add_file(&mut self, path: &str, data: &[u8]) {
-        let mut parts = path.split('/').filter(|p| !p.is_empty()).peekable();
-        let mut cur = &mut self.root;
-        while let Some(part) = parts.next() {
-            let last = parts.peek().is_none();
-            match &mut cur.kind {
-                NodeKind::Dir(children) => {
-                    if last {
-                        children.push(Box::new(Node { name: part.to_string(), kind: NodeKind::File(data.to_vec()) }));
-                    } else {
-                        let mut found: Option<&mut Node> = None;
-                        for ch in children.iter_mut() {
-                            if ch.name == part {
-                                // SAFETY: limited to this scope
-                                let p: *mut Node = ch.as_mut();
-                                found = Some(unsafe { &mut *p });
-                                break;
-                            }
-                        }
-                        cur = match found {
-                            Some(dir @ Node { kind: NodeKind::Dir(_), .. }) => dir,
-                            _ => {
-                                children.push(Box::new(Node { name: part.to_string(), kind: NodeKind::Dir(Vec::new()) }));
-                                children.last_mut().unwrap()
-                            }
-                        };
-                    }
-                }
-                _ => return,
-            }
+        let now = crate::rtc::now();
+        let root = Node { name: name.to_string(), parent: ROOT, kind: NodeKind::Dir(Vec::new()), mode: 0o755, uid: 0, gid: 0, ctime: now, mtime: now, hash: None };
+        Self { nodes: alloc::vec![Some(root)], unpacked: None }
+    }
+
+    /// Create or overwrite the file at `path`, making parent directories as needed.
+    pub fn add_file(&mut self, path: &str, data: &[u8]) -> Result<Ino, FsError> {
+        let (dir, name) = self.parent_of(path, true)?;
+        let ino = match self.child(dir, name) {
+            Some(i) => { self.truncate(i, 0)?; i }
+            None => self.create(dir, name)?,
+        };
+        self.write_at(ino, 0, data)?;
+        Ok(ino)
+    }
+
+    pub fn read(&mut self, path: &str) -> Result<Vec<u8>, FsError> {
+        let ino = self.find(path)?;
+        Ok(self.contents(ino)?.to_vec())
+    }
+
+    pub fn list(&self, path: &str) -> Result<Vec<String>, FsError> {
+        let ino = self.find(path)?;
+        Ok(self.children(ino)?.iter().filter_map(|c| self.node(*c).ok()).map(|c| c.name.clone()).collect())
+    }
+
+    /// Append to the file at `path`, creating it if needed.
+    pub fn append(&mut self, path: &str, data: &[u8]) -> Result<(), FsError> {
+        let (dir, name) = self.parent_of(path, true)?;
+        let ino = match self.child(dir, name) { Some(i) => i, None => self.create(dir, name)? };
+        let end = self.stat(ino)?.size;
+        self.write_at(ino, end, data).map(|_| ())
+    }
+
+    pub fn stat_path(&mut self, path: &str) -> Result<Stat, FsError> {
+        let ino = self.find(path)?;
+        self.stat(ino)
+    }
+
+    // Symlinks aren't followed here; that's the VFS's job.
+    fn find(&self, path: &str) -> Result<Ino, FsError> {
+        let mut cur = ROOT;
+        for part in path.split('/').filter(|p| !p.is_empty()) {
+            self.children(cur)?;
+            cur = self.child(cur, part).ok_or(FsError::NotFound)?;
         }
+        Ok(cur)
     }
 
-    pub fn read(&mut self, path: &str) -> Result<Vec<u8>, ()> {
-        match self.find(path) {
-            Some(Node { kind: NodeKind::File(data), .. }) => Ok(data.clone()),
-            _ => Err(())
+    // The directory holding `path` and the final name, optionally creating missing directories.
+    fn parent_of<'p>(&mut self, path: &'p str, create: bool) -> Result<(Ino, &'p str), FsError> {
+        let path = path.trim_end_matches('/');
+        let (dirs, name) = path.rsplit_once('/').unwrap_or(("", path));
+        if name.is_empty() { return Err(FsError::Invalid); }
+        let mut cur = ROOT;
+        for part in dirs.split('/').filter(|p| !p.is_empty()) {
+            cur = match self.child(cur, part) {
+                Some(i) => i,
+                None if create => self.mkdir(cur, part)?,
+                None => return Err(FsError::NotFound),
+            };
         }
+        self.children(cur)?;
+        Ok((cur, name))
+    }
+
+    fn node(&self, ino: Ino) -> Result<&Node, FsError> {
+        self.nodes.get(ino as usize).and_then(|n| n.as_ref()).ok_or(FsError::NotFound)
+    }
+
+    fn node_mut(&mut self, ino: Ino) -> Result<&mut Node, FsError> {
+        self.nodes.get_mut(ino as usize).and_then(|n| n.as_mut()).ok_or(FsError::NotFound)
     }
 
-    pub fn list(&self, path: &str) -> Result<Vec<String>, ()> {
-        match self.find_ref(path) {
-            Some(Node { kind: NodeKind::Dir(children), .. }) => Ok(children.iter().map(|c| c.name.clone()).collect()),
-            _ => Err(())
+    fn children(&self, dir: Ino) -> Result<&Vec<Ino>, FsError> {
+        match &self.node(dir)?.kind { NodeKind::Dir(c) => Ok(c), _ => Err(FsError::NotDir) }
+    }
+
+    fn children_mut(&mut self, dir: Ino) -> Result<&mut Vec<Ino>, FsError> {
+        match &mut self.node_mut(dir)?.kind { NodeKind::Dir(c) => Ok(c), _ => Err(FsError::NotDir) }
+    }
+
+    fn child(&self, dir: Ino, name: &str) -> Option<Ino> {
+        self.children(dir).ok()?.iter().copied().find(|c| self.node(*c).is_ok_and(|n| n.name == name))
+    }
+
+    fn touch(&mut self, ino: Ino) {
+        if let Ok(n) = self.node_mut(ino) { n.mtime = crate::rtc::now(); }
+    }
+
+    fn insert(&mut self, dir: Ino, name: &str, kind: NodeKind) -> Result<Ino, FsError> {
+        if name.is_empty() || name.contains('/') || name == "." || name == ".." { return Err(FsError::Invalid); }
+        self.children(dir)?;
+        if self.child(dir, name).is_some() { return Err(FsError::Exists); }
+        let mode = match kind { NodeKind::File(_) | NodeKind::Packed(_) => 0o644, NodeKind::Dir(_) => 0o755, NodeKind::Symlink(_) => 0o777 };
+        let now = crate::rtc::now();
+        self.nodes.push(Some(Node { name: name.to_string(), parent: dir, kind, mode, uid: 0, gid: 0, ctime: now, mtime: now, hash: None }));
+        let ino = (self.nodes.len() - 1) as Ino;
+        self.children_mut(dir)?.push(ino);
+        self.touch(dir);
+        Ok(ino)
+    }
+
+    // Unhook `ino` from `dir` and drop it.
+    fn remove(&mut self, dir: Ino, ino: Ino) -> Result<(), FsError> {
+        self.children_mut(dir)?.retain(|&c| c != ino);
+        self.nodes[ino as usize] = None;
+        if self.unpacked.as_ref().is_some_and(|u| u.0 == ino) { self.unpacked = None; }
+        self.touch(dir);
+        Ok(())
+    }
+
+    // A file's data, unpacking it if need be. Packed data that won't unpack is an I/O error.
+    fn contents(&mut self, ino: Ino) -> Result<Arc<Vec<u8>>, FsError> {
+        let p = match &self.node(ino)?.kind {
+            NodeKind::File(d) => return Ok(d.clone()),
+            NodeKind::Packed(p) => p,
+            NodeKind::Dir(_) => return Err(FsError::IsDir),
+            NodeKind::Symlink(_) => return Err(FsError::Invalid),
+        };
+        if let Some((i, d)) = &self.unpacked {
+            if *i == ino { return Ok(d.clone()); }
         }
+        let data = Arc::new(crate::compress::decompress(p.codec, &p.data, p.size).ok_or(FsError::Io)?);
+        self.unpacked = Some((ino, data.clone()));
+        Ok(data)
     }
 
-    pub fn write(&mut self, path: &str, data: &[u8]) -> Result<(), ()> {
-        // create or overwrite
-        let mut parts = path.split('/').filter(|p| !p.is_empty()).peekable();
-        let mut cur = &mut self.root;
-        while let Some(part) = parts.next() {
-            let last = parts.peek().is_none();
-            match &mut cur.kind {
-                NodeKind::Dir(children) => {
-                    if last {
-                        // find existing
-                        for ch in children.iter_mut() {
-                            if ch.name == part {
-                                ch.kind = NodeKind::File(data.to_vec());
-                                return Ok(());
-                            }
-                        }
-                        children.push(Box::new(Node { name: part.to_string(), kind: NodeKind::File(data.to_vec()) }));
-                        return Ok(());
-                    } else {
-                        let mut next_idx = None;
-                        for (i, ch) in children.iter().enumerate() { if ch.name == part { next_idx = Some(i); break; } }
-                        if let Some(i) = next_idx { cur = children.get_mut(i).unwrap().as_mut(); }
-                        else {
-                            children.push(Box::new(Node { name: part.to_string(), kind: NodeKind::Dir(Vec::new()) }));
-                            cur = children.last_mut().unwrap();
-                        }
-                    }
-                }
-                _ => return Err(()),
-            }
+    // For writing: a packed file is unpacked for good, and the hash no longer holds.
+    fn file_mut(&mut self, ino: Ino) -> Result<&mut Vec<u8>, FsError> {
+        if let NodeKind::Packed(_) = self.node(ino)?.kind {
+            let data = self.contents(ino)?;
+            self.unpacked = None;
+            self.node_mut(ino)?.kind = NodeKind::File(data);
+        }
+        let n = self.node_mut(ino)?;
+        n.hash = None;
+        match &mut n.kind {
+            NodeKind::File(d) => Ok(Arc::make_mut(d)),
+            NodeKind::Dir(_) => Err(FsError::IsDir),
+            _ => Err(FsError::Invalid),
         }
-        Ok(())
     }
+}
 
-    fn find_ref(&self, path: &str) -> Option<&Node> {
-        let mut cur = &self.root;
-        for part in path.split('/').filter(|p| !p.is_empty()) {
-            match &cur.kind {
-                NodeKind::Dir(children) => {
-                    let mut next = None;
-                    for ch in children.iter() { if ch.name == part { next = Some(ch.as_ref()); break; } }
-                    cur = next?;
-                }
-                _ => return None,
-            }
+impl Filesystem for MemFs {
+    fn name(&self) -> &'static str { "memfs" }
+
+    fn root(&self) -> Ino { ROOT }
+
+    fn lookup(&mut self, dir: Ino, name: &str) -> Result<Ino, FsError> {
+        self.children(dir)?;
+        self.child(dir, name).ok_or(FsError::NotFound)
+    }
+
+    fn stat(&mut self, ino: Ino) -> Result<Stat, FsError> {
+        let node = self.node(ino)?;
+        let (kind, size) = match &node.kind {
+            NodeKind::File(d) => (FileType::File, d.len() as u64),
+            NodeKind::Packed(p) => (FileType::File, p.size as u64),
+            NodeKind::Dir(c) => (FileType::Dir, c.len() as u64),
+            NodeKind::Symlink(t) => (FileType::Symlink, t.len() as u64),
+        };
+        Ok(Stat { ino, kind, size, mode: node.mode, uid: node.uid, gid: node.gid, ctime: node.ctime, mtime: node.mtime })
+    }
+
+    fn read_at(&mut self, ino: Ino, off: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let data = self.contents(ino)?;
+        let start = (off as usize).min(data.len());
+        let n = buf.len().min(data.len() - start);
+        buf[..n].copy_from_slice(&data[start..start + n]);
+        Ok(n)
+    }
+
+    fn readdir(&mut self, dir: Ino) -> Result<Vec<DirEntry>, FsError> {
+        let kids = self.children(dir)?.clone();
+        let mut out = Vec::with_capacity(kids.len());
+        for ino in kids {
+            let st = self.stat(ino)?;
+            out.push(DirEntry { name: self.node(ino)?.name.clone(), ino, kind: st.kind });
         }
-        Some(cur)
+        Ok(out)
     }
 
-    fn find(&mut self, path: &str) -> Option<&mut Node> {
-        let mut cur = &mut self.root;
-        for part in path.split('/').filter(|p| !p.is_empty()) {
-            match &mut cur.kind {
-                NodeKind::Dir(children) => {
-                    let mut idx = None;
-                    for (i, ch) in children.iter().enumerate() { if ch.name == part { idx = Some(i); break; } }
-                    cur = children.get_mut(idx?)?.as_mut();
-                }
-                _ => return None,
+    fn write_at(&mut self, ino: Ino, off: u64, data: &[u8]) -> Result<usize, FsError> {
+        let end = off.checked_add(data.len() as u64).ok_or(FsError::Invalid)?;
+        if end > MAX_FILE { return Err(FsError::NoSpace); }
+        let (off, end) = (off as usize, end as usize);
+        let file = self.file_mut(ino)?;
+        if file.len() < end { file.resize(end, 0); }
+        file[off..end].copy_from_slice(data);
+        self.touch(ino);
+        Ok(data.len())
+    }
+
+    fn truncate(&mut self, ino: Ino, size: u64) -> Result<(), FsError> {
+        if size > MAX_FILE { return Err(FsError::NoSpace); }
+        self.file_mut(ino)?.resize(size as usize, 0);
+        self.touch(ino);
+        Ok(())
+    }
+
+    fn create(&mut self, dir: Ino, name: &str) -> Result<Ino, FsError> { self.insert(dir, name, NodeKind::File(Arc::new(Vec::new()))) }
+
+    fn mkdir(&mut self, dir: Ino, name: &str) -> Result<Ino, FsError> { self.insert(dir, name, NodeKind::Dir(Vec::new())) }
+
+    fn symlink(&mut self, dir: Ino, name: &str, target: &str) -> Result<Ino, FsError> {
+        self.insert(dir, name, NodeKind::Symlink(target.to_string()))
+    }
+
+    fn readlink(&mut self, ino: Ino) -> Result<String, FsError> {
+        match &self.node(ino)?.kind { NodeKind::Symlink(t) => Ok(t.clone()), _ => Err(FsError::Invalid) }
+    }
+
+    fn unlink(&mut self, dir: Ino, name: &str) -> Result<(), FsError> {
+        let ino = self.lookup(dir, name)?;
+        if matches!(self.node(ino)?.kind, NodeKind::Dir(_)) { return Err(FsError::IsDir); }
+        self.remove(dir, ino)
+    }
+
+    fn rmdir(&mut self, dir: Ino, name: &str) -> Result<(), FsError> {
+        let ino = self.lookup(dir, name)?;
+        if !self.children(ino)?.is_empty() { return Err(FsError::NotEmpty); }
+        self.remove(dir, ino)
+    }
+
+    fn rename(&mut self, odir: Ino, oname: &str, ndir: Ino, nname: &str) -> Result<(), FsError> {
+        let ino = self.lookup(odir, oname)?;
+        if nname.is_empty() || nname.contains('/') || nname == "." || nname == ".." { return Err(FsError::Invalid); }
+        self.children(ndir)?;
+        // refuse to move a directory underneath itself
+        let mut p = ndir;
+        loop {
+            if p == ino { return Err(FsError::Invalid); }
+            if p == ROOT { break; }
+            p = self.node(p)?.parent;
+        }
+        if let Some(t) = self.child(ndir, nname) {
+            if t == ino { return Ok(()); }
+            let (src_dir, dst_dir) = (matches!(self.node(ino)?.kind, NodeKind::Dir(_)), matches!(self.node(t)?.kind, NodeKind::Dir(_)));
+            match (src_dir, dst_dir) {
+                (false, true) => return Err(FsError::IsDir),
+                (true, false) => return Err(FsError::NotDir),
+                (true, true) => self.rmdir(ndir, nname)?,
+                (false, false) => self.unlink(ndir, nname)?,
             }
         }
-        Some(cur)
+        self.children_mut(odir)?.retain(|&c| c != ino);
+        self.children_mut(ndir)?.push(ino);
+        let n = self.node_mut(ino)?;
+        n.name = nname.to_string();
+        n.parent = ndir;
+        self.touch(odir);
+        self.touch(ndir);
+        Ok(())
+    }
+
+    fn chmod(&mut self, ino: Ino, mode: u16) -> Result<(), FsError> {
+        self.node_mut(ino)?.mode = mode & 0o7777;
+        Ok(())
+    }
+
+    fn chown(&mut self, ino: Ino, uid: u32, gid: u32) -> Result<(), FsError> {
+        let n = self.node_mut(ino)?;
+        (n.uid, n.gid) = (uid, gid);
+        Ok(())
+    }
+
+    fn view(&mut self, ino: Ino) -> Option<Arc<Vec<u8>>> { self.contents(ino).ok() }
+
+    // Kept packed; `contents` unpacks on demand.
+    fn write_packed(&mut self, ino: Ino, codec: Codec, data: &[u8], size: u64) -> Result<(), FsError> {
+        if size > MAX_FILE { return Err(FsError::NoSpace); }
+        self.file_mut(ino)?;
+        self.node_mut(ino)?.kind = NodeKind::Packed(Packed { codec, data: Arc::new(data.to_vec()), size: size as usize });
+        self.touch(ino);
+        Ok(())
+    }
+
+    fn content_hash(&mut self, ino: Ino) -> Option<[u8; 32]> {
+        if let Some(h) = self.node(ino).ok()?.hash { return Some(h); }
+        let h = crate::sha256::sha256(&self.contents(ino).ok()?);
+        self.node_mut(ino).ok()?.hash = Some(h);
+        Some(h)
+    }
+
+    fn set_content_hash(&mut self, ino: Ino, hash: [u8; 32]) {
+        if let Ok(n) = self.node_mut(ino) { n.hash = Some(hash); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(fs: &mut MemFs) -> Ino { fs.create(ROOT, "f").unwrap() }
+
+    #[test]
+    fn write_past_the_end_fills_with_zeros() {
+        let mut fs = MemFs::new_dir("/");
+        let f = file(&mut fs);
+        assert_eq!(fs.write_at(f, 4, b"xy").unwrap(), 2);
+        let mut buf = [0xFFu8; 8];
+        assert_eq!(fs.read_at(f, 0, &mut buf).unwrap(), 6);
+        assert_eq!(&buf[..6], b"\0\0\0\0xy");
+        assert_eq!(fs.write_at(f, MAX_FILE - 2, b"zz").unwrap(), 2);
+        assert_eq!(fs.stat(f).unwrap().size, MAX_FILE);
+    }
+
+    // an offset from a seek far past the end is refused instead of overflowing or eating memory
+    #[test]
+    fn far_writes_are_refused() {
+        let mut fs = MemFs::new_dir("/");
+        let f = file(&mut fs);
+        fs.write_at(f, 0, b"keep").unwrap();
+        assert_eq!(fs.write_at(f, MAX_FILE - 1, b"zz"), Err(FsError::NoSpace));
+        assert_eq!(fs.write_at(f, 1 << 40, b"z"), Err(FsError::NoSpace));
+        assert_eq!(fs.write_at(f, u64::MAX, b"z"), Err(FsError::Invalid));
+        assert_eq!(fs.stat(f).unwrap().size, 4);
+    }
+
+    #[test]
+    fn huge_truncate_is_refused() {
+        let mut fs = MemFs::new_dir("/");
+        let f = file(&mut fs);
+        fs.write_at(f, 0, b"keep").unwrap();
+        assert_eq!(fs.truncate(f, u64::MAX), Err(FsError::NoSpace));
+        assert_eq!(fs.truncate(f, MAX_FILE + 1), Err(FsError::NoSpace));
+        assert_eq!(fs.stat(f).unwrap().size, 4);
+        fs.truncate(f, 2).unwrap();
+        assert_eq!(fs.stat(f).unwrap().size, 2);
     }
 }
@@ -1,38 +1,184 @@
+pub mod vfs;
 pub mod memfs;
 pub mod fat;
+pub mod ext2;
+pub mod initrd;
+pub mod procfs;
+pub mod devfs;
+pub mod snapshot;
+pub mod watch;
 
 use memfs::MemFs;
-use lazy_static::lazy_static;
-use spin::Mutex;
+use vfs::Filesystem;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
 
-lazy_static! {
-    static ref FS: Mutex<Option<MemFs>> = Mutex::new(None);
-}
+pub use vfs::{FsError, FileType, FileView, SeekFrom, Stat, DirEntry, O_RDONLY, O_WRONLY, O_RDWR, O_CREAT, O_TRUNC, O_APPEND};
 
-pub fn init() {
+/// Build the root memfs from the initrd embedded at build time (initrd/ plus the userlib
+/// programs, see build.rs), then unpack `ramdisk`, if the bootloader passed one, over it.
+pub fn init(ramdisk: Option<&[u8]>) {
     let mut fs = MemFs::new_dir("/");
-    fs.add_file("/README.txt", b"waemom OS\nThis is a tiny hobby kernel with a toy window system.\n");
-    fs.add_file("/etc/motd", b"Welcome to waemom!\nEnjoy your stay.\n");
-    // Include a tiny ELF64 header for Linux inspection
-    let hello: &'static [u8] = include_bytes!("../../assets/bin/hello");
-    fs.add_file("/bin/hello", hello);
-    // Include default lock file and web samples
-    let lock: &'static [u8] = include_bytes!("../../assets/waemon.lock");
-    fs.add_file("/waemon.lock", lock);
-    fs.add_file("/www/index.html", include_bytes!("../../assets/www/index.html"));
-    fs.add_file("/www/app.js", include_bytes!("../../assets/www/app.js"));
-    fs.add_file("/www/styles.css", include_bytes!("../../assets/www/styles.css"));
-    *FS.lock() = Some(fs);
+    for (what, archive) in [("built-in", Some(INITRD)), ("ramdisk", ramdisk)] {
+        let Some(archive) = archive else { continue };
+        match initrd::unpack(&mut fs, archive) {
+            Ok(s) => crate::serial_println!("initrd: {} {} files, {} dirs, {} symlinks, {} skipped", what, s.files, s.dirs, s.symlinks, s.skipped),
+            Err(e) => crate::serial_println!("initrd: {} archive unreadable: {:?}", what, e),
+        }
+    }
+    // block devices get mounted under /mnt by automount
+    let _ = fs.mkdir(fs.root(), "mnt");
+    let _ = fs.mkdir(fs.root(), "proc");
+    let _ = fs.mkdir(fs.root(), "dev");
+    let _ = vfs::mount("/", Box::new(fs));
+    let _ = vfs::mount("/proc", Box::new(procfs::ProcFs::new()));
+    let _ = vfs::mount("/dev", Box::new(devfs::DevFs));
+}
+
+static INITRD: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.tar"));
+
+/// Copy a whole file into a new buffer; prefer `view` when the data is only read.
+pub fn read(path: &str) -> Result<Vec<u8>, FsError> {
+    vfs::read_file(path)
+}
+
+/// Borrow a whole file without copying it (memfs) or read it once (disk filesystems).
+pub fn view(path: &str) -> Result<FileView, FsError> { vfs::view_file(path) }
+
+pub fn list(path: &str) -> Result<Vec<String>, FsError> {
+    Ok(vfs::read_dir(path)?.into_iter().map(|e| e.name).collect())
+}
+
+/// Create or overwrite `path`, making any missing parent directories.
+pub fn write(path: &str, data: &[u8]) -> Result<(), FsError> {
+    if let Some(i) = path.trim_end_matches('/').rfind('/') { vfs::mkdir_all(&path[..i])?; }
+    vfs::write_file(path, data)
+}
+
+/// Append to `path`, creating it if it is missing.
+pub fn append(path: &str, data: &[u8]) -> Result<(), FsError> { vfs::append_file(path, data) }
+
+pub fn truncate(path: &str, size: u64) -> Result<(), FsError> { vfs::truncate(path, size) }
+
+pub fn mkdir(path: &str) -> Result<(), FsError> { vfs::mkdir(path) }
+
+/// Set permission bits; the owner or root only.
+pub fn chmod(path: &str, mode: u16) -> Result<(), FsError> { vfs::chmod(path, mode) }
+
+/// Change owner and group; root only.
+pub fn chown(path: &str, uid: u32, gid: u32) -> Result<(), FsError> { vfs::chown(path, uid, gid) }
+
+/// Remove a file or symlink; directories need `remove_dir`.
+pub fn remove(path: &str) -> Result<(), FsError> { vfs::unlink(path) }
+
+/// Remove an empty directory.
+pub fn remove_dir(path: &str) -> Result<(), FsError> { vfs::rmdir(path) }
+
+/// Move or rename within one filesystem, replacing a file (or empty directory) at `to`.
+pub fn rename(from: &str, to: &str) -> Result<(), FsError> { vfs::rename(from, to) }
+
+/// Mount every FAT or ext2 volume on a registered block device at `/mnt/<device>`; returns the mount paths.
+pub fn automount() -> Vec<String> {
+    let mut out = Vec::new();
+    for name in crate::block::devices() {
+        let Some(mut dev) = crate::block::get(&name) else { continue };
+        let mount: fn(&str, Box<dyn crate::block::BlockDevice + Send>) -> Result<(), FsError> =
+            if fat::read_bpb(&mut dev).is_some() { mount_fat }
+            else if ext2::read_superblock(&mut dev).is_some() { mount_ext2 }
+            else { continue };
+        let path = alloc::format!("/mnt/{}", name);
+        if vfs::mkdir_all(&path).is_ok() && mount(&path, Box::new(dev)).is_ok() { out.push(path); }
+    }
+    out
+}
+
+// Whether checking `fs` leaves at least half the heap that's still free; nothing is ever given
+// back, so a check the size of the heap would end in an allocation failure.
+fn check_fits(fs: &fat::FatFs) -> bool {
+    let (used, size) = crate::heap::stats();
+    fs.check_memory() <= (size - used) / 2
 }
 
-pub fn read(path: &str) -> Result<Vec<u8>, ()> {
-    FS.lock().as_mut().ok_or(())?.read(path)
+/// Mount the FAT volume on `dev` at `path`, checking and repairing it first when that fits in
+/// memory; a volume the check can't get through is mounted as it is.
+pub fn mount_fat(path: &str, dev: Box<dyn crate::block::BlockDevice + Send>) -> Result<(), FsError> {
+    let mut fs = fat::FatFs::new(dev).ok_or(FsError::Invalid)?;
+    if !check_fits(&fs) {
+        crate::serial_println!("fsck {}: skipped, checking needs {} KiB", path, fs.check_memory() / 1024);
+        return vfs::mount(path, Box::new(fs));
+    }
+    match fs.check(true) {
+        Ok(r) if r.clean() => {}
+        Ok(r) => {
+            crate::serial_println!("fsck {}: {}", path, r.summary());
+            for p in &r.problems { crate::serial_println!("fsck {}: {}", path, p); }
+        }
+        Err(e) => crate::serial_println!("fsck {}: check failed ({:?})", path, e),
+    }
+    vfs::mount(path, Box::new(fs))
 }
 
-pub fn list(path: &str) -> Result<Vec<String>, ()> {
-    FS.lock().as_ref().ok_or(())?.list(path)
+/// Check the FAT volume on block device `name`, repairing it if asked. Repairs are refused
+/// while the volume is mounted where automount puts it, under `/mnt/<name>`, and volumes too
+/// big to check in the memory left are `NoSpace`.
+pub fn fsck(name: &str, repair: bool) -> Result<fat::Report, FsError> {
+    let dev = crate::block::get(name).ok_or(FsError::NotFound)?;
+    let at = alloc::format!("/mnt/{}", name);
+    if repair && vfs::mounts().iter().any(|m| m.0 == at) { return Err(FsError::Busy); }
+    let mut fs = fat::FatFs::new(Box::new(dev)).ok_or(FsError::Invalid)?;
+    if !check_fits(&fs) { return Err(FsError::NoSpace); }
+    fs.check(repair)
+}
+
+/// Mount the ext2 volume on `dev` at `path`.
+pub fn mount_ext2(path: &str, dev: Box<dyn crate::block::BlockDevice + Send>) -> Result<(), FsError> {
+    let fs = ext2::Ext2Fs::new(dev).ok_or(FsError::Unsupported)?;
+    vfs::mount(path, Box::new(fs))
+}
+
+pub fn stat(path: &str) -> Result<Stat, FsError> { vfs::stat(path) }
+
+/// SHA-256 of the file at `path`; see `vfs::hash`.
+pub fn hash(path: &str) -> Result<[u8; 32], FsError> { vfs::hash(path) }
+
+/// Rehash `path` and check it against the hash shipped with it; see `vfs::verify`.
+pub fn verify(path: &str) -> Result<([u8; 32], bool), FsError> { vfs::verify(path) }
+
+/// Open `path` and return a handle for `read_handle`/`write_handle`.
+pub fn open(path: &str, flags: u32) -> Result<usize, FsError> { vfs::open(path, flags) }
+
+pub fn read_handle(h: usize, buf: &mut [u8]) -> isize { vfs::read(h, buf).map_or(-1, |n| n as isize) }
+
+pub fn write_handle(h: usize, buf: &[u8]) -> isize { vfs::write(h, buf).map_or(-1, |n| n as isize) }
+
+pub fn seek_handle(h: usize, pos: SeekFrom) -> isize { vfs::seek(h, pos).map_or(-1, |n| n as isize) }
+
+pub fn dup_handle(h: usize) { vfs::dup(h) }
+
+pub fn close_handle(h: usize) { vfs::close(h) }
+
+/// An open file for kernel code; the handle is closed on drop.
+pub struct File(usize);
+
+impl File {
+    pub fn open(path: &str, flags: u32) -> Result<Self, FsError> { vfs::open(path, flags).map(File) }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, FsError> { vfs::read(self.0, buf) }
+
+    pub fn write(&mut self, data: &[u8]) -> Result<usize, FsError> { vfs::write(self.0, data) }
+
+    pub fn read_at(&self, off: u64, buf: &mut [u8]) -> Result<usize, FsError> { vfs::read_at(self.0, off, buf) }
+
+    pub fn write_at(&self, off: u64, data: &[u8]) -> Result<usize, FsError> { vfs::write_at(self.0, off, data) }
+
+    pub fn seek(&mut self, pos: SeekFrom) -> Result<u64, FsError> { vfs::seek(self.0, pos) }
+
+    pub fn stat(&self) -> Result<Stat, FsError> { vfs::fstat(self.0) }
+
+    pub fn view(&self) -> Result<FileView, FsError> { vfs::view(self.0) }
 }
 
-pub fn write(path: &str, data: &[u8]) -> Result<(), ()> {
-    FS.lock().as_mut().ok_or(())?.write(path, data)
+impl Drop for File {
+    fn drop(&mut self) { vfs::close(self.0) }
 }
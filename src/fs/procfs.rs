@@ -0,0 +1,246 @@
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::Write;
+use super::vfs::{DirEntry, FileType, Filesystem, FsError, Ino, Stat};
+use crate::fd::Fd;
+use crate::scheduler;
+use crate::task::State;
+
+// /proc: kernel state rendered as text on read. Files report size 0, as on Linux, and are read
+// until EOF; the text is rendered when a read starts at offset 0 and reused for the rest of it.
+
+// Inode numbers: the fixed entries sit below 1 << 16, per-task ones are pid << 16 | entry.
+const ROOT: Ino = 1;
+const MEMINFO: Ino = 2;
+const UPTIME: Ino = 3;
+const INTERRUPTS: Ino = 4;
+const PCI: Ino = 5;
+const NET: Ino = 6;
+const NET_DEV: Ino = 7;
+const NET_UDP: Ino = 8;
+const NET_TCP: Ino = 9;
+const SELF: Ino = 10;
+
+const TOP: &[(&str, Ino)] = &[("meminfo", MEMINFO), ("uptime", UPTIME), ("interrupts", INTERRUPTS), ("pci", PCI), ("net", NET), ("self", SELF)];
+const NET_FILES: &[(&str, Ino)] = &[("dev", NET_DEV), ("udp", NET_UDP), ("tcp", NET_TCP)];
+
+// entries under /proc/<pid>
+const PID_DIR: u64 = 0;
+const STATUS: u64 = 1;
+const CMDLINE: u64 = 2;
+const MAPS: u64 = 3;
+const FD_DIR: u64 = 4;
+const FD_BASE: u64 = 0x100; // FD_BASE + n is /proc/<pid>/fd/<n>
+const PID_FILES: &[(&str, u64)] = &[("status", STATUS), ("cmdline", CMDLINE), ("maps", MAPS), ("fd", FD_DIR)];
+
+fn pid_ino(pid: u64, entry: u64) -> Ino { pid << 16 | entry }
+
+fn split(ino: Ino) -> (u64, u64) { (ino >> 16, ino & 0xFFFF) }
+
+fn fds(pid: u64) -> Option<Vec<Option<Fd>>> { scheduler::with_task(pid, |t| t.fds.clone()) }
+
+pub struct ProcFs {
+    cache: Option<(Ino, String)>, // the file being read and its text
+}
+
+impl ProcFs {
+    pub fn new() -> Self { Self { cache: None } }
+
+    // Also checks the inode still exists: tasks come and go under us.
+    fn kind(&self, ino: Ino) -> Result<FileType, FsError> {
+        match split(ino) {
+            (0, ROOT | NET) => Ok(FileType::Dir),
+            (0, SELF) => Ok(FileType::Symlink),
+            (0, MEMINFO..=NET_TCP) => Ok(FileType::File),
+            (0, _) => Err(FsError::NotFound),
+            (pid, entry) => {
+                let fds = fds(pid).ok_or(FsError::NotFound)?;
+                match entry {
+                    PID_DIR | FD_DIR => Ok(FileType::Dir),
+                    STATUS | CMDLINE | MAPS => Ok(FileType::File),
+                    n if n >= FD_BASE && fds.get((n - FD_BASE) as usize).copied().flatten().is_some() => Ok(FileType::Symlink),
+                    _ => Err(FsError::NotFound),
+                }
+            }
+        }
+    }
+
+    fn render(&self, ino: Ino) -> Result<String, FsError> {
+        let mut s = String::new();
+        match split(ino) {
+            (0, MEMINFO) => meminfo(&mut s),
+            (0, UPTIME) => { let (t, hz) = (crate::pit::ticks(), crate::pit::hz()); let _ = writeln!(s, "{}.{:02}", t / hz, t % hz * 100 / hz); }
+            (0, INTERRUPTS) => interrupts(&mut s),
+            (0, PCI) => pci(&mut s),
+            (0, NET_DEV) => net_dev(&mut s),
+            (0, NET_UDP) => sockets(&mut s, false),
+            (0, NET_TCP) => sockets(&mut s, true),
+            (pid, STATUS) => status(&mut s, pid)?,
+            (pid, CMDLINE) => { s = scheduler::with_task(pid, |t| t.name.to_string()).ok_or(FsError::NotFound)?; s.push('\0'); }
+            (pid, MAPS) => maps(&mut s, pid)?,
+            _ => return Err(FsError::Invalid),
+        }
+        Ok(s)
+    }
+
+    fn link(&self, ino: Ino) -> Result<String, FsError> {
+        match split(ino) {
+            (0, SELF) => scheduler::current_pid().map(|p| p.to_string()).ok_or(FsError::NotFound),
+            (pid, n) if n >= FD_BASE => {
+                let fd = fds(pid).and_then(|f| f.get((n - FD_BASE) as usize).copied().flatten()).ok_or(FsError::NotFound)?;
+                Ok(match fd {
+                    Fd::Console => "/dev/tty0".to_string(),
+                    Fd::PipeRead(id) | Fd::PipeWrite(id) => alloc::format!("pipe:[{}]", id),
+                    Fd::File(h) => super::vfs::handle_path(h).unwrap_or_default(),
+                    Fd::Udp(_) => "socket:[udp]".to_string(),
+                    Fd::Watch(id) => alloc::format!("watch:[{}]", super::watch::path(id).unwrap_or_default()),
+                })
+            }
+            _ => Err(FsError::Invalid),
+        }
+    }
+}
+
+impl Filesystem for ProcFs {
+    fn name(&self) -> &'static str { "proc" }
+
+    fn root(&self) -> Ino { ROOT }
+
+    fn lookup(&mut self, dir: Ino, name: &str) -> Result<Ino, FsError> {
+        if self.kind(dir)? != FileType::Dir { return Err(FsError::NotDir); }
+        let find = |table: &[(&str, Ino)]| table.iter().find(|e| e.0 == name).map(|e| e.1).ok_or(FsError::NotFound);
+        let ino = match split(dir) {
+            (0, ROOT) => match find(TOP) {
+                Ok(ino) => ino,
+                Err(_) => pid_ino(name.parse().ok().filter(|&p| p > 0 && p < 1 << 48).ok_or(FsError::NotFound)?, PID_DIR),
+            },
+            (0, _) => find(NET_FILES)?,
+            (pid, PID_DIR) => pid_ino(pid, find(PID_FILES)?),
+            (pid, _) => pid_ino(pid, FD_BASE + name.parse::<u64>().ok().filter(|&n| n < 0xFFFF - FD_BASE).ok_or(FsError::NotFound)?),
+        };
+        self.kind(ino)?;
+        Ok(ino)
+    }
+
+    fn stat(&mut self, ino: Ino) -> Result<Stat, FsError> {
+        let kind = self.kind(ino)?;
+        let (size, mode) = match kind {
+            FileType::Dir => (0, 0o555),
+            FileType::File => (0, 0o444),
+            _ => (self.link(ino)?.len() as u64, 0o777),
+        };
+        let now = crate::rtc::now();
+        // a task's entries belong to whoever it runs as
+        let pid = split(ino).0;
+        let c = if pid == 0 { None } else { scheduler::with_task(pid, |t| t.creds) }.unwrap_or(crate::task::Creds::ROOT);
+        Ok(Stat { ino, kind, size, mode, uid: c.uid, gid: c.gid, ctime: now, mtime: now })
+    }
+
+    fn read_at(&mut self, ino: Ino, off: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        match self.kind(ino)? {
+            FileType::File => {}
+            FileType::Dir => return Err(FsError::IsDir),
+            _ => return Err(FsError::Invalid),
+        }
+        if off == 0 || self.cache.as_ref().map_or(true, |c| c.0 != ino) { self.cache = Some((ino, self.render(ino)?)); }
+        let text = self.cache.as_ref().map_or(&[][..], |c| c.1.as_bytes());
+        let start = (off as usize).min(text.len());
+        let n = buf.len().min(text.len() - start);
+        buf[..n].copy_from_slice(&text[start..start + n]);
+        Ok(n)
+    }
+
+    fn readdir(&mut self, dir: Ino) -> Result<Vec<DirEntry>, FsError> {
+        if self.kind(dir)? != FileType::Dir { return Err(FsError::NotDir); }
+        let inos: Vec<(String, Ino)> = match split(dir) {
+            (0, ROOT) => TOP.iter().map(|e| (e.0.to_string(), e.1))
+                .chain(scheduler::pids().into_iter().map(|p| (p.to_string(), pid_ino(p, PID_DIR))))
+                .collect(),
+            (0, _) => NET_FILES.iter().map(|e| (e.0.to_string(), e.1)).collect(),
+            (pid, PID_DIR) => PID_FILES.iter().map(|e| (e.0.to_string(), pid_ino(pid, e.1))).collect(),
+            (pid, _) => fds(pid).unwrap_or_default().iter().enumerate()
+                .filter(|f| f.1.is_some())
+                .map(|(n, _)| (n.to_string(), pid_ino(pid, FD_BASE + n as u64)))
+                .collect(),
+        };
+        // a task may exit while we list it
+        Ok(inos.into_iter().filter_map(|(name, ino)| Some(DirEntry { kind: self.kind(ino).ok()?, name, ino })).collect())
+    }
+
+    fn readlink(&mut self, ino: Ino) -> Result<String, FsError> { self.link(ino) }
+}
+
+fn meminfo(s: &mut String) {
+    let (frames, used) = crate::mm::frame_stats();
+    let (heap_used, heap_size) = crate::heap::stats();
+    let cache = crate::bcache::stats();
+    let _ = writeln!(s, "MemTotal:   {:>8} kB", frames * 4);
+    let _ = writeln!(s, "MemFree:    {:>8} kB", (frames - used) * 4);
+    let _ = writeln!(s, "HeapTotal:  {:>8} kB", heap_size / 1024);
+    let _ = writeln!(s, "HeapUsed:   {:>8} kB", heap_used / 1024);
+    let _ = writeln!(s, "BlockCache: {:>8} kB", cache.used * 4);
+    let _ = writeln!(s, "Dirty:      {:>8} kB", cache.dirty * 4);
+}
+
+fn interrupts(s: &mut String) {
+    for (irq, n) in crate::interrupts::counts().iter().enumerate() {
+        let name = match irq { 0 => "timer", 1 => "keyboard", 12 => "mouse", _ if *n > 0 => "pci", _ => continue };
+        let _ = writeln!(s, "{:>3}: {:>10}  {}", irq, n, name);
+    }
+}
+
+fn pci(s: &mut String) {
+    crate::pci::enumerate(|d| {
+        let _ = writeln!(s, "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}{:02x}{:02x} irq {}",
+            d.bus, d.slot, d.func, d.vendor, d.device, d.class, d.subclass, d.prog_if, d.irq_line());
+    });
+}
+
+fn net_dev(s: &mut String) {
+    if let Some(i) = crate::net::netstack::interface() {
+        let _ = writeln!(s, "eth0 {} {} {}", i.mac, i.addrs.join(","), i.driver);
+    }
+}
+
+fn sockets(s: &mut String, tcp: bool) {
+    let _ = writeln!(s, "{:<24} {:<24} state", "local", "remote");
+    for sock in crate::net::netstack::sockets().into_iter().filter(|k| k.tcp == tcp) {
+        let _ = writeln!(s, "{:<24} {:<24} {}", sock.local, sock.remote, sock.state);
+    }
+}
+
+fn status(s: &mut String, pid: u64) -> Result<(), FsError> {
+    scheduler::with_task(pid, |t| {
+        let state = match t.state {
+            State::Running => "R (running)",
+            State::Ready => "R (ready)",
+            State::Sleeping(_) => "S (sleeping)",
+            State::Zombie => "Z (zombie)",
+        };
+        let _ = writeln!(s, "Name:\t{}", t.name);
+        let _ = writeln!(s, "Pid:\t{}", t.pid);
+        let _ = writeln!(s, "State:\t{}", state);
+        let _ = writeln!(s, "Uid:\t{}", t.creds.uid);
+        let _ = writeln!(s, "Gid:\t{}", t.creds.gid);
+        let _ = writeln!(s, "Priority:\t{}", t.priority);
+        let _ = writeln!(s, "Kind:\t{}", if t.heap_end != 0 { "user" } else { "kernel" });
+        if t.heap_end != 0 { let _ = writeln!(s, "Brk:\t{:#x}", t.heap_end); }
+        let _ = writeln!(s, "FDSize:\t{}", t.fds.iter().flatten().count());
+        let _ = writeln!(s, "Traced:\t{}", if t.traced { "yes" } else { "no" });
+    }).ok_or(FsError::NotFound)
+}
+
+fn maps(s: &mut String, pid: u64) -> Result<(), FsError> {
+    use crate::syscalls::{USER_HEAP_BASE, USER_MMAP_BASE, USER_STACK_TOP};
+    let (cr3, heap_end, mmap_next) = scheduler::with_task(pid, |t| (t.cr3, t.heap_end, t.mmap_next)).ok_or(FsError::NotFound)?;
+    if heap_end == 0 { return Ok(()); } // kernel tasks have no user mappings
+    for (start, end, writable) in crate::mm::user_mappings(cr3) {
+        let label = if end == USER_STACK_TOP { "[stack]" }
+            else if (USER_MMAP_BASE..mmap_next).contains(&start) { "[mmap]" }
+            else if (USER_HEAP_BASE..USER_MMAP_BASE).contains(&start) { "[heap]" }
+            else { "" };
+        let line = alloc::format!("{:012x}-{:012x} r{}xp {}", start, end, if writable { 'w' } else { '-' }, label);
+        let _ = writeln!(s, "{}", line.trim_end());
+    }
+    Ok(())
+}
@@ -0,0 +1,163 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+use super::vfs::{self, FileType, Filesystem, FsError, Ino, O_CREAT, O_TRUNC, O_WRONLY};
+use super::initrd;
+
+// Root filesystem persistence. The memfs tree is written as a ustar archive to `rootfs.tar`
+// at the top of a FAT volume and unpacked over the root at the next boot with the initrd
+// reader. Unpacking overlays, so a file deleted since the initrd was built comes back.
+// FAT has no owners or modes and shows every file to everyone, so files other users can't
+// read (/etc/shadow) are left out and come from the initrd again after a reboot.
+
+/// Name of the snapshot file at the top of its volume.
+pub const FILE: &str = "rootfs.tar";
+
+// Volume the snapshot was restored from; `save` writes back to the same one.
+static VOLUME: Mutex<Option<String>> = Mutex::new(None);
+
+enum Item { File(Arc<Vec<u8>>), Dir, Symlink(String) }
+
+struct Entry { path: String, mode: u16, uid: u32, gid: u32, mtime: u64, item: Item }
+
+/// What `save` wrote.
+#[derive(Clone, Debug)]
+pub struct Saved {
+    pub path: String,
+    pub entries: usize,
+    pub bytes: u64,
+}
+
+/// Mount points of FAT volumes, in mount order.
+fn fat_volumes() -> Vec<String> {
+    vfs::mounts().into_iter().filter(|m| m.1.starts_with("fat")).map(|m| m.0).collect()
+}
+
+// Every node below `dir`, parents first. memfs hands out its buffers, so no data is copied.
+fn collect(fs: &mut dyn Filesystem, dir: Ino, prefix: &str, out: &mut Vec<Entry>) -> Result<(), FsError> {
+    for e in fs.readdir(dir)? {
+        let st = fs.stat(e.ino)?;
+        let path = if prefix.is_empty() { e.name } else { format!("{}/{}", prefix, e.name) };
+        let item = match st.kind {
+            FileType::File if st.mode & 0o004 == 0 => continue,
+            FileType::File => match fs.view(e.ino) {
+                Some(data) => Item::File(data),
+                None => {
+                    let mut data = alloc::vec![0u8; st.size as usize];
+                    let n = fs.read_at(e.ino, 0, &mut data)?;
+                    data.truncate(n);
+                    Item::File(Arc::new(data))
+                }
+            },
+            FileType::Dir => Item::Dir,
+            FileType::Symlink => Item::Symlink(fs.readlink(e.ino)?),
+            _ => continue,
+        };
+        let is_dir = matches!(item, Item::Dir);
+        out.push(Entry { path: path.clone(), mode: st.mode, uid: st.uid, gid: st.gid, mtime: st.mtime, item });
+        if is_dir { collect(fs, e.ino, &path, out)?; }
+    }
+    Ok(())
+}
+
+fn octal(field: &mut [u8], v: u64) {
+    let w = field.len() - 1; // NUL-terminated
+    let digits = format!("{:0w$o}", v, w = w);
+    // values too wide for the field keep their low digits; only mtime could get there
+    field[..w].copy_from_slice(&digits.as_bytes()[digits.len() - w..]);
+}
+
+fn header(name: &[u8], mode: u16, owner: (u32, u32), size: u64, mtime: u64, kind: u8, link: &[u8]) -> [u8; 512] {
+    let mut h = [0u8; 512];
+    h[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+    octal(&mut h[100..108], mode as u64);
+    octal(&mut h[108..116], owner.0 as u64);
+    octal(&mut h[116..124], owner.1 as u64);
+    octal(&mut h[124..136], size);
+    octal(&mut h[136..148], mtime);
+    h[156] = kind;
+    h[157..157 + link.len().min(100)].copy_from_slice(&link[..link.len().min(100)]);
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    h[148..156].fill(b' ');
+    let sum = h.iter().map(|&b| b as u64).sum();
+    octal(&mut h[148..155], sum);
+    h
+}
+
+struct Writer { file: super::File, bytes: u64 }
+
+impl Writer {
+    fn put(&mut self, data: &[u8]) -> Result<(), FsError> {
+        let mut done = 0;
+        while done < data.len() {
+            match self.file.write(&data[done..])? { 0 => return Err(FsError::NoSpace), n => done += n }
+        }
+        self.bytes += data.len() as u64;
+        Ok(())
+    }
+
+    // `data` followed by zeros up to the next 512-byte block
+    fn put_padded(&mut self, data: &[u8]) -> Result<(), FsError> {
+        self.put(data)?;
+        self.put(&[0u8; 512][..data.len().next_multiple_of(512) - data.len()])
+    }
+
+    // GNU long name ('L') or long link ('K') record for names that don't fit in 100 bytes
+    fn long(&mut self, kind: u8, name: &[u8]) -> Result<(), FsError> {
+        let mut body = name.to_vec();
+        body.push(0);
+        self.put(&header(b"././@LongLink", 0, (0, 0), body.len() as u64, 0, kind, b""))?;
+        self.put_padded(&body)
+    }
+
+    fn entry(&mut self, e: &Entry) -> Result<(), FsError> {
+        let (kind, size, link) = match &e.item {
+            Item::File(d) => (b'0', d.len() as u64, &b""[..]),
+            Item::Dir => (b'5', 0, &b""[..]),
+            Item::Symlink(t) => (b'2', 0, t.as_bytes()),
+        };
+        if e.path.len() > 100 { self.long(b'L', e.path.as_bytes())?; }
+        if link.len() > 100 { self.long(b'K', link)?; }
+        self.put(&header(e.path.as_bytes(), e.mode, (e.uid, e.gid), size, e.mtime, kind, link))?;
+        if let Item::File(d) = &e.item { self.put_padded(d)?; }
+        Ok(())
+    }
+}
+
+/// Write the root filesystem to the volume it was restored from, or else the first mounted
+/// FAT volume. The archive goes to a temporary file first and replaces the old one by rename,
+/// so a crash mid-write leaves the previous snapshot intact.
+pub fn save() -> Result<Saved, FsError> {
+    let volume = match VOLUME.lock().clone() {
+        Some(v) => v,
+        None => fat_volumes().into_iter().next().ok_or(FsError::NotFound)?,
+    };
+    let mut entries = Vec::new();
+    vfs::with_mount("/", |fs| { let root = fs.root(); collect(fs, root, "", &mut entries) })?;
+    let (tmp, path) = (format!("{}/{}.new", volume, FILE), format!("{}/{}", volume, FILE));
+    let mut w = Writer { file: super::File::open(&tmp, O_WRONLY | O_CREAT | O_TRUNC)?, bytes: 0 };
+    for e in &entries { w.entry(e)?; }
+    w.put(&[0u8; 1024])?; // end-of-archive marker
+    let bytes = w.bytes;
+    drop(w);
+    vfs::rename(&tmp, &path)?;
+    vfs::sync()?;
+    crate::bcache::sync_all()?;
+    *VOLUME.lock() = Some(volume);
+    Ok(Saved { path, entries: entries.len(), bytes })
+}
+
+/// Unpack the snapshot from the first mounted FAT volume that has one over the root
+/// filesystem. Returns the volume, or `NotFound` if there is no snapshot.
+pub fn restore() -> Result<(String, initrd::Summary), FsError> {
+    for volume in fat_volumes() {
+        let Ok(archive) = vfs::view_file(&format!("{}/{}", volume, FILE)) else { continue };
+        let sum = vfs::with_mount("/", |fs| initrd::unpack(fs, &archive))?;
+        *VOLUME.lock() = Some(volume.clone());
+        return Ok((volume, sum));
+    }
+    Err(FsError::NotFound)
+}
@@ -0,0 +1,648 @@
+use alloc::boxed::Box;
+use alloc::collections::VecDeque;
+use alloc::sync::Arc;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::compress::Codec;
+use crate::task::Creds;
+use super::watch;
+
+/// Inode number, meaningful only within one filesystem.
+pub type Ino = u64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotDir,
+    IsDir,
+    Exists,
+    NotEmpty,
+    NoSpace,
+    Io,
+    Invalid,
+    ReadOnly,
+    Loop,
+    BadHandle,
+    Busy,
+    CrossDevice,
+    Unsupported,
+    WouldBlock, // device has no data yet; `read` waits and retries
+    Denied,     // mode bits don't allow it for the calling task
+}
+
+impl From<crate::block::BlockError> for FsError {
+    fn from(e: crate::block::BlockError) -> Self {
+        match e { crate::block::BlockError::ReadOnly => FsError::ReadOnly, _ => FsError::Io }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType { File, Dir, Symlink, CharDev, BlockDev }
+
+#[derive(Clone, Copy, Debug)]
+pub struct Stat {
+    pub ino: Ino,
+    pub kind: FileType,
+    pub size: u64,
+    pub mode: u16,  // permission bits (0o7777)
+    pub uid: u32,
+    pub gid: u32,
+    pub ctime: u64, // seconds since the Unix epoch, 0 if unknown
+    pub mtime: u64,
+}
+
+/// Read-only contents of a file. Filesystems that keep files in memory hand out their buffer
+/// itself, shared copy-on-write, so taking a view copies nothing and later writes don't show.
+#[derive(Clone)]
+pub struct FileView(Arc<Vec<u8>>);
+
+impl core::ops::Deref for FileView {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] { &self.0 }
+}
+
+/// Where `seek` measures from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SeekFrom { Start(u64), Current(i64), End(i64) }
+
+#[derive(Clone, Debug)]
+pub struct DirEntry {
+    pub name: String,
+    pub ino: Ino,
+    pub kind: FileType,
+}
+
+/// An inode-based filesystem that can be mounted into the VFS tree.
+/// Write operations default to `ReadOnly` so read-only drivers only implement the lookups.
+pub trait Filesystem: Send {
+    fn name(&self) -> &'static str;
+    fn root(&self) -> Ino;
+    fn lookup(&mut self, dir: Ino, name: &str) -> Result<Ino, FsError>;
+    fn stat(&mut self, ino: Ino) -> Result<Stat, FsError>;
+    fn read_at(&mut self, ino: Ino, off: u64, buf: &mut [u8]) -> Result<usize, FsError>;
+    fn readdir(&mut self, dir: Ino) -> Result<Vec<DirEntry>, FsError>;
+
+    fn write_at(&mut self, _ino: Ino, _off: u64, _data: &[u8]) -> Result<usize, FsError> { Err(FsError::ReadOnly) }
+    fn truncate(&mut self, _ino: Ino, _size: u64) -> Result<(), FsError> { Err(FsError::ReadOnly) }
+    fn create(&mut self, _dir: Ino, _name: &str) -> Result<Ino, FsError> { Err(FsError::ReadOnly) }
+    fn mkdir(&mut self, _dir: Ino, _name: &str) -> Result<Ino, FsError> { Err(FsError::ReadOnly) }
+    fn symlink(&mut self, _dir: Ino, _name: &str, _target: &str) -> Result<Ino, FsError> { Err(FsError::ReadOnly) }
+    fn readlink(&mut self, _ino: Ino) -> Result<String, FsError> { Err(FsError::Invalid) }
+    fn unlink(&mut self, _dir: Ino, _name: &str) -> Result<(), FsError> { Err(FsError::ReadOnly) }
+    fn rmdir(&mut self, _dir: Ino, _name: &str) -> Result<(), FsError> { Err(FsError::ReadOnly) }
+    fn rename(&mut self, _odir: Ino, _oname: &str, _ndir: Ino, _nname: &str) -> Result<(), FsError> { Err(FsError::ReadOnly) }
+    fn chmod(&mut self, _ino: Ino, _mode: u16) -> Result<(), FsError> { Err(FsError::Unsupported) }
+    fn chown(&mut self, _ino: Ino, _uid: u32, _gid: u32) -> Result<(), FsError> { Err(FsError::Unsupported) }
+    /// The file's data without copying, for filesystems that hold it in memory.
+    fn view(&mut self, _ino: Ino) -> Option<Arc<Vec<u8>>> { None }
+    /// Fill an empty file with `data`, compressed with `codec` from `size` bytes. Filesystems
+    /// that can hold it compressed do; the rest get it unpacked.
+    fn write_packed(&mut self, ino: Ino, codec: Codec, data: &[u8], size: u64) -> Result<(), FsError> {
+        let plain = crate::compress::decompress(codec, data, size as usize).ok_or(FsError::Invalid)?;
+        self.write_at(ino, 0, &plain).map(|_| ())
+    }
+    /// SHA-256 of the file's data, for filesystems that keep one; `hash` works it out otherwise.
+    fn content_hash(&mut self, _ino: Ino) -> Option<[u8; 32]> { None }
+    /// Record the hash the data should have, as shipped alongside it; `verify` checks it.
+    fn set_content_hash(&mut self, _ino: Ino, _hash: [u8; 32]) {}
+    fn sync(&mut self) -> Result<(), FsError> { Ok(()) }
+}
+
+struct Mount {
+    path: String, // absolute, no trailing slash except for "/"
+    fs: Box<dyn Filesystem>,
+}
+
+/// An open file description; shared by every fd that dup'd it. `written` holds back the
+/// MODIFY event until the last close, so watchers see the file once it is complete.
+struct OpenFile { mount: usize, ino: Ino, pos: u64, flags: u32, refs: usize, path: String, written: bool }
+
+// Lock order is MOUNTS before OPEN: procfs looks up handle paths while the VFS holds MOUNTS.
+lazy_static! {
+    static ref MOUNTS: Mutex<Vec<Option<Mount>>> = Mutex::new(Vec::new());
+    static ref OPEN: Mutex<Vec<Option<OpenFile>>> = Mutex::new(Vec::new());
+}
+
+const MAX_SYMLINKS: usize = 8;
+
+// access(2) bits, the same as each rwx triplet of a mode
+pub const R_OK: u16 = 4;
+pub const W_OK: u16 = 2;
+pub const X_OK: u16 = 1;
+
+/// Whether `c` may access a node with `st` for `want` (R_OK, W_OK and X_OK or'd together).
+/// Root may do anything except execute a file that has no execute bit at all.
+pub fn permitted(st: &Stat, c: Creds, want: u16) -> bool {
+    if c.uid == 0 { return want & X_OK == 0 || st.kind == FileType::Dir || st.mode & 0o111 != 0; }
+    let bits = if st.uid == c.uid { st.mode >> 6 } else if st.gid == c.gid { st.mode >> 3 } else { st.mode };
+    bits & want == want
+}
+
+fn creds() -> Creds { crate::scheduler::current_creds() }
+
+fn check(fs: &mut dyn Filesystem, ino: Ino, want: u16) -> Result<(), FsError> {
+    if permitted(&fs.stat(ino)?, creds(), want) { Ok(()) } else { Err(FsError::Denied) }
+}
+
+// New nodes belong to the task that created them.
+fn own(fs: &mut dyn Filesystem, ino: Ino) -> Result<Ino, FsError> {
+    let c = creds();
+    match fs.chown(ino, c.uid, c.gid) {
+        Ok(()) | Err(FsError::Unsupported) => Ok(ino),
+        Err(e) => Err(e),
+    }
+}
+
+fn components(path: &str) -> impl DoubleEndedIterator<Item = &str> { path.split('/').filter(|p| !p.is_empty()) }
+
+fn normalize(path: &str) -> String { join(&components(path).map(|s| s.to_string()).collect::<Vec<_>>()) }
+
+fn join(parts: &[String]) -> String {
+    if parts.is_empty() { return "/".to_string(); }
+    let mut s = String::new();
+    for p in parts { s.push('/'); s.push_str(p); }
+    s
+}
+
+/// Attach `fs` at `path`. The mount point must not already be in use.
+pub fn mount(path: &str, fs: Box<dyn Filesystem>) -> Result<(), FsError> {
+    let path = normalize(path);
+    let mut mounts = MOUNTS.lock();
+    if mounts.iter().flatten().any(|m| m.path == path) { return Err(FsError::Busy); }
+    let m = Mount { path, fs };
+    match mounts.iter().position(|m| m.is_none()) {
+        Some(i) => mounts[i] = Some(m),
+        None => mounts.push(Some(m)),
+    }
+    Ok(())
+}
+
+pub fn umount(path: &str) -> Result<(), FsError> {
+    let path = normalize(path);
+    let mut mounts = MOUNTS.lock();
+    let open = OPEN.lock();
+    let i = mount_at(&mounts, &path).ok_or(FsError::NotFound)?;
+    if open.iter().flatten().any(|f| f.mount == i) { return Err(FsError::Busy); }
+    if let Some(mut m) = mounts[i].take() { m.fs.sync()?; }
+    Ok(())
+}
+
+/// Run `op` directly on the filesystem mounted at `path`, bypassing path resolution.
+pub fn with_mount<T>(path: &str, op: impl FnOnce(&mut dyn Filesystem) -> Result<T, FsError>) -> Result<T, FsError> {
+    let mut mounts = MOUNTS.lock();
+    let i = mount_at(&mounts, &normalize(path)).ok_or(FsError::NotFound)?;
+    op(fs_mut(&mut mounts, i)?)
+}
+
+/// (mount point, filesystem name) for every mounted filesystem.
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS.lock().iter().flatten().map(|m| (m.path.clone(), m.fs.name())).collect()
+}
+
+fn mount_at(mounts: &[Option<Mount>], path: &str) -> Option<usize> {
+    mounts.iter().position(|m| m.as_ref().is_some_and(|m| m.path == path))
+}
+
+fn fs_mut(mounts: &mut [Option<Mount>], i: usize) -> Result<&mut dyn Filesystem, FsError> {
+    Ok(mounts.get_mut(i).and_then(|m| m.as_mut()).ok_or(FsError::NotFound)?.fs.as_mut())
+}
+
+/// Walk `path` handling `.`, `..`, mount points and symlinks.
+/// Returns the (mount, inode) pairs from the root down to the target.
+fn walk(mounts: &mut [Option<Mount>], path: &str, follow_last: bool) -> Result<Vec<(usize, Ino)>, FsError> {
+    let root_m = mount_at(mounts, "/").ok_or(FsError::NotFound)?;
+    let root = (root_m, fs_mut(mounts, root_m)?.root());
+    let mut stack = alloc::vec![root];
+    let mut names: Vec<String> = Vec::new();
+    let mut todo: VecDeque<String> = components(path).map(|s| s.to_string()).collect();
+    let mut hops = 0;
+    let c = creds();
+    while let Some(part) = todo.pop_front() {
+        match part.as_str() {
+            "." => continue,
+            ".." => { if stack.len() > 1 { stack.pop(); names.pop(); } continue; }
+            _ => {}
+        }
+        let (m, dir) = *stack.last().unwrap();
+        let fs = fs_mut(mounts, m)?;
+        let st = fs.stat(dir)?;
+        if st.kind != FileType::Dir { return Err(FsError::NotDir); }
+        if !permitted(&st, c, X_OK) { return Err(FsError::Denied); }
+        let ino = fs.lookup(dir, &part)?;
+        if fs.stat(ino)?.kind == FileType::Symlink && (follow_last || !todo.is_empty()) {
+            hops += 1;
+            if hops > MAX_SYMLINKS { return Err(FsError::Loop); }
+            let target = fs.readlink(ino)?;
+            if target.starts_with('/') { stack.truncate(1); names.clear(); }
+            for p in components(&target).rev() { todo.push_front(p.to_string()); }
+            continue;
+        }
+        names.push(part);
+        // crossing into a mounted filesystem
+        let here = join(&names);
+        let next = match mount_at(mounts, &here) {
+            Some(mi) => (mi, fs_mut(mounts, mi)?.root()),
+            None => (m, ino),
+        };
+        stack.push(next);
+    }
+    Ok(stack)
+}
+
+fn resolve_in(mounts: &mut [Option<Mount>], path: &str, follow: bool) -> Result<(usize, Ino), FsError> {
+    Ok(*walk(mounts, path, follow)?.last().unwrap())
+}
+
+/// Split `path` into its resolved parent directory and final name, for adding or removing
+/// that name; the caller needs write and search permission on the directory.
+fn resolve_parent<'p>(mounts: &mut [Option<Mount>], path: &'p str) -> Result<(usize, Ino, &'p str), FsError> {
+    let trimmed = path.trim_end_matches('/');
+    let (dir, name) = match trimmed.rfind('/') { Some(i) => (&trimmed[..i], &trimmed[i + 1..]), None => ("", trimmed) };
+    if name.is_empty() || name == "." || name == ".." { return Err(FsError::Invalid); }
+    let (m, ino) = resolve_in(mounts, dir, true)?;
+    check(fs_mut(mounts, m)?, ino, W_OK | X_OK)?;
+    Ok((m, ino, name))
+}
+
+pub fn stat(path: &str) -> Result<Stat, FsError> {
+    let mut mounts = MOUNTS.lock();
+    let (m, ino) = resolve_in(&mut mounts, path, true)?;
+    fs_mut(&mut mounts, m)?.stat(ino)
+}
+
+/// Like `stat`, but does not follow a trailing symlink.
+pub fn lstat(path: &str) -> Result<Stat, FsError> {
+    let mut mounts = MOUNTS.lock();
+    let (m, ino) = resolve_in(&mut mounts, path, false)?;
+    fs_mut(&mut mounts, m)?.stat(ino)
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let mut mounts = MOUNTS.lock();
+    let (m, ino) = resolve_in(&mut mounts, path, true)?;
+    let fs = fs_mut(&mut mounts, m)?;
+    check(fs, ino, R_OK)?;
+    fs.readdir(ino)
+}
+
+pub fn mkdir(path: &str) -> Result<(), FsError> {
+    {
+        let mut mounts = MOUNTS.lock();
+        let (m, dir, name) = resolve_parent(&mut mounts, path)?;
+        let fs = fs_mut(&mut mounts, m)?;
+        fs.mkdir(dir, name).and_then(|i| own(fs, i))?;
+    }
+    watch::emit(watch::CREATE, path, None);
+    Ok(())
+}
+
+/// `mkdir -p`: create every missing directory along `path`.
+pub fn mkdir_all(path: &str) -> Result<(), FsError> {
+    let mut cur = String::new();
+    for part in components(path) {
+        cur.push('/'); cur.push_str(part);
+        match stat(&cur) {
+            Ok(st) if st.kind == FileType::Dir => {}
+            Ok(_) => return Err(FsError::NotDir),
+            Err(FsError::NotFound) => mkdir(&cur)?,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+pub fn symlink(target: &str, path: &str) -> Result<(), FsError> {
+    {
+        let mut mounts = MOUNTS.lock();
+        let (m, dir, name) = resolve_parent(&mut mounts, path)?;
+        let fs = fs_mut(&mut mounts, m)?;
+        fs.symlink(dir, name, target).and_then(|i| own(fs, i))?;
+    }
+    watch::emit(watch::CREATE, path, None);
+    Ok(())
+}
+
+pub fn readlink(path: &str) -> Result<String, FsError> {
+    let mut mounts = MOUNTS.lock();
+    let (m, ino) = resolve_in(&mut mounts, path, false)?;
+    fs_mut(&mut mounts, m)?.readlink(ino)
+}
+
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    {
+        let mut mounts = MOUNTS.lock();
+        let (m, dir, name) = resolve_parent(&mut mounts, path)?;
+        fs_mut(&mut mounts, m)?.unlink(dir, name)?;
+    }
+    watch::emit(watch::DELETE, path, None);
+    Ok(())
+}
+
+pub fn rmdir(path: &str) -> Result<(), FsError> {
+    {
+        let mut mounts = MOUNTS.lock();
+        let (m, dir, name) = resolve_parent(&mut mounts, path)?;
+        if mount_at(&mounts, &normalize(path)).is_some() { return Err(FsError::Busy); }
+        fs_mut(&mut mounts, m)?.rmdir(dir, name)?;
+    }
+    watch::emit(watch::DELETE, path, None);
+    Ok(())
+}
+
+pub fn rename(from: &str, to: &str) -> Result<(), FsError> {
+    {
+        let mut mounts = MOUNTS.lock();
+        let (m1, d1, n1) = resolve_parent(&mut mounts, from)?;
+        let (m2, d2, n2) = resolve_parent(&mut mounts, to)?;
+        if m1 != m2 { return Err(FsError::CrossDevice); }
+        fs_mut(&mut mounts, m1)?.rename(d1, n1, d2, n2)?;
+    }
+    watch::emit(watch::RENAME, to, Some(from));
+    Ok(())
+}
+
+// Resolve `path`, creating an empty regular file if it is missing and `create` is set.
+// The flag says whether it was created.
+fn lookup_or_create(mounts: &mut [Option<Mount>], path: &str, create: bool) -> Result<(usize, Ino, bool), FsError> {
+    match resolve_in(mounts, path, true) {
+        Err(FsError::NotFound) if create => {
+            let (m, dir, name) = resolve_parent(mounts, path)?;
+            let fs = fs_mut(mounts, m)?;
+            let ino = fs.create(dir, name)?;
+            Ok((m, own(fs, ino)?, true))
+        }
+        r => r.map(|(m, ino)| (m, ino, false)),
+    }
+}
+
+// CREATE for a file just made, then MODIFY
+fn created_or_modified(path: &str, created: bool) {
+    if created { watch::emit(watch::CREATE, path, None); }
+    watch::emit(watch::MODIFY, path, None);
+}
+
+fn read_all(fs: &mut dyn Filesystem, ino: Ino) -> Result<Vec<u8>, FsError> {
+    let st = fs.stat(ino)?;
+    if st.kind == FileType::Dir { return Err(FsError::IsDir); }
+    // generated files (procfs) report size 0 and are read until EOF
+    let mut out = alloc::vec![0u8; if st.size == 0 { 1024 } else { st.size as usize }];
+    let mut done = 0;
+    loop {
+        if done == out.len() {
+            if st.size != 0 { break; }
+            out.resize(done * 2, 0);
+        }
+        let n = fs.read_at(ino, done as u64, &mut out[done..])?;
+        if n == 0 { break; }
+        done += n;
+    }
+    out.truncate(done);
+    Ok(out)
+}
+
+fn view_of(fs: &mut dyn Filesystem, ino: Ino) -> Result<FileView, FsError> {
+    match fs.view(ino) {
+        Some(data) => Ok(FileView(data)),
+        None => Ok(FileView(Arc::new(read_all(fs, ino)?))),
+    }
+}
+
+/// Read a whole file into a fresh buffer.
+pub fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    let mut mounts = MOUNTS.lock();
+    let (m, ino) = resolve_in(&mut mounts, path, true)?;
+    let fs = fs_mut(&mut mounts, m)?;
+    check(fs, ino, R_OK)?;
+    read_all(fs, ino)
+}
+
+/// A whole file as a `FileView`; only filesystems without in-memory data have to copy.
+pub fn view_file(path: &str) -> Result<FileView, FsError> {
+    let mut mounts = MOUNTS.lock();
+    let (m, ino) = resolve_in(&mut mounts, path, true)?;
+    let fs = fs_mut(&mut mounts, m)?;
+    check(fs, ino, R_OK)?;
+    view_of(fs, ino)
+}
+
+/// SHA-256 of a file's contents. Filesystems that keep hashes answer without reading the data.
+pub fn hash(path: &str) -> Result<[u8; 32], FsError> {
+    let mut mounts = MOUNTS.lock();
+    let (m, ino) = resolve_in(&mut mounts, path, true)?;
+    let fs = fs_mut(&mut mounts, m)?;
+    check(fs, ino, R_OK)?;
+    match fs.content_hash(ino) {
+        Some(h) => Ok(h),
+        None => Ok(crate::sha256::sha256(&read_all(fs, ino)?)),
+    }
+}
+
+/// Hash a file's data afresh and compare it with the hash its filesystem holds for it, if any.
+/// Returns the fresh hash and whether it matches; `true` when there is nothing to compare with.
+pub fn verify(path: &str) -> Result<([u8; 32], bool), FsError> {
+    let mut mounts = MOUNTS.lock();
+    let (m, ino) = resolve_in(&mut mounts, path, true)?;
+    let fs = fs_mut(&mut mounts, m)?;
+    check(fs, ino, R_OK)?;
+    let actual = crate::sha256::sha256(&read_all(fs, ino)?);
+    Ok((actual, fs.content_hash(ino).is_none_or(|h| h == actual)))
+}
+
+/// Create or replace a whole file.
+pub fn write_file(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let created = {
+        let mut mounts = MOUNTS.lock();
+        let (m, ino, created) = lookup_or_create(&mut mounts, path, true)?;
+        let fs = fs_mut(&mut mounts, m)?;
+        if fs.stat(ino)?.kind == FileType::Dir { return Err(FsError::IsDir); }
+        check(fs, ino, W_OK)?;
+        fs.truncate(ino, 0)?;
+        let mut done = 0;
+        while done < data.len() { done += fs.write_at(ino, done as u64, &data[done..])?; }
+        created
+    };
+    created_or_modified(path, created);
+    Ok(())
+}
+
+/// Append to a file, creating it if it is missing.
+pub fn append_file(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let created = {
+        let mut mounts = MOUNTS.lock();
+        let (m, ino, created) = lookup_or_create(&mut mounts, path, true)?;
+        let fs = fs_mut(&mut mounts, m)?;
+        let st = fs.stat(ino)?;
+        if st.kind == FileType::Dir { return Err(FsError::IsDir); }
+        check(fs, ino, W_OK)?;
+        let mut done = 0;
+        while done < data.len() { done += fs.write_at(ino, st.size + done as u64, &data[done..])?; }
+        created
+    };
+    created_or_modified(path, created);
+    Ok(())
+}
+
+/// Cut a file down to, or zero-extend it to, `size` bytes.
+pub fn truncate(path: &str, size: u64) -> Result<(), FsError> {
+    {
+        let mut mounts = MOUNTS.lock();
+        let (m, ino) = resolve_in(&mut mounts, path, true)?;
+        let fs = fs_mut(&mut mounts, m)?;
+        check(fs, ino, W_OK)?;
+        fs.truncate(ino, size)?;
+    }
+    watch::emit(watch::MODIFY, path, None);
+    Ok(())
+}
+
+/// Set the permission bits; only the owner or root may.
+pub fn chmod(path: &str, mode: u16) -> Result<(), FsError> {
+    let mut mounts = MOUNTS.lock();
+    let (m, ino) = resolve_in(&mut mounts, path, true)?;
+    let fs = fs_mut(&mut mounts, m)?;
+    let c = creds();
+    if c.uid != 0 && fs.stat(ino)?.uid != c.uid { return Err(FsError::Denied); }
+    fs.chmod(ino, mode)
+}
+
+/// Give `path` a new owner and group; root only.
+pub fn chown(path: &str, uid: u32, gid: u32) -> Result<(), FsError> {
+    if creds().uid != 0 { return Err(FsError::Denied); }
+    let mut mounts = MOUNTS.lock();
+    let (m, ino) = resolve_in(&mut mounts, path, true)?;
+    fs_mut(&mut mounts, m)?.chown(ino, uid, gid)
+}
+
+/// Whether the calling task may access `path` for `want`, as access(2).
+pub fn access(path: &str, want: u16) -> Result<(), FsError> {
+    let mut mounts = MOUNTS.lock();
+    let (m, ino) = resolve_in(&mut mounts, path, true)?;
+    check(fs_mut(&mut mounts, m)?, ino, want)
+}
+
+/// Flush every mounted filesystem.
+pub fn sync() -> Result<(), FsError> {
+    for m in MOUNTS.lock().iter_mut().flatten() { m.fs.sync()?; }
+    Ok(())
+}
+
+// open(2) flags, same values as Linux so userlib can pass them straight through
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+pub const O_CREAT: u32 = 0x40;
+pub const O_TRUNC: u32 = 0x200;
+pub const O_APPEND: u32 = 0x400;
+
+/// Open `path` and return an open-file handle.
+pub fn open(path: &str, flags: u32) -> Result<usize, FsError> {
+    let (m, ino, created, truncated) = {
+        let mut mounts = MOUNTS.lock();
+        let (m, ino, created) = lookup_or_create(&mut mounts, path, flags & O_CREAT != 0)?;
+        let fs = fs_mut(&mut mounts, m)?;
+        let writable = flags & 3 != O_RDONLY;
+        if writable && fs.stat(ino)?.kind == FileType::Dir { return Err(FsError::IsDir); }
+        check(fs, ino, match flags & 3 { O_RDONLY => R_OK, O_WRONLY => W_OK, _ => R_OK | W_OK })?;
+        let truncated = writable && flags & O_TRUNC != 0;
+        if truncated { fs.truncate(ino, 0)?; }
+        (m, ino, created, truncated)
+    };
+    if created { watch::emit(watch::CREATE, path, None); }
+    let f = OpenFile { mount: m, ino, pos: 0, flags, refs: 1, path: normalize(path), written: truncated };
+    let mut open = OPEN.lock();
+    if let Some(i) = open.iter().position(|f| f.is_none()) { open[i] = Some(f); return Ok(i); }
+    open.push(Some(f));
+    Ok(open.len() - 1)
+}
+
+// Run `op` on open file `h` and the filesystem it lives on.
+fn with_file<T>(h: usize, op: impl FnOnce(&mut OpenFile, &mut dyn Filesystem) -> Result<T, FsError>) -> Result<T, FsError> {
+    let mut mounts = MOUNTS.lock();
+    let mut open = OPEN.lock();
+    let f = open.get_mut(h).and_then(|f| f.as_mut()).ok_or(FsError::BadHandle)?;
+    op(f, fs_mut(&mut mounts, f.mount)?)
+}
+
+pub fn read(h: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+    loop {
+        let r = with_file(h, |f, fs| {
+            if f.flags & 3 == O_WRONLY { return Err(FsError::BadHandle); }
+            let n = fs.read_at(f.ino, f.pos, buf)?;
+            f.pos += n as u64;
+            Ok(n)
+        });
+        // wait for the device with the locks dropped
+        if r != Err(FsError::WouldBlock) { return r; }
+        crate::scheduler::yield_now();
+    }
+}
+
+pub fn write(h: usize, data: &[u8]) -> Result<usize, FsError> {
+    with_file(h, |f, fs| {
+        if f.flags & 3 == O_RDONLY { return Err(FsError::BadHandle); }
+        if f.flags & O_APPEND != 0 { f.pos = fs.stat(f.ino)?.size; }
+        let n = fs.write_at(f.ino, f.pos, data)?;
+        f.pos += n as u64;
+        f.written = true;
+        Ok(n)
+    })
+}
+
+/// Read at `off` without touching the handle's position.
+pub fn read_at(h: usize, off: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+    with_file(h, |f, fs| {
+        if f.flags & 3 == O_WRONLY { return Err(FsError::BadHandle); }
+        fs.read_at(f.ino, off, buf)
+    })
+}
+
+/// Write at `off` without touching the handle's position; `O_APPEND` is ignored, as with pwrite.
+pub fn write_at(h: usize, off: u64, data: &[u8]) -> Result<usize, FsError> {
+    with_file(h, |f, fs| {
+        if f.flags & 3 == O_RDONLY { return Err(FsError::BadHandle); }
+        f.written = true;
+        fs.write_at(f.ino, off, data)
+    })
+}
+
+/// Move the handle's position; returns the new offset. Seeking past the end is allowed.
+pub fn seek(h: usize, pos: SeekFrom) -> Result<u64, FsError> {
+    with_file(h, |f, fs| {
+        let new = match pos {
+            SeekFrom::Start(n) => Some(n),
+            SeekFrom::Current(d) => f.pos.checked_add_signed(d),
+            SeekFrom::End(d) => fs.stat(f.ino)?.size.checked_add_signed(d),
+        };
+        f.pos = new.ok_or(FsError::Invalid)?;
+        Ok(f.pos)
+    })
+}
+
+pub fn fstat(h: usize) -> Result<Stat, FsError> { with_file(h, |f, fs| fs.stat(f.ino)) }
+
+/// The whole file behind `h`, see `FileView`.
+pub fn view(h: usize) -> Result<FileView, FsError> {
+    with_file(h, |f, fs| {
+        if f.flags & 3 == O_WRONLY { return Err(FsError::BadHandle); }
+        view_of(fs, f.ino)
+    })
+}
+
+/// The path `h` was opened with.
+pub fn handle_path(h: usize) -> Option<String> { OPEN.lock().get(h)?.as_ref().map(|f| f.path.clone()) }
+
+pub fn dup(h: usize) { if let Some(Some(f)) = OPEN.lock().get_mut(h) { f.refs += 1; } }
+
+pub fn close(h: usize) {
+    let done = {
+        let mut open = OPEN.lock();
+        let Some(slot) = open.get_mut(h) else { return };
+        let Some(f) = slot.as_mut() else { return };
+        f.refs -= 1;
+        if f.refs > 0 { return; }
+        slot.take()
+    };
+    if let Some(f) = done.filter(|f| f.written) { watch::emit(watch::MODIFY, &f.path, None); }
+}
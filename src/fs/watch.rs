@@ -0,0 +1,163 @@
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use super::vfs::FsError;
+
+// Change notification on paths, in the spirit of inotify. A watch on a directory reports
+// events for the entries directly inside it; a watch on anything else reports events for that
+// path only. Watches go by path rather than inode, so a file that is deleted and created again
+// (or renamed into place, as editors and `snapshot` do) keeps being watched.
+//
+// The VFS reports a change once it has dropped its locks. Tasks get events through a watch
+// fd that `read` blocks on; kernel code can subscribe a callback instead, which runs on the
+// task that made the change.
+
+// Event bits, same values in userlib
+pub const CREATE: u32 = 1 << 0;
+pub const MODIFY: u32 = 1 << 1; // whole-file writes, truncation, and the last close after writing
+pub const DELETE: u32 = 1 << 2;
+pub const RENAME: u32 = 1 << 3;
+pub const ALL: u32 = CREATE | MODIFY | DELETE | RENAME;
+
+/// Events a queue holds before the oldest are dropped.
+pub const MAX_QUEUED: usize = 128;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Event {
+    pub kind: u32,            // one of the event bits
+    pub path: String,         // the path created, changed or removed; the new name for RENAME
+    pub from: Option<String>, // the old name for RENAME
+}
+
+enum Sink { Queue(VecDeque<Event>), Callback(fn(&Event)) }
+
+struct Watch { path: String, mask: u32, refs: usize, sink: Sink }
+
+lazy_static! {
+    static ref WATCHES: Mutex<Vec<Option<Watch>>> = Mutex::new(Vec::new());
+}
+
+fn insert(w: Watch) -> usize {
+    let mut watches = WATCHES.lock();
+    match watches.iter().position(|w| w.is_none()) {
+        Some(i) => { watches[i] = Some(w); i }
+        None => { watches.push(Some(w)); watches.len() - 1 }
+    }
+}
+
+fn normalize(path: &str) -> String {
+    let mut s = String::new();
+    for p in path.split('/').filter(|p| !p.is_empty()) { s.push('/'); s.push_str(p); }
+    if s.is_empty() { "/".to_string() } else { s }
+}
+
+// `path` itself, or an entry directly inside it
+fn covers(watched: &str, path: &str) -> bool {
+    if path == watched { return true; }
+    match path.rfind('/') {
+        Some(0) => watched == "/" && path.len() > 1,
+        Some(i) => &path[..i] == watched,
+        None => false,
+    }
+}
+
+/// Start queueing `mask` events on `path` for the caller to `read`. The path must exist and be
+/// visible to the caller.
+pub fn add(path: &str, mask: u32) -> Result<usize, FsError> {
+    if mask & ALL == 0 { return Err(FsError::Invalid); }
+    super::vfs::stat(path)?;
+    Ok(insert(Watch { path: normalize(path), mask: mask & ALL, refs: 1, sink: Sink::Queue(VecDeque::new()) }))
+}
+
+/// Call `f` for every `mask` event on `path`, which need not exist yet. `f` runs after the
+/// VFS has dropped its locks, so it may use the filesystem itself.
+pub fn subscribe(path: &str, mask: u32, f: fn(&Event)) -> usize {
+    insert(Watch { path: normalize(path), mask: mask & ALL, refs: 1, sink: Sink::Callback(f) })
+}
+
+/// Take another reference, for a dup'd or inherited watch fd.
+pub fn dup(id: usize) { if let Some(Some(w)) = WATCHES.lock().get_mut(id) { w.refs += 1; } }
+
+/// Drop a reference; the watch and anything still queued go with the last one.
+pub fn close(id: usize) {
+    let mut watches = WATCHES.lock();
+    if let Some(slot) = watches.get_mut(id) {
+        if let Some(w) = slot.as_mut() { w.refs -= 1; if w.refs == 0 { *slot = None; } }
+    }
+}
+
+/// The next queued event, if there is one.
+pub fn try_next(id: usize) -> Result<Option<Event>, FsError> {
+    match WATCHES.lock().get_mut(id).and_then(|w| w.as_mut()).map(|w| &mut w.sink) {
+        Some(Sink::Queue(q)) => Ok(q.pop_front()),
+        _ => Err(FsError::BadHandle),
+    }
+}
+
+/// Wait for the next event.
+pub fn next(id: usize) -> Result<Event, FsError> {
+    loop {
+        if let Some(e) = try_next(id)? { return Ok(e); }
+        crate::scheduler::yield_now();
+    }
+}
+
+// kind, path length and old-path length as u32 LE, then the two paths
+fn encode(e: &Event, out: &mut [u8]) -> Option<usize> {
+    let from = e.from.as_deref().unwrap_or("");
+    let len = 12 + e.path.len() + from.len();
+    if len > out.len() { return None; }
+    out[0..4].copy_from_slice(&e.kind.to_le_bytes());
+    out[4..8].copy_from_slice(&(e.path.len() as u32).to_le_bytes());
+    out[8..12].copy_from_slice(&(from.len() as u32).to_le_bytes());
+    out[12..12 + e.path.len()].copy_from_slice(e.path.as_bytes());
+    out[12 + e.path.len()..len].copy_from_slice(from.as_bytes());
+    Some(len)
+}
+
+/// Wait for at least one event and pack as many whole events into `buf` as fit. A buffer too
+/// small for the first event is `Invalid` and leaves it queued.
+pub fn read(id: usize, buf: &mut [u8]) -> Result<usize, FsError> {
+    loop {
+        {
+            let mut watches = WATCHES.lock();
+            let Some(Sink::Queue(q)) = watches.get_mut(id).and_then(|w| w.as_mut()).map(|w| &mut w.sink) else { return Err(FsError::BadHandle) };
+            let mut n = 0;
+            while let Some(e) = q.front() {
+                let Some(len) = encode(e, &mut buf[n..]) else { break };
+                q.pop_front();
+                n += len;
+            }
+            if n > 0 { return Ok(n); }
+            if !q.is_empty() { return Err(FsError::Invalid); }
+        }
+        crate::scheduler::yield_now();
+    }
+}
+
+/// The watched path, for /proc/<pid>/fd.
+pub fn path(id: usize) -> Option<String> { WATCHES.lock().get(id)?.as_ref().map(|w| w.path.clone()) }
+
+/// Deliver `kind` on `path` (and `from`, for a rename) to every watch covering either.
+/// Called by the VFS without its locks held.
+pub fn emit(kind: u32, path: &str, from: Option<&str>) {
+    let e = Event { kind, path: normalize(path), from: from.map(normalize) };
+    let mut calls = Vec::new();
+    {
+        let mut watches = WATCHES.lock();
+        if watches.is_empty() { return; }
+        for w in watches.iter_mut().flatten() {
+            if w.mask & kind == 0 || !(covers(&w.path, &e.path) || e.from.as_deref().is_some_and(|f| covers(&w.path, f))) { continue; }
+            match &mut w.sink {
+                Sink::Queue(q) => {
+                    if q.len() == MAX_QUEUED { q.pop_front(); }
+                    q.push_back(e.clone());
+                }
+                Sink::Callback(f) => calls.push(*f),
+            }
+        }
+    }
+    for f in calls { f(&e); }
+}
@@ -60,6 +60,27 @@ pub fn clear_screen(color: Color) { fill_rect(0, 0, screen_width(), screen_heigh
 pub fn screen_width() -> usize { FB.lock().as_ref().map(|f| f.width).unwrap_or(0) }
 pub fn screen_height() -> usize { FB.lock().as_ref().map(|f| f.height).unwrap_or(0) }
 
+/// Size in bytes of the raw framebuffer (stride * height), 0 without one.
+pub fn fb_len() -> usize { FB.lock().as_ref().map_or(0, |f| f.buf.len()) }
+
+/// Copy raw framebuffer bytes at `off` into `buf`; returns the count copied.
+pub fn fb_read(off: usize, buf: &mut [u8]) -> usize {
+    with_fb(|fb| {
+        let n = buf.len().min(fb.buf.len().saturating_sub(off));
+        buf[..n].copy_from_slice(&fb.buf[off..off + n]);
+        n
+    }).unwrap_or(0)
+}
+
+/// Overwrite raw framebuffer bytes at `off`; returns the count written.
+pub fn fb_write(off: usize, data: &[u8]) -> usize {
+    with_fb(|fb| {
+        let n = data.len().min(fb.buf.len().saturating_sub(off));
+        fb.buf[off..off + n].copy_from_slice(&data[..n]);
+        n
+    }).unwrap_or(0)
+}
+
 pub fn put_pixel(x: usize, y: usize, c: Color) {
     let _ = with_fb(|fb| {
         if x >= fb.width || y >= fb.height { return; }
@@ -50,3 +50,9 @@ unsafe impl GlobalAlloc for WaemomAlloc {
 static mut HEAP: [u8; 512 * 1024] = [0; 512 * 1024];
 
 pub fn init() { BUMP.lock().init(); }
+
+/// (bytes handed out, heap size). Nothing is ever freed, so this only grows.
+pub fn stats() -> (usize, usize) {
+    let b = BUMP.lock();
+    (b.next - b.start, unsafe { HEAP.len() })
+}
@@ -17,6 +17,11 @@ pub fn draw_image(x: usize, y: usize, img: &Image) {
     }
 }
 
+/// Decode a PPM file straight out of the filesystem without copying it first.
+pub fn load_ppm(path: &str) -> Option<Image> {
+    parse_ppm_p3(&crate::fs::view(path).ok()?)
+}
+
 // Minimal PPM P3 (ASCII) parser: "P3\n<width> <height>\n255\n" then ASCII triples
 pub fn parse_ppm_p3(bytes: &[u8]) -> Option<Image> {
     let s = core::str::from_utf8(bytes).ok()?;
@@ -1,5 +1,6 @@
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
+use core::sync::atomic::{AtomicU64, Ordering};
 use spin::Mutex;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use x86_64::instructions::{interrupts, port::Port};
@@ -24,9 +25,48 @@ lazy_static! { static ref IDT: InterruptDescriptorTable = {
     idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
     idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
     idt[InterruptIndex::Syscall.as_usize()].set_handler_fn(syscall_interrupt_handler);
+    idt[PIC_1_OFFSET as usize + 9].set_handler_fn(irq9_handler);
+    idt[PIC_1_OFFSET as usize + 10].set_handler_fn(irq10_handler);
+    idt[PIC_1_OFFSET as usize + 11].set_handler_fn(irq11_handler);
     idt
 }; }
 
+// Drivers for PCI devices hook the legacy lines the firmware routed them to (9-11 under QEMU).
+static IRQ_HANDLERS: Mutex<[Option<fn()>; 16]> = Mutex::new([None; 16]);
+
+/// Install `f` for PIC line `irq` and unmask it. Only lines 9-11 have IDT entries.
+pub fn register_irq(irq: u8, f: fn()) -> bool {
+    if !(9..=11).contains(&irq) { return false; }
+    interrupts::without_interrupts(|| {
+        IRQ_HANDLERS.lock()[irq as usize] = Some(f);
+        unsafe {
+            // unmask on the slave PIC and the cascade line on the master
+            let mut slave: Port<u8> = Port::new(0xA1);
+            let m = slave.read();
+            slave.write(m & !(1 << (irq - 8)));
+            let mut master: Port<u8> = Port::new(0x21);
+            let m = master.read();
+            master.write(m & !(1 << 2));
+        }
+    });
+    true
+}
+
+// Interrupts taken per PIC line, for /proc/interrupts.
+static COUNTS: [AtomicU64; 16] = [const { AtomicU64::new(0) }; 16];
+
+fn count(irq: u8) { COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed); }
+
+/// Interrupts taken so far on each PIC line.
+pub fn counts() -> [u64; 16] { core::array::from_fn(|i| COUNTS[i].load(Ordering::Relaxed)) }
+
+fn dispatch_irq(irq: u8) {
+    count(irq);
+    let handler = IRQ_HANDLERS.lock()[irq as usize];
+    if let Some(f) = handler { f(); }
+    unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq); }
+}
+
 pub fn init() {
     unsafe { PICS.lock().initialize() };
     IDT.load();
@@ -40,11 +80,13 @@ pub fn notify_end_of_interrupt(idx: InterruptIndex) {
 lazy_static! { pub static ref PICS: Mutex<ChainedPics> = Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) }); }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack: InterruptStackFrame) {
+    count(0);
     crate::pit::tick();
     notify_end_of_interrupt(InterruptIndex::Timer);
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack: InterruptStackFrame) {
+    count(1);
     let mut port: Port<u8> = Port::new(0x60);
     let scancode: u8 = unsafe { port.read() };
     crate::keyboard::on_scancode(scancode);
@@ -52,6 +94,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack: InterruptStackFrame
 }
 
 extern "x86-interrupt" fn mouse_interrupt_handler(_stack: InterruptStackFrame) {
+    count(12);
     crate::mouse::on_irq();
     notify_end_of_interrupt(InterruptIndex::Mouse);
 }
@@ -59,3 +102,7 @@ extern "x86-interrupt" fn mouse_interrupt_handler(_stack: InterruptStackFrame) {
 extern "x86-interrupt" fn syscall_interrupt_handler(_stack: InterruptStackFrame) {
     crate::syscalls::handle();
 }
+
+extern "x86-interrupt" fn irq9_handler(_stack: InterruptStackFrame) { dispatch_irq(9); }
+extern "x86-interrupt" fn irq10_handler(_stack: InterruptStackFrame) { dispatch_irq(10); }
+extern "x86-interrupt" fn irq11_handler(_stack: InterruptStackFrame) { dispatch_irq(11); }
@@ -0,0 +1,353 @@
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use bitflags::bitflags;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use crate::scheduler;
+
+/// Largest payload a single message may carry.
+pub const MAX_MSG: usize = 4096;
+
+bitflags! {
+    #[derive(Clone, Copy, PartialEq, Eq)]
+    pub struct Rights: u8 {
+        const SEND = 1 << 0;
+        const RECV = 1 << 1;
+        const NOTIFY = 1 << 2;
+    }
+}
+
+/// A capability held in a task's handle table. `gen` is the port slot's generation when the
+/// capability was made, so one outliving its endpoint never reaches a later one in that slot.
+#[derive(Clone, Copy)]
+pub struct Cap { pub port: usize, pub gen: u64, pub rights: Rights }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IpcError { BadHandle, NoRights, NotFound, Exists, Closed, TooLarge }
+
+impl IpcError {
+    pub fn code(self) -> isize {
+        match self {
+            IpcError::BadHandle => -1,
+            IpcError::NoRights => -2,
+            IpcError::NotFound => -3,
+            IpcError::Exists => -4,
+            IpcError::Closed => -5,
+            IpcError::TooLarge => -6,
+        }
+    }
+}
+
+impl From<IpcError> for isize {
+    fn from(e: IpcError) -> isize { e.code() }
+}
+
+pub struct Message {
+    pub id: u64,
+    pub sender: u64,
+    pub data: Vec<u8>,
+    pub needs_reply: bool,
+}
+
+struct Endpoint {
+    name: String,
+    owner: u64,
+    queue: VecDeque<Message>,
+    notify_bits: u64,
+}
+
+// A port slot; `gen` goes up each time the slot gets a new endpoint.
+#[derive(Default)]
+struct Slot { gen: u64, ep: Option<Endpoint> }
+
+// A call by `caller` waiting for its reply on `port` (slot and generation). Once the message is
+// received `receiver` says who got it, and only that task may fill in `reply`.
+struct Pending { id: u64, caller: u64, port: (usize, u64), receiver: Option<u64>, reply: Option<Vec<u8>>, dead: bool }
+
+lazy_static! {
+    static ref PORTS: Mutex<Vec<Slot>> = Mutex::new(Vec::new());
+    static ref PENDING: Mutex<Vec<Pending>> = Mutex::new(Vec::new());
+}
+
+static NEXT_MSG: core::sync::atomic::AtomicU64 = core::sync::atomic::AtomicU64::new(1);
+
+fn cap(handle: usize, need: Rights) -> Result<Cap, IpcError> {
+    let c = scheduler::with_current(|t| t.caps.get(handle).copied().flatten()).flatten().ok_or(IpcError::BadHandle)?;
+    if !c.rights.contains(need) { return Err(IpcError::NoRights); }
+    Ok(c)
+}
+
+// The endpoint `c` refers to, unless it has been closed since.
+fn endpoint(ports: &mut [Slot], c: Cap) -> Result<&mut Endpoint, IpcError> {
+    ports.get_mut(c.port).filter(|s| s.gen == c.gen).and_then(|s| s.ep.as_mut()).ok_or(IpcError::Closed)
+}
+
+fn install(c: Cap) -> Result<usize, IpcError> {
+    scheduler::with_current(|t| t.install_cap(c)).ok_or(IpcError::BadHandle)
+}
+
+/// Register a named endpoint owned by the current task. The returned handle has all rights.
+pub fn create(name: &str) -> Result<usize, IpcError> {
+    let owner = scheduler::current_pid().unwrap_or(0);
+    let (port, gen) = {
+        let mut ports = PORTS.lock();
+        if ports.iter().filter_map(|s| s.ep.as_ref()).any(|p| p.name == name) { return Err(IpcError::Exists); }
+        let i = ports.iter().position(|s| s.ep.is_none()).unwrap_or_else(|| { ports.push(Slot::default()); ports.len() - 1 });
+        let slot = &mut ports[i];
+        slot.gen += 1;
+        slot.ep = Some(Endpoint { name: name.to_string(), owner, queue: VecDeque::new(), notify_bits: 0 });
+        (i, slot.gen)
+    };
+    install(Cap { port, gen, rights: Rights::all() })
+}
+
+/// Look up an endpoint by name and get a send/notify handle to it.
+pub fn open(name: &str) -> Result<usize, IpcError> {
+    let (port, gen) = PORTS.lock().iter().enumerate()
+        .find(|(_, s)| s.ep.as_ref().is_some_and(|p| p.name == name))
+        .map(|(i, s)| (i, s.gen)).ok_or(IpcError::NotFound)?;
+    install(Cap { port, gen, rights: Rights::SEND | Rights::NOTIFY })
+}
+
+fn enqueue(c: Cap, data: &[u8], needs_reply: bool) -> Result<u64, IpcError> {
+    if data.len() > MAX_MSG { return Err(IpcError::TooLarge); }
+    let id = NEXT_MSG.fetch_add(1, core::sync::atomic::Ordering::Relaxed);
+    let sender = scheduler::current_pid().unwrap_or(0);
+    let mut ports = PORTS.lock();
+    endpoint(&mut ports, c)?.queue.push_back(Message { id, sender, data: data.to_vec(), needs_reply });
+    Ok(id)
+}
+
+/// One-way message; returns as soon as it is queued.
+pub fn send(handle: usize, data: &[u8]) -> Result<(), IpcError> {
+    let c = cap(handle, Rights::SEND)?;
+    enqueue(c, data, false).map(|_| ())
+}
+
+/// Send and block until the receiver replies. The reply is copied into `reply`.
+pub fn call(handle: usize, data: &[u8], reply: &mut [u8]) -> Result<usize, IpcError> {
+    let c = cap(handle, Rights::SEND)?;
+    let id = {
+        // register before queuing so a fast reply is never lost
+        let mut pending = PENDING.lock();
+        let id = enqueue(c, data, true)?;
+        let caller = scheduler::current_pid().unwrap_or(0);
+        pending.push(Pending { id, caller, port: (c.port, c.gen), receiver: None, reply: None, dead: false });
+        id
+    };
+    loop {
+        {
+            let mut pending = PENDING.lock();
+            if let Some(i) = pending.iter().position(|p| p.id == id && (p.reply.is_some() || p.dead)) {
+                let p = pending.swap_remove(i);
+                let Some(r) = p.reply else { return Err(IpcError::Closed) };
+                let n = r.len().min(reply.len());
+                reply[..n].copy_from_slice(&r[..n]);
+                return Ok(n);
+            }
+        }
+        scheduler::yield_now();
+    }
+}
+
+/// Block until a message arrives on an endpoint we hold RECV rights for.
+pub fn recv(handle: usize) -> Result<Message, IpcError> {
+    let c = cap(handle, Rights::RECV)?;
+    let m = loop {
+        let m = endpoint(&mut PORTS.lock(), c)?.queue.pop_front();
+        if let Some(m) = m { break m; }
+        scheduler::yield_now();
+    };
+    if m.needs_reply {
+        let me = scheduler::current_pid().unwrap_or(0);
+        if let Some(p) = PENDING.lock().iter_mut().find(|p| p.id == m.id) { p.receiver = Some(me); }
+    }
+    Ok(m)
+}
+
+/// Answer a message received with `needs_reply` set. Only the task that received it may.
+pub fn reply(msg_id: u64, data: &[u8]) -> Result<(), IpcError> {
+    if data.len() > MAX_MSG { return Err(IpcError::TooLarge); }
+    let me = scheduler::current_pid().unwrap_or(0);
+    let mut pending = PENDING.lock();
+    let p = pending.iter_mut().find(|p| p.id == msg_id && p.receiver == Some(me) && p.reply.is_none() && !p.dead).ok_or(IpcError::NotFound)?;
+    p.reply = Some(data.to_vec());
+    Ok(())
+}
+
+/// Asynchronously OR `bits` into the endpoint's notification word.
+pub fn notify(handle: usize, bits: u64) -> Result<(), IpcError> {
+    let c = cap(handle, Rights::NOTIFY)?;
+    let mut ports = PORTS.lock();
+    endpoint(&mut ports, c)?.notify_bits |= bits;
+    Ok(())
+}
+
+/// Block until any notification bit is set, then return and clear them.
+pub fn wait(handle: usize) -> Result<u64, IpcError> {
+    let c = cap(handle, Rights::RECV)?;
+    loop {
+        {
+            let mut ports = PORTS.lock();
+            let ep = endpoint(&mut ports, c)?;
+            if ep.notify_bits != 0 { return Ok(core::mem::take(&mut ep.notify_bits)); }
+        }
+        scheduler::yield_now();
+    }
+}
+
+/// Drop a handle. Closing the owner's RECV handle tears the endpoint down.
+pub fn close(handle: usize) -> Result<(), IpcError> {
+    let c = cap(handle, Rights::empty())?;
+    scheduler::with_current(|t| t.caps[handle] = None);
+    if c.rights.contains(Rights::RECV) { destroy(c.port, c.gen); }
+    Ok(())
+}
+
+// Wake every caller whose call `dead` matches and that has no reply yet.
+fn fail_pending(dead: impl Fn(&Pending) -> bool) {
+    for p in PENDING.lock().iter_mut().filter(|p| p.reply.is_none() && dead(p)) { p.dead = true; }
+}
+
+fn destroy(port: usize, gen: u64) {
+    let ep = PORTS.lock().get_mut(port).filter(|s| s.gen == gen).and_then(|s| s.ep.take());
+    // queued or already received, no reply is coming through this endpoint any more
+    if ep.is_some() { fail_pending(|p| p.port == (port, gen)); }
+}
+
+/// Tear down every endpoint owned by `pid`, fail the calls it received but never answered and
+/// forget the calls it was still waiting on; called when a task exits.
+pub fn release_task(pid: u64) {
+    let owned: Vec<(usize, u64)> = PORTS.lock().iter().enumerate()
+        .filter(|(_, s)| s.ep.as_ref().is_some_and(|p| p.owner == pid))
+        .map(|(i, s)| (i, s.gen)).collect();
+    for (port, gen) in owned { destroy(port, gen); }
+    fail_pending(|p| p.receiver == Some(pid));
+    PENDING.lock().retain(|p| p.caller != pid);
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+    use std::thread;
+
+    // Ports are global and tests run in parallel, so every test uses its own names and pids.
+
+    #[test]
+    fn send_and_receive() {
+        scheduler::set_pid(100);
+        let h = create("test.send").unwrap();
+        assert_eq!(create("test.send").err(), Some(IpcError::Exists));
+        let s = open("test.send").unwrap();
+        send(s, b"one").unwrap();
+        send(s, b"two").unwrap();
+        let m = recv(h).unwrap();
+        assert_eq!((m.data.as_slice(), m.sender, m.needs_reply), (&b"one"[..], 100, false));
+        assert_eq!(recv(h).unwrap().data, b"two");
+        assert_eq!(send(s, &[0u8; MAX_MSG + 1]).err(), Some(IpcError::TooLarge));
+        assert_eq!(open("test.nobody").err(), Some(IpcError::NotFound));
+        close(h).unwrap();
+    }
+
+    #[test]
+    fn handles_carry_rights() {
+        scheduler::set_pid(110);
+        let h = create("test.rights").unwrap();
+        let s = open("test.rights").unwrap();
+        assert_eq!(recv(s).err(), Some(IpcError::NoRights));
+        assert_eq!(wait(s).err(), Some(IpcError::NoRights));
+        notify(s, 0b101).unwrap();
+        notify(s, 0b010).unwrap();
+        assert_eq!(wait(h).unwrap(), 0b111);
+        close(s).unwrap();
+        assert_eq!(send(s, b"x").err(), Some(IpcError::BadHandle));
+        assert_eq!(send(9999, b"x").err(), Some(IpcError::BadHandle));
+        close(h).unwrap();
+    }
+
+    #[test]
+    fn call_and_reply() {
+        scheduler::set_pid(120);
+        let h = create("test.call").unwrap();
+        let client = thread::spawn(|| {
+            scheduler::set_pid(121);
+            let s = open("test.call").unwrap();
+            let mut buf = [0u8; 4];
+            let n = call(s, b"ping", &mut buf).unwrap();
+            buf[..n].to_vec()
+        });
+        let m = recv(h).unwrap();
+        assert!(m.needs_reply);
+        assert_eq!((m.data.as_slice(), m.sender), (&b"ping"[..], 121));
+        // another task can't answer it, and it can only be answered once
+        let id = m.id;
+        assert_eq!(thread::spawn(move || { scheduler::set_pid(122); reply(id, b"evil") }).join().unwrap(), Err(IpcError::NotFound));
+        reply(m.id, b"pong!").unwrap(); // cut to the caller's buffer
+        assert_eq!(reply(m.id, b"again"), Err(IpcError::NotFound));
+        assert_eq!(client.join().unwrap(), b"pong");
+        close(h).unwrap();
+    }
+
+    #[test]
+    fn stale_handle_does_not_reach_a_reused_slot() {
+        scheduler::set_pid(130);
+        let h = create("test.stale").unwrap();
+        let s = open("test.stale").unwrap();
+        close(h).unwrap();
+        assert_eq!(send(s, b"x").err(), Some(IpcError::Closed));
+        let h2 = create("test.stale").unwrap();
+        assert_eq!(send(s, b"x").err(), Some(IpcError::Closed));
+        send(open("test.stale").unwrap(), b"y").unwrap();
+        assert_eq!(recv(h2).unwrap().data, b"y");
+        close(h2).unwrap();
+    }
+
+    // a caller blocked on a call gets Closed once no reply can come, whether or not the
+    // message was received
+    #[test]
+    fn calls_fail_when_the_port_goes() {
+        for (name, pid, received, exit) in [("test.queued", 140, false, false), ("test.received", 150, true, false), ("test.exited", 160, true, true)] {
+            scheduler::set_pid(pid);
+            let h = create(name).unwrap();
+            let client = thread::spawn(move || {
+                scheduler::set_pid(pid + 1);
+                let s = open(name).unwrap();
+                call(s, b"hi", &mut [0u8; 4])
+            });
+            if received {
+                let _ = recv(h).unwrap();
+            } else {
+                while endpoint(&mut PORTS.lock(), cap(h, Rights::RECV).unwrap()).unwrap().queue.is_empty() { thread::yield_now(); }
+            }
+            if exit { release_task(pid); } else { close(h).unwrap(); }
+            assert_eq!(client.join().unwrap(), Err(IpcError::Closed), "{}", name);
+            assert_eq!(open(name).err(), Some(IpcError::NotFound));
+        }
+    }
+
+    // a caller that exits mid-call leaves nothing behind, and its message can't be answered
+    #[test]
+    fn exited_caller_is_forgotten() {
+        scheduler::set_pid(170);
+        let h = create("test.gone").unwrap();
+        scheduler::set_pid(171);
+        let s = open("test.gone").unwrap();
+        // what `call` does before it blocks; the task then exits instead of waiting
+        let id = {
+            let mut pending = PENDING.lock();
+            let c = cap(s, Rights::SEND).unwrap();
+            let id = enqueue(c, b"bye", true).unwrap();
+            pending.push(Pending { id, caller: 171, port: (c.port, c.gen), receiver: None, reply: None, dead: false });
+            id
+        };
+        release_task(171);
+        assert!(!PENDING.lock().iter().any(|p| p.id == id));
+        scheduler::set_pid(170);
+        let m = recv(h).unwrap();
+        assert_eq!((m.id, m.sender), (id, 171));
+        assert_eq!(reply(m.id, b"late"), Err(IpcError::NotFound));
+        close(h).unwrap();
+    }
+}
@@ -8,6 +8,7 @@ pub mod serial;
 pub mod vga_buffer;
 pub mod window;
 pub mod fs;
+pub mod bcache;
 pub mod elf;
 pub mod heap;
 pub mod apps;
@@ -18,12 +19,25 @@ pub mod appmgr;
 pub mod loader;
 pub mod net { pub mod wifi; pub mod ip; pub mod crypto; pub mod e1000; pub mod netstack; pub use super::net::*; }
 pub mod pci;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod ahci;
+pub mod partition;
 pub mod interrupts;
 pub mod pit;
+pub mod rtc;
+pub mod sha256;
+pub mod compress;
+pub mod users;
 pub mod keyboard;
 pub mod syscalls;
+pub mod uaccess;
 pub mod tty;
 pub mod shell;
+pub mod pipe;
+pub mod fd;
+pub mod ipc;
+pub mod strace;
 pub mod gdt;
 pub mod context;
 pub mod task;
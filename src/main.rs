@@ -9,6 +9,7 @@ mod graphics;
 mod window;
 mod fs;
 mod block;
+mod bcache;
 mod ata;
 mod elf;
 mod heap;
@@ -19,8 +20,16 @@ mod appmgr;
 mod loader;
 mod net { pub mod wifi; pub mod ip; pub mod crypto; pub mod e1000; pub mod netstack; pub use super::net::*; }
 mod pci;
+mod virtio;
+mod virtio_blk;
+mod ahci;
+mod partition;
 mod interrupts;
 mod pit;
+mod rtc;
+mod sha256;
+mod compress;
+mod users;
 mod keyboard;
 mod mouse;
 mod console;
@@ -28,7 +37,12 @@ mod gdt;
 mod syscalls;
 mod tty;
 mod shell;
+mod pipe;
+mod fd;
+mod ipc;
+mod strace;
 mod mm;
+mod uaccess;
 mod elfloader;
 mod context;
 mod task;
@@ -53,12 +67,14 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // Initialize interrupts (PIC, IDT), PIT, keyboard, mouse
     interrupts::init();
     pit::init(100); // 100 Hz
+    rtc::init();
     mouse::init();
 
     // Init scheduler
     scheduler::init();
     // Start shell task
     scheduler::spawn_kernel("shell", crate::shell::shell_task);
+    scheduler::spawn_kernel("bcache-flush", crate::bcache::flusher_task);
 
     // Init memory management (frames/page tables)
     mm::init(boot_info);
@@ -67,13 +83,29 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     if let Some(_) = graphics::init(boot_info) {
         graphics::clear_screen(graphics::Color::rgb(32, 34, 36));
 
-        // Initialize in-memory filesystem and demo files
-        fs::init();
+        // Populate the root filesystem from the built-in initrd and any bootloader ramdisk
+        let ramdisk = boot_info.ramdisk_addr.into_option()
+            .map(|addr| unsafe { core::slice::from_raw_parts(addr as *const u8, boot_info.ramdisk_len as usize) });
+        fs::init(ramdisk);
+
+        // Probe disks, register their partitions and mount any FAT/ext2 volumes under /mnt
+        crate::ata::init();
+        crate::virtio_blk::init();
+        crate::ahci::init();
+        crate::partition::init();
+        for path in fs::automount() {
+            crate::console::println(&alloc::format!("{}:", path));
+            for e in fs::list(&path).unwrap_or_default() { crate::console::println(e.as_str()); }
+        }
 
-        // Load settings from waemon.lock if present
-        if let Ok(lock) = fs::read("/waemon.lock") {
-            if let Ok(s) = core::str::from_utf8(&lock) { settings::load_from_lock(s); }
+        // Bring back what was saved last session, before anything reads its settings
+        match fs::snapshot::restore() {
+            Ok((volume, s)) => serial_println!("snapshot: restored {} files, {} dirs from {}", s.files, s.dirs, volume),
+            Err(e) => serial_println!("snapshot: none restored ({:?})", e),
         }
+
+        // Load settings from waemon.lock if present; the Settings window reloads it on change
+        settings::reload();
         let cfg = settings::current();
 
         // Optional loading animation
@@ -107,7 +139,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
         // Linux ELF inspection (controlled by settings)
         if cfg.elf_enabled {
-            if let Some(bytes) = fs::read("/bin/hello").ok() {
+            if let Ok(bytes) = fs::view("/bin/hello") {
                 let slice = &bytes[..bytes.len().min(cfg.elf_max_bytes)];
                 let info = elf::inspect_elf64(slice);
                 window::open_window_icon_animated(220, 180, 460, 220, "ELF64 Inspector", &info, 18, ui::icons::icon_files());
@@ -126,14 +158,6 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         let net_view = apps::network::view();
         window::open_window_icon_animated(980, 320, 420, 160, "Network", &net_view, 14, ui::icons::icon_task());
 
-        // Try mount FAT root (if available) and list entries to console
-        let mut ata = crate::block::AtaDevice::new();
-        let entries = crate::fs::fat::list_root(&mut ata);
-        if !entries.is_empty() {
-            crate::console::println("FAT root:");
-            for e in entries { crate::console::println(e.as_str()); }
-        }
-
         // Broom browser demo (also register in app manager with states)
         apps::broom::launch_demo();
 
@@ -141,6 +165,8 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         use appmgr::{spawn, set_state, WindowState};
         let id_notes = spawn("Notes", 20, 30, 420, 200, ui::icons::icon_notes(), notes.to_string());
         let id_files = spawn("Files", 460, 60, 420, 220, ui::icons::icon_files(), files.clone());
+        apps::follow_files(id_files);
+        apps::settings::launch(80, 320, 420, 180);
         set_state(id_notes, WindowState::Minimized);
         set_state(id_files, WindowState::Maximized);
 
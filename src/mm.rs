@@ -45,6 +45,21 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAlloc {
     }
 }
 
+impl BootInfoFrameAlloc {
+    /// Take `count` physically contiguous frames, skipping (and leaking) any that break the run.
+    fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let mut start = self.next;
+        while start + count <= self.frames.len() {
+            let base = self.frames[start].start_address().as_u64();
+            match (1..count).find(|&i| self.frames[start + i].start_address().as_u64() != base + i as u64 * 4096) {
+                Some(i) => start += i,
+                None => { self.next = start + count; return Some(self.frames[start]); }
+            }
+        }
+        None
+    }
+}
+
 pub static ref_frame_alloc: () = ();
 lazy_static! { pub static ref FRAME_ALLOC: Mutex<Option<BootInfoFrameAlloc>> = Mutex::new(None); }
 
@@ -63,6 +78,11 @@ fn init_frame_alloc(boot_info: &'static BootInfo) {
     *FRAME_ALLOC.lock() = Some(BootInfoFrameAlloc{ next: 0, frames });
 }
 
+/// (usable frames, frames handed out) from the boot frame allocator.
+pub fn frame_stats() -> (usize, usize) {
+    FRAME_ALLOC.lock().as_ref().map_or((0, 0), |fa| (fa.frames.len(), fa.next))
+}
+
 pub fn mapper<'a>() -> Option<&'a mut OffsetPageTable<'a>> {
     unsafe { MAPPER.as_mut() }
 }
@@ -116,6 +136,68 @@ pub fn map_user_region(cr3: u64, vaddr: u64, len: usize, writable: bool) -> Opti
     Some(frames)
 }
 
+/// User-accessible ranges in the lower half of `cr3` as (start, end, writable), adjacent pages merged.
+pub fn user_mappings(cr3: u64) -> Vec<(u64, u64, bool)> {
+    let mut out: Vec<(u64, u64, bool)> = Vec::new();
+    let mut add = |start: u64, len: u64, flags: PTF| {
+        let w = flags.contains(PTF::WRITABLE);
+        match out.last_mut() {
+            Some(last) if last.1 == start && last.2 == w => last.1 += len,
+            _ => out.push((start, start + len, w)),
+        }
+    };
+    let table = |pa: PhysAddr| unsafe { &*phys_to_virt(pa).as_ptr::<PageTable>() };
+    let user = |f: PTF| f.contains(PTF::PRESENT | PTF::USER_ACCESSIBLE);
+    let l4 = table(PhysAddr::new(cr3));
+    for i4 in 0..256 {
+        if !user(l4[i4].flags()) { continue; }
+        let l3 = table(l4[i4].addr());
+        for i3 in 0..512 {
+            let (e3, va3) = (&l3[i3], (i4 as u64) << 39 | (i3 as u64) << 30);
+            if !user(e3.flags()) { continue; }
+            if e3.flags().contains(PTF::HUGE_PAGE) { add(va3, 1 << 30, e3.flags()); continue; }
+            let l2 = table(e3.addr());
+            for i2 in 0..512 {
+                let (e2, va2) = (&l2[i2], va3 | (i2 as u64) << 21);
+                if !user(e2.flags()) { continue; }
+                if e2.flags().contains(PTF::HUGE_PAGE) { add(va2, 1 << 21, e2.flags()); continue; }
+                let l1 = table(e2.addr());
+                for i1 in 0..512 {
+                    if user(l1[i1].flags()) { add(va2 | (i1 as u64) << 12, 4096, l1[i1].flags()); }
+                }
+            }
+        }
+    }
+    out
+}
+
+/// Whether every page of `addr..addr + len` is mapped user-accessible (and writable, if `write`)
+/// in the active address space. Every level of the walk has to allow it, not just the leaf.
+pub fn user_range_mapped(addr: u64, len: u64, write: bool) -> bool {
+    use x86_64::registers::control::Cr3;
+    let need = PTF::PRESENT | PTF::USER_ACCESSIBLE | if write { PTF::WRITABLE } else { PTF::empty() };
+    let table = |pa: PhysAddr| unsafe { &*phys_to_virt(pa).as_ptr::<PageTable>() };
+    let l4 = table(Cr3::read().0.start_address());
+    let (mut va, end) = (addr & !4095, addr + len);
+    while va < end {
+        let idx = |shift: u32| ((va >> shift) & 511) as usize;
+        let e4 = &l4[idx(39)];
+        if !e4.flags().contains(need) { return false; }
+        let e3 = &table(e4.addr())[idx(30)];
+        if !e3.flags().contains(need) { return false; }
+        let step: u64 = if e3.flags().contains(PTF::HUGE_PAGE) { 1 << 30 } else {
+            let e2 = &table(e3.addr())[idx(21)];
+            if !e2.flags().contains(need) { return false; }
+            if e2.flags().contains(PTF::HUGE_PAGE) { 1 << 21 } else {
+                if !table(e2.addr())[idx(12)].flags().contains(need) { return false; }
+                4096
+            }
+        };
+        va = (va & !(step - 1)) + step;
+    }
+    true
+}
+
 pub fn map_user_stack(cr3: u64, top: u64, pages: usize) -> Option<u64> {
     let size = pages * 4096;
     let base = top - size as u64;
@@ -126,3 +208,35 @@ pub fn map_user_stack(cr3: u64, top: u64, pages: usize) -> Option<u64> {
 pub fn alloc_user_space() -> Option<u64> {
     create_user_pml4()
 }
+
+/// Zeroed, physically contiguous memory for device DMA. Returns (physical, virtual) addresses.
+pub fn alloc_dma(pages: usize) -> Option<(u64, *mut u8)> {
+    let frame = FRAME_ALLOC.lock().as_mut()?.allocate_contiguous(pages)?;
+    let phys = frame.start_address().as_u64();
+    let virt = phys_to_virt(frame.start_address()).as_mut_ptr::<u8>();
+    unsafe { core::ptr::write_bytes(virt, 0, pages * 4096); }
+    Some((phys, virt))
+}
+
+// Device registers get their own window so they can be mapped uncached.
+const MMIO_BASE: u64 = 0xFFFF_A000_0000_0000;
+static MMIO_NEXT: Mutex<u64> = Mutex::new(MMIO_BASE);
+
+/// Map `len` bytes of device memory at `phys` uncached into the kernel address space.
+pub fn map_mmio(phys: u64, len: usize) -> Option<*mut u8> {
+    let start = phys & !0xFFF;
+    let pages = ((phys - start) as usize + len + 4095) / 4096;
+    let mut next = MMIO_NEXT.lock();
+    let virt = *next;
+    let mut alloc = FRAME_ALLOC.lock();
+    let fa = alloc.as_mut()?;
+    let mapper = mapper()?;
+    let flags = PTF::PRESENT | PTF::WRITABLE | PTF::NO_CACHE | PTF::WRITE_THROUGH | PTF::NO_EXECUTE;
+    for i in 0..pages as u64 {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(virt + i * 4096));
+        let frame = PhysFrame::containing_address(PhysAddr::new(start + i * 4096));
+        unsafe { mapper.map_to(page, frame, flags, fa).ok()?.flush(); }
+    }
+    *next += pages as u64 * 4096;
+    Some((virt + (phys - start)) as *mut u8)
+}
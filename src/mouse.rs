@@ -5,9 +5,9 @@ use spin::Mutex;
 
 lazy_static! { static ref MOUSE: Mutex<Mouse> = Mutex::new(Mouse::new()); }
 
-pub struct Mouse { x: i32, y: i32, byte_idx: u8, packet: [u8;3] }
+pub struct Mouse { x: i32, y: i32, byte_idx: u8, packet: [u8;3], packets: heapless::Deque<[u8; 3], 32> }
 impl Mouse {
-    const fn new() -> Self { Self { x: 100, y: 100, byte_idx: 0, packet: [0;3] } }
+    const fn new() -> Self { Self { x: 100, y: 100, byte_idx: 0, packet: [0;3], packets: heapless::Deque::new() } }
 }
 
 pub fn init() {
@@ -50,10 +50,19 @@ pub fn on_irq() {
             let dy = (ms.packet[2] as i8) as i32;
             ms.x = (ms.x + dx).max(0);
             ms.y = (ms.y - dy).max(0);
+            // keep the newest packets for /dev/input/mouse readers
+            let p = ms.packet;
+            if ms.packets.is_full() { ms.packets.pop_front(); }
+            let _ = ms.packets.push_back(p);
         }
     }
 }
 
+/// Oldest raw 3-byte PS/2 packet not yet read, if any.
+pub fn read_packet() -> Option<[u8; 3]> {
+    x86_64::instructions::interrupts::without_interrupts(|| MOUSE.lock().packets.pop_front())
+}
+
 pub fn draw_cursor() {
     use crate::graphics::{self, Color};
     let ms = MOUSE.lock();
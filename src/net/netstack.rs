@@ -5,7 +5,9 @@ use smoltcp::socket::{udp, tcp};
 use smoltcp::time::Instant;
 use lazy_static::lazy_static;
 use spin::Mutex;
+use alloc::collections::BTreeMap;
 use alloc::{vec, vec::Vec};
+use alloc::string::{String, ToString};
 
 // Loopback-like PHY: tx goes to rx buffer
 pub struct LoopPhy { rx: heapless::Vec<heapless::Vec<u8, 1536>, 4> }
@@ -86,6 +88,9 @@ pub struct NetStack<'a> {
 
 lazy_static! { pub static ref NET: Mutex<Option<NetStack<'static>>> = Mutex::new(None); }
 
+// Open fds per user UDP socket; the socket goes when the last one is closed.
+lazy_static! { static ref UDP_REFS: Mutex<BTreeMap<SocketHandle, usize>> = Mutex::new(BTreeMap::new()); }
+
 impl<'a> NetStack<'a> {
     pub fn init(mac: [u8;6], ip: [u8;4]) {
         let mut cfg = Config::new(EthernetAddress(mac).into());
@@ -169,3 +174,74 @@ pub fn udp_send(handle: SocketHandle, dst: (Ipv4Address, u16), data: &[u8]) -> b
     }
     false
 }
+
+/// Bind a UDP socket for a user program (not wired to the echo service).
+pub fn udp_bind(local_port: u16) -> Option<SocketHandle> {
+    if let Some(ref mut ns) = *NET.lock() {
+        let rx = udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 8], vec![0; 2048]);
+        let tx = udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; 8], vec![0; 2048]);
+        let mut sock = udp::Socket::new(rx, tx);
+        sock.bind(IpEndpoint::new(Ipv4Address::UNSPECIFIED.into(), local_port)).ok()?;
+        let handle = ns.sockets.add(sock);
+        UDP_REFS.lock().insert(handle, 1);
+        return Some(handle);
+    }
+    None
+}
+
+/// Non-blocking receive; returns the byte count and the sender's address.
+pub fn udp_recv(handle: SocketHandle, buf: &mut [u8]) -> Option<(usize, [u8;4], u16)> {
+    if let Some(ref mut ns) = *NET.lock() {
+        let sock = ns.sockets.get_mut::<udp::Socket>(handle);
+        if let Ok((n, meta)) = sock.recv_slice(buf) {
+            let IpAddress::Ipv4(a) = meta.endpoint.addr;
+            return Some((n, a.0, meta.endpoint.port));
+        }
+    }
+    None
+}
+
+/// One open socket, for /proc/net.
+pub struct SocketInfo { pub tcp: bool, pub local: String, pub remote: String, pub state: String }
+
+pub fn sockets() -> Vec<SocketInfo> {
+    use smoltcp::socket::Socket;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let Some(ref ns) = *NET.lock() else { return Vec::new() };
+        let ep = |e: Option<IpEndpoint>| e.map_or("*:*".to_string(), |e| e.to_string());
+        ns.sockets.iter().filter_map(|(_, s)| match s {
+            Socket::Udp(u) => Some(SocketInfo { tcp: false, local: u.endpoint().to_string(), remote: "*:*".to_string(), state: "-".to_string() }),
+            Socket::Tcp(t) => Some(SocketInfo { tcp: true, local: ep(t.local_endpoint()), remote: ep(t.remote_endpoint()), state: t.state().to_string() }),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }).collect()
+    })
+}
+
+/// The network interface, for /proc/net.
+pub struct IfaceInfo { pub mac: String, pub addrs: Vec<String>, pub driver: &'static str }
+
+pub fn interface() -> Option<IfaceInfo> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let ns = NET.lock();
+        let ns = ns.as_ref()?;
+        let driver = if crate::net::e1000::NIC.lock().is_some() { "e1000" } else { "loopback" };
+        Some(IfaceInfo { mac: ns.iface.hardware_addr().to_string(), addrs: ns.iface.ip_addrs().iter().map(|a| a.to_string()).collect(), driver })
+    })
+}
+
+/// Another fd for a socket from `udp_bind`; each needs its own `udp_close`.
+pub fn udp_dup(handle: SocketHandle) {
+    if let Some(n) = UDP_REFS.lock().get_mut(&handle) { *n += 1; }
+}
+
+pub fn udp_close(handle: SocketHandle) {
+    {
+        let mut refs = UDP_REFS.lock();
+        let Some(n) = refs.get_mut(&handle) else { return };
+        *n -= 1;
+        if *n > 0 { return; }
+        refs.remove(&handle);
+    }
+    if let Some(ref mut ns) = *NET.lock() { ns.sockets.remove(handle); }
+}
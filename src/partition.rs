@@ -0,0 +1,384 @@
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use crate::block::{self, BlockDevice, BlockError};
+
+#[derive(Clone, Debug)]
+pub enum PartKind {
+    Mbr(u8), // partition type byte
+    Gpt { type_guid: [u8; 16], name: String },
+}
+
+#[derive(Clone, Debug)]
+pub struct Partition {
+    pub index: usize, // 1-based; MBR logical partitions start at 5
+    pub start: u64,   // in device blocks
+    pub blocks: u64,
+    pub kind: PartKind,
+}
+
+const MBR_PROTECTIVE: u8 = 0xEE;
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+// EBR chains this long are certainly corrupt (or loop back on themselves).
+const MAX_LOGICAL: usize = 64;
+
+fn le32(b: &[u8], at: usize) -> u32 { u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]]) }
+fn le64(b: &[u8], at: usize) -> u64 { le32(b, at) as u64 | (le32(b, at + 4) as u64) << 32 }
+
+fn read_block(dev: &mut dyn BlockDevice, lba: u64) -> Result<Vec<u8>, BlockError> {
+    let mut buf = vec![0u8; dev.block_size()];
+    dev.read_blocks(lba, &mut buf)?;
+    Ok(buf)
+}
+
+// (status, type, start, blocks) for the four slots of an MBR/EBR
+fn mbr_entries(sector: &[u8]) -> [(u8, u8, u64, u64); 4] {
+    core::array::from_fn(|i| {
+        let e = 446 + i * 16;
+        (sector[e], sector[e + 4], le32(sector, e + 8) as u64, le32(sector, e + 12) as u64)
+    })
+}
+
+fn is_extended(t: u8) -> bool { matches!(t, 0x05 | 0x0F | 0x85) }
+
+/// Partitions on `dev`, from a GPT if the MBR is protective, else from the MBR (including logical ones).
+/// A disk whose first sector is a FAT boot sector rather than an MBR has none.
+pub fn scan(dev: &mut dyn BlockDevice) -> Vec<Partition> {
+    let Ok(mbr) = read_block(dev, 0) else { return Vec::new() };
+    if mbr.len() < 512 || mbr[510..512] != [0x55, 0xAA] { return Vec::new(); }
+    if crate::fs::fat::read_bpb(dev).is_some() { return Vec::new(); }
+    let entries = mbr_entries(&mbr);
+    let cap = dev.capacity();
+    // boot code in a volume boot record rarely looks like four sane entries
+    let sane = entries.iter().all(|&(status, t, start, n)| (status == 0 || status == 0x80) && (t == 0 || (start > 0 && start + n <= cap)));
+    if !sane { return Vec::new(); }
+    if entries.iter().any(|e| e.1 == MBR_PROTECTIVE) { return scan_gpt(dev); }
+
+    let mut out = Vec::new();
+    for (i, &(_, t, start, blocks)) in entries.iter().enumerate() {
+        if t == 0 || blocks == 0 { continue; }
+        if is_extended(t) { scan_logical(dev, start, &mut out); continue; }
+        out.push(Partition { index: i + 1, start, blocks, kind: PartKind::Mbr(t) });
+    }
+    out
+}
+
+// Walk the EBR chain of an extended partition. Each EBR's first entry is relative to the EBR,
+// its second points at the next EBR relative to the start of the extended partition.
+fn scan_logical(dev: &mut dyn BlockDevice, ext_start: u64, out: &mut Vec<Partition>) {
+    let mut ebr = ext_start;
+    for n in 0..MAX_LOGICAL {
+        let Ok(sector) = read_block(dev, ebr) else { return };
+        if sector[510..512] != [0x55, 0xAA] { return; }
+        let e = mbr_entries(&sector);
+        if e[0].1 != 0 && e[0].3 != 0 {
+            out.push(Partition { index: 5 + n, start: ebr + e[0].2, blocks: e[0].3, kind: PartKind::Mbr(e[0].1) });
+        }
+        if e[1].1 == 0 || e[1].2 == 0 { return; }
+        ebr = ext_start + e[1].2;
+    }
+}
+
+// A GPT header whose own CRC checks out.
+fn gpt_header_ok(hdr: &[u8]) -> bool {
+    let len = le32(hdr, 12) as usize;
+    if &hdr[0..8] != GPT_SIGNATURE || !(92..=hdr.len()).contains(&len) { return false; }
+    let mut h = hdr[..len].to_vec();
+    h[16..20].fill(0);
+    crate::compress::crc32(&h) == le32(hdr, 16)
+}
+
+fn scan_gpt(dev: &mut dyn BlockDevice) -> Vec<Partition> {
+    let mut out = Vec::new();
+    let Ok(hdr) = read_block(dev, 1) else { return out };
+    if !gpt_header_ok(&hdr) { return out; }
+    let (table, count, size) = (le64(&hdr, 72), le32(&hdr, 80) as usize, le32(&hdr, 84) as usize);
+    // entries are 128 bytes or a larger multiple of it; anything else is corrupt or hostile
+    if !(128..=512).contains(&size) || size % 128 != 0 || count > 1024 { return out; }
+    let bs = dev.block_size();
+    let mut raw = vec![0u8; (count * size).div_ceil(bs) * bs];
+    if dev.read_blocks(table, &mut raw).is_err() { return out; }
+    if crate::compress::crc32(&raw[..count * size]) != le32(&hdr, 88) { return out; }
+    for (i, e) in raw.chunks_exact(size).take(count).enumerate() {
+        let mut type_guid = [0u8; 16];
+        type_guid.copy_from_slice(&e[0..16]);
+        if type_guid == [0; 16] { continue; }
+        let (first, last) = (le64(e, 32), le64(e, 40));
+        if last < first { continue; }
+        let units = e[56..128].as_chunks::<2>().0.iter().map(|&c| u16::from_le_bytes(c)).take_while(|&c| c != 0);
+        let name = char::decode_utf16(units).map(|c| c.unwrap_or('?')).collect();
+        out.push(Partition { index: i + 1, start: first, blocks: last - first + 1, kind: PartKind::Gpt { type_guid, name } });
+    }
+    out
+}
+
+/// GPT type GUID of a FAT or NTFS data partition, in on-disk byte order.
+pub const GPT_BASIC_DATA: [u8; 16] = [0xA2, 0xA0, 0xD0, 0xEB, 0xE5, 0xB9, 0x33, 0x44, 0x87, 0xC0, 0x68, 0xB6, 0xB7, 0x26, 0x99, 0xC7];
+const GPT_ENTRIES: usize = 128;
+const GPT_ENTRY_SIZE: usize = 128;
+
+// An MBR slot covering `start..start + blocks`; CHS fields say "use LBA".
+fn mbr_slot(sector: &mut [u8], i: usize, t: u8, start: u64, blocks: u64) -> Result<(), BlockError> {
+    let e = 446 + i * 16;
+    let (start, blocks) = (u32::try_from(start).map_err(|_| BlockError::OutOfRange)?, u32::try_from(blocks).unwrap_or(u32::MAX));
+    sector[e..e + 16].fill(0);
+    sector[e + 1..e + 4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    sector[e + 4] = t;
+    sector[e + 5..e + 8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+    sector[e + 8..e + 12].copy_from_slice(&start.to_le_bytes());
+    sector[e + 12..e + 16].copy_from_slice(&blocks.to_le_bytes());
+    Ok(())
+}
+
+/// Write an MBR holding up to four primary `parts` (their `Mbr` type and extent; the index is
+/// ignored). Boot code already in sector 0 is kept.
+pub fn write_mbr(dev: &mut dyn BlockDevice, parts: &[Partition]) -> Result<(), BlockError> {
+    let mut mbr = read_block(dev, 0)?;
+    if mbr.len() < 512 || parts.len() > 4 { return Err(BlockError::BadBuffer); }
+    for i in 0..4 { mbr_slot(&mut mbr, i, 0, 0, 0)?; }
+    for (i, p) in parts.iter().enumerate() {
+        let PartKind::Mbr(t) = p.kind else { return Err(BlockError::BadBuffer) };
+        if p.start == 0 || p.start + p.blocks > dev.capacity() { return Err(BlockError::OutOfRange); }
+        mbr_slot(&mut mbr, i, t, p.start, p.blocks)?;
+    }
+    mbr[510..512].copy_from_slice(&[0x55, 0xAA]);
+    dev.write_blocks(0, &mbr)?;
+    dev.flush()
+}
+
+/// Write a GPT for `parts` (their `Gpt` type and name and their extent, which must lie within
+/// the usable area) with a protective MBR and the backup header and table at the end of the
+/// disk. Partition GUIDs are `disk_guid` with the partition number mixed into the last byte.
+pub fn write_gpt(dev: &mut dyn BlockDevice, parts: &[Partition], disk_guid: [u8; 16]) -> Result<(), BlockError> {
+    let (bs, cap) = (dev.block_size(), dev.capacity());
+    let table_blocks = (GPT_ENTRIES * GPT_ENTRY_SIZE).div_ceil(bs) as u64;
+    if bs < 512 || parts.len() > GPT_ENTRIES || cap < 3 + 2 * table_blocks { return Err(BlockError::OutOfRange); }
+    let (first_usable, last_usable) = (2 + table_blocks, cap - 2 - table_blocks);
+
+    let mut table = vec![0u8; table_blocks as usize * bs];
+    for (i, p) in parts.iter().enumerate() {
+        let PartKind::Gpt { type_guid, name } = &p.kind else { return Err(BlockError::BadBuffer) };
+        if p.blocks == 0 || p.start < first_usable || p.start + p.blocks - 1 > last_usable { return Err(BlockError::OutOfRange); }
+        let e = &mut table[i * GPT_ENTRY_SIZE..(i + 1) * GPT_ENTRY_SIZE];
+        e[0..16].copy_from_slice(type_guid);
+        e[16..32].copy_from_slice(&disk_guid);
+        e[31] ^= i as u8 + 1;
+        e[32..40].copy_from_slice(&p.start.to_le_bytes());
+        e[40..48].copy_from_slice(&(p.start + p.blocks - 1).to_le_bytes());
+        for (j, u) in name.encode_utf16().take(36).enumerate() { e[56 + j * 2..58 + j * 2].copy_from_slice(&u.to_le_bytes()); }
+    }
+    let table_crc = crate::compress::crc32(&table[..GPT_ENTRIES * GPT_ENTRY_SIZE]);
+
+    // (this header's LBA, the other header's LBA, where its table starts)
+    let header = |mine: u64, other: u64, entries: u64| {
+        let mut h = vec![0u8; bs];
+        h[0..8].copy_from_slice(GPT_SIGNATURE);
+        h[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes()); // revision 1.0
+        h[12..16].copy_from_slice(&92u32.to_le_bytes());
+        h[24..32].copy_from_slice(&mine.to_le_bytes());
+        h[32..40].copy_from_slice(&other.to_le_bytes());
+        h[40..48].copy_from_slice(&first_usable.to_le_bytes());
+        h[48..56].copy_from_slice(&last_usable.to_le_bytes());
+        h[56..72].copy_from_slice(&disk_guid);
+        h[72..80].copy_from_slice(&entries.to_le_bytes());
+        h[80..84].copy_from_slice(&(GPT_ENTRIES as u32).to_le_bytes());
+        h[84..88].copy_from_slice(&(GPT_ENTRY_SIZE as u32).to_le_bytes());
+        h[88..92].copy_from_slice(&table_crc.to_le_bytes());
+        let crc = crate::compress::crc32(&h[..92]);
+        h[16..20].copy_from_slice(&crc.to_le_bytes());
+        h
+    };
+    dev.write_blocks(2, &table)?;
+    dev.write_blocks(1, &header(1, cap - 1, 2))?;
+    dev.write_blocks(cap - 1 - table_blocks, &table)?;
+    dev.write_blocks(cap - 1, &header(cap - 1, 1, cap - 1 - table_blocks))?;
+
+    let mut mbr = read_block(dev, 0)?;
+    for i in 0..4 { mbr_slot(&mut mbr, i, 0, 0, 0)?; }
+    mbr_slot(&mut mbr, 0, MBR_PROTECTIVE, 1, cap - 1)?;
+    mbr[510..512].copy_from_slice(&[0x55, 0xAA]);
+    dev.write_blocks(0, &mbr)?;
+    dev.flush()
+}
+
+/// A window onto part of another device.
+pub struct PartitionDevice {
+    dev: Box<dyn BlockDevice + Send>,
+    start: u64,
+    blocks: u64,
+}
+
+impl PartitionDevice {
+    pub fn new(dev: Box<dyn BlockDevice + Send>, start: u64, blocks: u64) -> Self { Self { dev, start, blocks } }
+
+    fn check(&self, lba: u64, len: usize) -> Result<u64, BlockError> {
+        let bs = self.dev.block_size();
+        if !len.is_multiple_of(bs) { return Err(BlockError::BadBuffer); }
+        if lba.checked_add((len / bs) as u64).is_none_or(|end| end > self.blocks) { return Err(BlockError::OutOfRange); }
+        Ok(self.start + lba)
+    }
+}
+
+impl BlockDevice for PartitionDevice {
+    fn block_size(&self) -> usize { self.dev.block_size() }
+    fn capacity(&self) -> u64 { self.blocks }
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let at = self.check(lba, buf.len())?;
+        self.dev.read_blocks(at, buf)
+    }
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let at = self.check(lba, buf.len())?;
+        self.dev.write_blocks(at, buf)
+    }
+    fn flush(&mut self) -> Result<(), BlockError> { self.dev.flush() }
+}
+
+/// Scan every registered disk and register its partitions as `<disk><n>` (e.g. `hda1`, `vda5`).
+pub fn init() {
+    for name in block::devices() {
+        let Some(mut dev) = block::get(&name) else { continue };
+        for p in scan(&mut dev) {
+            let pname = alloc::format!("{}{}", name, p.index);
+            crate::serial_println!("part: {} start={} blocks={} {:?}", pname, p.start, p.blocks, p.kind);
+            block::register_view(&pname, Box::new(PartitionDevice::new(Box::new(dev.clone()), p.start, p.blocks)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::RamDisk;
+
+    const DISK: u64 = 8192;
+
+    fn mbr(start: u64, blocks: u64, t: u8) -> Partition { Partition { index: 0, start, blocks, kind: PartKind::Mbr(t) } }
+    fn gpt(start: u64, blocks: u64, name: &str) -> Partition {
+        Partition { index: 0, start, blocks, kind: PartKind::Gpt { type_guid: GPT_BASIC_DATA, name: name.into() } }
+    }
+    fn extents(parts: &[Partition]) -> Vec<(usize, u64, u64)> { parts.iter().map(|p| (p.index, p.start, p.blocks)).collect() }
+
+    // An EBR at `lba` holding a logical partition `rel` blocks in and linking to `next`
+    // (relative to the extended partition; 0 ends the chain).
+    fn ebr(disk: &mut RamDisk, lba: u64, rel: u64, blocks: u64, next: u64) {
+        let mut s = vec![0u8; 512];
+        mbr_slot(&mut s, 0, 0x0C, rel, blocks).unwrap();
+        if next != 0 { mbr_slot(&mut s, 1, 0x05, next, 1).unwrap(); }
+        s[510..512].copy_from_slice(&[0x55, 0xAA]);
+        disk.write_blocks(lba, &s).unwrap();
+    }
+
+    // Recompute the CRC of the GPT header at `lba` after a test has edited it.
+    fn reseal(disk: &mut RamDisk, lba: u64) {
+        let mut h = read_block(disk, lba).unwrap();
+        h[16..20].fill(0);
+        let crc = crate::compress::crc32(&h[..92]);
+        h[16..20].copy_from_slice(&crc.to_le_bytes());
+        disk.write_blocks(lba, &h).unwrap();
+    }
+
+    #[test]
+    fn mbr_round_trip() {
+        let mut disk = RamDisk::new(DISK, 512);
+        write_mbr(&mut disk, &[mbr(2048, 1000, 0x0C), mbr(4096, 4096, 0x83)]).unwrap();
+        let found = scan(&mut disk);
+        assert_eq!(extents(&found), [(1, 2048, 1000), (2, 4096, 4096)]);
+        assert!(matches!(found[1].kind, PartKind::Mbr(0x83)));
+        assert_eq!(write_mbr(&mut disk, &[mbr(8000, 500, 0x0C)]), Err(BlockError::OutOfRange));
+    }
+
+    #[test]
+    fn mbr_logical_partitions() {
+        let mut disk = RamDisk::new(DISK, 512);
+        write_mbr(&mut disk, &[mbr(2048, 1000, 0x0C), mbr(4096, 4096, 0x0F)]).unwrap();
+        ebr(&mut disk, 4096, 63, 100, 1000);
+        ebr(&mut disk, 5096, 63, 200, 0);
+        assert_eq!(extents(&scan(&mut disk)), [(1, 2048, 1000), (5, 4159, 100), (6, 5159, 200)]);
+        // a chain that links back to itself stops at MAX_LOGICAL
+        ebr(&mut disk, 5096, 63, 200, 1000);
+        assert_eq!(scan(&mut disk).len(), 1 + MAX_LOGICAL);
+    }
+
+    #[test]
+    fn not_a_partition_table() {
+        let mut disk = RamDisk::new(DISK, 512);
+        assert!(scan(&mut disk).is_empty()); // no signature
+        write_mbr(&mut disk, &[mbr(2048, 1000, 0x0C)]).unwrap();
+        let mut s = read_block(&mut disk, 0).unwrap();
+        s[446 + 12..446 + 16].copy_from_slice(&u32::MAX.to_le_bytes()); // runs off the disk
+        disk.write_blocks(0, &s).unwrap();
+        assert!(scan(&mut disk).is_empty());
+    }
+
+    #[test]
+    fn gpt_round_trip() {
+        let mut disk = RamDisk::new(DISK, 512);
+        write_gpt(&mut disk, &[gpt(2048, 2048, "boot"), gpt(4096, 4000, "data volume")], [0x11; 16]).unwrap();
+        let found = scan(&mut disk);
+        assert_eq!(extents(&found), [(1, 2048, 2048), (2, 4096, 4000)]);
+        let PartKind::Gpt { type_guid, name } = &found[1].kind else { panic!("{:?}", found[1].kind) };
+        assert_eq!((type_guid, name.as_str()), (&GPT_BASIC_DATA, "data volume"));
+        // the backup header points back at the primary one
+        let backup = read_block(&mut disk, DISK - 1).unwrap();
+        assert!(gpt_header_ok(&backup));
+        assert_eq!((le64(&backup, 24), le64(&backup, 32)), (DISK - 1, 1));
+        // past the usable area, which ends before the backup table
+        assert_eq!(write_gpt(&mut disk, &[gpt(4096, DISK - 4096 - 32, "x")], [0; 16]), Err(BlockError::OutOfRange));
+    }
+
+    #[test]
+    fn gpt_corruption_is_rejected() {
+        let fresh = || {
+            let mut disk = RamDisk::new(DISK, 512);
+            write_gpt(&mut disk, &[gpt(2048, 2048, "a")], [0x22; 16]).unwrap();
+            disk
+        };
+        let poke = |disk: &mut RamDisk, lba: u64, at: usize, bytes: &[u8]| {
+            let mut b = read_block(disk, lba).unwrap();
+            b[at..at + bytes.len()].copy_from_slice(bytes);
+            disk.write_blocks(lba, &b).unwrap();
+        };
+        // header CRC
+        let mut disk = fresh();
+        poke(&mut disk, 1, 48, &[0x5A]);
+        assert!(scan(&mut disk).is_empty());
+        // entry array CRC
+        let mut disk = fresh();
+        poke(&mut disk, 2, 40, &[0x5A]);
+        assert!(scan(&mut disk).is_empty());
+        // entry sizes that aren't a multiple of 128, or are too big, even with the CRC fixed up
+        for size in [0u32, 100, 1024] {
+            let mut disk = fresh();
+            poke(&mut disk, 1, 84, &size.to_le_bytes());
+            reseal(&mut disk, 1);
+            assert!(scan(&mut disk).is_empty(), "entry size {}", size);
+        }
+        // an entry count whose table can't be read
+        let mut disk = fresh();
+        poke(&mut disk, 1, 80, &u32::MAX.to_le_bytes());
+        reseal(&mut disk, 1);
+        assert!(scan(&mut disk).is_empty());
+        // header length outside 92..=block size
+        let mut disk = fresh();
+        poke(&mut disk, 1, 12, &4096u32.to_le_bytes());
+        reseal(&mut disk, 1);
+        assert!(scan(&mut disk).is_empty());
+    }
+
+    #[test]
+    fn partition_device_bounds() {
+        let mut disk = RamDisk::new(64, 512);
+        disk.write_blocks(10, &[7u8; 512]).unwrap();
+        let mut part = PartitionDevice::new(Box::new(disk), 10, 4);
+        let mut buf = [0u8; 512];
+        part.read_blocks(0, &mut buf).unwrap();
+        assert_eq!(buf, [7u8; 512]);
+        assert_eq!(part.capacity(), 4);
+        assert_eq!(part.read_blocks(3, &mut [0u8; 1024]), Err(BlockError::OutOfRange));
+        assert_eq!(part.read_blocks(4, &mut buf), Err(BlockError::OutOfRange));
+        assert_eq!(part.read_blocks(u64::MAX, &mut buf), Err(BlockError::OutOfRange));
+        assert_eq!(part.write_blocks(u64::MAX - 1, &[0u8; 1024]), Err(BlockError::OutOfRange));
+        assert_eq!(part.read_blocks(0, &mut [0u8; 100]), Err(BlockError::BadBuffer));
+    }
+}
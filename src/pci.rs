@@ -32,7 +32,45 @@ pub fn write_u32(bus: u8, slot: u8, func: u8, offset: u8, val: u32) {
     }
 }
 
-pub struct PciDevice { pub bus: u8, pub slot: u8, pub func: u8, pub vendor: u16, pub device: u16, pub class: u8, pub subclass: u8 }
+#[derive(Clone, Copy, Debug)]
+pub struct PciDevice { pub bus: u8, pub slot: u8, pub func: u8, pub vendor: u16, pub device: u16, pub class: u8, pub subclass: u8, pub prog_if: u8 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bar { Io(u16), Mem(u64), None }
+
+impl PciDevice {
+    pub fn read(&self, offset: u8) -> u32 { read_u32(self.bus, self.slot, self.func, offset) }
+    pub fn write(&self, offset: u8, val: u32) { write_u32(self.bus, self.slot, self.func, offset, val) }
+
+    /// Decode BAR `i`; a 64-bit memory BAR also consumes `i + 1`.
+    pub fn bar(&self, i: u8) -> Bar {
+        let v = self.read(0x10 + i * 4);
+        if v & 1 != 0 { return Bar::Io((v & 0xFFFC) as u16); }
+        let lo = (v & 0xFFFF_FFF0) as u64;
+        let addr = if (v >> 1) & 3 == 2 { lo | (self.read(0x14 + i * 4) as u64) << 32 } else { lo };
+        if addr == 0 { Bar::None } else { Bar::Mem(addr) }
+    }
+
+    /// Turn on I/O, memory and bus-master decoding.
+    pub fn enable(&self) { self.write(0x04, self.read(0x04) | 0x7); }
+
+    /// Legacy PIC line from the interrupt-line register.
+    pub fn irq_line(&self) -> u8 { self.read(0x3C) as u8 }
+
+    /// Offsets of capabilities with ID `id` in config space.
+    pub fn capabilities(&self, id: u8) -> alloc::vec::Vec<u8> {
+        let mut out = alloc::vec::Vec::new();
+        if self.read(0x04) >> 16 & 0x10 == 0 { return out; } // no capability list
+        let mut ptr = (self.read(0x34) & 0xFC) as u8;
+        for _ in 0..48 {
+            if ptr == 0 { break; }
+            let hdr = self.read(ptr);
+            if hdr as u8 == id { out.push(ptr); }
+            ptr = ((hdr >> 8) & 0xFC) as u8;
+        }
+        out
+    }
+}
 
 pub fn enumerate(mut f: impl FnMut(PciDevice)) {
     for bus in 0..=255u8 { for slot in 0..32u8 { for func in 0..8u8 {
@@ -43,6 +81,7 @@ pub fn enumerate(mut f: impl FnMut(PciDevice)) {
         let class_reg = read_u32(bus, slot, func, 0x08);
         let class = ((class_reg >> 24) & 0xFF) as u8;
         let subclass = ((class_reg >> 16) & 0xFF) as u8;
-        f(PciDevice{bus,slot,func,vendor,device,class,subclass});
+        let prog_if = ((class_reg >> 8) & 0xFF) as u8;
+        f(PciDevice{bus,slot,func,vendor,device,class,subclass,prog_if});
     }}}
 }
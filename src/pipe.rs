@@ -0,0 +1,164 @@
+use alloc::collections::VecDeque;
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Bytes a pipe buffers before writers block.
+pub const PIPE_CAP: usize = 4096;
+
+pub struct Pipe {
+    buf: VecDeque<u8>,
+    readers: usize,
+    writers: usize,
+}
+
+lazy_static! {
+    static ref PIPES: Mutex<Vec<Option<Pipe>>> = Mutex::new(Vec::new());
+}
+
+/// Create a pipe with one read and one write end open; returns its id.
+pub fn create() -> usize {
+    let p = Pipe { buf: VecDeque::with_capacity(PIPE_CAP), readers: 1, writers: 1 };
+    let mut pipes = PIPES.lock();
+    if let Some(i) = pipes.iter().position(|p| p.is_none()) { pipes[i] = Some(p); return i; }
+    pipes.push(Some(p));
+    pipes.len() - 1
+}
+
+/// Blocking read. Returns 0 once the buffer is empty and every writer has closed.
+pub fn read(id: usize, out: &mut [u8]) -> isize {
+    if out.is_empty() { return 0; }
+    loop {
+        if let Some(n) = try_read(id, out) { return n; }
+        crate::scheduler::yield_now();
+    }
+}
+
+/// Non-blocking read: like `read`, but `None` while the buffer is empty and a writer remains.
+pub fn try_read(id: usize, out: &mut [u8]) -> Option<isize> {
+    let mut pipes = PIPES.lock();
+    let p = match pipes.get_mut(id).and_then(|p| p.as_mut()) { Some(p) => p, None => return Some(-1) };
+    if !p.buf.is_empty() {
+        let n = out.len().min(p.buf.len());
+        for (dst, b) in out.iter_mut().zip(p.buf.drain(..n)) { *dst = b; }
+        return Some(n as isize);
+    }
+    if p.writers == 0 { Some(0) } else { None }
+}
+
+/// Blocking write. Returns -1 if there are no readers left (broken pipe).
+pub fn write(id: usize, data: &[u8]) -> isize {
+    let mut done = 0usize;
+    while done < data.len() {
+        match try_write(id, &data[done..]) {
+            n if n < 0 => return if done > 0 { done as isize } else { -1 },
+            0 => crate::scheduler::yield_now(),
+            n => done += n as usize,
+        }
+    }
+    done as isize
+}
+
+/// Non-blocking write: takes as much of `data` as fits, 0 if the pipe is full, -1 if there
+/// are no readers left.
+pub fn try_write(id: usize, data: &[u8]) -> isize {
+    let mut pipes = PIPES.lock();
+    let p = match pipes.get_mut(id).and_then(|p| p.as_mut()) { Some(p) => p, None => return -1 };
+    if p.readers == 0 { return -1; }
+    let n = (PIPE_CAP - p.buf.len()).min(data.len());
+    p.buf.extend(&data[..n]);
+    n as isize
+}
+
+pub fn add_reader(id: usize) { if let Some(Some(p)) = PIPES.lock().get_mut(id) { p.readers += 1; } }
+pub fn add_writer(id: usize) { if let Some(Some(p)) = PIPES.lock().get_mut(id) { p.writers += 1; } }
+
+pub fn close_read(id: usize) { close(id, true) }
+pub fn close_write(id: usize) { close(id, false) }
+
+fn close(id: usize, read_end: bool) {
+    let mut pipes = PIPES.lock();
+    if let Some(slot) = pipes.get_mut(id) {
+        if let Some(p) = slot.as_mut() {
+            if read_end { p.readers = p.readers.saturating_sub(1); } else { p.writers = p.writers.saturating_sub(1); }
+            if p.readers == 0 && p.writers == 0 { *slot = None; }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+    use super::*;
+
+    #[test]
+    fn read_and_write() {
+        let p = create();
+        assert_eq!(write(p, b"hello "), 6);
+        assert_eq!(try_write(p, b"world"), 5);
+        let mut buf = [0u8; 8];
+        assert_eq!(read(p, &mut buf), 8);
+        assert_eq!(&buf, b"hello wo");
+        assert_eq!(try_read(p, &mut buf), Some(3));
+        assert_eq!(&buf[..3], b"rld");
+        assert_eq!(try_read(p, &mut buf), None); // empty, but the writer is still there
+        assert_eq!(read(p, &mut []), 0);
+        close_write(p);
+        close_read(p);
+    }
+
+    #[test]
+    fn fills_up_at_capacity() {
+        let p = create();
+        let data = [7u8; PIPE_CAP + 100];
+        assert_eq!(try_write(p, &data), PIPE_CAP as isize);
+        assert_eq!(try_write(p, &data), 0);
+        let mut buf = [0u8; 100];
+        assert_eq!(read(p, &mut buf), 100);
+        assert_eq!(try_write(p, &data), 100);
+        close_write(p);
+        close_read(p);
+    }
+
+    #[test]
+    fn end_of_file_once_every_writer_closes() {
+        let p = create();
+        add_writer(p);
+        write(p, b"x");
+        close_write(p);
+        let mut buf = [0u8; 4];
+        assert_eq!(read(p, &mut buf), 1);
+        assert_eq!(try_read(p, &mut buf), None); // one writer left
+        close_write(p);
+        assert_eq!(read(p, &mut buf), 0);
+        assert_eq!(try_read(p, &mut buf), Some(0));
+        close_read(p);
+    }
+
+    #[test]
+    fn broken_pipe_once_every_reader_closes() {
+        let p = create();
+        add_reader(p);
+        close_read(p);
+        assert_eq!(write(p, b"still read"), 10);
+        close_read(p);
+        assert_eq!(write(p, b"x"), -1);
+        assert_eq!(try_write(p, b"x"), -1);
+        close_write(p);
+    }
+
+    // a blocking write that runs out of readers part way reports what it got through, and the
+    // next one fails
+    #[test]
+    fn partial_write_when_the_reader_goes() {
+        let q = create();
+        assert_eq!(try_write(q, &[1u8; PIPE_CAP - 10]), (PIPE_CAP - 10) as isize);
+        let writer = std::thread::spawn(move || write(q, &[2u8; PIPE_CAP * 2]));
+        // wait for the writer to fill the pipe and block
+        while PIPES.lock()[q].as_ref().unwrap().buf.len() < PIPE_CAP { std::thread::yield_now(); }
+        close_read(q);
+        assert_eq!(writer.join().unwrap(), 10);
+        assert_eq!(write(q, b"x"), -1);
+        close_write(q);
+    }
+}
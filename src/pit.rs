@@ -20,6 +20,10 @@ pub fn init(hz: u32) {
 
 pub fn tick() { TICKS.fetch_add(1, Ordering::Relaxed); crate::scheduler::on_tick(); crate::net::netstack::poll(); }
 
+pub fn ticks() -> u64 { TICKS.load(Ordering::Relaxed) }
+
+pub fn hz() -> u64 { unsafe { HZ as u64 } }
+
 pub fn uptime_secs() -> u64 { let t = TICKS.load(Ordering::Relaxed); let hz = unsafe { HZ as u64 }; t / hz }
 
 pub fn format_uptime() -> heapless::String<32> {
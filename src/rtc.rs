@@ -0,0 +1,69 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+// Wall-clock time: the CMOS clock is read once at boot, the PIT keeps time after that.
+// The CMOS clock is assumed to hold UTC (QEMU's default).
+static BOOT_SECS: AtomicU64 = AtomicU64::new(0); // Unix time when BOOT_TICKS was sampled
+static BOOT_TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn cmos(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(0x70).write(reg);
+        Port::<u8>::new(0x71).read()
+    }
+}
+
+// seconds, minutes, hours, day, month, year, century, as stored
+fn sample() -> [u8; 7] {
+    while cmos(0x0A) & 0x80 != 0 { core::hint::spin_loop(); } // update in progress
+    [cmos(0x00), cmos(0x02), cmos(0x04), cmos(0x07), cmos(0x08), cmos(0x09), cmos(0x32)]
+}
+
+/// Days since 1970-01-01 for a proleptic Gregorian date (H. Hinnant's days_from_civil).
+pub fn days_from_civil(y: i64, m: i64, d: i64) -> i64 {
+    let (y, m) = if m <= 2 { (y - 1, m + 9) } else { (y, m - 3) };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * m + 2) / 5 + d - 1;
+    era * 146097 + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719468
+}
+
+/// (year, month, day) for a day count since 1970-01-01; the inverse of `days_from_civil`.
+pub fn civil_from_days(z: i64) -> (i64, i64, i64) {
+    let z = z + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + (m <= 2) as i64, m, d)
+}
+
+pub fn init() {
+    // two identical samples in a row can't straddle an update
+    let mut r = sample();
+    loop {
+        let again = sample();
+        if again == r { break; }
+        r = again;
+    }
+    let status_b = cmos(0x0B);
+    let bin = |v: u8| if status_b & 0x04 == 0 { (v & 0x0F) + (v >> 4) * 10 } else { v } as i64;
+    let mut hour = bin(r[2] & 0x7F);
+    if status_b & 0x02 == 0 { hour = hour % 12 + if r[2] & 0x80 != 0 { 12 } else { 0 }; } // 12-hour mode
+    // the century register isn't universal; trust it only when it looks sane
+    let century = match bin(r[6]) { c @ 19..=21 => c, _ => 20 };
+    let days = days_from_civil(century * 100 + bin(r[5]), bin(r[4]), bin(r[3]));
+    let secs = days * 86400 + hour * 3600 + bin(r[1]) * 60 + bin(r[0]);
+    BOOT_TICKS.store(crate::pit::ticks(), Ordering::Relaxed);
+    BOOT_SECS.store(secs.max(0) as u64, Ordering::Relaxed);
+}
+
+/// Seconds since the Unix epoch, or 0 before `init`.
+pub fn now() -> u64 {
+    let base = BOOT_SECS.load(Ordering::Relaxed);
+    if base == 0 { return 0; }
+    base + (crate::pit::ticks() - BOOT_TICKS.load(Ordering::Relaxed)) / crate::pit::hz()
+}
@@ -21,12 +21,10 @@ pub fn init() {
 
 extern "C" fn idle_task() -> ! { loop { core::hint::spin_loop(); } }
 
-extern "C" fn user_trampoline() -> ! {
+pub(crate) extern "C" fn user_trampoline() -> ! {
     // Enter user mode via sysret using current task stored rip/rsp
-    if let Some(mut t) = current_task_mut() {
-        if let Some(uc) = t.user.take() {
-            unsafe { super::syscalls::enter_user(uc.rip, uc.rsp); }
-        }
+    if let Some(Some(uc)) = with_current(|t| t.user.take()) {
+        unsafe { super::syscalls::enter_user(uc.rip, uc.rsp); }
     }
     loop { core::hint::spin_loop(); }
 }
@@ -38,6 +36,15 @@ pub fn on_tick() {
     for (i, t) in tasks.iter_mut().enumerate() {
         if let State::Sleeping(until) = t.state { if now >= until { t.state = State::Ready; READY.lock().push_back(i); } }
     }
+    schedule(tasks);
+}
+
+/// Give up the CPU voluntarily; used by blocking kernel paths (pipes, console reads).
+pub fn yield_now() {
+    x86_64::instructions::interrupts::without_interrupts(|| schedule(TASKS.lock()));
+}
+
+fn schedule(mut tasks: spin::MutexGuard<'static, alloc::vec::Vec<Task>>) {
     // preempt current
     if let Some(cur) = *CURRENT.lock() {
         if matches!(tasks[cur].state, State::Running | State::Ready) {
@@ -75,11 +82,51 @@ pub fn on_tick() {
     }
 }
 
+pub fn current_pid() -> Option<u64> {
+    let cur = (*CURRENT.lock())?;
+    x86_64::instructions::interrupts::without_interrupts(|| TASKS.lock().get(cur).map(|t| t.pid))
+}
+
+/// Run `f` against the task that is currently on the CPU.
+pub fn with_current<T>(f: impl FnOnce(&mut Task) -> T) -> Option<T> {
+    let cur = (*CURRENT.lock())?;
+    x86_64::instructions::interrupts::without_interrupts(|| TASKS.lock().get_mut(cur).map(f))
+}
+
+/// Run `f` against the task with the given pid.
+pub fn with_task<T>(pid: u64, f: impl FnOnce(&mut Task) -> T) -> Option<T> {
+    x86_64::instructions::interrupts::without_interrupts(|| TASKS.lock().iter_mut().find(|t| t.pid == pid).map(f))
+}
+
+/// Pids of every task, zombies included.
+pub fn pids() -> alloc::vec::Vec<u64> {
+    x86_64::instructions::interrupts::without_interrupts(|| TASKS.lock().iter().map(|t| t.pid).collect())
+}
+
+/// Mark the current task as a zombie, release its descriptors and never return.
+pub fn exit_current() -> ! {
+    if let Some(pid) = current_pid() { crate::ipc::release_task(pid); }
+    with_current(|t| { t.close_all_fds(); t.caps.clear(); t.state = State::Zombie; });
+    loop { yield_now(); }
+}
+
 pub fn current_task_mut() -> Option<spin::MutexGuard<'static, alloc::vec::Vec<crate::task::Task>>> { Some(crate::task::TASKS.lock()) }
 
-pub fn spawn_kernel(name: &str, entry: extern "C" fn() -> !) -> u64 {
+/// Credentials of the running task; code running outside any task acts as root.
+pub fn current_creds() -> crate::task::Creds { with_current(|t| t.creds).unwrap_or(crate::task::Creds::ROOT) }
+
+/// Change the running task's credentials.
+pub fn set_creds(c: crate::task::Creds) { with_current(|t| t.creds = c); }
+
+/// New tasks run as whoever spawned them.
+pub fn spawn_kernel(name: &str, entry: extern "C" fn() -> !) -> u64 { spawn_kernel_with(name, entry, |_| {}) }
+
+/// Like `spawn_kernel`, with `setup` run on the task before it can be scheduled.
+pub fn spawn_kernel_with(name: &str, entry: extern "C" fn() -> !, setup: impl FnOnce(&mut Task)) -> u64 {
     let pid = alloc_pid();
-    let t = Task::new_kernel(pid, name, entry);
+    let mut t = Task::new_kernel(pid, name, entry);
+    t.creds = current_creds();
+    setup(&mut t);
     let mut tasks = TASKS.lock();
     let idx = tasks.len();
     tasks.push(t);
@@ -11,6 +11,22 @@ lazy_static! {
     };
 }
 
+/// Send raw bytes to COM1.
+pub fn write_bytes(data: &[u8]) {
+    let mut port = SERIAL1.lock();
+    for &b in data { port.send(b); }
+}
+
+/// A received byte from COM1, if one is waiting.
+pub fn try_read_byte() -> Option<u8> {
+    use x86_64::instructions::port::Port;
+    let _port = SERIAL1.lock();
+    unsafe {
+        if Port::<u8>::new(0x3F8 + 5).read() & 1 == 0 { return None; } // line status: data ready
+        Some(Port::<u8>::new(0x3F8).read())
+    }
+}
+
 pub fn _serial_print(args: fmt::Arguments) {
     use core::fmt::Write;
     SERIAL1.lock().write_fmt(args).ok();
@@ -66,6 +66,19 @@ pub fn load_from_lock(contents: &str) {
     *SETTINGS.lock() = s;
 }
 
+/// Where the settings live.
+pub const LOCK_PATH: &str = "/waemon.lock";
+
+/// Start over from the defaults and apply waemon.lock, so keys removed from the file go back
+/// to their default. Returns false, keeping the current settings, if the file can't be read.
+pub fn reload() -> bool {
+    let Ok(lock) = crate::fs::read(LOCK_PATH) else { return false };
+    let Ok(text) = core::str::from_utf8(&lock) else { return false };
+    *SETTINGS.lock() = Settings::default();
+    load_from_lock(text);
+    true
+}
+
 fn parse_bool(v: &str, default: bool) -> bool {
     match v.to_ascii_lowercase().as_str() {
         "true"|"1"|"yes"|"on" => true,
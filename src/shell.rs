@@ -1,33 +1,371 @@
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
 use heapless::String;
+use crate::fd::Fd;
+use crate::task::Creds;
 
 pub extern "C" fn shell_task() -> ! {
+    login();
     let mut buf = String::<256>::new();
     loop {
         if crate::tty::read_line(&mut buf) {
             handle_cmd(&buf);
             buf.clear();
+            if LOGGED_OUT.swap(false, Ordering::Relaxed) { login(); }
         }
         crate::window::sleep(50_000);
     }
 }
 
+static LOGGED_OUT: AtomicBool = AtomicBool::new(false);
+
+// Wait for a whole line. Keys aren't echoed, so this does for passwords too.
+fn prompt(text: &str) -> String<256> {
+    crate::console::println(text);
+    let mut buf = String::new();
+    while !crate::tty::read_line(&mut buf) { crate::window::sleep(50_000); }
+    buf
+}
+
+/// Ask for a user name and password until they check out against /etc/passwd and
+/// /etc/shadow, then run the shell as that user; an account without a password yet has to
+/// choose one first. Without a user database it stays root.
+fn login() {
+    crate::scheduler::set_creds(Creds::ROOT);
+    if crate::users::all().is_empty() { return; }
+    loop {
+        let name = prompt("login:");
+        let password = prompt("password:");
+        match crate::users::authenticate(name.trim(), &password) {
+            Some(u) => {
+                if crate::users::must_change_password(&u.name) && !choose_password(&u.name) {
+                    crate::console::println("Login incorrect");
+                    continue;
+                }
+                crate::users::login(&u);
+                crate::console::println(&alloc::format!("Welcome, {}.", u.name));
+                return;
+            }
+            None => crate::console::println("Login incorrect"),
+        }
+    }
+}
+
+// First login to an account without a password yet: insist on a non-empty one.
+fn choose_password(name: &str) -> bool {
+    crate::console::println("You must choose a password for this account.");
+    loop {
+        let new = prompt("new password:");
+        if new.is_empty() { crate::console::println("The password can't be empty"); continue; }
+        if prompt("retype new password:") != new { crate::console::println("Passwords do not match"); continue; }
+        return crate::users::set_password(name, &new).is_ok();
+    }
+}
+
+fn user_name(uid: u32) -> alloc::string::String {
+    crate::users::by_uid(uid).map(|u| u.name).unwrap_or_else(|| alloc::format!("{}", uid))
+}
+
+fn group_name(gid: u32) -> alloc::string::String {
+    crate::users::group_name(gid).unwrap_or_else(|| alloc::format!("{}", gid))
+}
+
+/// One stage of a pipeline: the command words plus any `<`/`>`/`>>` redirections.
+struct Stage<'a> {
+    argv: Vec<&'a str>,
+    stdin: Option<&'a str>,
+    stdout: Option<(&'a str, bool)>,
+}
+
+fn parse_stage(s: &str) -> Stage<'_> {
+    let mut st = Stage { argv: Vec::new(), stdin: None, stdout: None };
+    let mut words = s.split_whitespace();
+    while let Some(w) = words.next() {
+        match w {
+            "<" => st.stdin = words.next(),
+            ">" => st.stdout = words.next().map(|p| (p, false)),
+            ">>" => st.stdout = words.next().map(|p| (p, true)),
+            _ if w.starts_with(">>") => st.stdout = Some((&w[2..], true)),
+            _ if w.starts_with('>') => st.stdout = Some((&w[1..], false)),
+            _ if w.starts_with('<') => st.stdin = Some(&w[1..]),
+            _ => st.argv.push(w),
+        }
+    }
+    st
+}
+
 fn handle_cmd(cmd: &str) {
-    match cmd.trim() {
-        "hello" => crate::console::println("Hello!"),
+    let line = cmd.trim();
+    if line.is_empty() { return; }
+    // Each stage's stdout becomes the next stage's stdin; the last one goes to the console.
+    let mut input: Option<Vec<u8>> = None;
+    for part in line.split('|') {
+        let stage = parse_stage(part.trim());
+        if let Some(path) = stage.stdin {
+            match crate::fs::read(path) {
+                Ok(data) => input = Some(data),
+                Err(_) => { crate::console::println("shell: cannot open input"); return; }
+            }
+        }
+        let mut out = Vec::new();
+        let ok = run_stage(&stage.argv, input.as_deref(), &mut out);
+        if let Some((path, append)) = stage.stdout {
+            let r = if append { crate::fs::append(path, &out) } else { crate::fs::write(path, &out) };
+            if r.is_err() { crate::console::println("shell: cannot write output"); return; }
+            out = Vec::new();
+        }
+        // a failing stage ends the pipeline, but what it wrote (usually why) is still shown
+        if !ok { print_out(&out); return; }
+        input = Some(out);
+    }
+    if let Some(out) = input { print_out(&out); }
+}
+
+fn print_out(out: &[u8]) {
+    if !out.is_empty() {
+        crate::console::println(core::str::from_utf8(out).unwrap_or("<binary>").trim_end_matches('\n'));
+    }
+}
+
+fn emit(out: &mut Vec<u8>, s: &str) { out.extend_from_slice(s.as_bytes()); out.push(b'\n'); }
+
+/// Run a single command; returns false if the pipeline should stop.
+fn run_stage(argv: &[&str], input: Option<&[u8]>, out: &mut Vec<u8>) -> bool {
+    let Some(&name) = argv.first() else { return true };
+    let args = &argv[1..];
+    let text = input.and_then(|i| core::str::from_utf8(i).ok()).unwrap_or("");
+    match name {
+        "hello" => emit(out, "Hello!"),
         "clear" => crate::console::clear(),
-        "uptime" => { let t = crate::pit::format_uptime(); crate::console::println(t.as_str()); },
-        s if s.starts_with("sleep ") => {
-            if let Ok(t) = s[6..].trim().parse::<u64>() { crate::syscalls::sleep_ticks(t); crate::console::println("(sleep)"); }
+        "uptime" => { let t = crate::pit::format_uptime(); emit(out, t.as_str()); },
+        "sleep" => {
+            if let Some(Ok(t)) = args.first().map(|a| a.parse::<u64>()) { crate::syscalls::sleep_ticks(t); emit(out, "(sleep)"); }
+        }
+        "spawn" => {
+            let name = args.first().copied().unwrap_or(""); let _ = crate::syscalls::spawn(name); emit(out, "(spawn)");
+        }
+        "echo" => emit(out, &args.join(" ")),
+        "cat" if args.is_empty() => out.extend_from_slice(input.unwrap_or(&[])),
+        "cat" => {
+            for path in args {
+                match crate::fs::read(path) {
+                    Ok(data) => out.extend_from_slice(&data),
+                    Err(_) => { emit(out, "cat: not found"); return false; }
+                }
+            }
+        }
+        "ls" => match crate::fs::list(args.first().copied().unwrap_or("/")) {
+            Ok(names) => for n in names { emit(out, &n); },
+            Err(_) => { emit(out, "ls: not found"); return false; }
+        },
+        "stat" => for path in args {
+            match crate::fs::stat(path) {
+                Ok(st) => emit(out, &alloc::format!("{}: {:?} ino={} size={} mode={:o} owner={}:{} ctime={} mtime={}",
+                    path, st.kind, st.ino, st.size, st.mode, user_name(st.uid), group_name(st.gid), st.ctime, st.mtime)),
+                Err(e) => { emit(out, &alloc::format!("stat: {}: {:?}", path, e)); return false; }
+            }
+        },
+        "sha256sum" => {
+            // -c rehashes and checks against the hash the file was shipped with
+            let check = args.first() == Some(&"-c");
+            let paths = &args[check as usize..];
+            if paths.is_empty() { emit(out, "usage: sha256sum [-c] <path>..."); return false; }
+            let mut ok = true;
+            for path in paths {
+                match (check, if check { crate::fs::verify(path) } else { crate::fs::hash(path).map(|h| (h, true)) }) {
+                    (_, Err(e)) => { emit(out, &alloc::format!("sha256sum: {}: {:?}", path, e)); ok = false; }
+                    (false, Ok((h, _))) => emit(out, &alloc::format!("{}  {}", crate::sha256::hex(&h), path)),
+                    (true, Ok((_, good))) => { emit(out, &alloc::format!("{}: {}", path, if good { "OK" } else { "FAILED" })); ok &= good; }
+                }
+            }
+            if !ok { return false; }
+        }
+        "mkdir" | "rm" | "rmdir" | "touch" => for path in args {
+            let r = match name {
+                "mkdir" => crate::fs::mkdir(path),
+                "rm" => crate::fs::remove(path),
+                "rmdir" => crate::fs::remove_dir(path),
+                _ => crate::fs::append(path, &[]),
+            };
+            if let Err(e) = r { emit(out, &alloc::format!("{}: {}: {:?}", name, path, e)); return false; }
+        },
+        "mv" => match args {
+            [from, to] => if let Err(e) = crate::fs::rename(from, to) { emit(out, &alloc::format!("mv: {:?}", e)); return false; },
+            _ => { emit(out, "usage: mv <from> <to>"); return false; }
+        },
+        "truncate" => match (args, args.get(1).and_then(|s| s.parse().ok())) {
+            ([path, _], Some(size)) => if let Err(e) = crate::fs::truncate(path, size) { emit(out, &alloc::format!("truncate: {:?}", e)); return false; },
+            _ => { emit(out, "usage: truncate <path> <size>"); return false; }
+        },
+        "chmod" => match args.split_first() {
+            Some((mode, paths)) if !paths.is_empty() => {
+                let Ok(mode) = u16::from_str_radix(mode, 8) else { emit(out, "chmod: mode must be octal"); return false };
+                for path in paths {
+                    if let Err(e) = crate::fs::chmod(path, mode) { emit(out, &alloc::format!("chmod: {}: {:?}", path, e)); return false; }
+                }
+            }
+            _ => { emit(out, "usage: chmod <octal mode> <path>..."); return false; }
+        },
+        "chown" => match args.split_first() {
+            Some((owner, paths)) if !paths.is_empty() => {
+                let (u, g) = owner.split_once(':').map_or((*owner, None), |(u, g)| (u, Some(g)));
+                let user = crate::users::lookup(u);
+                let uid = user.as_ref().map(|u| u.uid).or_else(|| u.parse().ok());
+                let gid = match g { Some(g) => crate::users::group_id(g).or_else(|| g.parse().ok()), None => user.as_ref().map(|u| u.gid) };
+                let (Some(uid), Some(gid)) = (uid, gid) else { emit(out, "chown: unknown user or group"); return false };
+                for path in paths {
+                    if let Err(e) = crate::fs::chown(path, uid, gid) { emit(out, &alloc::format!("chown: {}: {:?}", path, e)); return false; }
+                }
+            }
+            _ => { emit(out, "usage: chown <user>[:<group>] <path>..."); return false; }
+        },
+        "whoami" => emit(out, &user_name(crate::scheduler::current_creds().uid)),
+        "id" => {
+            let c = crate::scheduler::current_creds();
+            emit(out, &alloc::format!("uid={}({}) gid={}({})", c.uid, user_name(c.uid), c.gid, group_name(c.gid)));
         }
-        s if s.starts_with("spawn ") => {
-            let name = &s[6..]; let _ = crate::syscalls::spawn(name); crate::console::println("(spawn)");
+        "passwd" => return passwd_cmd(args, out),
+        "logout" => LOGGED_OUT.store(true, Ordering::Relaxed),
+        "sync" => {
+            let fs = crate::fs::vfs::sync();
+            if fs.is_err() || crate::bcache::sync_all().is_err() { emit(out, "sync: write-back failed"); return false; }
         }
-        s if s.starts_with("run ") => {
-            let path = &s[4..];
-            let _ = crate::syscalls::spawn_user_elf(path);
-            crate::console::println("(run)");
+        "snapshot" => match crate::fs::snapshot::save() {
+            Ok(s) => emit(out, &alloc::format!("snapshot: {} entries, {} bytes to {}", s.entries, s.bytes, s.path)),
+            Err(e) => { emit(out, &alloc::format!("snapshot: {:?}", e)); return false; }
+        },
+        "shutdown" => {
+            if let Err(e) = crate::fs::snapshot::save() { emit(out, &alloc::format!("shutdown: snapshot not saved: {:?}", e)); }
+            let _ = crate::fs::vfs::sync();
+            let _ = crate::bcache::sync_all();
+            // ACPI power-off on QEMU's PIIX4 PM block; on anything else just stop
+            unsafe { x86_64::instructions::port::Port::<u16>::new(0x604).write(0x2000); }
+            x86_64::instructions::interrupts::disable();
+            loop { x86_64::instructions::hlt(); }
+        }
+        "bcache" => {
+            let s = crate::bcache::stats();
+            emit(out, &alloc::format!("hits {} misses {} readahead {} writebacks {} pages {} dirty {}",
+                s.hits, s.misses, s.readahead, s.writebacks, s.used, s.dirty));
+        }
+        "fsck" => {
+            let repair = args.first() == Some(&"-r");
+            let [dev] = &args[repair as usize..] else { emit(out, "usage: fsck [-r] <device>"); return false };
+            match crate::fs::fsck(dev, repair) {
+                Ok(r) => {
+                    for p in &r.problems { emit(out, p); }
+                    emit(out, &alloc::format!("{}: {}", dev, if r.clean() { "clean".into() } else { r.summary() }));
+                    if !r.clean() && !repair { return false; }
+                }
+                Err(crate::fs::FsError::Busy) => { emit(out, &alloc::format!("fsck: {} is mounted; it is repaired when mounted at boot", dev)); return false; }
+                Err(crate::fs::FsError::NoSpace) => { emit(out, &alloc::format!("fsck: {} is too big to check in the memory left", dev)); return false; }
+                Err(e) => { emit(out, &alloc::format!("fsck: {}: {:?}", dev, e)); return false; }
+            }
+        }
+        "mount" => for (path, name) in crate::fs::vfs::mounts() { emit(out, &alloc::format!("{} on {}", name, path)); },
+        "grep" => {
+            let pat = args.first().copied().unwrap_or("");
+            for l in text.lines().filter(|l| l.contains(pat)) { emit(out, l); }
+        }
+        "wc" => {
+            let s = alloc::format!("{} {} {}", text.lines().count(), text.split_whitespace().count(), text.len());
+            emit(out, &s);
+        }
+        "head" => {
+            let n = args.first().and_then(|a| a.trim_start_matches('-').parse().ok()).unwrap_or(10);
+            for l in text.lines().take(n) { emit(out, l); }
+        }
+        "run" => return run_user(args, input, out),
+        "strace" => return strace_cmd(args, out),
+        _ => { emit(out, "Unknown"); return false; }
+    }
+    true
+}
+
+// Run a user ELF with pipes on stdin/stdout so it can sit in the middle of a pipeline.
+fn run_user(args: &[&str], input: Option<&[u8]>, out: &mut Vec<u8>) -> bool {
+    use crate::pipe;
+    let Some(&path) = args.first() else { return false };
+    let (inp, outp) = (pipe::create(), pipe::create());
+    let fds = [Some(Fd::PipeRead(inp)), Some(Fd::PipeWrite(outp)), Some(Fd::PipeWrite(outp))];
+    let spawned = crate::syscalls::spawn_user_elf_with_fds(path, &fds, false);
+    // The child holds its own references now; drop the ones we won't use.
+    pipe::close_read(inp);
+    pipe::close_write(outp);
+    if spawned.is_err() {
+        pipe::close_write(inp);
+        pipe::close_read(outp);
+        emit(out, "run: cannot execute");
+        return false;
+    }
+    // Feed the input and drain the output in turns: either can be more than a pipe holds, and
+    // the child may need its output read before it takes more input.
+    let data = input.unwrap_or(&[]);
+    let (mut sent, mut feeding) = (0, true);
+    let mut buf = [0u8; 512];
+    loop {
+        if feeding && sent < data.len() {
+            match pipe::try_write(inp, &data[sent..]) {
+                n if n < 0 => sent = data.len(), // the child closed its input
+                n => sent += n as usize,
+            }
+        }
+        if feeding && sent == data.len() { pipe::close_write(inp); feeding = false; }
+        match pipe::try_read(outp, &mut buf) {
+            Some(n) if n <= 0 => break,
+            Some(n) => out.extend_from_slice(&buf[..n as usize]),
+            None => crate::scheduler::yield_now(),
+        }
+    }
+    if feeding { pipe::close_write(inp); }
+    pipe::close_read(outp);
+    true
+}
+
+// passwd [user]: users change their own password after giving the current one; root may
+// set anyone's
+fn passwd_cmd(args: &[&str], out: &mut Vec<u8>) -> bool {
+    let c = crate::scheduler::current_creds();
+    let me = user_name(c.uid);
+    let name = args.first().copied().unwrap_or(&me);
+    if c.uid != 0 {
+        if name != me { emit(out, "passwd: only root may change another user's password"); return false; }
+        if crate::users::authenticate(name, &prompt("current password:")).is_none() { emit(out, "passwd: authentication failed"); return false; }
+    }
+    let new = prompt("new password:");
+    if prompt("retype new password:") != new { emit(out, "passwd: passwords do not match"); return false; }
+    match crate::users::set_password(name, &new) {
+        Ok(()) => emit(out, "passwd: password updated"),
+        Err(e) => { emit(out, &alloc::format!("passwd: {:?}", e)); return false; }
+    }
+    true
+}
+
+// strace <pid> | strace off <pid> | strace run <path> | strace dump [pid] [serial] | strace clear
+fn strace_cmd(args: &[&str], out: &mut Vec<u8>) -> bool {
+    use crate::strace;
+    match args {
+        ["run", path, ..] => match crate::syscalls::spawn_user_elf_with_fds(path, &[Some(Fd::Console); 3], true) {
+            Ok(pid) => { emit(out, &alloc::format!("tracing pid {}", pid)); }
+            Err(_) => { emit(out, "strace: cannot execute"); return false; }
+        },
+        ["off", pid] => match pid.parse().map(|pid| strace::set_traced(pid, false)) {
+            Ok(Ok(())) => emit(out, "(strace off)"),
+            Ok(Err(strace::TraceError::Denied)) => { emit(out, "strace: not your process"); return false; }
+            _ => { emit(out, "strace: no such pid"); return false; }
+        },
+        ["dump", rest @ ..] => {
+            let serial = rest.contains(&"serial");
+            let pid = rest.iter().find_map(|a| a.parse().ok());
+            strace::dump(pid, serial);
         }
-        s if s.is_empty() => {}
-        _ => crate::console::println("Unknown"),
+        ["clear"] => strace::clear(),
+        [pid] => match pid.parse().map(|pid| strace::set_traced(pid, true)) {
+            Ok(Ok(())) => emit(out, "(strace on)"),
+            Ok(Err(strace::TraceError::Denied)) => { emit(out, "strace: not your process"); return false; }
+            _ => { emit(out, "strace: no such pid"); return false; }
+        },
+        _ => { emit(out, "usage: strace <pid>|off <pid>|run <path>|dump [pid] [serial]|clear"); return false; }
     }
+    true
 }
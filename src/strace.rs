@@ -0,0 +1,155 @@
+use alloc::string::String;
+use core::fmt::Write;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+/// Records kept before the oldest ones are overwritten.
+pub const LOG_LEN: usize = 256;
+
+#[derive(Clone)]
+pub struct Record {
+    pub pid: u64,
+    pub uid: u32, // owner of the traced task; only they and root get to see the record
+    pub nr: u64,
+    pub args: [u64; 6],
+    pub ret: u64,
+    pub start_tick: u64,
+    pub cycles: u64, // TSC cycles spent in the handler
+    pub text: heapless::String<32>, // string argument, copied while the caller's memory was mapped
+}
+
+lazy_static! {
+    static ref LOG: Mutex<heapless::Deque<Record, LOG_LEN>> = Mutex::new(heapless::Deque::new());
+}
+
+#[derive(Clone, Copy)]
+enum Arg { Int, Hex, Fd, Str(usize) } // Str(i): pointer whose length is argument i
+
+// (name, argument kinds) indexed by syscall number
+const TABLE: &[(&str, &[Arg])] = &[
+    ("write", &[Arg::Fd, Arg::Str(2), Arg::Int]),
+    ("sleep", &[Arg::Int]),
+    ("exit", &[Arg::Int]),
+    ("spawn", &[Arg::Str(1), Arg::Int]),
+    ("read", &[Arg::Fd, Arg::Hex, Arg::Int]),
+    ("pipe", &[Arg::Hex]),
+    ("close", &[Arg::Fd]),
+    ("dup2", &[Arg::Fd, Arg::Fd]),
+    ("port_create", &[Arg::Str(1), Arg::Int]),
+    ("port_open", &[Arg::Str(1), Arg::Int]),
+    ("port_send", &[Arg::Int, Arg::Hex, Arg::Int]),
+    ("port_call", &[Arg::Int, Arg::Hex, Arg::Int, Arg::Hex, Arg::Int]),
+    ("port_recv", &[Arg::Int, Arg::Hex, Arg::Int, Arg::Hex]),
+    ("port_reply", &[Arg::Int, Arg::Hex, Arg::Int]),
+    ("port_notify", &[Arg::Int, Arg::Hex]),
+    ("port_wait", &[Arg::Int, Arg::Hex]),
+    ("port_close", &[Arg::Int]),
+    ("brk", &[Arg::Hex]),
+    ("mmap", &[Arg::Int]),
+    ("open", &[Arg::Str(1), Arg::Int, Arg::Hex]),
+    ("socket_udp", &[Arg::Int]),
+    ("sendto", &[Arg::Fd, Arg::Hex, Arg::Int, Arg::Hex, Arg::Int]),
+    ("recvfrom", &[Arg::Fd, Arg::Hex, Arg::Int, Arg::Hex]),
+    ("uptime", &[]),
+    ("lseek", &[Arg::Fd, Arg::Int, Arg::Int]),
+    ("getuid", &[]),
+    ("getgid", &[]),
+    ("watch", &[Arg::Str(1), Arg::Int, Arg::Hex]),
+    ("sha256", &[Arg::Str(1), Arg::Int, Arg::Hex]),
+];
+
+pub fn name(nr: u64) -> &'static str { TABLE.get(nr as usize).map_or("unknown", |e| e.0) }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceError { NoSuchPid, Denied }
+
+// Whether `me` may trace, or read the records of, a task owned by `uid`.
+fn may_trace(me: crate::task::Creds, uid: u32) -> bool { me.uid == 0 || me.uid == uid }
+
+/// Turn tracing on or off for a task, which the caller must own unless it is root.
+pub fn set_traced(pid: u64, on: bool) -> Result<(), TraceError> {
+    let me = crate::scheduler::current_creds();
+    match crate::scheduler::with_task(pid, |t| {
+        let ok = may_trace(me, t.creds.uid);
+        if ok { t.traced = on; }
+        ok
+    }) {
+        Some(true) => Ok(()),
+        Some(false) => Err(TraceError::Denied),
+        None => Err(TraceError::NoSuchPid),
+    }
+}
+
+pub fn current_traced() -> bool { crate::scheduler::with_current(|t| t.traced).unwrap_or(false) }
+
+/// Copy the string argument (if the syscall has one) out of the calling task's memory.
+/// Must run on the syscall path, while that address space is active.
+pub fn capture_text(nr: u64, args: &[u64; 6]) -> heapless::String<32> {
+    let mut out = heapless::String::new();
+    let kinds = TABLE.get(nr as usize).map_or(&[][..], |e| e.1);
+    if let Some((i, Arg::Str(li))) = kinds.iter().enumerate().find(|(_, k)| matches!(k, Arg::Str(_))).map(|(i, k)| (i, *k)) {
+        let (ptr, len) = (args[i], args[li] as usize);
+        let Ok(bytes) = crate::uaccess::slice(ptr, len.min(out.capacity())) else {
+            let _ = out.push_str("<bad ptr>");
+            return out;
+        };
+        let text = core::str::from_utf8(bytes).unwrap_or_else(|e| core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""));
+        let _ = out.push_str(text);
+    }
+    out
+}
+
+pub fn record(r: Record) {
+    let mut log = LOG.lock();
+    if log.is_full() { log.pop_front(); }
+    let _ = log.push_back(r);
+}
+
+#[inline(always)]
+pub fn cycles() -> u64 { unsafe { core::arch::x86_64::_rdtsc() } }
+
+/// Render one record as `[pid @tick] name(args...) = ret <cycles>`, `tick` being the PIT tick
+/// the syscall was entered on.
+pub fn format(r: &Record) -> String {
+    let mut s = String::new();
+    let _ = write!(s, "[{} @{}] {}(", r.pid, r.start_tick, name(r.nr));
+    let kinds = TABLE.get(r.nr as usize).map_or(&[][..], |e| e.1);
+    for (i, k) in kinds.iter().enumerate() {
+        if i > 0 { s.push_str(", "); }
+        let v = r.args[i];
+        let _ = match *k {
+            Arg::Int => write!(s, "{}", v as i64),
+            Arg::Hex => write!(s, "{:#x}", v),
+            Arg::Fd => write!(s, "fd{}", v),
+            Arg::Str(li) if !r.text.is_empty() || r.args[li] == 0 => {
+                write!(s, "\"{}\"{}", r.text.escape_debug(), if r.args[li] as usize > r.text.len() { "..." } else { "" })
+            }
+            Arg::Str(_) => write!(s, "{:#x}", v),
+        };
+    }
+    if r.nr == 2 { s.push(')'); } else { let _ = write!(s, ") = {}", r.ret as i64); }
+    let _ = write!(s, " <{} cyc>", r.cycles);
+    s
+}
+
+/// Print the buffered records (optionally for one pid) to the console or serial port. Records
+/// of tasks the caller doesn't own are left out unless it is root.
+pub fn dump(pid: Option<u64>, serial: bool) {
+    let me = crate::scheduler::current_creds();
+    let log = LOG.lock().clone();
+    for r in log.iter().filter(|r| pid.is_none_or(|p| p == r.pid) && may_trace(me, r.uid)) {
+        let line = format(r);
+        if serial { crate::serial_println!("{}", line); } else { crate::console::println(&line); }
+    }
+}
+
+/// Drop the buffered records the caller could see; root clears them all.
+pub fn clear() {
+    let me = crate::scheduler::current_creds();
+    let mut log = LOG.lock();
+    for _ in 0..log.len() {
+        if let Some(r) = log.pop_front() {
+            if !may_trace(me, r.uid) { let _ = log.push_back(r); }
+        }
+    }
+}
@@ -1,4 +1,6 @@
 use core::arch::asm;
+use crate::fd::{self, Fd};
+use crate::uaccess::{self, EFAULT};
 
 pub fn sys_uptime_secs() -> u64 {
     // In lack of user mode, this can be called directly; syscall path provided for future
@@ -14,12 +16,17 @@ __syscall_entry_trampoline:
     // Save RCX/R11
     push rcx
     push r11
-    // Move R10 into RCX for calling convention; we'll pass 6 args
-    mov rcx, r10
     // Call Rust handler: u64 handle_syscall(u64 nr, u64 a1, u64 a2, u64 a3, u64 a4, u64 a5, u64 a6)
-    // Arg regs: RDI,RSI,RDX,RCX,R8,R9 ; we already have them
+    // Shift the user args one register right so nr lands in RDI; a6 goes on the stack
+    push r9
+    mov r9, r8
+    mov r8, r10
+    mov rcx, rdx
+    mov rdx, rsi
+    mov rsi, rdi
     mov rdi, rax
     call handle_syscall
+    add rsp, 8
     // Return value in RAX
     // Restore RCX(user RIP) / R11(RFLAGS)
     pop r11
@@ -30,26 +37,244 @@ __syscall_entry_trampoline:
 
 #[no_mangle]
 pub extern "C" fn handle_syscall(nr: u64, a1: u64, a2: u64, a3: u64, a4: u64, a5: u64, a6: u64) -> u64 {
+    if !crate::strace::current_traced() { return dispatch(nr, a1, a2, a3, a4, a5, a6); }
+    use crate::strace::{self, Record};
+    let args = [a1, a2, a3, a4, a5, a6];
+    let mut rec = Record {
+        pid: crate::scheduler::current_pid().unwrap_or(0), uid: crate::scheduler::current_creds().uid, nr, args, ret: 0,
+        start_tick: crate::pit::ticks(), cycles: 0, text: strace::capture_text(nr, &args),
+    };
+    // exit never comes back, so log it up front
+    if nr == 2 { strace::record(rec.clone()); }
+    let start = strace::cycles();
+    let ret = dispatch(nr, a1, a2, a3, a4, a5, a6);
+    rec.cycles = strace::cycles().wrapping_sub(start);
+    rec.ret = ret;
+    strace::record(rec);
+    ret
+}
+
+fn dispatch(nr: u64, a1: u64, a2: u64, a3: u64, a4: u64, a5: u64, a6: u64) -> u64 {
     match nr {
-        0 => sys_write(a1 as u64, a2 as *const u8, a3 as usize) as u64,
+        0 => sys_write(a1, a2, a3 as usize) as u64,
         1 => { crate::scheduler::sleep_current(a1 as u64); 0 }
-        2 => { /* exit */ 0 }
+        2 => crate::scheduler::exit_current(),
         3 => { // spawn_user_elf(path_ptr, len)
-            let s = unsafe { core::slice::from_raw_parts(a1 as *const u8, a2 as usize) };
+            let Ok(s) = uaccess::slice(a1, a2 as usize) else { return EFAULT as u64 };
             if let Ok(p) = core::str::from_utf8(s) { crate::syscalls::spawn_user_elf(p).ok().unwrap_or(0) as u64 } else { u64::MAX }
         }
+        4 => sys_read(a1, a2, a3 as usize) as u64,
+        5 => sys_pipe(a1) as u64,
+        6 => sys_close(a1) as u64,
+        7 => sys_dup2(a1, a2) as u64,
+        8..=16 => sys_ipc(nr, a1, a2, a3, a4, a5) as u64,
+        17 => sys_brk(a1),
+        18 => sys_mmap(a1),
+        19 => sys_open(a1, a2, a3 as u32) as u64,
+        20 => sys_socket_udp(a1 as u16) as u64,
+        21 => sys_sendto(a1, a2, a3 as usize, a4 as u32, a5 as u16) as u64,
+        22 => sys_recvfrom(a1, a2, a3 as usize, a4) as u64,
+        23 => crate::pit::uptime_secs(),
+        24 => sys_lseek(a1, a2 as i64, a3) as u64,
+        25 => crate::scheduler::current_creds().uid as u64,
+        26 => crate::scheduler::current_creds().gid as u64,
+        27 => sys_watch(a1, a2, a3 as u32) as u64,
+        28 => sys_sha256(a1, a2, a3) as u64,
         _ => u64::MAX,
     }
 }
 
-fn sys_write(fd: u64, buf: *const u8, len: usize) -> isize {
-    if fd == 1 {
-        unsafe {
-            let s = core::slice::from_raw_parts(buf, len);
-            if let Ok(text) = core::str::from_utf8(s) { crate::console::println(text); return len as isize; }
+fn current_fd(fd: u64) -> Option<Fd> { crate::scheduler::with_current(|t| t.fd(fd as usize)).flatten() }
+
+fn sys_write(fd: u64, buf: u64, len: usize) -> isize {
+    let Some(f) = current_fd(fd) else { return -1 };
+    match uaccess::slice(buf, len) {
+        Ok(data) => fd::write(f, data),
+        Err(e) => e,
+    }
+}
+
+fn sys_read(fd: u64, buf: u64, len: usize) -> isize {
+    let Some(f) = current_fd(fd) else { return -1 };
+    match uaccess::slice_mut(buf, len) {
+        Ok(out) => fd::read(f, out),
+        Err(e) => e,
+    }
+}
+
+// pipe(int fds[2]): fds[0] is the read end, fds[1] the write end
+fn sys_pipe(fds: u64) -> isize {
+    // check the out-param before making anything, so a bad pointer leaks no fds
+    if let Err(e) = uaccess::check(fds, 8, true) { return e; }
+    let id = crate::pipe::create();
+    let ends = crate::scheduler::with_current(|t| (t.install_fd(Fd::PipeRead(id)), t.install_fd(Fd::PipeWrite(id))));
+    match ends {
+        Some((r, w)) => uaccess::write(fds, [r as i32, w as i32]).map_or_else(|e| e, |_| 0),
+        None => { crate::pipe::close_read(id); crate::pipe::close_write(id); -1 }
+    }
+}
+
+fn sys_close(fd: u64) -> isize {
+    crate::scheduler::with_current(|t| match t.fd(fd as usize) {
+        Some(_) => { t.set_fd(fd as usize, None); 0 }
+        None => -1,
+    }).unwrap_or(-1)
+}
+
+fn sys_dup2(old: u64, new: u64) -> isize {
+    if new >= crate::task::MAX_FDS as u64 { return -1; }
+    crate::scheduler::with_current(|t| match t.fd(old as usize) {
+        Some(_) if old == new => new as isize,
+        Some(f) => { t.set_fd(new as usize, Some(fd::dup(f))); new as isize }
+        None => -1,
+    }).unwrap_or(-1)
+}
+
+/// Base of the brk heap in every user address space.
+pub const USER_HEAP_BASE: u64 = 0x0000_4000_0000_0000;
+/// Anonymous mmap regions are handed out upwards from here.
+pub const USER_MMAP_BASE: u64 = 0x0000_5000_0000_0000;
+/// Initial user stack pointer; the stack grows down from here.
+pub const USER_STACK_TOP: u64 = 0x0000_7fff_ffff_f000;
+
+fn page_up(v: u64) -> u64 { (v + 4095) & !4095 }
+
+// brk(0) queries the break; anything else grows it (shrinking is not supported)
+fn sys_brk(addr: u64) -> u64 {
+    crate::scheduler::with_current(|t| {
+        if addr == 0 || addr <= t.heap_end { return t.heap_end; }
+        let (old, new) = (page_up(t.heap_end), page_up(addr));
+        if new > old && crate::mm::map_user_region(t.cr3, old, (new - old) as usize, true).is_none() { return t.heap_end; }
+        t.heap_end = addr;
+        addr
+    }).unwrap_or(0)
+}
+
+// mmap(len): anonymous, private, read/write; returns 0 on failure
+fn sys_mmap(len: u64) -> u64 {
+    crate::scheduler::with_current(|t| {
+        let base = t.mmap_next;
+        let size = page_up(len.max(1));
+        if crate::mm::map_user_region(t.cr3, base, size as usize, true).is_none() { return 0; }
+        t.mmap_next += size;
+        base
+    }).unwrap_or(0)
+}
+
+fn sys_open(path: u64, len: u64, flags: u32) -> isize {
+    let p = match user_str(path, len) { Ok(p) => p, Err(e) => return e };
+    match crate::fs::open(p, flags) {
+        Ok(h) => crate::scheduler::with_current(|t| t.install_fd(Fd::File(h)) as isize).unwrap_or(-1),
+        Err(_) => -1,
+    }
+}
+
+// watch(path, mask): an fd whose reads wait for change events on `path`, see fs::watch
+fn sys_watch(path: u64, len: u64, mask: u32) -> isize {
+    let p = match user_str(path, len) { Ok(p) => p, Err(e) => return e };
+    match crate::fs::watch::add(p, mask) {
+        Ok(id) => crate::scheduler::with_current(|t| t.install_fd(Fd::Watch(id)) as isize).unwrap_or(-1),
+        Err(_) => -1,
+    }
+}
+
+// sha256(path, digest): the 32-byte SHA-256 of a file's contents, see fs::hash
+fn sys_sha256(path: u64, len: u64, out: u64) -> isize {
+    let p = match user_str(path, len) { Ok(p) => p, Err(e) => return e };
+    match crate::fs::hash(p) {
+        Ok(h) => uaccess::copy_to_user(out, &h).map_or_else(|e| e, |_| 0),
+        Err(_) => -1,
+    }
+}
+
+// lseek(fd, offset, whence) with SEEK_SET/SEEK_CUR/SEEK_END = 0/1/2; returns the new offset
+fn sys_lseek(fd: u64, off: i64, whence: u64) -> isize {
+    let Some(Fd::File(h)) = current_fd(fd) else { return -1 };
+    let pos = match whence {
+        0 if off >= 0 => crate::fs::SeekFrom::Start(off as u64),
+        1 => crate::fs::SeekFrom::Current(off),
+        2 => crate::fs::SeekFrom::End(off),
+        _ => return -1,
+    };
+    crate::fs::seek_handle(h, pos)
+}
+
+fn sys_socket_udp(port: u16) -> isize {
+    match crate::net::netstack::udp_bind(port) {
+        Some(s) => crate::scheduler::with_current(|t| t.install_fd(Fd::Udp(s)) as isize).unwrap_or(-1),
+        None => -1,
+    }
+}
+
+// ip is packed big-endian (a.b.c.d => 0xaabbccdd)
+fn sys_sendto(fd: u64, buf: u64, len: usize, ip: u32, port: u16) -> isize {
+    let Some(Fd::Udp(s)) = current_fd(fd) else { return -1 };
+    let data = match uaccess::slice(buf, len) { Ok(d) => d, Err(e) => return e };
+    let dst = smoltcp::wire::Ipv4Address(ip.to_be_bytes());
+    if crate::net::netstack::udp_send(s, (dst, port), data) { len as isize } else { -1 }
+}
+
+// Non-blocking; `from` receives (ip << 16 | port) when non-null
+fn sys_recvfrom(fd: u64, buf: u64, len: usize, from: u64) -> isize {
+    let Some(Fd::Udp(s)) = current_fd(fd) else { return -1 };
+    let out = match uaccess::slice_mut(buf, len) { Ok(o) => o, Err(e) => return e };
+    // checked up front so a bad `from` doesn't drop the datagram
+    if from != 0 { if let Err(e) = uaccess::check(from, 8, true) { return e; } }
+    match crate::net::netstack::udp_recv(s, out) {
+        Some((n, ip, port)) => {
+            if from != 0 && uaccess::write(from, ((u32::from_be_bytes(ip) as u64) << 16) | port as u64).is_err() { return EFAULT; }
+            n as isize
         }
+        None => 0,
     }
-    -1
+}
+
+// A path or name argument: EFAULT for a bad pointer, -1 when it isn't UTF-8.
+fn user_str<'a>(ptr: u64, len: u64) -> Result<&'a str, isize> {
+    core::str::from_utf8(uaccess::slice(ptr, len as usize)?).map_err(|_| -1)
+}
+
+/// Receive info written back to user space by `port_recv`.
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct RecvInfo { msg_id: u64, sender: u64, len: u64, needs_reply: u64 }
+
+fn sys_ipc(nr: u64, a1: u64, a2: u64, a3: u64, a4: u64, a5: u64) -> isize {
+    ipc_op(nr, a1, a2, a3, a4, a5).unwrap_or_else(|e| e)
+}
+
+// Out-params are checked before blocking, so a bad pointer never swallows a message.
+fn ipc_op(nr: u64, a1: u64, a2: u64, a3: u64, a4: u64, a5: u64) -> Result<isize, isize> {
+    use crate::ipc;
+    Ok(match nr {
+        8 => ipc::create(user_str(a1, a2)?)? as isize,
+        9 => ipc::open(user_str(a1, a2)?)? as isize,
+        10 => { ipc::send(a1 as usize, uaccess::slice(a2, a3 as usize)?)?; 0 }
+        11 => {
+            let msg = uaccess::slice(a2, a3 as usize)?;
+            let reply = uaccess::slice_mut(a4, a5 as usize)?;
+            ipc::call(a1 as usize, msg, reply)? as isize
+        }
+        12 => {
+            let buf = uaccess::slice_mut(a2, a3 as usize)?;
+            uaccess::check(a4, core::mem::size_of::<RecvInfo>(), true)?;
+            let m = ipc::recv(a1 as usize)?;
+            let n = m.data.len().min(buf.len());
+            buf[..n].copy_from_slice(&m.data[..n]);
+            uaccess::write(a4, RecvInfo { msg_id: m.id, sender: m.sender, len: n as u64, needs_reply: m.needs_reply as u64 })?;
+            n as isize
+        }
+        13 => { ipc::reply(a1, uaccess::slice(a2, a3 as usize)?)?; 0 }
+        14 => { ipc::notify(a1 as usize, a2)?; 0 }
+        // the bits can be any u64, so they go back through a pointer rather than the result
+        15 => {
+            uaccess::check(a2, 8, true)?;
+            let b = ipc::wait(a1 as usize)?;
+            uaccess::write(a2, b)?;
+            0
+        }
+        _ => { ipc::close(a1 as usize)?; 0 }
+    })
 }
 
 pub fn handle() {
@@ -61,7 +286,7 @@ pub fn spawn(name: &str) -> u64 {
     crate::scheduler::spawn_kernel(name, kthread_demo)
 }
 
-pub fn exit() { /* TODO mark current as Zombie */ }
+pub fn exit() -> ! { crate::scheduler::exit_current() }
 
 pub fn sleep_ticks(ticks: u64) { crate::scheduler::sleep_current(ticks); }
 
@@ -91,24 +316,30 @@ pub unsafe fn enter_user(rip: u64, rsp: u64) -> ! {
 }
 
 pub fn spawn_user_elf(path: &str) -> Result<u64, ()> {
-    // Load from RAMFS
-    let bytes = crate::fs::read(path).map_err(|_| ())?;
+    spawn_user_elf_with_fds(path, &[Some(Fd::Console); 3], false)
+}
+
+/// Spawn `path` with its descriptor table seeded from `fds` (each entry is dup'd). A `traced`
+/// task has its syscalls logged from the first one on.
+pub fn spawn_user_elf_with_fds(path: &str, fds: &[Option<Fd>], traced: bool) -> Result<u64, ()> {
+    crate::fs::vfs::access(path, crate::fs::vfs::X_OK).map_err(|_| ())?;
+    // memfs hands out the file itself, so nothing is copied until the segments are mapped
+    let bytes = crate::fs::view(path).map_err(|_| ())?;
     let img = crate::elfloader::parse_elf(&bytes).ok_or(())?;
     let cr3 = crate::mm::alloc_user_space().ok_or(())?;
     // Map ELF into new address space
     if !crate::elfloader::map_into_cr3(cr3, &img) { return Err(()); }
     // Map a user stack and set RSP
-    let user_stack_top = 0x0000_7fff_ffff_f000u64;
-    let _ = crate::mm::map_user_stack(cr3, user_stack_top, 8).ok_or(())?;
-    // Create task that enters user
-    let pid = crate::scheduler::spawn_kernel("user", super::scheduler::user_trampoline);
-    // Patch its user ctx
-    {
-        let mut tasks = crate::task::TASKS.lock();
-        if let Some(t) = tasks.iter_mut().find(|t| t.pid == pid) {
-            t.user = Some(crate::task::UserCtx{ rip: img.entry, rsp: user_stack_top });
-            t.cr3 = cr3;
-        }
-    }
+    let _ = crate::mm::map_user_stack(cr3, USER_STACK_TOP, 8).ok_or(())?;
+    // Create task that enters user; it is named after the program (see /proc/<pid>/cmdline).
+    // Its user ctx is filled in before it is queued, so it never runs half set up.
+    let pid = crate::scheduler::spawn_kernel_with(path, super::scheduler::user_trampoline, |t| {
+        t.user = Some(crate::task::UserCtx{ rip: img.entry, rsp: USER_STACK_TOP });
+        t.cr3 = cr3;
+        t.fds = fds.iter().map(|f| f.map(fd::dup)).collect();
+        t.heap_end = USER_HEAP_BASE;
+        t.mmap_next = USER_MMAP_BASE;
+        t.traced = traced;
+    });
     Ok(pid)
 }
@@ -1,4 +1,5 @@
 use crate::context::Context;
+use crate::fd::{self, Fd};
 use alloc::vec::Vec;
 use alloc::alloc;
 use core::sync::atomic::{AtomicU64, Ordering};
@@ -8,6 +9,9 @@ use spin::Mutex;
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub enum State { Ready, Running, Sleeping(u64), Zombie }
 
+/// Highest descriptor number plus one; `dup2` refuses anything past it.
+pub const MAX_FDS: usize = 256;
+
 pub struct Task {
     pub pid: u64,
     pub name: heapless::String<32>,
@@ -17,10 +21,24 @@ pub struct Task {
     pub user: Option<UserCtx>,
     pub state: State,
     pub priority: u8,
+    pub fds: Vec<Option<Fd>>,
+    pub caps: Vec<Option<crate::ipc::Cap>>,
+    pub heap_end: u64, // current brk
+    pub mmap_next: u64,
+    pub traced: bool, // log syscalls to strace
+    pub creds: Creds,
 }
 
 pub struct UserCtx { pub rip: u64, pub rsp: u64 }
 
+/// Who a task runs as; uid 0 bypasses permission checks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Creds { pub uid: u32, pub gid: u32 }
+
+impl Creds {
+    pub const ROOT: Creds = Creds { uid: 0, gid: 0 };
+}
+
 impl Task {
     pub fn new_kernel(pid: u64, name: &str, entry: extern "C" fn() -> !) -> Self {
         // allocate stack
@@ -31,10 +49,37 @@ impl Task {
         let mut ctx = Context::zero();
         ctx.rsp = sp;
         ctx.rip = entry as u64;
-        let mut n = heapless::String::<32>::new(); let _ = n.push_str(name);
+        let mut n = heapless::String::<32>::new();
+        for c in name.chars() { if n.push(c).is_err() { break; } } // long names are cut short
         // inherit current CR3 for now (kernel-only address space)
         let cr3 = unsafe { x86_64::registers::control::Cr3::read().0.start_address().as_u64() };
-        Self { pid, name: n, ctx, stack_ptr, cr3, user: None, state: State::Ready, priority: 10 }
+        let fds = alloc::vec![Some(Fd::Console), Some(Fd::Console), Some(Fd::Console)];
+        Self { pid, name: n, ctx, stack_ptr, cr3, user: None, state: State::Ready, priority: 10, fds, caps: Vec::new(), heap_end: 0, mmap_next: 0, traced: false, creds: Creds::ROOT }
+    }
+
+    pub fn fd(&self, n: usize) -> Option<Fd> { self.fds.get(n).copied().flatten() }
+
+    /// Install `f` in the lowest free descriptor slot.
+    pub fn install_fd(&mut self, f: Fd) -> usize {
+        if let Some(i) = self.fds.iter().position(|s| s.is_none()) { self.fds[i] = Some(f); return i; }
+        self.fds.push(Some(f));
+        self.fds.len() - 1
+    }
+
+    /// Replace descriptor `n` (below `MAX_FDS`), closing whatever was there before.
+    pub fn set_fd(&mut self, n: usize, f: Option<Fd>) {
+        if self.fds.len() <= n { self.fds.resize(n + 1, None); }
+        if let Some(old) = core::mem::replace(&mut self.fds[n], f) { fd::close(old); }
+    }
+
+    pub fn install_cap(&mut self, c: crate::ipc::Cap) -> usize {
+        if let Some(i) = self.caps.iter().position(|s| s.is_none()) { self.caps[i] = Some(c); return i; }
+        self.caps.push(Some(c));
+        self.caps.len() - 1
+    }
+
+    pub fn close_all_fds(&mut self) {
+        for slot in self.fds.iter_mut() { if let Some(f) = slot.take() { fd::close(f); } }
     }
 }
 
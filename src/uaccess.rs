@@ -0,0 +1,42 @@
+// Checked access to user memory: every pointer a syscall is handed goes through here
+
+/// Returned instead of dereferencing a user pointer that isn't mapped for the access.
+pub const EFAULT: isize = -14;
+
+/// First address above user space; nothing from here up belongs to a task.
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+
+/// `ptr..ptr + len` must not wrap, must end at or below `USER_END`, and every page in it
+/// must be mapped user-accessible (writable too when `write`).
+pub fn check(ptr: u64, len: usize, write: bool) -> Result<(), isize> {
+    let end = ptr.checked_add(len as u64).ok_or(EFAULT)?;
+    if end > USER_END || !crate::mm::user_range_mapped(ptr, len as u64, write) { return Err(EFAULT); }
+    Ok(())
+}
+
+/// `len` bytes of user memory at `ptr`, read-only.
+pub fn slice<'a>(ptr: u64, len: usize) -> Result<&'a [u8], isize> {
+    if len == 0 { return Ok(&[]); }
+    check(ptr, len, false)?;
+    Ok(unsafe { core::slice::from_raw_parts(ptr as *const u8, len) })
+}
+
+/// `len` bytes of writable user memory at `ptr`.
+pub fn slice_mut<'a>(ptr: u64, len: usize) -> Result<&'a mut [u8], isize> {
+    if len == 0 { return Ok(&mut []); }
+    check(ptr, len, true)?;
+    Ok(unsafe { core::slice::from_raw_parts_mut(ptr as *mut u8, len) })
+}
+
+/// Copies `data` out to user memory at `ptr`.
+pub fn copy_to_user(ptr: u64, data: &[u8]) -> Result<(), isize> {
+    slice_mut(ptr, data.len())?.copy_from_slice(data);
+    Ok(())
+}
+
+/// Writes one plain value (an out-parameter) to user memory at `ptr`, which need not be aligned.
+pub fn write<T: Copy>(ptr: u64, v: T) -> Result<(), isize> {
+    check(ptr, core::mem::size_of::<T>(), true)?;
+    unsafe { (ptr as *mut T).write_unaligned(v) };
+    Ok(())
+}
@@ -11,17 +11,19 @@ lazy_static! {
     static ref BROOM: Mutex<Option<Image>> = Mutex::new(None);
 }
 
-fn parse_or_set(slot: &Mutex<Option<Image>>, bytes: &[u8]) -> Option<&'static Image> {
+// An icon at /share/icons/<name>.ppm overrides the built-in one.
+fn parse_or_set(slot: &Mutex<Option<Image>>, name: &str, bytes: &[u8]) -> Option<&'static Image> {
     if slot.lock().is_none() {
-        if let Some(img) = image::parse_ppm_p3(bytes) { *slot.lock() = Some(img); }
+        let img = image::load_ppm(&alloc::format!("/share/icons/{}.ppm", name)).or_else(|| image::parse_ppm_p3(bytes));
+        if let Some(img) = img { *slot.lock() = Some(img); }
     }
     // extend lifetime by leaking; safe here for demo OS
     if slot.lock().is_some() { Some(Box::leak(Box::new(slot.lock().clone().unwrap()))) } else { None }
 }
 
-pub fn icon_notes() -> Option<&'static Image> { parse_or_set(&NOTES, include_bytes!("../../../assets/icons/notes.ppm")) }
-pub fn icon_files() -> Option<&'static Image> { parse_or_set(&FILES, include_bytes!("../../../assets/icons/files.ppm")) }
-pub fn icon_about() -> Option<&'static Image> { parse_or_set(&ABOUT, include_bytes!("../../../assets/icons/about.ppm")) }
-pub fn icon_settings() -> Option<&'static Image> { parse_or_set(&SETTINGS, include_bytes!("../../../assets/icons/settings.ppm")) }
-pub fn icon_task() -> Option<&'static Image> { parse_or_set(&TASK, include_bytes!("../../../assets/icons/task.ppm")) }
-pub fn icon_broom() -> Option<&'static Image> { parse_or_set(&BROOM, include_bytes!("../../../assets/icons/broom.ppm")) }
+pub fn icon_notes() -> Option<&'static Image> { parse_or_set(&NOTES, "notes", include_bytes!("../../../assets/icons/notes.ppm")) }
+pub fn icon_files() -> Option<&'static Image> { parse_or_set(&FILES, "files", include_bytes!("../../../assets/icons/files.ppm")) }
+pub fn icon_about() -> Option<&'static Image> { parse_or_set(&ABOUT, "about", include_bytes!("../../../assets/icons/about.ppm")) }
+pub fn icon_settings() -> Option<&'static Image> { parse_or_set(&SETTINGS, "settings", include_bytes!("../../../assets/icons/settings.ppm")) }
+pub fn icon_task() -> Option<&'static Image> { parse_or_set(&TASK, "task", include_bytes!("../../../assets/icons/task.ppm")) }
+pub fn icon_broom() -> Option<&'static Image> { parse_or_set(&BROOM, "broom", include_bytes!("../../../assets/icons/broom.ppm")) }
@@ -0,0 +1,148 @@
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use crate::fs::{self, FsError};
+use crate::sha256::{self, Sha256};
+use crate::task::Creds;
+
+// User database: /etc/passwd ("name:x:uid:gid:comment:home:shell"), /etc/group
+// ("name:x:gid:members") and /etc/shadow ("name:hash"), root-only. A hash is
+// "sha256$<rounds>$<salt hex>$<digest hex>"; an empty one means no password, and anything
+// else that doesn't parse ("!", "*") locks the account.
+
+const ROUNDS: u32 = 5000;
+
+#[derive(Clone, Debug)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub home: String,
+    pub shell: String,
+}
+
+impl User {
+    pub fn creds(&self) -> Creds { Creds { uid: self.uid, gid: self.gid } }
+}
+
+// Run `f` as root; the shadow file is only readable by root but every user can log in and
+// change their own password, like a setuid passwd.
+fn privileged<T>(f: impl FnOnce() -> T) -> T {
+    let saved = crate::scheduler::current_creds();
+    crate::scheduler::set_creds(Creds::ROOT);
+    let r = f();
+    crate::scheduler::set_creds(saved);
+    r
+}
+
+// Lines of a colon-separated database, comments and blank lines skipped.
+fn records(path: &str) -> Vec<Vec<String>> {
+    let Ok(data) = fs::read(path) else { return Vec::new() };
+    let text = String::from_utf8_lossy(&data);
+    text.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .map(|l| l.split(':').map(|f| f.to_string()).collect())
+        .collect()
+}
+
+pub fn all() -> Vec<User> {
+    records("/etc/passwd").into_iter().filter_map(|r| {
+        let field = |i: usize| r.get(i).cloned().unwrap_or_default();
+        Some(User { name: field(0), uid: field(2).parse().ok()?, gid: field(3).parse().ok()?, home: field(5), shell: field(6) })
+    }).collect()
+}
+
+pub fn lookup(name: &str) -> Option<User> { all().into_iter().find(|u| u.name == name) }
+
+pub fn by_uid(uid: u32) -> Option<User> { all().into_iter().find(|u| u.uid == uid) }
+
+/// Name of group `gid`, if /etc/group lists it.
+pub fn group_name(gid: u32) -> Option<String> {
+    records("/etc/group").into_iter().find(|r| r.get(2).and_then(|g| g.parse().ok()) == Some(gid)).map(|r| r[0].clone())
+}
+
+pub fn group_id(name: &str) -> Option<u32> {
+    records("/etc/group").into_iter().find(|r| r[0] == name).and_then(|r| r.get(2)?.parse().ok())
+}
+
+fn digest(password: &str, salt: &[u8], rounds: u32) -> [u8; 32] {
+    let mut h = Sha256::new();
+    h.update(salt);
+    h.update(password.as_bytes());
+    let mut d = h.finish();
+    for _ in 1..rounds {
+        let mut h = Sha256::new();
+        h.update(&d);
+        h.update(salt);
+        h.update(password.as_bytes());
+        d = h.finish();
+    }
+    d
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 { return None; }
+    (0..s.len()).step_by(2).map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok()).collect()
+}
+
+/// A fresh shadow entry for `password` with a random salt.
+pub fn hash_password(password: &str) -> String {
+    let mut salt = [0u8; 16];
+    crate::fs::devfs::fill_random(&mut salt);
+    format!("sha256${}${}${}", ROUNDS, sha256::hex(&salt), sha256::hex(&digest(password, &salt, ROUNDS)))
+}
+
+fn verify(hash: &str, password: &str) -> bool {
+    if hash.is_empty() { return password.is_empty(); }
+    let parts: Vec<&str> = hash.split('$').collect();
+    let [tag, rounds, salt, want] = parts[..] else { return false };
+    let (Ok(rounds), Some(salt), Some(want)) = (rounds.parse(), unhex(salt), unhex(want)) else { return false };
+    // compare every byte so the time taken says nothing about where they differ
+    tag == "sha256" && rounds > 0 && want.len() == 32 && digest(password, &salt, rounds).iter().zip(&want).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+fn shadow_record(name: &str) -> Option<Vec<String>> {
+    privileged(|| records("/etc/shadow")).into_iter().find(|r| r[0] == name)
+}
+
+fn shadow_hash(name: &str) -> Option<String> { shadow_record(name).map(|r| r.get(1).cloned().unwrap_or_default()) }
+
+/// Whether `name` has to choose a new password before logging in: its last-changed field is
+/// 0, as for the accounts the initrd ships, which have no password until then.
+pub fn must_change_password(name: &str) -> bool {
+    shadow_record(name).is_some_and(|r| r.get(2).is_some_and(|f| f == "0"))
+}
+
+/// The user, if `name` exists and `password` is right.
+pub fn authenticate(name: &str, password: &str) -> Option<User> {
+    let user = lookup(name)?;
+    verify(&shadow_hash(name)?, password).then_some(user)
+}
+
+/// Replace `name`'s password, which also ends a forced change; an empty one allows logging in
+/// without.
+pub fn set_password(name: &str, password: &str) -> Result<(), FsError> {
+    lookup(name).ok_or(FsError::NotFound)?;
+    let hash = if password.is_empty() { String::new() } else { hash_password(password) };
+    privileged(|| {
+        let mut out = String::new();
+        let mut found = false;
+        for r in records("/etc/shadow") {
+            if r[0] == name { out += &format!("{}:{}\n", name, hash); found = true; }
+            else { out += &r.join(":"); out.push('\n'); }
+        }
+        if !found { out += &format!("{}:{}\n", name, hash); }
+        fs::write("/etc/shadow", out.as_bytes())?;
+        fs::chmod("/etc/shadow", 0o600)
+    })
+}
+
+/// Make `user` the owner of the running task, creating their home directory on first login.
+pub fn login(user: &User) {
+    if !user.home.is_empty() && fs::stat(&user.home).is_err() && fs::vfs::mkdir_all(&user.home).is_ok() {
+        let _ = fs::chown(&user.home, user.uid, user.gid);
+        let _ = fs::chmod(&user.home, 0o700);
+    }
+    crate::scheduler::set_creds(user.creds());
+}
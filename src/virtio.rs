@@ -0,0 +1,271 @@
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{fence, Ordering};
+use x86_64::instructions::port::Port;
+use crate::pci::{Bar, PciDevice};
+
+pub const VENDOR: u16 = 0x1AF4;
+
+pub const STATUS_ACK: u8 = 1;
+pub const STATUS_DRIVER: u8 = 2;
+pub const STATUS_DRIVER_OK: u8 = 4;
+pub const STATUS_FEATURES_OK: u8 = 8;
+pub const STATUS_FAILED: u8 = 0x80;
+
+pub const F_VERSION_1: u64 = 1 << 32;
+
+pub const DESC_NEXT: u16 = 1;
+pub const DESC_WRITE: u16 = 2; // device writes into this buffer
+
+// Legacy (virtio 0.9.5) I/O register offsets
+const L_DEVICE_FEATURES: u16 = 0;
+const L_DRIVER_FEATURES: u16 = 4;
+const L_QUEUE_PFN: u16 = 8;
+const L_QUEUE_SIZE: u16 = 12;
+const L_QUEUE_SELECT: u16 = 14;
+const L_QUEUE_NOTIFY: u16 = 16;
+const L_STATUS: u16 = 18;
+const L_ISR: u16 = 19;
+const L_CONFIG: u16 = 20;
+
+// Modern (virtio 1.0) common configuration offsets
+const C_DEVICE_FEATURE_SELECT: usize = 0;
+const C_DEVICE_FEATURE: usize = 4;
+const C_DRIVER_FEATURE_SELECT: usize = 8;
+const C_DRIVER_FEATURE: usize = 12;
+const C_STATUS: usize = 20;
+const C_QUEUE_SELECT: usize = 22;
+const C_QUEUE_SIZE: usize = 24;
+const C_QUEUE_ENABLE: usize = 28;
+const C_QUEUE_NOTIFY_OFF: usize = 30;
+const C_QUEUE_DESC: usize = 32;
+const C_QUEUE_DRIVER: usize = 40;
+const C_QUEUE_DEVICE: usize = 48;
+
+/// Where a device's registers live: the legacy I/O BAR or the modern capability regions.
+pub enum Transport {
+    Legacy { io: u16 },
+    Modern { common: *mut u8, notify: *mut u8, notify_mul: u32, isr: *mut u8, device: *mut u8, queue_notify: *mut u16 },
+}
+
+// The MMIO pointers are only touched by whoever owns the driver.
+unsafe impl Send for Transport {}
+
+/// Something an interrupt handler can read to acknowledge a device.
+#[derive(Clone, Copy)]
+pub enum Isr { Io(u16), Mmio(usize) }
+
+impl Isr {
+    /// Reading the ISR status clears it and deasserts the interrupt line.
+    pub fn ack(self) -> u8 {
+        match self {
+            Isr::Io(port) => unsafe { Port::<u8>::new(port).read() },
+            Isr::Mmio(p) => unsafe { read_volatile(p as *const u8) },
+        }
+    }
+}
+
+unsafe fn rd<T: Copy>(base: *mut u8, off: usize) -> T { read_volatile(base.add(off) as *const T) }
+unsafe fn wr<T: Copy>(base: *mut u8, off: usize, v: T) { write_volatile(base.add(off) as *mut T, v) }
+
+impl Transport {
+    /// Prefer the modern interface when the device advertises one.
+    pub fn probe(dev: &PciDevice) -> Option<Self> {
+        let (mut common, mut notify, mut isr, mut device, mut notify_mul) = (None, None, None, None, 0);
+        for cap in dev.capabilities(0x09) {
+            let cfg_type = (dev.read(cap) >> 24) as u8;
+            let bar = dev.read(cap + 4) as u8;
+            let (off, len) = (dev.read(cap + 8) as u64, dev.read(cap + 12) as usize);
+            let Bar::Mem(base) = dev.bar(bar) else { continue };
+            let Some(ptr) = crate::mm::map_mmio(base + off, len.max(1)) else { continue };
+            match cfg_type {
+                1 => common = Some(ptr),
+                2 => { notify = Some(ptr); notify_mul = dev.read(cap + 16); }
+                3 => isr = Some(ptr),
+                4 => device = Some(ptr),
+                _ => {}
+            }
+        }
+        if let (Some(common), Some(notify), Some(isr)) = (common, notify, isr) {
+            let device = device.unwrap_or(core::ptr::null_mut());
+            return Some(Transport::Modern { common, notify, notify_mul, isr, device, queue_notify: notify as *mut u16 });
+        }
+        match dev.bar(0) { Bar::Io(io) => Some(Transport::Legacy { io }), _ => None }
+    }
+
+    pub fn is_modern(&self) -> bool { matches!(self, Transport::Modern { .. }) }
+
+    pub fn isr(&self) -> Isr {
+        match self { Transport::Legacy { io } => Isr::Io(io + L_ISR), Transport::Modern { isr, .. } => Isr::Mmio(*isr as usize) }
+    }
+
+    pub fn status(&self) -> u8 {
+        match self {
+            Transport::Legacy { io } => unsafe { Port::<u8>::new(io + L_STATUS).read() },
+            Transport::Modern { common, .. } => unsafe { rd(*common, C_STATUS) },
+        }
+    }
+
+    pub fn set_status(&mut self, s: u8) {
+        match self {
+            Transport::Legacy { io } => unsafe { Port::<u8>::new(*io + L_STATUS).write(s) },
+            Transport::Modern { common, .. } => unsafe { wr(*common, C_STATUS, s) },
+        }
+    }
+
+    pub fn device_features(&self) -> u64 {
+        match self {
+            Transport::Legacy { io } => unsafe { Port::<u32>::new(io + L_DEVICE_FEATURES).read() as u64 },
+            Transport::Modern { common, .. } => unsafe {
+                wr(*common, C_DEVICE_FEATURE_SELECT, 0u32);
+                let lo: u32 = rd(*common, C_DEVICE_FEATURE);
+                wr(*common, C_DEVICE_FEATURE_SELECT, 1u32);
+                let hi: u32 = rd(*common, C_DEVICE_FEATURE);
+                lo as u64 | (hi as u64) << 32
+            },
+        }
+    }
+
+    pub fn set_driver_features(&mut self, f: u64) {
+        match self {
+            Transport::Legacy { io } => unsafe { Port::<u32>::new(*io + L_DRIVER_FEATURES).write(f as u32) },
+            Transport::Modern { common, .. } => unsafe {
+                wr(*common, C_DRIVER_FEATURE_SELECT, 0u32);
+                wr(*common, C_DRIVER_FEATURE, f as u32);
+                wr(*common, C_DRIVER_FEATURE_SELECT, 1u32);
+                wr(*common, C_DRIVER_FEATURE, (f >> 32) as u32);
+            },
+        }
+    }
+
+    /// Reset, acknowledge and negotiate `wanted` features. Returns the accepted set.
+    pub fn begin_init(&mut self, wanted: u64) -> Option<u64> {
+        self.set_status(0);
+        self.set_status(STATUS_ACK);
+        self.set_status(STATUS_ACK | STATUS_DRIVER);
+        let wanted = if self.is_modern() { wanted | F_VERSION_1 } else { wanted & 0xFFFF_FFFF };
+        let features = self.device_features() & wanted;
+        if self.is_modern() && features & F_VERSION_1 == 0 { self.set_status(STATUS_FAILED); return None; }
+        self.set_driver_features(features);
+        if self.is_modern() {
+            self.set_status(STATUS_ACK | STATUS_DRIVER | STATUS_FEATURES_OK);
+            if self.status() & STATUS_FEATURES_OK == 0 { self.set_status(STATUS_FAILED); return None; }
+        }
+        Some(features)
+    }
+
+    pub fn finish_init(&mut self) {
+        let s = self.status();
+        self.set_status(s | STATUS_DRIVER_OK);
+    }
+
+    /// Largest size the device allows for queue `q` (0 if absent).
+    pub fn max_queue_size(&mut self, q: u16) -> u16 {
+        match self {
+            Transport::Legacy { io } => unsafe {
+                Port::<u16>::new(*io + L_QUEUE_SELECT).write(q);
+                Port::<u16>::new(*io + L_QUEUE_SIZE).read()
+            },
+            Transport::Modern { common, .. } => unsafe {
+                wr(*common, C_QUEUE_SELECT, q);
+                rd(*common, C_QUEUE_SIZE)
+            },
+        }
+    }
+
+    /// Hand queue `q` to the device.
+    pub fn setup_queue(&mut self, q: u16, vq: &Virtqueue) {
+        match self {
+            Transport::Legacy { io } => unsafe {
+                Port::<u16>::new(*io + L_QUEUE_SELECT).write(q);
+                Port::<u32>::new(*io + L_QUEUE_PFN).write((vq.phys >> 12) as u32);
+            },
+            Transport::Modern { common, notify, notify_mul, queue_notify, .. } => unsafe {
+                wr(*common, C_QUEUE_SELECT, q);
+                wr(*common, C_QUEUE_SIZE, vq.size);
+                wr(*common, C_QUEUE_DESC, vq.phys);
+                wr(*common, C_QUEUE_DRIVER, vq.phys + vq.avail_off as u64);
+                wr(*common, C_QUEUE_DEVICE, vq.phys + vq.used_off as u64);
+                let off: u16 = rd(*common, C_QUEUE_NOTIFY_OFF);
+                *queue_notify = notify.add(off as usize * *notify_mul as usize) as *mut u16;
+                wr(*common, C_QUEUE_ENABLE, 1u16);
+            },
+        }
+    }
+
+    /// Tell the device queue `q` has new buffers. Modern devices only support the last queue set up.
+    pub fn notify(&mut self, q: u16) {
+        match self {
+            Transport::Legacy { io } => unsafe { Port::<u16>::new(*io + L_QUEUE_NOTIFY).write(q) },
+            Transport::Modern { queue_notify, .. } => unsafe { write_volatile(*queue_notify, q) },
+        }
+    }
+
+    pub fn config_u32(&self, off: usize) -> u32 {
+        match self {
+            Transport::Legacy { io } => unsafe { Port::<u32>::new(io + L_CONFIG + off as u16).read() },
+            Transport::Modern { device, .. } if !device.is_null() => unsafe { rd(*device, off) },
+            _ => 0,
+        }
+    }
+
+    pub fn config_u64(&self, off: usize) -> u64 {
+        self.config_u32(off) as u64 | (self.config_u32(off + 4) as u64) << 32
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Desc { addr: u64, len: u32, flags: u16, next: u16 }
+
+/// A split virtqueue in one DMA allocation, laid out the way legacy devices require:
+/// descriptors, then the available ring, then the used ring on the next page boundary.
+pub struct Virtqueue {
+    pub size: u16,
+    phys: u64,
+    base: *mut u8,
+    avail_off: usize,
+    used_off: usize,
+    avail_idx: u16,
+    last_used: u16,
+}
+
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    pub fn new(size: u16) -> Option<Self> {
+        let n = size as usize;
+        let avail_off = 16 * n;
+        let used_off = (avail_off + 6 + 2 * n + 4095) & !4095;
+        let pages = (used_off + 6 + 8 * n + 4095) / 4096;
+        let (phys, base) = crate::mm::alloc_dma(pages)?;
+        Some(Self { size, phys, base, avail_off, used_off, avail_idx: 0, last_used: 0 })
+    }
+
+    pub fn set_desc(&mut self, i: u16, addr: u64, len: u32, flags: u16, next: u16) {
+        let d = Desc { addr, len, flags, next };
+        unsafe { write_volatile((self.base as *mut Desc).add(i as usize), d); }
+    }
+
+    /// Queue the chain starting at descriptor `head`; the device sees it after `publish`.
+    pub fn push(&mut self, head: u16) {
+        let slot = self.avail_off + 4 + 2 * (self.avail_idx % self.size) as usize;
+        unsafe { wr(self.base, slot, head); }
+        self.avail_idx = self.avail_idx.wrapping_add(1);
+    }
+
+    pub fn publish(&mut self) {
+        fence(Ordering::SeqCst);
+        unsafe { wr(self.base, self.avail_off + 2, self.avail_idx); }
+        fence(Ordering::SeqCst);
+    }
+
+    /// Next completed chain as (head descriptor, bytes written).
+    pub fn pop_used(&mut self) -> Option<(u32, u32)> {
+        let idx: u16 = unsafe { rd(self.base, self.used_off + 2) };
+        if idx == self.last_used { return None; }
+        fence(Ordering::SeqCst);
+        let e = self.used_off + 4 + 8 * (self.last_used % self.size) as usize;
+        self.last_used = self.last_used.wrapping_add(1);
+        unsafe { Some((rd(self.base, e), rd(self.base, e + 4))) }
+    }
+}
@@ -0,0 +1,195 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use crate::block::{BlockDevice, BlockError};
+use crate::virtio::{Isr, Transport, Virtqueue, DESC_NEXT, DESC_WRITE};
+
+const F_RO: u64 = 1 << 5;
+const F_FLUSH: u64 = 1 << 9;
+
+const T_IN: u32 = 0;
+const T_OUT: u32 = 1;
+const T_FLUSH: u32 = 4;
+
+// Requests in flight at once; each has a 3-descriptor chain and its own bounce buffer.
+const SLOTS: usize = 8;
+const SLOT_BYTES: usize = 32 * 1024;
+
+const QUEUE_MAX: u16 = 128;
+
+#[repr(C)]
+struct ReqHeader { kind: u32, reserved: u32, sector: u64 }
+
+struct Slot {
+    hdr_phys: u64,
+    hdr: *mut ReqHeader,
+    status_phys: u64,
+    status: *mut u8,
+    buf_phys: u64,
+    buf: *mut u8,
+}
+
+pub struct VirtioBlk {
+    t: Transport,
+    q: Virtqueue,
+    slots: Vec<Slot>,
+    sectors: u64,
+    read_only: bool,
+    can_flush: bool,
+}
+
+unsafe impl Send for VirtioBlk {}
+
+// ISR registers of every virtio-blk device; the shared IRQ handler acknowledges them all.
+static ISRS: Mutex<Vec<Isr>> = Mutex::new(Vec::new());
+static IRQS: AtomicUsize = AtomicUsize::new(0);
+
+fn on_irq() {
+    for isr in ISRS.lock().iter() { isr.ack(); }
+    IRQS.fetch_add(1, Ordering::Relaxed);
+}
+
+impl VirtioBlk {
+    pub fn new(dev: &crate::pci::PciDevice) -> Option<Self> {
+        dev.enable();
+        let mut t = Transport::probe(dev)?;
+        let features = t.begin_init(F_RO | F_FLUSH)?;
+        let max = t.max_queue_size(0);
+        if max == 0 { return None; }
+        // legacy devices dictate the queue size, modern ones let us shrink it
+        let size = if t.is_modern() { max.min(QUEUE_MAX) } else { max };
+        if (size as usize) < SLOTS * 3 { return None; }
+        let q = Virtqueue::new(size)?;
+        t.setup_queue(0, &q);
+
+        // one page holds every header and status byte; bounce buffers follow
+        let (meta_phys, meta) = crate::mm::alloc_dma(1)?;
+        let mut slots = Vec::with_capacity(SLOTS);
+        for i in 0..SLOTS {
+            let (buf_phys, buf) = crate::mm::alloc_dma(SLOT_BYTES / 4096)?;
+            let at = i * 32;
+            slots.push(Slot {
+                hdr_phys: meta_phys + at as u64,
+                hdr: unsafe { meta.add(at) } as *mut ReqHeader,
+                status_phys: meta_phys + at as u64 + 16,
+                status: unsafe { meta.add(at + 16) },
+                buf_phys,
+                buf,
+            });
+        }
+
+        let line = dev.irq_line();
+        crate::interrupts::register_irq(line, on_irq);
+        x86_64::instructions::interrupts::without_interrupts(|| ISRS.lock().push(t.isr()));
+        t.finish_init();
+        let sectors = t.config_u64(0);
+        Some(Self { t, q, slots, sectors, read_only: features & F_RO != 0, can_flush: features & F_FLUSH != 0 })
+    }
+
+    /// Submit `reqs` as (type, sector, bytes) using slots 0.., notify once and wait for all of them.
+    fn batch(&mut self, reqs: &[(u32, u64, usize)]) -> Result<(), BlockError> {
+        for (i, &(kind, sector, len)) in reqs.iter().enumerate() {
+            let s = &self.slots[i];
+            unsafe {
+                s.hdr.write_volatile(ReqHeader { kind, reserved: 0, sector });
+                s.status.write_volatile(0xFF);
+            }
+            let (hdr_phys, buf_phys, status_phys) = (s.hdr_phys, s.buf_phys, s.status_phys);
+            let d = (i * 3) as u16;
+            if len > 0 {
+                let flags = DESC_NEXT | if kind == T_IN { DESC_WRITE } else { 0 };
+                self.q.set_desc(d, hdr_phys, 16, DESC_NEXT, d + 1);
+                self.q.set_desc(d + 1, buf_phys, len as u32, flags, d + 2);
+            } else {
+                self.q.set_desc(d, hdr_phys, 16, DESC_NEXT, d + 2);
+            }
+            self.q.set_desc(d + 2, status_phys, 1, DESC_WRITE, 0);
+            self.q.push(d);
+        }
+        self.q.publish();
+        self.t.notify(0);
+
+        // completions raise an interrupt, which also wakes us from hlt
+        let mut pending = reqs.len();
+        let deadline = crate::pit::ticks() + crate::pit::hz() * 5;
+        let mut spins = 0u64;
+        while pending > 0 {
+            while self.q.pop_used().is_some() { pending -= 1; }
+            if pending == 0 { break; }
+            if x86_64::instructions::interrupts::are_enabled() {
+                if crate::pit::ticks() > deadline { return Err(BlockError::Timeout); }
+                x86_64::instructions::hlt();
+            } else {
+                spins += 1;
+                if spins > 200_000_000 { return Err(BlockError::Timeout); }
+                core::hint::spin_loop();
+            }
+        }
+        for s in &self.slots[..reqs.len()] {
+            if unsafe { s.status.read_volatile() } != 0 { return Err(BlockError::Io); }
+        }
+        Ok(())
+    }
+
+    fn check(&self, lba: u64, len: usize) -> Result<(), BlockError> {
+        if len % 512 != 0 { return Err(BlockError::BadBuffer); }
+        if lba + (len / 512) as u64 > self.sectors { return Err(BlockError::OutOfRange); }
+        Ok(())
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize { 512 }
+    fn capacity(&self) -> u64 { self.sectors }
+
+    fn read_blocks(&mut self, lba: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        self.check(lba, buf.len())?;
+        let mut sector = lba;
+        for group in buf.chunks_mut(SLOTS * SLOT_BYTES) {
+            let reqs: Vec<_> = group.chunks(SLOT_BYTES).enumerate()
+                .map(|(i, c)| (T_IN, sector + (i * SLOT_BYTES / 512) as u64, c.len())).collect();
+            self.batch(&reqs)?;
+            for (i, c) in group.chunks_mut(SLOT_BYTES).enumerate() {
+                unsafe { core::ptr::copy_nonoverlapping(self.slots[i].buf, c.as_mut_ptr(), c.len()); }
+            }
+            sector += (group.len() / 512) as u64;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, lba: u64, buf: &[u8]) -> Result<(), BlockError> {
+        if self.read_only { return Err(BlockError::ReadOnly); }
+        self.check(lba, buf.len())?;
+        let mut sector = lba;
+        for group in buf.chunks(SLOTS * SLOT_BYTES) {
+            for (i, c) in group.chunks(SLOT_BYTES).enumerate() {
+                unsafe { core::ptr::copy_nonoverlapping(c.as_ptr(), self.slots[i].buf, c.len()); }
+            }
+            let reqs: Vec<_> = group.chunks(SLOT_BYTES).enumerate()
+                .map(|(i, c)| (T_OUT, sector + (i * SLOT_BYTES / 512) as u64, c.len())).collect();
+            self.batch(&reqs)?;
+            sector += (group.len() / 512) as u64;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        if !self.can_flush { return Ok(()); }
+        self.batch(&[(T_FLUSH, 0, 0)])
+    }
+}
+
+/// Find virtio-blk devices (transitional 0x1001 or modern 0x1042) and register them as `vda`, `vdb`, ...
+pub fn init() {
+    let mut found = Vec::new();
+    crate::pci::enumerate(|dev| {
+        if dev.vendor == crate::virtio::VENDOR && (dev.device == 0x1001 || dev.device == 0x1042) { found.push(dev); }
+    });
+    for (i, dev) in found.iter().enumerate().take(26) {
+        let Some(blk) = VirtioBlk::new(dev) else { continue };
+        let name = alloc::format!("vd{}", (b'a' + i as u8) as char);
+        crate::serial_println!("virtio-blk: {} {} sectors{}", name, blk.sectors, if blk.read_only { " (ro)" } else { "" });
+        crate::block::register(&name, Box::new(blk));
+    }
+}
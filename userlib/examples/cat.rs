@@ -0,0 +1,16 @@
+#![no_std]
+#![no_main]
+
+use waemom_userlib::io;
+
+// Copy stdin to stdout until end of input.
+#[no_mangle]
+fn main() -> i32 {
+    let mut buf = [0u8; 512];
+    loop {
+        let n = io::read(io::STDIN, &mut buf);
+        if n < 0 { return 1; }
+        if n == 0 { return 0; }
+        if !io::write_all(io::STDOUT, &buf[..n as usize]) { return 1; }
+    }
+}
@@ -0,0 +1,10 @@
+#![no_std]
+#![no_main]
+
+use waemom_userlib::println;
+
+#[no_mangle]
+fn main() -> i32 {
+    println!("Hello from userland! uptime={}s", waemom_userlib::uptime_secs());
+    0
+}
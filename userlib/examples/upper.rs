@@ -0,0 +1,21 @@
+#![no_std]
+#![no_main]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use waemom_userlib::io;
+
+// Upper-case stdin; handy at the end of a shell pipeline.
+#[no_mangle]
+fn main() -> i32 {
+    let mut all = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let n = io::read(io::STDIN, &mut buf);
+        if n <= 0 { break; }
+        all.extend_from_slice(&buf[..n as usize]);
+    }
+    all.make_ascii_uppercase();
+    if io::write_all(io::STDOUT, &all) { 0 } else { 1 }
+}
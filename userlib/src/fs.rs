@@ -0,0 +1,120 @@
+use crate::io;
+use crate::sys::*;
+
+// open(2) flags; same values as the kernel's fs module
+pub const O_RDONLY: u32 = 0;
+pub const O_WRONLY: u32 = 1;
+pub const O_RDWR: u32 = 2;
+pub const O_CREAT: u32 = 0x40;
+pub const O_TRUNC: u32 = 0x200;
+pub const O_APPEND: u32 = 0x400;
+
+/// Where `File::seek` measures from.
+pub enum SeekFrom { Start(u64), Current(i64), End(i64) }
+
+/// An open file; closed on drop.
+pub struct File(i32);
+
+impl File {
+    pub fn open(path: &str, flags: u32) -> Option<Self> {
+        let fd = unsafe { syscall3(SYS_OPEN, path.as_ptr() as u64, path.len() as u64, flags as u64) };
+        if fd < 0 { None } else { Some(File(fd as i32)) }
+    }
+
+    pub fn create(path: &str) -> Option<Self> { Self::open(path, O_WRONLY | O_CREAT | O_TRUNC) }
+
+    pub fn fd(&self) -> i32 { self.0 }
+
+    pub fn read(&mut self, buf: &mut [u8]) -> isize { io::read(self.0, buf) }
+
+    pub fn write(&mut self, buf: &[u8]) -> isize { io::write(self.0, buf) }
+
+    /// Move the file position; returns the new offset or a negative value on error.
+    pub fn seek(&mut self, pos: SeekFrom) -> i64 {
+        let (off, whence) = match pos { SeekFrom::Start(n) => (n as i64, 0), SeekFrom::Current(d) => (d, 1), SeekFrom::End(d) => (d, 2) };
+        unsafe { syscall3(SYS_LSEEK, self.0 as u64, off as u64, whence) }
+    }
+
+    /// Read until end of file.
+    pub fn read_to_end(&mut self, out: &mut alloc::vec::Vec<u8>) -> isize {
+        let mut buf = [0u8; 512];
+        let start = out.len();
+        loop {
+            let n = self.read(&mut buf);
+            if n < 0 { return n; }
+            if n == 0 { return (out.len() - start) as isize; }
+            out.extend_from_slice(&buf[..n as usize]);
+        }
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) { io::close(self.0); }
+}
+
+/// Read a whole file into memory.
+pub fn read(path: &str) -> Option<alloc::vec::Vec<u8>> {
+    let mut f = File::open(path, O_RDONLY)?;
+    let mut v = alloc::vec::Vec::new();
+    if f.read_to_end(&mut v) < 0 { None } else { Some(v) }
+}
+
+pub fn write(path: &str, data: &[u8]) -> bool {
+    File::create(path).is_some_and(|f| io::write_all(f.0, data))
+}
+
+/// SHA-256 of a file's contents. Files from the initrd carry theirs, so this needn't read them.
+pub fn sha256(path: &str) -> Option<[u8; 32]> {
+    let mut digest = [0u8; 32];
+    let r = unsafe { syscall3(SYS_SHA256, path.as_ptr() as u64, path.len() as u64, digest.as_mut_ptr() as u64) };
+    if r < 0 { None } else { Some(digest) }
+}
+
+// Change event bits for `Watch`; same values as the kernel's fs::watch
+pub const CREATE: u32 = 1 << 0;
+pub const MODIFY: u32 = 1 << 1;
+pub const DELETE: u32 = 1 << 2;
+pub const RENAME: u32 = 1 << 3;
+
+/// One change reported by a `Watch`.
+pub struct Event {
+    pub kind: u32,
+    pub path: alloc::string::String,
+    pub from: alloc::string::String, // old name of a RENAME, empty otherwise
+}
+
+/// Change events for a path, or for the entries of a directory; removed on drop.
+pub struct Watch { fd: i32, buf: alloc::vec::Vec<u8>, pos: usize }
+
+impl Watch {
+    pub fn new(path: &str, mask: u32) -> Option<Self> {
+        let fd = unsafe { syscall3(SYS_WATCH, path.as_ptr() as u64, path.len() as u64, mask as u64) };
+        if fd < 0 { None } else { Some(Watch { fd: fd as i32, buf: alloc::vec::Vec::new(), pos: 0 }) }
+    }
+}
+
+impl Iterator for Watch {
+    type Item = Event;
+
+    /// Wait for the next event; None if the watch is gone.
+    fn next(&mut self) -> Option<Event> {
+        if self.pos >= self.buf.len() {
+            self.buf.resize(4096, 0);
+            let n = io::read(self.fd, &mut self.buf);
+            if n <= 0 { return None; }
+            self.buf.truncate(n as usize);
+            self.pos = 0;
+        }
+        let b = &self.buf[self.pos..];
+        let word = |i: usize| u32::from_le_bytes([b[i], b[i + 1], b[i + 2], b[i + 3]]) as usize;
+        let (kind, plen, flen) = (word(0) as u32, word(4), word(8));
+        let text = |r: core::ops::Range<usize>| alloc::string::String::from_utf8_lossy(&b[r]).into_owned();
+        let e = Event { kind, path: text(12..12 + plen), from: text(12 + plen..12 + plen + flen) };
+        self.pos += 12 + plen + flen;
+        Some(e)
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) { io::close(self.fd); }
+}
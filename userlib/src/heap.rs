@@ -0,0 +1,55 @@
+use core::alloc::{GlobalAlloc, Layout};
+use core::ptr::null_mut;
+use crate::sys::*;
+
+// Small blocks come from a brk-grown arena and are recycled through per-size-class
+// free lists; anything above MAX_CLASS gets its own mmap region.
+const MIN_CLASS: usize = 16;
+const MAX_CLASS: usize = 64 * 1024;
+const CLASSES: usize = 13; // 16 B ..= 64 KiB
+
+pub struct UserAlloc;
+
+struct State { brk: usize, end: usize, free: [*mut u8; CLASSES] }
+
+static mut STATE: State = State { brk: 0, end: 0, free: [null_mut(); CLASSES] };
+
+fn class_of(size: usize) -> usize { size.max(MIN_CLASS).next_power_of_two().trailing_zeros() as usize - 4 }
+
+unsafe fn grow(st: &mut State, need: usize) -> bool {
+    let want = st.end + need.max(64 * 1024);
+    let got = syscall1(SYS_BRK, want as u64) as usize;
+    if got < want { return false; }
+    st.end = got;
+    true
+}
+
+unsafe impl GlobalAlloc for UserAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let size = layout.size().max(layout.align());
+        if size > MAX_CLASS {
+            let p = syscall1(SYS_MMAP, size as u64);
+            return if p <= 0 { null_mut() } else { p as *mut u8 };
+        }
+        let st = &mut *core::ptr::addr_of_mut!(STATE);
+        let c = class_of(size);
+        let head = st.free[c];
+        if !head.is_null() { st.free[c] = *(head as *mut *mut u8); return head; }
+        if st.brk == 0 { st.brk = syscall1(SYS_BRK, 0) as usize; st.end = st.brk; }
+        // power-of-two blocks aligned to their own size satisfy any align <= size
+        let bsize = MIN_CLASS << c;
+        let start = (st.brk + bsize - 1) & !(bsize - 1);
+        if start + bsize > st.end && !grow(st, start + bsize - st.end) { return null_mut(); }
+        st.brk = start + bsize;
+        start as *mut u8
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        let size = layout.size().max(layout.align());
+        if size > MAX_CLASS { return; } // no munmap yet
+        let st = &mut *core::ptr::addr_of_mut!(STATE);
+        let c = class_of(size);
+        *(ptr as *mut *mut u8) = st.free[c];
+        st.free[c] = ptr;
+    }
+}
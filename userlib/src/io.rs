@@ -0,0 +1,60 @@
+use core::fmt;
+use crate::sys::*;
+
+pub const STDIN: i32 = 0;
+pub const STDOUT: i32 = 1;
+pub const STDERR: i32 = 2;
+
+pub fn read(fd: i32, buf: &mut [u8]) -> isize {
+    unsafe { syscall3(SYS_READ, fd as u64, buf.as_mut_ptr() as u64, buf.len() as u64) as isize }
+}
+
+pub fn write(fd: i32, buf: &[u8]) -> isize {
+    unsafe { syscall3(SYS_WRITE, fd as u64, buf.as_ptr() as u64, buf.len() as u64) as isize }
+}
+
+pub fn close(fd: i32) -> isize { unsafe { syscall1(SYS_CLOSE, fd as u64) as isize } }
+
+pub fn dup2(old: i32, new: i32) -> isize { unsafe { syscall3(SYS_DUP2, old as u64, new as u64, 0) as isize } }
+
+/// Returns (read end, write end).
+pub fn pipe() -> Option<(i32, i32)> {
+    let mut fds = [0i32; 2];
+    let r = unsafe { syscall1(SYS_PIPE, fds.as_mut_ptr() as u64) };
+    if r < 0 { None } else { Some((fds[0], fds[1])) }
+}
+
+/// Write all of `buf`, retrying short writes.
+pub fn write_all(fd: i32, mut buf: &[u8]) -> bool {
+    while !buf.is_empty() {
+        let n = write(fd, buf);
+        if n <= 0 { return false; }
+        buf = &buf[n as usize..];
+    }
+    true
+}
+
+pub struct Stdout;
+
+impl fmt::Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if write_all(STDOUT, s.as_bytes()) { Ok(()) } else { Err(fmt::Error) }
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    let _ = Stdout.write_fmt(args);
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::io::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::io::_print(format_args!("{}\n", format_args!($($arg)*))));
+}
@@ -0,0 +1,73 @@
+use crate::sys::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error { BadHandle, NoRights, NotFound, Exists, Closed, TooLarge, Unknown }
+
+fn check(r: i64) -> Result<u64, Error> {
+    match r {
+        r if r >= 0 => Ok(r as u64),
+        -1 => Err(Error::BadHandle),
+        -2 => Err(Error::NoRights),
+        -3 => Err(Error::NotFound),
+        -4 => Err(Error::Exists),
+        -5 => Err(Error::Closed),
+        -6 => Err(Error::TooLarge),
+        _ => Err(Error::Unknown),
+    }
+}
+
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+pub struct RecvInfo { pub msg_id: u64, pub sender: u64, pub len: u64, pub needs_reply: u64 }
+
+/// A capability handle to an IPC endpoint.
+pub struct Port(u64);
+
+impl Port {
+    /// Create and own a named endpoint.
+    pub fn create(name: &str) -> Result<Self, Error> {
+        check(unsafe { syscall5(PORT_CREATE, name.as_ptr() as u64, name.len() as u64, 0, 0, 0) }).map(Port)
+    }
+
+    /// Connect to an endpoint someone else created.
+    pub fn open(name: &str) -> Result<Self, Error> {
+        check(unsafe { syscall5(PORT_OPEN, name.as_ptr() as u64, name.len() as u64, 0, 0, 0) }).map(Port)
+    }
+
+    pub fn send(&self, msg: &[u8]) -> Result<(), Error> {
+        check(unsafe { syscall5(PORT_SEND, self.0, msg.as_ptr() as u64, msg.len() as u64, 0, 0) }).map(|_| ())
+    }
+
+    /// Send and wait for the reply; returns the number of reply bytes.
+    pub fn call(&self, msg: &[u8], reply: &mut [u8]) -> Result<usize, Error> {
+        check(unsafe {
+            syscall5(PORT_CALL, self.0, msg.as_ptr() as u64, msg.len() as u64, reply.as_mut_ptr() as u64, reply.len() as u64)
+        }).map(|n| n as usize)
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> Result<RecvInfo, Error> {
+        let mut info = RecvInfo::default();
+        check(unsafe {
+            syscall5(PORT_RECV, self.0, buf.as_mut_ptr() as u64, buf.len() as u64, &mut info as *mut RecvInfo as u64, 0)
+        }).map(|_| info)
+    }
+
+    pub fn notify(&self, bits: u64) -> Result<(), Error> {
+        check(unsafe { syscall5(PORT_NOTIFY, self.0, bits, 0, 0, 0) }).map(|_| ())
+    }
+
+    /// Block until notified; returns the accumulated bits.
+    pub fn wait(&self) -> Result<u64, Error> {
+        let mut bits = 0u64;
+        check(unsafe { syscall5(PORT_WAIT, self.0, &mut bits as *mut u64 as u64, 0, 0, 0) }).map(|_| bits)
+    }
+}
+
+impl Drop for Port {
+    fn drop(&mut self) { unsafe { syscall5(PORT_CLOSE, self.0, 0, 0, 0, 0); } }
+}
+
+/// Answer a message received with `needs_reply` set.
+pub fn reply(info: &RecvInfo, msg: &[u8]) -> Result<(), Error> {
+    check(unsafe { syscall5(PORT_REPLY, info.msg_id, msg.as_ptr() as u64, msg.len() as u64, 0, 0) }).map(|_| ())
+}
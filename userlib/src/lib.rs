@@ -1,11 +1,34 @@
 #![no_std]
 
-pub fn spawn(_name: &str) -> u64 { 0 }
-pub fn exit() -> ! { loop {} }
-pub fn sleep(_ticks: u64) {}
-
-pub mod net {
-    pub fn socket_udp(_port: u16) -> i32 { 0 }
-    pub fn send(_sock: i32, _buf: &[u8]) -> isize { _buf.len() as isize }
-    pub fn recv(_sock: i32, _buf: &mut [u8]) -> isize { 0 }
+extern crate alloc;
+
+mod sys;
+pub mod io;
+pub mod fs;
+pub mod net;
+/// Named message-passing endpoints (see the kernel's `ipc` module).
+pub mod ipc;
+pub mod heap;
+#[cfg(feature = "rt")]
+mod rt;
+
+use sys::*;
+
+/// Start `/path/to/elf` as a new task; returns its pid or 0 on failure.
+pub fn spawn(path: &str) -> u64 {
+    unsafe { syscall3(SYS_SPAWN, path.as_ptr() as u64, path.len() as u64, 0) as u64 }
+}
+
+pub fn exit(code: i32) -> ! {
+    unsafe { syscall1(SYS_EXIT, code as u64); }
+    loop { core::hint::spin_loop(); }
 }
+
+pub fn sleep(ticks: u64) { unsafe { syscall1(SYS_SLEEP, ticks); } }
+
+pub fn uptime_secs() -> u64 { unsafe { syscall1(SYS_UPTIME, 0) as u64 } }
+
+/// User id the task runs as (0 is root).
+pub fn getuid() -> u32 { unsafe { syscall1(SYS_GETUID, 0) as u32 } }
+
+pub fn getgid() -> u32 { unsafe { syscall1(SYS_GETGID, 0) as u32 } }
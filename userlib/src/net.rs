@@ -0,0 +1,30 @@
+use crate::io;
+use crate::sys::*;
+
+/// A bound UDP socket; closed on drop.
+pub struct UdpSocket(i32);
+
+impl UdpSocket {
+    pub fn bind(port: u16) -> Option<Self> {
+        let fd = unsafe { syscall1(SYS_SOCKET_UDP, port as u64) };
+        if fd < 0 { None } else { Some(UdpSocket(fd as i32)) }
+    }
+
+    pub fn send_to(&self, buf: &[u8], ip: [u8; 4], port: u16) -> isize {
+        unsafe {
+            syscall5(SYS_SENDTO, self.0 as u64, buf.as_ptr() as u64, buf.len() as u64, u32::from_be_bytes(ip) as u64, port as u64) as isize
+        }
+    }
+
+    /// Non-blocking; returns None when nothing is queued.
+    pub fn recv_from(&self, buf: &mut [u8]) -> Option<(usize, [u8; 4], u16)> {
+        let mut from = 0u64;
+        let n = unsafe { syscall5(SYS_RECVFROM, self.0 as u64, buf.as_mut_ptr() as u64, buf.len() as u64, &mut from as *mut u64 as u64, 0) };
+        if n <= 0 { return None; }
+        Some((n as usize, ((from >> 16) as u32).to_be_bytes(), from as u16))
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) { io::close(self.0); }
+}
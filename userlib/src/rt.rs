@@ -0,0 +1,21 @@
+// Program entry and panic handling for binaries linked against userlib.
+
+extern "Rust" {
+    // Provided by the program, e.g. `#[no_mangle] fn main() -> i32`.
+    fn main() -> i32;
+}
+
+#[no_mangle]
+pub extern "C" fn _start() -> ! {
+    let code = unsafe { main() };
+    crate::exit(code)
+}
+
+#[global_allocator]
+static ALLOC: crate::heap::UserAlloc = crate::heap::UserAlloc;
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    crate::println!("panic: {}", info);
+    crate::exit(101)
+}
@@ -0,0 +1,53 @@
+// Syscall numbers; must match `handle_syscall` in the kernel's syscalls.rs.
+pub const SYS_WRITE: u64 = 0;
+pub const SYS_SLEEP: u64 = 1;
+pub const SYS_EXIT: u64 = 2;
+pub const SYS_SPAWN: u64 = 3;
+pub const SYS_READ: u64 = 4;
+pub const SYS_PIPE: u64 = 5;
+pub const SYS_CLOSE: u64 = 6;
+pub const SYS_DUP2: u64 = 7;
+pub const PORT_CREATE: u64 = 8;
+pub const PORT_OPEN: u64 = 9;
+pub const PORT_SEND: u64 = 10;
+pub const PORT_CALL: u64 = 11;
+pub const PORT_RECV: u64 = 12;
+pub const PORT_REPLY: u64 = 13;
+pub const PORT_NOTIFY: u64 = 14;
+pub const PORT_WAIT: u64 = 15;
+pub const PORT_CLOSE: u64 = 16;
+pub const SYS_BRK: u64 = 17;
+pub const SYS_MMAP: u64 = 18;
+pub const SYS_OPEN: u64 = 19;
+pub const SYS_SOCKET_UDP: u64 = 20;
+pub const SYS_SENDTO: u64 = 21;
+pub const SYS_RECVFROM: u64 = 22;
+pub const SYS_UPTIME: u64 = 23;
+pub const SYS_LSEEK: u64 = 24;
+pub const SYS_GETUID: u64 = 25;
+pub const SYS_GETGID: u64 = 26;
+pub const SYS_WATCH: u64 = 27;
+pub const SYS_SHA256: u64 = 28;
+
+// Raw `syscall` instruction: nr in RAX, args in RDI, RSI, RDX, R10, R8. The kernel entry
+// shuffles the argument registers into a Rust call and doesn't restore them, so every
+// caller-saved register comes back clobbered.
+#[inline(always)]
+pub unsafe fn syscall5(nr: u64, a1: u64, a2: u64, a3: u64, a4: u64, a5: u64) -> i64 {
+    let ret: i64;
+    core::arch::asm!(
+        "syscall",
+        inlateout("rax") nr as i64 => ret,
+        inlateout("rdi") a1 => _, inlateout("rsi") a2 => _, inlateout("rdx") a3 => _,
+        inlateout("r10") a4 => _, inlateout("r8") a5 => _,
+        lateout("r9") _, lateout("rcx") _, lateout("r11") _,
+        options(nostack)
+    );
+    ret
+}
+
+#[inline(always)]
+pub unsafe fn syscall3(nr: u64, a1: u64, a2: u64, a3: u64) -> i64 { syscall5(nr, a1, a2, a3, 0, 0) }
+
+#[inline(always)]
+pub unsafe fn syscall1(nr: u64, a1: u64) -> i64 { syscall5(nr, a1, 0, 0, 0, 0) }